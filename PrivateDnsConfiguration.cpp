@@ -507,6 +507,10 @@ int PrivateDnsConfiguration::setDoh(int32_t netId, uint32_t mark,
                         getTimeoutFromFlag("doh_idle_timeout_ms", kDohIdleDefaultTimeoutMs),
                 .use_session_resumption =
                         Experiments::getInstance()->getFlag("doh_session_resumption", 0) == 1,
+                // Handshakes are left to the idle timeout, and res_doh_send() always passes
+                // doh_query() a timeout of its own.
+                .connect_timeout_ms = 0,
+                .query_timeout_ms = 0,
         };
         LOG(DEBUG) << __func__ << ": probe_timeout_ms=" << flags.probe_timeout_ms
                    << ", idle_timeout_ms=" << flags.idle_timeout_ms
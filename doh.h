@@ -54,6 +54,26 @@ struct FeatureFlags {
     uint64_t probe_timeout_ms;
     uint64_t idle_timeout_ms;
     bool use_session_resumption;
+    /// Longest a connection's handshake may take before it fails. 0 leaves it to the idle
+    /// timeout.
+    uint64_t connect_timeout_ms;
+    /// How long queries wait for an answer when `doh_query` is given a timeout of 0. 0 means
+    /// `DEFAULT_QUERY_TIMEOUT`.
+    uint64_t query_timeout_ms;
+    /// Interface to bind sockets to with `SO_BINDTODEVICE` rather than marking them with
+    /// `sk_mark`, as a null terminated string. Null or empty marks them.
+    const char* bind_device;
+    /// Whether connections use `socket_fd`, a UDP socket set up by the caller, rather than
+    /// creating their own sockets. `sk_mark` and `bind_device` are then ignored.
+    bool use_socket_fd;
+    /// Descriptor of the socket for `use_socket_fd`, which `doh_net_new` takes ownership of. It is
+    /// closed once the network is deleted, or probed again, and its connections are done with it,
+    /// or at once if it is unusable.
+    int32_t socket_fd;
+    /// Whether to send DNS messages straight over QUIC streams, as DNS over QUIC (RFC 9250) does,
+    /// rather than over HTTP/3, for interoperability experiments. The server must then offer the
+    /// `doq` ALPN protocol, and is reached on port 853 rather than 443.
+    bool use_dns_over_quic;
 };
 
 using ValidationCallback = void (*)(uint32_t net_id, bool success, const char* ip_addr,
@@ -88,14 +108,46 @@ void doh_dispatcher_delete(DohDispatcher* doh);
 /// # Safety
 /// `doh` must be a non-null pointer previously created by `doh_dispatcher_new()`
 /// and not yet deleted by `doh_dispatcher_delete()`.
-/// `url`, `domain`, `ip_addr`, `cert_path` are null terminated strings.
+/// `url`, `domain`, `ip_addr`, `cert_path` are null terminated strings, as is
+/// `flags.bind_device` unless it is null.
+/// If `flags.use_socket_fd` is set, `flags.socket_fd` must be an open descriptor which nothing
+/// else owns, as it is closed whatever this returns.
 int32_t doh_net_new(DohDispatcher* doh, uint32_t net_id, const char* url, const char* domain,
                     const char* ip_addr, uint32_t sk_mark, const char* cert_path,
                     const FeatureFlags* flags);
 
+/// Checks that the trust store at `cert_path` loads, for provisioning to reject a bad certificate
+/// directory at once. Only the certificates are loaded, and nothing is cached. As for
+/// `doh_net_new()`, an empty `cert_path` means the system trust store. Returns 0, or `-EINVAL` if
+/// the directory is missing or empty, or its certificates don't load.
+/// # Safety
+/// `cert_path` is a null terminated string.
+int32_t doh_validate_cert_path(const char* cert_path);
+
+/// Checks that the DoH server `doh_net_new()` would be given could be connected to as
+/// configured, without contacting it or caching anything, for configuration UIs to flag a bad
+/// setup early. The trust store at `cert_path` must load, and the settings in `flags` must be in
+/// range and consistent, such as `connect_timeout_ms` being shorter than `idle_timeout_ms`.
+/// The socket settings of `flags` are ignored, and `flags.socket_fd` is left to the caller.
+/// Returns 0, or `-EINVAL` for a setup `doh_net_new()` would fail to use.
+/// # Safety
+/// `url`, `domain`, `ip_addr`, `cert_path` are null terminated strings.
+int32_t doh_validate_config(const char* url, const char* domain, const char* ip_addr,
+                            const char* cert_path, const FeatureFlags* flags);
+
+#if defined(DOH_SELF_TEST)
+/// Sends a DNS query through the DoH encoding and connection driver that queries go through to
+/// an in-process server on the loopback interface, as a sanity check at boot which needs no
+/// network. Returns 0 if the answer came back intact, or `-EIO` with the reason logged.
+/// Only present in builds with the `self_test` feature, for which `DOH_SELF_TEST` must be
+/// defined to declare it.
+int32_t doh_self_test();
+#endif
+
 /// Sends a DNS query via the network associated to the given |net_id| and waits for the response.
 /// The return code should be either one of the public constant RESULT_* to indicate the error or
-/// the size of the answer.
+/// the size of the answer. A `timeout_ms` of 0 waits for the network's
+/// `FeatureFlags::query_timeout_ms`.
 /// # Safety
 /// `doh` must be a non-null pointer previously created by `doh_dispatcher_new()`
 /// and not yet deleted by `doh_dispatcher_delete()`.
@@ -110,4 +162,32 @@ ssize_t doh_query(DohDispatcher* doh, uint32_t net_id, uint8_t* dns_query, size_
 /// and not yet deleted by `doh_dispatcher_delete()`.
 void doh_net_delete(DohDispatcher* doh, uint32_t net_id);
 
+/// Fails all queries on the network with the given |net_id| immediately, and keeps failing new
+/// ones until a DoH server is probed for the network again.
+/// # Safety
+/// `doh` must be a non-null pointer previously created by `doh_dispatcher_new()`
+/// and not yet deleted by `doh_dispatcher_delete()`.
+void doh_net_lost(DohDispatcher* doh, uint32_t net_id);
+
+/// Releases memory the DoH engine can do without, for when the system is short of it. `level` is
+/// as passed to `ComponentCallbacks2.onTrimMemory`: from `TRIM_MEMORY_RUNNING_CRITICAL` up, idle
+/// connections are closed too, at the cost of a handshake on their network's next query.
+/// # Safety
+/// `doh` must be a non-null pointer previously created by `doh_dispatcher_new()`
+/// and not yet deleted by `doh_dispatcher_delete()`.
+void doh_trim_memory(DohDispatcher* doh, int32_t level);
+
+#if defined(DOH_METRICS_TEXT)
+/// Writes the dispatcher's counters, and those of its QUIC config cache, to `out` in the
+/// Prometheus text exposition format, for monitoring outside Android. Returns the size of the
+/// text. If that is more than `out_len`, or `out` is null, nothing is written, and the call should
+/// be repeated with a larger buffer. Only present in builds with the `metrics_text` feature, for
+/// which `DOH_METRICS_TEXT` must be defined to declare it.
+/// # Safety
+/// `doh` must be a non-null pointer previously created by `doh_dispatcher_new()`
+/// and not yet deleted by `doh_dispatcher_delete()`.
+/// `out` must point to a buffer at least `out_len` in size, or be null to only ask for the size.
+size_t doh_metrics_text(DohDispatcher* doh, uint8_t* out, size_t out_len);
+#endif
+
 }  // extern "C"
@@ -29,6 +29,9 @@ use std::convert::TryInto;
 use std::fmt;
 use std::future::Future;
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+#[cfg(test)]
+use std::sync::Mutex;
 use tokio::io::unix::AsyncFd;
 use tokio::select;
 
@@ -82,6 +85,12 @@ impl BootTime {
         Some(BootTime { d: self.d.checked_add(duration)? })
     }
 
+    /// Subtract a specified time delta from a moment in time. If this would go before boot,
+    /// returns `None`.
+    pub fn checked_sub(&self, duration: Duration) -> Option<BootTime> {
+        Some(BootTime { d: self.d.checked_sub(duration)? })
+    }
+
     /// Finds the difference from an earlier point in time. If the provided time is later, returns
     /// `None`.
     pub fn checked_duration_since(&self, earlier: BootTime) -> Option<Duration> {
@@ -89,6 +98,64 @@ impl BootTime {
     }
 }
 
+/// Source of the current time for deadline and elapsed-time decisions, so that they can be tested
+/// without waiting on the real clock. Timers (`sleep`, `timeout`) always run on `CLOCK_BOOTTIME`.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Gets the current moment in time.
+    fn now(&self) -> BootTime;
+
+    /// Determines how long has elapsed since `earlier`, or zero if it is in the future.
+    fn elapsed(&self, earlier: BootTime) -> Duration {
+        self.now().checked_duration_since(earlier).unwrap_or_default()
+    }
+}
+
+/// Shareable handle to a `Clock`
+pub type SharedClock = Arc<dyn Clock>;
+
+/// The `CLOCK_BOOTTIME` clock, as used in production.
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> BootTime {
+        BootTime::now()
+    }
+}
+
+/// Gets a handle to the `SystemClock`.
+pub fn system_clock() -> SharedClock {
+    Arc::new(SystemClock)
+}
+
+/// Clock which only moves when told to, for deterministic tests.
+#[cfg(test)]
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<BootTime>,
+}
+
+#[cfg(test)]
+impl MockClock {
+    /// Creates a clock stopped at the current moment.
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self { now: Mutex::new(BootTime::now()) })
+    }
+
+    /// Moves the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now = now.checked_add(duration).unwrap();
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> BootTime {
+        *self.now.lock().unwrap()
+    }
+}
+
 struct TimerFd(RawFd);
 
 impl Drop for TimerFd {
@@ -213,6 +280,21 @@ async fn timeout_drift() {
     }
 }
 
+#[test]
+fn mock_clock() {
+    let clock = MockClock::new();
+    let start = clock.now();
+    std::thread::sleep(Duration::from_millis(1));
+    assert_eq!(clock.now(), start);
+    clock.advance(Duration::from_secs(30));
+    assert_eq!(clock.elapsed(start), Duration::from_secs(30));
+    // Moments after the clock's present haven't elapsed at all.
+    assert_eq!(
+        clock.elapsed(start.checked_add(Duration::from_secs(60)).unwrap()),
+        Duration::from_secs(0)
+    );
+}
+
 #[tokio::test]
 async fn timeout_duration_zero() {
     let start = BootTime::now();
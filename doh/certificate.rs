@@ -0,0 +1,331 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Reports which certificate a server presented, for auditing handshakes
+
+use std::convert::TryFrom;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+/// What the handshake made of the server's certificate
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CertOutcome {
+    /// The handshake completed. For servers configured without a cert path the certificate is
+    /// accepted without being checked against a trust store.
+    Accepted,
+    /// The server presented a certificate, but the connection closed before the handshake
+    /// completed. This is most likely, but not certainly, a failed verification.
+    Rejected,
+}
+
+/// Details of a leaf certificate
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CertInfo {
+    /// Common name of the subject
+    pub subject_cn: Option<String>,
+    /// DNS names and IP addresses from the subject alternative name extension
+    pub subject_alt_names: Vec<String>,
+    /// End of the validity period, as `YYYY-MM-DDTHH:MM:SSZ`
+    pub not_after: Option<String>,
+}
+
+/// Handed to a `CertObserver` once per connection
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HandshakeReport {
+    pub net_id: u32,
+    pub outcome: CertOutcome,
+    /// `None` if the certificate could not be parsed
+    pub leaf: Option<CertInfo>,
+}
+
+/// Closure told about each handshake, for logging. It only observes: the verification decision
+/// has already been made by the time it runs.
+pub type CertObserver = Arc<dyn Fn(&HandshakeReport) + Send + Sync>;
+
+const BOOLEAN: u8 = 0x01;
+const OCTET_STRING: u8 = 0x04;
+const OID: u8 = 0x06;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+// Context-specific tags within a TBSCertificate
+const VERSION: u8 = 0xa0;
+const EXTENSIONS: u8 = 0xa3;
+// Context-specific tags within a GeneralName
+const DNS_NAME: u8 = 0x82;
+const IP_ADDRESS: u8 = 0x87;
+
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+// Reader for the DER elements in a byte string. Only definite lengths of up to 4 bytes are
+// supported, which is all a certificate needs.
+struct Der<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Der<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    // Reads the next element, returning its tag and contents.
+    fn next(&mut self) -> Option<(u8, &'a [u8])> {
+        let (&tag, rest) = self.bytes.split_first()?;
+        let (&first, mut rest) = rest.split_first()?;
+        let len = if first < 0x80 {
+            first as usize
+        } else {
+            let len_len = (first & 0x7f) as usize;
+            if len_len == 0 || len_len > 4 || rest.len() < len_len {
+                return None;
+            }
+            let (len, after) = rest.split_at(len_len);
+            rest = after;
+            len.iter().fold(0, |len, &byte| (len << 8) | byte as usize)
+        };
+        if rest.len() < len {
+            return None;
+        }
+        let (contents, rest) = rest.split_at(len);
+        self.bytes = rest;
+        Some((tag, contents))
+    }
+
+    // Reads the next element if it has the given tag.
+    fn expect(&mut self, tag: u8) -> Option<&'a [u8]> {
+        match self.bytes.first() {
+            Some(&next) if next == tag => self.next().map(|(_, contents)| contents),
+            _ => None,
+        }
+    }
+
+    fn skip_if(&mut self, tag: u8) -> Option<()> {
+        if self.bytes.first() == Some(&tag) {
+            self.next()?;
+        }
+        Some(())
+    }
+}
+
+/// Extracts the details of a DER-encoded X.509 certificate, or `None` if it is malformed.
+pub fn parse(der: &[u8]) -> Option<CertInfo> {
+    let certificate = Der::new(der).expect(SEQUENCE)?;
+    let mut tbs = Der::new(Der::new(certificate).expect(SEQUENCE)?);
+    tbs.skip_if(VERSION)?;
+    // serialNumber, signature and issuer
+    for _ in 0..3 {
+        tbs.next()?;
+    }
+    let mut validity = Der::new(tbs.expect(SEQUENCE)?);
+    validity.next()?;
+    let not_after = validity.next().and_then(|(tag, time)| format_time(tag, time));
+    let subject_cn = common_name(tbs.expect(SEQUENCE)?);
+    // subjectPublicKeyInfo, followed by optional fields of which only extensions matter
+    tbs.next()?;
+    let mut subject_alt_names = Vec::new();
+    while let Some((tag, contents)) = tbs.next() {
+        if tag == EXTENSIONS {
+            subject_alt_names = alt_names(contents).unwrap_or_default();
+        }
+    }
+    Some(CertInfo { subject_cn, subject_alt_names, not_after })
+}
+
+fn common_name(name: &[u8]) -> Option<String> {
+    let mut rdns = Der::new(name);
+    while let Some(rdn) = rdns.expect(SET) {
+        let mut attributes = Der::new(rdn);
+        while let Some(attribute) = attributes.expect(SEQUENCE) {
+            let mut attribute = Der::new(attribute);
+            if attribute.expect(OID)? == OID_COMMON_NAME {
+                let (_, value) = attribute.next()?;
+                return String::from_utf8(value.to_vec()).ok();
+            }
+        }
+    }
+    None
+}
+
+fn alt_names(extensions: &[u8]) -> Option<Vec<String>> {
+    let mut extensions = Der::new(Der::new(extensions).expect(SEQUENCE)?);
+    while let Some(extension) = extensions.expect(SEQUENCE) {
+        let mut extension = Der::new(extension);
+        if extension.expect(OID)? != OID_SUBJECT_ALT_NAME {
+            continue;
+        }
+        extension.skip_if(BOOLEAN)?;
+        let value = extension.expect(OCTET_STRING)?;
+        let mut names = Der::new(Der::new(value).expect(SEQUENCE)?);
+        let mut result = Vec::new();
+        while let Some((tag, name)) = names.next() {
+            match tag {
+                DNS_NAME => result.push(String::from_utf8_lossy(name).into_owned()),
+                IP_ADDRESS => result.extend(ip_address(name).map(|addr| addr.to_string())),
+                _ => (),
+            }
+        }
+        return Some(result);
+    }
+    Some(Vec::new())
+}
+
+fn ip_address(bytes: &[u8]) -> Option<IpAddr> {
+    match bytes.len() {
+        4 => Some(IpAddr::from(<[u8; 4]>::try_from(bytes).ok()?)),
+        16 => Some(IpAddr::from(<[u8; 16]>::try_from(bytes).ok()?)),
+        _ => None,
+    }
+}
+
+/// Names `cert` is valid for: its subject alternative names, or its common name if it has none.
+pub fn presented_names(cert: &CertInfo) -> Vec<String> {
+    if cert.subject_alt_names.is_empty() {
+        cert.subject_cn.iter().cloned().collect()
+    } else {
+        cert.subject_alt_names.clone()
+    }
+}
+
+/// Whether `cert` is valid for `name`, a DNS name or an IP address. DNS names are compared
+/// without regard to case, and a wildcard matches just the leftmost label, as RFC 6125 describes.
+pub fn matches_name(cert: &CertInfo, name: &str) -> bool {
+    let names = presented_names(cert);
+    match name.parse::<IpAddr>() {
+        Ok(addr) => names.iter().any(|presented| presented.parse() == Ok(addr)),
+        Err(_) => names.iter().any(|presented| dns_name_matches(presented, name)),
+    }
+}
+
+fn dns_name_matches(pattern: &str, name: &str) -> bool {
+    let (pattern, name) = (pattern.trim_end_matches('.'), name.trim_end_matches('.'));
+    match pattern.strip_prefix("*.") {
+        // A wildcard must leave at least two labels, so that `*.com` matches nothing.
+        Some(suffix) if suffix.contains('.') => match name.split_once('.') {
+            Some((label, rest)) => !label.is_empty() && rest.eq_ignore_ascii_case(suffix),
+            None => false,
+        },
+        _ => pattern.eq_ignore_ascii_case(name),
+    }
+}
+
+// Converts a UTCTime or GeneralizedTime, as used in certificates, to `YYYY-MM-DDTHH:MM:SSZ`.
+fn format_time(tag: u8, time: &[u8]) -> Option<String> {
+    let time = std::str::from_utf8(time).ok()?;
+    let (year, rest) = match tag {
+        UTC_TIME => {
+            // RFC 5280 puts two-digit years from 50 in the 20th century.
+            let year: u32 = time.get(..2)?.parse().ok()?;
+            (if year < 50 { 2000 + year } else { 1900 + year }, time.get(2..)?)
+        }
+        GENERALIZED_TIME => (time.get(..4)?.parse().ok()?, time.get(4..)?),
+        _ => return None,
+    };
+    if rest.len() != 11 || !rest.ends_with('Z') || !rest[..10].bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some(format!(
+        "{:04}-{}-{}T{}:{}:{}Z",
+        year,
+        &rest[0..2],
+        &rest[2..4],
+        &rest[4..6],
+        &rest[6..8],
+        &rest[8..10]
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        format_time, matches_name, parse, presented_names, CertInfo, GENERALIZED_TIME, UTC_TIME,
+    };
+
+    // Self-signed, for O=Android, CN=dns.example.com, with alternative names dns.example.com,
+    // *.dns.example.com and 192.0.2.1.
+    const CERT: &str = "\
+        MIIBszCCAVigAwIBAgIUWUNGdSAZyp2/9YFtQew/wF0zQUUwCgYIKoZIzj0EAwIwLDEQMA4GA1UECgwHQW5kcm9pZDEYMBYG\
+        A1UEAwwPZG5zLmV4YW1wbGUuY29tMCAXDTI2MTAxNDExNDgwMFoYDzIwNTYxMTI1MTE0ODAwWjAsMRAwDgYDVQQKDAdBbmRy\
+        b2lkMRgwFgYDVQQDDA9kbnMuZXhhbXBsZS5jb20wWTATBgcqhkjOPQIBBggqhkjOPQMBBwNCAARpvjXBzKArCax9XBywlXOe\
+        VHJqyJTRJ6flLuE8huLspoIHjBM45x4peWFgN4+m9DH+tBE72CSXEq4Plz30UOfVo1YwVDAzBgNVHREELDAqgg9kbnMuZXhh\
+        bXBsZS5jb22CESouZG5zLmV4YW1wbGUuY29thwTAAAIBMB0GA1UdDgQWBBS4cQ+USs5MuxUNwxFQ/AUfsCndyTAKBggqhkjO\
+        PQQDAgNJADBGAiEA1steUaoeavpU+oiVtfmeILVeTrTliR0lbHxXBV9vs+UCIQDTHscbsUT/JrfEA6BE/6XuNuZqcLNgGDVC\
+        6T405WEsWA==";
+
+    #[test]
+    fn leaf_details() {
+        let der = base64::decode(CERT).unwrap();
+        assert_eq!(
+            parse(&der),
+            Some(CertInfo {
+                subject_cn: Some("dns.example.com".to_string()),
+                subject_alt_names: vec![
+                    "dns.example.com".to_string(),
+                    "*.dns.example.com".to_string(),
+                    "192.0.2.1".to_string()
+                ],
+                not_after: Some("2056-11-25T11:48:00Z".to_string()),
+            })
+        );
+        // Truncation anywhere leaves the certificate unparseable rather than misread.
+        assert_eq!(parse(&der[..der.len() - 1]), None);
+        assert_eq!(parse(&der[..100]), None);
+    }
+
+    #[test]
+    fn certificate_times() {
+        assert_eq!(
+            format_time(UTC_TIME, b"460317121351Z").as_deref(),
+            Some("2046-03-17T12:13:51Z")
+        );
+        assert_eq!(
+            format_time(UTC_TIME, b"991231235959Z").as_deref(),
+            Some("1999-12-31T23:59:59Z")
+        );
+        assert_eq!(
+            format_time(GENERALIZED_TIME, b"20561125114800Z").as_deref(),
+            Some("2056-11-25T11:48:00Z")
+        );
+        assert_eq!(format_time(UTC_TIME, b"4603171213Z"), None);
+    }
+
+    #[test]
+    fn server_name_match() {
+        let cert = parse(&base64::decode(CERT).unwrap()).unwrap();
+        for name in ["dns.example.com", "DNS.Example.com.", "a.dns.example.com", "192.0.2.1"] {
+            assert!(matches_name(&cert, name), "{}", name);
+        }
+        let mismatched =
+            ["example.com", "a.b.dns.example.com", "dns.example.org", "192.0.2.2", "::1", ""];
+        for name in mismatched {
+            assert!(!matches_name(&cert, name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn server_name_from_common_name() {
+        let cert =
+            CertInfo { subject_cn: Some("dns.example.com".to_string()), ..Default::default() };
+        assert_eq!(presented_names(&cert), ["dns.example.com"]);
+        assert!(matches_name(&cert, "dns.example.com"));
+        // A common name is ignored once there are alternative names.
+        let cert = CertInfo { subject_alt_names: vec!["*.com".to_string()], ..cert };
+        assert!(!matches_name(&cert, "dns.example.com"));
+        assert!(!matches_name(&cert, "example.com"));
+        assert!(!matches_name(&CertInfo::default(), "dns.example.com"));
+    }
+}
@@ -24,24 +24,334 @@
 //! This module provides a caching layer for loading and constructing
 //! these configurations.
 
-use quiche::{h3, Result};
+use crate::boot_time::{self, BootTime, Duration, SharedClock};
+use crate::connection::DEFAULT_MAX_RESPONSE_SIZE;
+use log::{debug, warn};
+use quiche::h3;
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
 use std::ops::DerefMut;
-use std::sync::{Arc, RwLock, Weak};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock, RwLockWriteGuard, TryLockError, Weak};
+use thiserror::Error;
 use tokio::sync::Mutex;
+use tokio::task;
 
-type WeakConfig = Weak<Mutex<quiche::Config>>;
+/// Error type for constructing a `Config`
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    /// The certificate directory exists but holds no certificates, so every peer would fail
+    /// verification.
+    #[error("No certificates found in {0}")]
+    EmptyTrustStore(String),
+    /// The certificate path is not a readable directory. Only `Key::validate` checks this, as
+    /// quiche itself accepts such a path and fails each handshake instead.
+    #[error("Unable to read cert directory {0}")]
+    MissingTrustStore(String),
+    /// A setting is outside the range QUIC allows, or can't work with the others. Only
+    /// `Key::validate` checks this, as quiche itself clamps or ignores such settings.
+    #[error("Setting out of range: {0}")]
+    OutOfRange(String),
+    /// The certificate path names a file. It must be a directory of certificates, such as the
+    /// system's `/system/etc/security/cacerts`, rather than a bundle of them.
+    #[error("Cert path {0} is a file, not a directory")]
+    CertPathNotDirectory(String),
+    /// A relative certificate path was given, but the working directory to resolve it against
+    /// could not be determined.
+    #[error("Unable to resolve relative cert path {0}")]
+    RelativeCertPath(String),
+    /// The config was built, but the cache stayed locked for longer than `Cache::LOCK_PATIENCE`
+    /// so it could not be installed.
+    #[error("Config cache is busy")]
+    CacheBusy,
+    /// The blocking task building the config for `Cache::get_async` panicked or was cancelled
+    /// by the runtime shutting down.
+    #[error("Config construction did not complete")]
+    BuildAbandoned,
+    /// The PEM certificates of `Key::cert_pem` hold no certificate, aren't valid PEM, or could
+    /// not be handed to quiche.
+    #[error("Unable to load PEM certificates: {0}")]
+    CertPem(String),
+    /// Both a certificate path and PEM certificates were given. Servers are verified against
+    /// one or the other.
+    #[error("Both a cert path and PEM certificates were given")]
+    CertSources,
+    /// A debug log could not be opened for writing.
+    #[error("Unable to open debug log {0}: {1}")]
+    DebugLog(String, io::Error),
+    /// A debug log was set on a config which a connection has already been built from. That
+    /// connection, and any others sharing the config, would miss it.
+    #[error("Config is already in use")]
+    InUse,
+    /// quiche could not load the certificates of the directory at the certificate path.
+    #[error("Unable to load certificates from {0}: {1}")]
+    CertLoad(String, quiche::Error),
+    /// `Key::quic_versions` is empty or holds a version quiche doesn't support.
+    #[error("Unsupported QUIC versions {0:x?}")]
+    ProtocolVersion(Vec<u32>),
+    /// One of `Key::extra_application_protos` is empty or too long, or quiche rejected the ALPN
+    /// protocols.
+    #[error("Unable to set application protocols: {0}")]
+    ApplicationProtos(String),
+    /// Quiche rejected another part of the configuration
+    #[error("QUIC error: {0}")]
+    Quiche(#[from] quiche::Error),
+}
+
+/// Common result type for constructing a `Config`
+pub type Result<T> = std::result::Result<T, ConfigError>;
+
+type WeakConfig = Weak<Shared>;
+
+struct Shared {
+    config: Mutex<quiche::Config>,
+    // quiche doesn't let these settings be read back, so they are kept alongside.
+    verifies_peer: bool,
+    congestion_control: CongestionControl,
+    max_datagram_size: usize,
+    logs: std::sync::Mutex<DebugLogs>,
+    // Whether the config has been taken to build a connection, after which logs can't be set.
+    taken: AtomicBool,
+}
+
+/// Where connections built from a `Config` write logs for debugging their handshakes, as set
+/// by `Config::set_keylog` and `Config::set_qlog_dir`
+#[derive(Clone, Debug, Default)]
+pub struct DebugLogs {
+    keylog: Option<Arc<fs::File>>,
+    #[cfg(feature = "qlog")]
+    qlog_dir: Option<PathBuf>,
+}
+
+impl DebugLogs {
+    // Points a freshly created connection at the logs. A log which can't be opened is left
+    // out, as the connection works without it.
+    pub(crate) fn install(&self, conn: &mut quiche::Connection) {
+        if let Some(keylog) = &self.keylog {
+            match keylog.try_clone() {
+                Ok(file) => conn.set_keylog(Box::new(file)),
+                Err(e) => warn!("Unable to share keylog: {}", e),
+            }
+        }
+        #[cfg(feature = "qlog")]
+        if let Some(dir) = &self.qlog_dir {
+            let path = dir.join(format!("{}.qlog", conn.trace_id()));
+            match fs::File::create(&path) {
+                Ok(file) => {
+                    let title = "DoH connection".to_string();
+                    conn.set_qlog(Box::new(file), title, path.display().to_string())
+                }
+                Err(e) => warn!("Unable to create qlog {}: {}", path.display(), e),
+            }
+        }
+    }
+}
 
 /// A cheaply clonable `quiche::Config`
 #[derive(Clone)]
-pub struct Config(Arc<Mutex<quiche::Config>>);
+pub struct Config(Arc<Shared>);
+
+impl std::fmt::Debug for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // quiche::Config has nothing printable, so just identify the shared instance.
+        write!(f, "Config({:p})", Arc::as_ptr(&self.0))
+    }
+}
 
-const MAX_INCOMING_BUFFER_SIZE_WHOLE: u64 = 10000000;
+/// Connection flow-control window configs are built with.
+pub const MAX_INCOMING_BUFFER_SIZE_WHOLE: u64 = 10000000;
 const MAX_INCOMING_BUFFER_SIZE_EACH: u64 = 1000000;
 const MAX_CONCURRENT_STREAM_SIZE: u64 = 100;
-/// Maximum datagram size we will accept.
+// Room on a request stream for the HEADERS frame and DATA frame headers around the body.
+const RESPONSE_FRAMING_ALLOWANCE: u64 = 16 * 1024;
+/// Maximum datagram size we will accept, unless `TransportParams::max_datagram_size` says
+/// otherwise.
 pub const MAX_DATAGRAM_SIZE: usize = 1350;
 
+/// Flow-control window for request streams whose responses are capped at `max_response_size`.
+///
+/// A response up to the cap fits in the initial window, so it never waits on window updates. When
+/// a response hits the cap, the stream is stopped and the request fails with
+/// `QueryError::ResponseTooLarge`, but quiche never credits the connection window with anything a
+/// server keeps sending on a stopped stream. Keeping the stream window close to the cap bounds
+/// that loss, so a few oversized responses can't use up the connection window and stall it.
+/// Requests which ask for a larger cap still work, with quiche extending the window as the body
+/// is read.
+pub fn stream_window(max_response_size: usize) -> u64 {
+    (max_response_size as u64).saturating_add(RESPONSE_FRAMING_ALLOWANCE)
+}
+
+// Attempts at scanning a trust store which keeps coming up inconclusive, and the pause between
+// them. A directory replaced by an atomic rename settles almost at once.
+const TRUST_STORE_SCAN_ATTEMPTS: usize = 3;
+const TRUST_STORE_SCAN_RETRY_DELAY: Duration = Duration::from_millis(20);
+
+// Errors which a later read of the same cert directory might not hit: the entry was listed but
+// had gone by the time it was read, as while the directory is being replaced, or the read was
+// interrupted. Anything else, such as an unreadable file, would fail the same way next time.
+fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::NotFound | io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+    )
+}
+
+fn holds_pem_cert(contents: &[u8]) -> bool {
+    const PEM_CERT_HEADER: &[u8] = b"-----BEGIN CERTIFICATE-----";
+    contents.windows(PEM_CERT_HEADER.len()).any(|window| window == PEM_CERT_HEADER)
+}
+
+// Whether `path` is a readable directory without a single PEM certificate in it. A directory we
+// can't read is left for BoringSSL to report, since it may only be inaccessible to us. A scan
+// which finds no certificate but hit a transient error on some entry is inconclusive, and fails
+// with that error rather than calling the store empty.
+fn is_empty_trust_store(path: &str) -> io::Result<bool> {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(false),
+    };
+    let mut inconclusive = None;
+    for contents in entries.map(|entry| entry.and_then(|entry| fs::read(entry.path()))) {
+        match contents {
+            Ok(contents) => {
+                if holds_pem_cert(&contents) {
+                    return Ok(false);
+                }
+            }
+            Err(e) if is_transient(&e) => inconclusive = Some(e),
+            Err(_) => {}
+        }
+    }
+    match inconclusive {
+        Some(e) => Err(e),
+        None => Ok(true),
+    }
+}
+
+// Makes a cert path absolute and drops `.` components and redundant separators, so that one
+// directory always maps to the same key however it was spelled.
+fn normalize_cert_path(path: &str) -> Result<String> {
+    let path = Path::new(path);
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir()
+            .map_err(|_| ConfigError::RelativeCertPath(path.display().to_string()))?
+            .join(path)
+    };
+    let normalized: PathBuf = absolute.components().collect();
+    Ok(normalized.to_string_lossy().into_owned())
+}
+
+// Has `config` verify peers against the certificate directory at `path`.
+fn load_trust_store(config: &mut quiche::Config, path: &str) -> Result<()> {
+    // BoringSSL would accept a file here, and then fail every handshake.
+    if Path::new(path).is_file() {
+        return Err(ConfigError::CertPathNotDirectory(path.to_string()));
+    }
+    // Only this scan can catch the directory mid-update: BoringSSL reads the certificates as
+    // handshakes look them up, not when the directory is loaded. A store which still looks empty
+    // once the retries run out is reported as empty.
+    let empty = retry_transient(TRUST_STORE_SCAN_ATTEMPTS, TRUST_STORE_SCAN_RETRY_DELAY, || {
+        is_empty_trust_store(path)
+    })
+    .unwrap_or(true);
+    if empty {
+        return Err(ConfigError::EmptyTrustStore(path.to_string()));
+    }
+    config.verify_peer(true);
+    config
+        .load_verify_locations_from_directory(path)
+        .map_err(|e| ConfigError::CertLoad(path.to_string(), e))
+}
+
+// Fails for a cert path which isn't a readable directory, which quiche would accept only for
+// every handshake to fail.
+fn check_cert_dir(path: &str) -> Result<()> {
+    if Path::new(path).is_file() {
+        return Err(ConfigError::CertPathNotDirectory(path.to_string()));
+    }
+    if fs::read_dir(path).is_err() {
+        return Err(ConfigError::MissingTrustStore(path.to_string()));
+    }
+    Ok(())
+}
+
+// The ALPN protocols `key` calls for, in wire format.
+fn application_protos(key: &Key) -> Result<Vec<u8>> {
+    let mut protos = key.stream_mode.application_protos().to_vec();
+    for proto in &key.extra_application_protos {
+        let len = match u8::try_from(proto.len()) {
+            Ok(len) if len > 0 => len,
+            _ => {
+                let message = format!("{} byte protocol {:?}", proto.len(), proto);
+                return Err(ConfigError::ApplicationProtos(message));
+            }
+        };
+        protos.push(len);
+        protos.extend_from_slice(proto);
+    }
+    Ok(protos)
+}
+
+// Runs `scan` up to `attempts` times, pausing `delay` between attempts, for as long as it fails
+// with a transient error. Other errors, and the last transient one, are returned as they are.
+fn retry_transient<T>(
+    attempts: usize,
+    delay: Duration,
+    mut scan: impl FnMut() -> io::Result<T>,
+) -> io::Result<T> {
+    let mut attempt = 1;
+    loop {
+        match scan() {
+            Err(e) if is_transient(&e) && attempt < attempts => {
+                debug!("Retrying transient cert directory error: {}", e);
+                std::thread::sleep(delay);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+// Adds the certificates in `pem` to those the config verifies servers against. quiche only loads
+// certificates from the filesystem, so they are handed over through a pipe, which BoringSSL opens
+// as /proc/self/fd/N. Nothing reaches the disk, and unlike `memfd_create`, which needs API 30,
+// pipes are there on every release we run on. Nothing reads the pipe until quiche loads it, so
+// it is grown to hold all of `pem` and written without blocking, then the write end is closed
+// for the load to see the end of the file.
+fn load_verify_pem(config: &mut quiche::Config, pem: &[u8]) -> Result<()> {
+    use std::io::Write;
+    use std::os::unix::io::FromRawFd;
+    // BoringSSL would report a file without certificates only as a generic TLS failure.
+    if !holds_pem_cert(pem) {
+        return Err(ConfigError::CertPem("no certificate found".to_string()));
+    }
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } < 0 {
+        return Err(ConfigError::CertPem(io::Error::last_os_error().to_string()));
+    }
+    // Both ends were just opened, and are owned by nothing else.
+    let (read, mut write) =
+        unsafe { (fs::File::from_raw_fd(fds[0]), fs::File::from_raw_fd(fds[1])) };
+    // Growing the pipe can fail beyond the system's limit, which the write then reports.
+    let size = libc::c_int::try_from(pem.len()).unwrap_or(libc::c_int::MAX);
+    unsafe { libc::fcntl(fds[1], libc::F_SETPIPE_SZ, size) };
+    write
+        .write_all(pem)
+        .map_err(|e| ConfigError::CertPem(format!("{} bytes: {}", pem.len(), e)))?;
+    drop(write);
+    let path = format!("/proc/self/fd/{}", fds[0]);
+    let loaded = config
+        .load_verify_locations_from_file(&path)
+        .map_err(|e| ConfigError::CertPem(format!("rejected by quiche: {}", e)));
+    drop(read);
+    loaded
+}
+
 impl Config {
     fn from_weak(weak: &WeakConfig) -> Option<Self> {
         weak.upgrade().map(Self)
@@ -53,57 +363,486 @@ impl Config {
 
     /// Construct a `Config` object from certificate path. If no path
     /// is provided, peers will not be verified.
+    ///
+    /// PEM certificates in `Key::cert_pem` take the place of the path, as `from_cert_pem`
+    /// describes. A key with both fails with `ConfigError::CertSources`.
+    ///
+    /// This always builds a fresh config and does not consult or populate any `Cache`, so it
+    /// suits one-off uses: the config is freed as soon as the last connection built from it is
+    /// gone. Use `Cache::get` to share configs between connections.
+    ///
+    /// A relative certificate path is resolved against the current working directory.
     pub fn from_key(key: &Key) -> Result<Self> {
-        let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
-        config.set_application_protos(h3::APPLICATION_PROTOCOL)?;
-        match key.cert_path.as_deref() {
-            Some(path) => {
-                config.verify_peer(true);
-                config.load_verify_locations_from_directory(path)?;
+        let key = key.normalized()?;
+        match key.cert_source()? {
+            Some(CertSource::Pem(pem)) => Self::from_cert_pem(&pem, &key),
+            Some(CertSource::Dir(path)) => {
+                Self::build(&key, true, |config| load_trust_store(config, &path))
             }
-            None => config.verify_peer(false),
+            None => Self::build(&key, false, |_| Ok(())),
         }
+    }
+
+    /// Builds a `Config` which verifies servers against the PEM certificates `pem`, with its other
+    /// settings taken from `key`. This is what `from_key` does for a key with `Key::cert_pem`
+    /// set, for trust anchors held apart from a key: the key's own `cert_pem` is ignored, and it
+    /// must not have a `cert_path`, as the two can't be combined.
+    ///
+    /// As with `from_key`, no `Cache` is consulted or populated.
+    pub fn from_cert_pem(pem: &PemCerts, key: &Key) -> Result<Self> {
+        if key.cert_path.is_some() {
+            return Err(ConfigError::CertSources);
+        }
+        Self::build(key, true, |config| load_verify_pem(config, pem.as_bytes()))
+    }
+
+    // Builds the config for `key`, whose cert path has been normalized. `load_certs` adds the
+    // certificates to verify servers against, if `verifies_peer`.
+    fn build(
+        key: &Key,
+        verifies_peer: bool,
+        load_certs: impl FnOnce(&mut quiche::Config) -> Result<()>,
+    ) -> Result<Self> {
+        // quiche only takes the version to start with. The rest of the list is enforced by the
+        // connection driver when a server asks for version negotiation.
+        let version = match key.quic_versions.first() {
+            Some(&version)
+                if key.quic_versions.iter().all(|&v| quiche::version_is_supported(v)) =>
+            {
+                version
+            }
+            _ => return Err(ConfigError::ProtocolVersion(key.quic_versions.clone())),
+        };
+        let mut config = quiche::Config::new(version)?;
+        config
+            .set_application_protos(&application_protos(key)?)
+            .map_err(|e| ConfigError::ApplicationProtos(format!("rejected by quiche: {}", e)))?;
+        config.verify_peer(verifies_peer);
+        load_certs(&mut config)?;
+        // The TLS 1.3 cipher suites and key exchange groups are left at BoringSSL's defaults.
+        // quiche 0.9 does not expose the underlying `SSL_CTX`, and BoringSSL has no knob for
+        // TLS 1.3 suites at all, so a caller-specified policy cannot be honoured here. That
+        // includes post-quantum hybrids such as X25519Kyber768: one is offered only if the
+        // BoringSSL we link offers it by default. If groups become configurable, add them to `Key`
+        // so differing policies don't share a config.
+        //
+        // The TLS version needs no policy of ours: quiche sets both the minimum and the maximum
+        // to TLS 1.3 on every handshake it creates, so nothing else can be negotiated. It
+        // exposes neither a setter nor the negotiated version, so there is nothing to add to
+        // `Key` or to assert on once connected.
 
         // Some of these configs are necessary, or the server can't respond the HTTP/3 request.
+        // There is no keep-alive to reconcile with this: quiche 0.9 cannot send a bare PING, so an
+        // idle connection is simply re-established on the next query. A keep-alive added after a
+        // quiche upgrade must fire well inside the negotiated timeout (the minimum of ours and the
+        // peer's), not just inside `max_idle_timeout`.
         config.set_max_idle_timeout(key.max_idle_timeout);
-        config.set_max_recv_udp_payload_size(MAX_DATAGRAM_SIZE);
-        config.set_initial_max_data(MAX_INCOMING_BUFFER_SIZE_WHOLE);
-        config.set_initial_max_stream_data_bidi_local(MAX_INCOMING_BUFFER_SIZE_EACH);
-        config.set_initial_max_stream_data_bidi_remote(MAX_INCOMING_BUFFER_SIZE_EACH);
-        config.set_initial_max_stream_data_uni(MAX_INCOMING_BUFFER_SIZE_EACH);
-        config.set_initial_max_streams_bidi(MAX_CONCURRENT_STREAM_SIZE);
-        config.set_initial_max_streams_uni(MAX_CONCURRENT_STREAM_SIZE);
-        config.set_disable_active_migration(true);
-        Ok(Self(Arc::new(Mutex::new(config))))
+        // The send payload size stays at quiche's 1200 byte default, which is also what datagrams
+        // carrying an Initial are padded to. Anything shorter would leave the server stuck at its
+        // anti-amplification limit during the handshake. The receive size is a transport
+        // parameter.
+        config.set_initial_max_stream_data_bidi_local(stream_window(
+            key.max_response_size.unwrap_or(DEFAULT_MAX_RESPONSE_SIZE),
+        ));
+        key.transport.apply(&mut config);
+        config.set_disable_active_migration(key.disable_active_migration);
+        if key.enable_early_data {
+            config.enable_early_data();
+        }
+        let congestion_control = key.transport.congestion_control.unwrap_or_default();
+        Ok(Self(Arc::new(Shared {
+            config: Mutex::new(config),
+            verifies_peer,
+            congestion_control,
+            max_datagram_size: key.transport.max_datagram_size(),
+            logs: Default::default(),
+            taken: AtomicBool::new(false),
+        })))
+    }
+
+    /// Checks that the certificate directory at `cert_path` could be verified against, as
+    /// `Key::validate` would, but only loads the trust store, into a config which is then thrown
+    /// away. Nothing is cached, and the rest of a key isn't needed. `None`, which verifies
+    /// nothing, is always fine.
+    pub fn validate_cert_path(cert_path: Option<&str>) -> Result<()> {
+        let path = match cert_path {
+            Some(path) => normalize_cert_path(path)?,
+            None => return Ok(()),
+        };
+        check_cert_dir(&path)?;
+        let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+        load_trust_store(&mut config, &path)
+    }
+
+    /// Whether connections built from this config verify the server's certificate, which they
+    /// do if the config was built with a certificate path or PEM certificates.
+    pub fn verifies_peer(&self) -> bool {
+        self.0.verifies_peer
+    }
+
+    /// The congestion control algorithm connections built from this config use, unless they
+    /// swap in another.
+    pub fn congestion_control(&self) -> CongestionControl {
+        self.0.congestion_control
+    }
+
+    /// Largest datagram connections built from this config accept, which they also keep the
+    /// datagrams they send within.
+    pub fn max_datagram_size(&self) -> usize {
+        self.0.max_datagram_size
     }
 
     /// Take the underlying config, usable as `&mut quiche::Config` for use
     /// with `quiche::connect`.
     pub async fn take(&mut self) -> impl DerefMut<Target = quiche::Config> + '_ {
-        self.0.lock().await
+        let config = self.0.config.lock().await;
+        self.0.taken.store(true, Ordering::Relaxed);
+        config
+    }
+
+    /// As `take`, but returns `None` rather than waiting if a connection is being built from
+    /// another handle to the config, so that the caller can come back to it later.
+    #[cfg(test)]
+    pub fn try_take(&mut self) -> Option<impl DerefMut<Target = quiche::Config> + '_> {
+        let config = self.0.config.try_lock().ok()?;
+        self.0.taken.store(true, Ordering::Relaxed);
+        Some(config)
+    }
+
+    /// Appends the TLS secrets of connections built from this config to `file`, as opened by
+    /// `open_keylog`, in the NSS key log format, so that their traffic can be decrypted when
+    /// debugging. Only connections created by `Connection::new` log them.
+    ///
+    /// Configs are shared, and quiche reads this when a connection is created, so it must be
+    /// set before the config is first taken. Afterwards this fails with `ConfigError::InUse`.
+    /// Every holder of the config then logs, including other users of a cached one.
+    pub fn set_keylog(&mut self, file: Arc<fs::File>) -> Result<()> {
+        self.update_logs(|logs, config| {
+            config.log_keys();
+            logs.keylog = Some(file);
+        })
+    }
+
+    /// Writes a qlog trace of each connection built from this config to a file named after its
+    /// trace ID in `dir`. Only connections created by `Connection::new` write one, and as for
+    /// `set_keylog` it must be set before the config is first taken.
+    #[cfg(feature = "qlog")]
+    pub fn set_qlog_dir(&mut self, dir: &str) -> Result<()> {
+        self.update_logs(|logs, _| logs.qlog_dir = Some(PathBuf::from(dir)))
+    }
+
+    /// The logs connections built from this config write.
+    pub fn debug_logs(&self) -> DebugLogs {
+        self.0.logs.lock().unwrap().clone()
+    }
+
+    fn update_logs(
+        &mut self,
+        update: impl FnOnce(&mut DebugLogs, &mut quiche::Config),
+    ) -> Result<()> {
+        // `take` marks the config while holding its lock, so holding it here rules out a
+        // connection being built from it meanwhile.
+        let mut config = self.0.config.try_lock().map_err(|_| ConfigError::InUse)?;
+        if self.0.taken.load(Ordering::Relaxed) {
+            return Err(ConfigError::InUse);
+        }
+        update(&mut self.0.logs.lock().unwrap(), &mut config);
+        Ok(())
     }
 }
 
-#[derive(Clone, Default)]
+/// Opens the file at `path` for `Config::set_keylog` to append TLS secrets to, creating it if
+/// need be.
+pub fn open_keylog(path: &str) -> Result<Arc<fs::File>> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| ConfigError::DebugLog(path.to_string(), e))?;
+    Ok(Arc::new(file))
+}
+
+/// Why a `Cache` let go of a config
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvictionReason {
+    /// A newer config took the keep-alive slot of the least recently requested one. The config
+    /// is still cached for as long as something else holds it.
+    Capacity,
+    /// `garbage_collect` found that nothing holds the config any more.
+    Dead,
+    /// `Cache::invalidate`, `Cache::invalidate_cert_path` or `Cache::invalidate_all` picked the
+    /// config's key.
+    Invalidated,
+    /// `Cache::trim` emptied the keep-alive slots. As with `Capacity`, the config is still cached
+    /// for as long as something else holds it.
+    Trimmed,
+    /// Garbage collection emptied a keep-alive slot, as the config in it had gone unused and
+    /// unrequested for the `Cache::set_keep_alive_grace` period.
+    Unrequested,
+}
+
+/// Closure told the cert path (if any) of each config a `Cache` lets go of, and why
+pub type EvictionObserver = Arc<dyn Fn(Option<&str>, EvictionReason) + Send + Sync>;
+
+type Eviction = (Option<String>, EvictionReason);
+
+/// How much work a `Cache` has done building configs, and how often lookups were spared it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Lookups served a live config from the cache.
+    pub hits: u64,
+    /// Lookups which found no live config and built one.
+    pub misses: u64,
+    /// Built configs thrown away because an identical one was installed while building.
+    pub races: u64,
+    /// Number of configs built, including ones which failed or lost a race to an identical one.
+    pub constructions: u64,
+    /// Time spent building them, dominated by `SSL_CTX` setup and trust store parsing.
+    pub total_construction_time: Duration,
+    /// Longest time spent building a single config.
+    pub max_construction_time: Duration,
+}
+
+// Lookup counters, bumped under either lock.
+#[derive(Default)]
+struct LookupCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    races: AtomicU64,
+}
+
+impl CacheStats {
+    fn record(&mut self, elapsed: Duration) {
+        self.constructions += 1;
+        self.total_construction_time += elapsed;
+        self.max_construction_time = self.max_construction_time.max(elapsed);
+    }
+}
+
+/// A config held by a `Cache`, as listed by `Cache::resident`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResidentConfig {
+    pub cert_path: Option<String>,
+    /// How long ago the config was built. Long-lived configs mean lookups are reusing them,
+    /// while young ones alongside a high `CacheStats::constructions` mean the cache is churning.
+    pub age: Duration,
+    /// Whether the cache holds the config alive itself for reuse, rather than only through the
+    /// connections using it.
+    pub kept_alive: bool,
+}
+
+struct Entry {
+    config: WeakConfig,
+    // When the config was built, on the cache's clock.
+    built: BootTime,
+}
+
+// A config the cache holds alive for reuse, whatever else holds it.
+struct KeptAlive {
+    key: Key,
+    config: Config,
+    // When the config was last requested, and that request's place in the order of requests
+    // for kept configs. Lookups only hold the read lock, so this has a lock of its own.
+    requested: std::sync::Mutex<(BootTime, u64)>,
+}
+
 struct State {
     // Mapping from cert_path to configs
-    key_to_config: HashMap<Key, WeakConfig>,
-    // Keep latest config alive to minimize reparsing when flapping
-    // If more keep-alive is needed, replace with a LRU LinkedList
-    latest: Option<Config>,
+    key_to_config: HashMap<Key, Entry>,
+    // The most recently built or requested configs, kept alive to minimize reparsing when
+    // flapping between them. There are only ever a few, so they are simply scanned.
+    kept_alive: Vec<KeptAlive>,
+    // Most configs `kept_alive` holds. The least recently requested one makes way for a new one.
+    keep_alive_capacity: usize,
+    // Orders requests for kept configs, so the least recent can be found.
+    requests: AtomicU64,
+    // How long a kept config is kept once nothing else holds it and it isn't requested. `None`
+    // keeps it until it is displaced.
+    keep_alive_grace: Option<Duration>,
+    clock: SharedClock,
+    observer: Option<EvictionObserver>,
+    stats: CacheStats,
+    lookups: LookupCounters,
+    // Most entries garbage collection removes per hold of the write lock.
+    gc_chunk: usize,
+    // Most entries `key_to_config` may hold, live or dead. `None` leaves it unbounded.
+    max_entries: Option<usize>,
+    // Debug logs configs are set up with as they are built.
+    log_paths: LogPaths,
+}
+
+// Where connections built from the configs a `Cache` builds write debug logs, as set by
+// `Cache::set_keylog_path` and `Cache::set_qlog_dir`.
+#[derive(Clone, Default)]
+struct LogPaths {
+    keylog: Option<Arc<fs::File>>,
+    #[cfg(feature = "qlog")]
+    qlog_dir: Option<String>,
+}
+
+impl LogPaths {
+    // Sets the logs on a freshly built config. A log which can't be set is left out, as
+    // connections work without it.
+    fn apply(&self, config: &mut Config) {
+        if let Some(file) = &self.keylog {
+            if let Err(e) = config.set_keylog(file.clone()) {
+                warn!("Unable to set keylog: {}", e);
+            }
+        }
+        #[cfg(feature = "qlog")]
+        if let Some(dir) = &self.qlog_dir {
+            if let Err(e) = config.set_qlog_dir(dir) {
+                warn!("Unable to set qlog directory: {}", e);
+            }
+        }
+    }
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new(boot_time::system_clock())
+    }
 }
 
 impl State {
+    fn new(clock: SharedClock) -> Self {
+        Self {
+            key_to_config: HashMap::new(),
+            kept_alive: Vec::new(),
+            keep_alive_capacity: Cache::DEFAULT_KEEP_ALIVE_CAPACITY,
+            requests: AtomicU64::new(0),
+            keep_alive_grace: None,
+            clock,
+            observer: None,
+            stats: CacheStats::default(),
+            lookups: LookupCounters::default(),
+            gc_chunk: Cache::DEFAULT_GC_CHUNK,
+            max_entries: None,
+            log_paths: LogPaths::default(),
+        }
+    }
+
     fn get_config(&self, key: &Key) -> Option<Config> {
-        self.key_to_config.get(key).and_then(Config::from_weak)
+        let config = Config::from_weak(&self.key_to_config.get(key)?.config)?;
+        self.note_request(key);
+        Some(config)
+    }
+
+    // As `get_config`, counting the lookup as a hit if it finds a config.
+    fn lookup(&self, key: &Key) -> Option<Config> {
+        let config = self.get_config(key)?;
+        self.lookups.hits.fetch_add(1, Ordering::Relaxed);
+        Some(config)
+    }
+
+    // Promotes a kept config to the most recently requested. Configs which aren't kept stay
+    // out: only building a config earns it a slot.
+    fn note_request(&self, key: &Key) {
+        if let Some(kept) = self.kept_alive.iter().find(|kept| kept.key == *key) {
+            let order = self.requests.fetch_add(1, Ordering::Relaxed);
+            *kept.requested.lock().unwrap() = (self.clock.now(), order);
+        }
+    }
+
+    fn keep_alive(&mut self, key: Key, config: Config) -> Vec<Eviction> {
+        let evictions = self.shrink_kept_alive(self.keep_alive_capacity - 1);
+        let order = self.requests.fetch_add(1, Ordering::Relaxed);
+        let requested = std::sync::Mutex::new((self.clock.now(), order));
+        self.kept_alive.push(KeptAlive { key, config, requested });
+        evictions
+    }
+
+    // Lets go of the least recently requested kept configs until at most `capacity` are left.
+    fn shrink_kept_alive(&mut self, capacity: usize) -> Vec<Eviction> {
+        let mut evictions = Vec::new();
+        while self.kept_alive.len() > capacity {
+            let least_recent = self
+                .kept_alive
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, kept)| kept.requested.lock().unwrap().1)
+                .map(|(index, _)| index);
+            let released = match least_recent {
+                Some(index) => self.kept_alive.remove(index),
+                None => break,
+            };
+            evictions.push((released.key.cert_path, EvictionReason::Capacity));
+        }
+        evictions
+    }
+
+    // Empties the keep-alive slots whose configs are held by nothing else and haven't been
+    // requested for the grace period.
+    fn release_unrequested(&mut self) -> Vec<Eviction> {
+        let grace = match self.keep_alive_grace {
+            Some(grace) => grace,
+            None => return Vec::new(),
+        };
+        let clock = self.clock.clone();
+        let mut evictions = Vec::new();
+        self.kept_alive.retain(|kept| {
+            let (requested, _) = *kept.requested.lock().unwrap();
+            if Arc::strong_count(&kept.config.0) > 1 || clock.elapsed(requested) < grace {
+                return true;
+            }
+            evictions.push((kept.key.cert_path.clone(), EvictionReason::Unrequested));
+            false
+        });
+        evictions
     }
 
-    fn keep_alive(&mut self, config: Config) {
-        self.latest = Some(config);
+    fn dead_keys(&self) -> Vec<Key> {
+        let dead = self.key_to_config.iter().filter(|(_, entry)| entry.config.strong_count() == 0);
+        dead.map(|(key, _)| key.clone()).collect()
     }
 
-    fn garbage_collect(&mut self) {
-        self.key_to_config.retain(|_, config| config.strong_count() != 0)
+    // Removes the entry for `key` if nothing holds its config. The entry may have changed since
+    // it was found dead: `get` may have rebuilt it, or `invalidate` removed it, and either way it
+    // is left alone.
+    fn remove_if_dead(&mut self, key: &Key) -> Option<Eviction> {
+        if self.key_to_config.get(key)?.config.strong_count() != 0 {
+            return None;
+        }
+        self.key_to_config.remove(key);
+        Some((key.cert_path.clone(), EvictionReason::Dead))
+    }
+
+    fn install(&mut self, key: Key, config: &Config) -> Vec<Eviction> {
+        let entry = Entry { config: config.to_weak(), built: self.clock.now() };
+        self.key_to_config.insert(key, entry);
+        self.enforce_entry_limit()
+    }
+
+    // Brings the map back within `max_entries` after an installation takes it over, by removing
+    // the entries for dead configs. Live ones are never evicted, as the next `get` for one would
+    // build a duplicate of a config still in use, so if they alone are over the limit the map is
+    // left over it. The map is bounded by `max_entries` plus what is in use, so collecting it
+    // here is quick enough to do under the write lock.
+    fn enforce_entry_limit(&mut self) -> Vec<Eviction> {
+        let max_entries = match self.max_entries {
+            Some(max_entries) if self.key_to_config.len() > max_entries => max_entries,
+            _ => return Vec::new(),
+        };
+        let dead = self.dead_keys();
+        let evictions: Vec<_> = dead.iter().filter_map(|key| self.remove_if_dead(key)).collect();
+        if self.key_to_config.len() > max_entries {
+            warn!(
+                "Config cache holds {} configs in use, over its limit of {}",
+                self.key_to_config.len(),
+                max_entries
+            );
+        }
+        evictions
+    }
+
+    // Forgets the configs for every key `doomed` picks, kept alive or not.
+    fn invalidate(&mut self, doomed: impl Fn(&Key) -> bool) -> Vec<Eviction> {
+        self.kept_alive.retain(|kept| !doomed(&kept.key));
+        let keys: Vec<_> = self.key_to_config.keys().filter(|key| doomed(key)).cloned().collect();
+        for key in &keys {
+            self.key_to_config.remove(key);
+        }
+        keys.into_iter().map(|key| (key.cert_path, EvictionReason::Invalidated)).collect()
     }
 }
 
@@ -112,97 +851,986 @@ impl State {
 /// Cloning this cache will create another handle to the same cache.
 ///
 /// Loading a config object through this caching layer will only keep the
-/// latest config loaded alive directly, but will still act as a cache
-/// for any configurations still in use - if the returned `Config` is still
-/// live, queries to `Cache` will not reconstruct it.
+/// most recently requested configs alive directly (see `Cache::with_capacity`),
+/// but will still act as a cache for any configurations still in use - if the
+/// returned `Config` is still live, queries to `Cache` will not reconstruct it.
 #[derive(Clone, Default)]
 pub struct Cache {
     // Shared state amongst cache handles
     state: Arc<RwLock<State>>,
+    // Locks `get_async` callers queue on while one of them builds the config for a key. Entries
+    // are dropped along with the last caller holding them.
+    builds: Arc<std::sync::Mutex<HashMap<Key, Weak<Mutex<()>>>>>,
+}
+
+// A handle to a cache which doesn't keep it alive.
+struct WeakCache {
+    state: Weak<RwLock<State>>,
+    builds: Weak<std::sync::Mutex<HashMap<Key, Weak<Mutex<()>>>>>,
+}
+
+impl WeakCache {
+    fn upgrade(&self) -> Option<Cache> {
+        Some(Cache { state: self.state.upgrade()?, builds: self.builds.upgrade()? })
+    }
+}
+
+/// Background garbage collection of a `Cache`, started by `Cache::spawn_gc`. Dropping the
+/// handle stops it, waiting for a collection in progress to finish.
+pub struct GcHandle {
+    // Dropping the sender wakes the thread to exit.
+    stop: Option<std::sync::mpsc::Sender<()>>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Drop for GcHandle {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                warn!("Config cache garbage collection panicked");
+            }
+        }
+    }
 }
 
 /// Key used for getting an associated Quiche Config from Cache.
+///
+/// Only settings baked into the `quiche::Config` belong here. Per-network state such as the
+/// socket mark is applied when a connection is built, so one `Config` can safely back
+/// connections on several networks.
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub struct Key {
     pub cert_path: Option<String>,
+    /// PEM certificates to verify servers against, for trust anchors which are held in memory
+    /// rather than in a directory. They can't be combined with a `cert_path`. Keys tell them apart
+    /// by their digest, so configs are only shared by servers given the same certificates.
+    pub cert_pem: Option<PemCerts>,
     pub max_idle_timeout: u64,
+    /// Largest response body connections are expected to accept by default, which sizes the
+    /// per-stream flow-control window. `None` means `DEFAULT_MAX_RESPONSE_SIZE`.
+    pub max_response_size: Option<usize>,
+    /// Acceptable QUIC versions, most preferred first. Connections start out with the first, and
+    /// a server can only move them to another of these through version negotiation.
+    pub quic_versions: Vec<u32>,
+    /// Whether to tell servers, with the `disable_active_migration` transport parameter, that
+    /// connections won't move to another path. This should be the inverse of
+    /// `connection::Options::active_migration`.
+    pub disable_active_migration: bool,
+    /// Whether connections resuming a session may send 0-RTT early data, which saves the
+    /// round trip of a reconnect's handshake. Off unless asked for, since a server can be made
+    /// to act on early data more than once by replaying it: only idempotent DNS queries should
+    /// be sent on a config with it set.
+    pub enable_early_data: bool,
+    /// Transport parameters tuned for the server, in place of the ones configs are otherwise
+    /// built with. Servers which agree on every setting here and above share a config, whatever
+    /// else tells them apart, and those which differ in any of them never do.
+    pub transport: TransportParams,
+    /// How requests are carried, which decides the ALPN protocol offered. This should match
+    /// `connection::Options::stream_mode`.
+    pub stream_mode: StreamMode,
+    /// ALPN protocols to offer after the one `stream_mode` calls for, most preferred first, such
+    /// as a vendor protocol a server expects alongside h3. The protocol of the stream mode is
+    /// always offered first, and is the only one connections can speak, so this is only for
+    /// servers which want to see these listed. Each must be 1 to 255 bytes.
+    pub extra_application_protos: Vec<Vec<u8>>,
+}
+
+/// PEM certificates held in memory, for `Key::cert_pem`
+///
+/// They are compared and hashed by their SHA-256 digest, which is taken once, so keys holding
+/// them stay cheap to clone and look up however large the bundle is.
+#[derive(Clone)]
+pub struct PemCerts {
+    pem: Arc<[u8]>,
+    digest: [u8; 32],
+}
+
+impl From<&[u8]> for PemCerts {
+    fn from(pem: &[u8]) -> Self {
+        let mut digest = [0; 32];
+        digest.copy_from_slice(ring::digest::digest(&ring::digest::SHA256, pem).as_ref());
+        Self { pem: pem.into(), digest }
+    }
+}
+
+impl PemCerts {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.pem
+    }
+}
+
+impl PartialEq for PemCerts {
+    fn eq(&self, other: &Self) -> bool {
+        self.digest == other.digest
+    }
+}
+
+impl Eq for PemCerts {}
+
+impl std::hash::Hash for PemCerts {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.digest.hash(state)
+    }
+}
+
+impl std::fmt::Debug for PemCerts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PemCerts({} bytes, sha256 ", self.pem.len())?;
+        self.digest.iter().try_for_each(|byte| write!(f, "{:02x}", byte))?;
+        write!(f, ")")
+    }
+}
+
+/// Where a config gets the certificates it verifies servers against
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum CertSource {
+    /// A directory of certificates, such as `/system/etc/security/cacerts`
+    Dir(String),
+    /// Certificates held in memory
+    Pem(PemCerts),
+}
+
+/// What a connection's streams carry
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum StreamMode {
+    /// DNS over HTTPS: each query is an HTTP/3 request
+    #[default]
+    Http3,
+    /// DNS over QUIC (RFC 9250): each query is sent on a stream of its own, prefixed by its
+    /// length, with no HTTP/3 layer. This is experimental, for interop testing; queries are
+    /// written by the network exactly as they are for DoH, and the connection takes the DNS
+    /// message back out of the request.
+    RawDns,
+}
+
+impl StreamMode {
+    // ALPN protocols to offer, in wire format.
+    fn application_protos(self) -> &'static [u8] {
+        match self {
+            Self::Http3 => h3::APPLICATION_PROTOCOL,
+            Self::RawDns => b"\x03doq",
+        }
+    }
+}
+
+/// Transport settings a `Key` may override, each `None` keeping what configs are built with
+/// by default.
+///
+/// The flow-control window of request streams isn't here, as it follows
+/// `Key::max_response_size`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct TransportParams {
+    /// Connection flow-control window. `connection::Options::connection_window` can still swap
+    /// in another one per connection.
+    pub initial_max_data: Option<u64>,
+    /// Flow-control window of streams the server opens
+    pub initial_max_stream_data_bidi_remote: Option<u64>,
+    pub initial_max_stream_data_uni: Option<u64>,
+    /// How many streams of each kind the server may open
+    pub initial_max_streams_bidi: Option<u64>,
+    pub initial_max_streams_uni: Option<u64>,
+    /// Longest we delay acknowledgements, in milliseconds
+    pub max_ack_delay: Option<u64>,
+    pub ack_delay_exponent: Option<u64>,
+    /// Whether the congestion controller leaves slow start early on rising delay (HyStart++)
+    pub hystart: Option<bool>,
+    /// Congestion control algorithm of connections which aren't metered. Metered connections
+    /// use Reno whatever this says (see `connection::Options::metered`).
+    pub congestion_control: Option<CongestionControl>,
+    /// Largest UDP payload we accept, advertised to the server as `max_udp_payload_size`, and
+    /// largest datagram connections send. Lower it for paths with a smaller MTU, such as some
+    /// VPN tunnels, where larger packets are fragmented or dropped. Sizes below the 1200 bytes
+    /// every QUIC path must carry are raised to it. Defaults to `MAX_DATAGRAM_SIZE`.
+    pub max_datagram_size: Option<usize>,
+}
+
+/// Congestion control algorithms a config can be built with
+///
+/// The quiche version we build against has no BBR, which has to wait for an upgrade.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum CongestionControl {
+    Reno,
+    /// quiche's default
+    #[default]
+    Cubic,
+}
+
+impl CongestionControl {
+    pub(crate) fn algorithm(self) -> quiche::CongestionControlAlgorithm {
+        match self {
+            Self::Reno => quiche::CongestionControlAlgorithm::Reno,
+            Self::Cubic => quiche::CongestionControlAlgorithm::CUBIC,
+        }
+    }
+}
+
+impl TransportParams {
+    fn max_datagram_size(&self) -> usize {
+        self.max_datagram_size.unwrap_or(MAX_DATAGRAM_SIZE).max(quiche::MIN_CLIENT_INITIAL_LEN)
+    }
+
+    fn apply(&self, config: &mut quiche::Config) {
+        config
+            .set_initial_max_data(self.initial_max_data.unwrap_or(MAX_INCOMING_BUFFER_SIZE_WHOLE));
+        config.set_initial_max_stream_data_bidi_remote(
+            self.initial_max_stream_data_bidi_remote.unwrap_or(MAX_INCOMING_BUFFER_SIZE_EACH),
+        );
+        config.set_initial_max_stream_data_uni(
+            self.initial_max_stream_data_uni.unwrap_or(MAX_INCOMING_BUFFER_SIZE_EACH),
+        );
+        config.set_initial_max_streams_bidi(
+            self.initial_max_streams_bidi.unwrap_or(MAX_CONCURRENT_STREAM_SIZE),
+        );
+        config.set_initial_max_streams_uni(
+            self.initial_max_streams_uni.unwrap_or(MAX_CONCURRENT_STREAM_SIZE),
+        );
+        // The rest stay at quiche's defaults unless set.
+        if let Some(delay) = self.max_ack_delay {
+            config.set_max_ack_delay(delay);
+        }
+        if let Some(exponent) = self.ack_delay_exponent {
+            config.set_ack_delay_exponent(exponent);
+        }
+        if let Some(hystart) = self.hystart {
+            config.enable_hystart(hystart);
+        }
+        config.set_cc_algorithm(self.congestion_control.unwrap_or_default().algorithm());
+        config.set_max_recv_udp_payload_size(self.max_datagram_size());
+    }
+}
+
+impl Key {
+    // The key with its cert path normalized by `normalize_cert_path`.
+    fn normalized(&self) -> Result<Self> {
+        let cert_path = self.cert_path.as_deref().map(normalize_cert_path).transpose()?;
+        Ok(Self { cert_path, ..self.clone() })
+    }
+
+    /// Where configs for this key get the certificates to verify servers against, if they verify
+    /// them at all. Fails if both `cert_path` and `cert_pem` are set.
+    pub fn cert_source(&self) -> Result<Option<CertSource>> {
+        match (&self.cert_path, &self.cert_pem) {
+            (Some(_), Some(_)) => Err(ConfigError::CertSources),
+            (Some(path), None) => Ok(Some(CertSource::Dir(path.clone()))),
+            (None, Some(pem)) => Ok(Some(CertSource::Pem(pem.clone()))),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// Checks that a config could be built for this key and would be usable, without caching it
+    /// or contacting any server. Each setting must be within the range QUIC allows, and building
+    /// the config surfaces trust store errors as `from_key` would report them.
+    pub fn validate(&self) -> Result<()> {
+        self.check_ranges()?;
+        if let Some(path) = self.normalized()?.cert_path {
+            check_cert_dir(&path)?;
+        }
+        Config::from_key(self).map(|_| ())
+    }
+
+    fn check_ranges(&self) -> Result<()> {
+        let transport = &self.transport;
+        let limits = [
+            ("max_idle_timeout", Some(self.max_idle_timeout), MAX_VARINT),
+            ("initial_max_data", transport.initial_max_data, MAX_VARINT),
+            (
+                "initial_max_stream_data_bidi_remote",
+                transport.initial_max_stream_data_bidi_remote,
+                MAX_VARINT,
+            ),
+            ("initial_max_stream_data_uni", transport.initial_max_stream_data_uni, MAX_VARINT),
+            // RFC 9000 section 4.6: a stream count can't exceed 2^60.
+            ("initial_max_streams_bidi", transport.initial_max_streams_bidi, MAX_STREAMS),
+            ("initial_max_streams_uni", transport.initial_max_streams_uni, MAX_STREAMS),
+            // RFC 9000 section 18.2.
+            ("max_ack_delay", transport.max_ack_delay, MAX_ACK_DELAY),
+            ("ack_delay_exponent", transport.ack_delay_exponent, MAX_ACK_DELAY_EXPONENT),
+        ];
+        for (name, value, max) in limits {
+            match value {
+                Some(value) if value > max => {
+                    let message = format!("{} is {}, above {}", name, value, max);
+                    return Err(ConfigError::OutOfRange(message));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+// Largest value a QUIC variable-length integer can hold.
+const MAX_VARINT: u64 = (1 << 62) - 1;
+const MAX_STREAMS: u64 = 1 << 60;
+const MAX_ACK_DELAY: u64 = (1 << 14) - 1;
+const MAX_ACK_DELAY_EXPONENT: u64 = 20;
+
+/// A key for tests, with a one second idle timeout and everything else left to the defaults.
+/// Tests name only the fields they care about, and take the rest with `..test_key()`.
+#[cfg(test)]
+pub fn test_key() -> Key {
+    Key {
+        cert_path: None,
+        cert_pem: None,
+        max_idle_timeout: 1000,
+        max_response_size: None,
+        quic_versions: vec![quiche::PROTOCOL_VERSION],
+        disable_active_migration: true,
+        enable_early_data: false,
+        transport: Default::default(),
+        stream_mode: Default::default(),
+        extra_application_protos: Vec::new(),
+    }
 }
 
 impl Cache {
-    /// Creates a fresh empty cache
+    /// How long `get` waits for the write lock before failing with `ConfigError::CacheBusy`. The
+    /// lock is only ever held for map updates, so waiting this long means something is wrong.
+    pub const LOCK_PATIENCE: Duration = Duration::from_millis(500);
+    // Bounds on the sleep between attempts at the write lock, which doubles each time.
+    const MIN_LOCK_BACKOFF: Duration = Duration::from_micros(50);
+    const MAX_LOCK_BACKOFF: Duration = Duration::from_millis(20);
+    /// Entries garbage collection removes per hold of the write lock, unless changed with
+    /// `set_gc_chunk`.
+    pub const DEFAULT_GC_CHUNK: usize = 64;
+    /// Configs the cache keeps alive for reuse, unless created with `with_capacity`. Enough for
+    /// a device moving between a few networks, such as VPN profiles, each with its own trust
+    /// store.
+    pub const DEFAULT_KEEP_ALIVE_CAPACITY: usize = 4;
+
+    /// Creates a fresh empty cache on the system clock. The dispatcher builds its cache with
+    /// `with_clock`, so this is for tests.
+    #[cfg(test)]
     pub fn new() -> Self {
         Default::default()
     }
 
-    /// Behaves as `Config::from_cert_path`, but with a cache.
+    /// Creates a fresh empty cache which reports each config it lets go of to `observer`.
+    #[cfg(test)]
+    pub fn with_observer(observer: EvictionObserver) -> Self {
+        let cache = Self::new();
+        cache.set_observer(observer);
+        cache
+    }
+
+    /// Creates a fresh empty cache which keeps the `capacity` most recently requested configs
+    /// alive, at least one, for reuse even while nothing else holds them. Only configs it has
+    /// built take a slot, and each request for one already kept makes it the most recent.
+    #[cfg(test)]
+    pub fn with_capacity(capacity: usize) -> Self {
+        let cache = Self::new();
+        cache.set_keep_alive_capacity(capacity);
+        cache
+    }
+
+    /// Creates a fresh empty cache holding at most `entries` configs, at least one. The other
+    /// constructors leave the cache unbounded. See `set_max_entries` for what happens when it
+    /// fills up.
+    #[cfg(test)]
+    pub fn with_max_entries(entries: usize) -> Self {
+        let cache = Self::new();
+        cache.set_max_entries(entries);
+        cache
+    }
+
+    /// Creates a fresh empty cache which times how long configs go unrequested on `clock`.
+    pub fn with_clock(clock: SharedClock) -> Self {
+        Self { state: Arc::new(RwLock::new(State::new(clock))), builds: Default::default() }
+    }
+
+    // Reports evictions to the observer. This must be called without the state lock held, so
+    // that the observer may use the cache.
+    fn report(&self, observer: Option<EvictionObserver>, evictions: Vec<Eviction>) {
+        if let Some(observer) = observer {
+            for (cert_path, reason) in evictions {
+                observer(cert_path.as_deref(), reason);
+            }
+        }
+    }
+
+    // Takes the write lock, retrying with backoff for up to `LOCK_PATIENCE` rather than blocking
+    // for as long as someone else holds it.
+    fn write_state(&self) -> Result<RwLockWriteGuard<'_, State>> {
+        let start = BootTime::now();
+        let mut backoff = Self::MIN_LOCK_BACKOFF;
+        loop {
+            match self.state.try_write() {
+                Ok(state) => return Ok(state),
+                Err(TryLockError::Poisoned(e)) => panic!("Config cache lock poisoned: {}", e),
+                Err(TryLockError::WouldBlock) if start.elapsed() >= Self::LOCK_PATIENCE => {
+                    return Err(ConfigError::CacheBusy)
+                }
+                Err(TryLockError::WouldBlock) => {
+                    std::thread::sleep(backoff);
+                    backoff = (backoff * 2).min(Self::MAX_LOCK_BACKOFF);
+                }
+            }
+        }
+    }
+
+    /// Behaves as `Config::from_key`, but with a cache.
     /// If any object previously given out by this cache is still live,
     /// a duplicate will not be made.
+    ///
+    /// Callers racing to build the same missing config share whichever one is installed first.
+    /// A caller whose own build fails takes that shared config if another caller's build has
+    /// succeeded in the meantime, and otherwise gets its own error; a failed build never leaves
+    /// anything in the cache, so the next `get` tries again.
     pub fn get(&self, key: &Key) -> Result<Config> {
+        self.get_or_build(key, Config::from_key)
+    }
+
+    fn get_or_build(
+        &self,
+        key: &Key,
+        build: impl FnOnce(&Key) -> Result<Config>,
+    ) -> Result<Config> {
+        let key = &key.normalized()?;
         // Fast path - read-only access to state retrieves config
-        if let Some(config) = self.state.read().unwrap().get_config(key) {
-            return Ok(config);
-        }
+        let log_paths = {
+            let state = self.state.read().unwrap();
+            if let Some(config) = state.lookup(key) {
+                return Ok(config);
+            }
+            state.lookups.misses.fetch_add(1, Ordering::Relaxed);
+            state.log_paths.clone()
+        };
 
         // Unlocked, calculate config. If we have two racing attempts to load
         // the cert path, we'll arbitrate that in the next step, but this
         // makes sure loading a new cert path doesn't block other loads to
         // refresh connections.
-        let config = Config::from_key(key)?;
+        let start = BootTime::now();
+        let config = build(key).map(|mut config| {
+            log_paths.apply(&mut config);
+            config
+        });
+        let elapsed = start.elapsed();
+        debug!("Built config for {:?} in {:?}", key.cert_path, elapsed);
 
-        let mut state = self.state.write().unwrap();
+        let mut state = self.write_state()?;
+        state.stats.record(elapsed);
         // We now have exclusive access to the state.
         // If someone else calculated a config at the same time as us, we
         // want to discard ours and use theirs, since it will result in
-        // less total memory used.
+        // less total memory used. Theirs also stands in for ours if ours
+        // failed.
         if let Some(config) = state.get_config(key) {
+            state.lookups.races.fetch_add(1, Ordering::Relaxed);
             return Ok(config);
         }
+        let config = config?;
 
         // We have exclusive access and a fresh config. Install it into
         // the cache.
-        state.keep_alive(config.clone());
-        state.key_to_config.insert(key.clone(), config.to_weak());
+        let mut evictions = state.keep_alive(key.clone(), config.clone());
+        evictions.extend(state.install(key.clone(), &config));
+        let observer = state.observer.clone();
+        drop(state);
+        self.report(observer, evictions);
         Ok(config)
     }
 
-    /// Purges any config paths which no longer point to a config entry.
-    pub fn garbage_collect(&self) {
-        self.state.write().unwrap().garbage_collect();
+    /// As `get`, but a missing config is built on the blocking thread pool, so that parsing a
+    /// large trust store doesn't hold up the caller's thread. Concurrent `get_async` calls for one
+    /// key share a single build, each waiting for it rather than building its own, while a `get`
+    /// racing them is arbitrated as it would be with another `get`. A failed build isn't shared:
+    /// the next caller waiting tries again.
+    pub async fn get_async(&self, key: &Key) -> Result<Config> {
+        let key = key.normalized()?;
+        if let Some(config) = self.state.read().unwrap().lookup(&key) {
+            return Ok(config);
+        }
+        let build = self.build_lock(&key);
+        let _building = build.lock().await;
+        // Whoever held the lock before us may have installed the config.
+        if let Some(config) = self.state.read().unwrap().lookup(&key) {
+            return Ok(config);
+        }
+        let cache = self.clone();
+        task::spawn_blocking(move || cache.get(&key)).await.map_err(|e| {
+            debug!("Config construction task failed: {}", e);
+            ConfigError::BuildAbandoned
+        })?
+    }
+
+    // The lock `get_async` callers for `key` queue on, shared with any which already hold it.
+    fn build_lock(&self, key: &Key) -> Arc<Mutex<()>> {
+        let mut builds = self.builds.lock().unwrap();
+        builds.retain(|_, lock| lock.strong_count() > 0);
+        if let Some(lock) = builds.get(key).and_then(Weak::upgrade) {
+            return lock;
+        }
+        let lock = Arc::new(Mutex::new(()));
+        builds.insert(key.clone(), Arc::downgrade(&lock));
+        lock
+    }
+
+    /// Counters for the configs this cache has built and the lookups it has served, shared by
+    /// every handle to it.
+    pub fn stats(&self) -> CacheStats {
+        let state = self.state.read().unwrap();
+        CacheStats {
+            hits: state.lookups.hits.load(Ordering::Relaxed),
+            misses: state.lookups.misses.load(Ordering::Relaxed),
+            races: state.lookups.races.load(Ordering::Relaxed),
+            ..state.stats
+        }
+    }
+
+    /// The configs this cache holds, oldest first. Entries for configs nothing holds any more
+    /// are left out, even before garbage collection removes them.
+    pub fn resident(&self) -> Vec<ResidentConfig> {
+        let state = self.state.read().unwrap();
+        let mut resident: Vec<_> = state
+            .key_to_config
+            .iter()
+            .filter(|(_, entry)| entry.config.strong_count() > 0)
+            .map(|(key, entry)| ResidentConfig {
+                cert_path: key.cert_path.clone(),
+                age: state.clock.elapsed(entry.built),
+                kept_alive: state.kept_alive.iter().any(|kept| kept.key == *key),
+            })
+            .collect();
+        resident.sort_by_key(|config| std::cmp::Reverse(config.age));
+        resident
+    }
+
+    /// Reports each config the cache lets go of from now on to `observer`, in place of any
+    /// observer it had.
+    pub fn set_observer(&self, observer: EvictionObserver) {
+        self.state.write().unwrap().observer = Some(observer);
+    }
+
+    /// Has connections built from the configs the cache builds from now on append their TLS
+    /// secrets to the file at `path`, as `Config::set_keylog` does, or stop if `None`. The file
+    /// is opened here, failing with `ConfigError::DebugLog` and leaving the log as it was if it
+    /// can't be. Configs already built are unaffected; invalidating them has them rebuilt with
+    /// the log.
+    pub fn set_keylog_path(&self, path: Option<&str>) -> Result<()> {
+        let file = path.map(open_keylog).transpose()?;
+        self.state.write().unwrap().log_paths.keylog = file;
+        Ok(())
+    }
+
+    /// As `set_keylog_path`, for the qlog traces `Config::set_qlog_dir` writes to `dir`.
+    #[cfg(feature = "qlog")]
+    pub fn set_qlog_dir(&self, dir: Option<String>) {
+        self.state.write().unwrap().log_paths.qlog_dir = dir;
+    }
+
+    /// Sets how many entries garbage collection removes each time it takes the write lock, at
+    /// least one. Smaller chunks hold up `get` for less time in one go, at the cost of taking the
+    /// lock more often.
+    pub fn set_gc_chunk(&self, entries: usize) {
+        self.state.write().unwrap().gc_chunk = entries.max(1);
+    }
+
+    /// Caps the entries the cache holds, at least one, so that churning through distinct keys
+    /// can't grow it without bound between garbage collections. When installing a config takes
+    /// it over, the entries for dead configs are removed. Configs still in use are kept even if
+    /// that leaves the cache over the cap, which is logged.
+    pub fn set_max_entries(&self, entries: usize) {
+        let mut state = self.state.write().unwrap();
+        state.max_entries = Some(entries.max(1));
+        let evictions = state.enforce_entry_limit();
+        let observer = state.observer.clone();
+        drop(state);
+        self.report(observer, evictions);
+    }
+
+    /// Changes how many configs the cache keeps alive for reuse, at least one. Shrinking lets go
+    /// of the least recently requested ones straight away.
+    pub fn set_keep_alive_capacity(&self, capacity: usize) {
+        let mut state = self.state.write().unwrap();
+        let capacity = capacity.max(1);
+        state.keep_alive_capacity = capacity;
+        let evictions = state.shrink_kept_alive(capacity);
+        let observer = state.observer.clone();
+        drop(state);
+        self.report(observer, evictions);
+    }
+
+    /// Lets garbage collection release each config kept alive for reuse once nothing else holds
+    /// it and it hasn't been requested for `grace`, so a config used once and never again doesn't
+    /// stay in memory just for being recent. With `None`, the default, it is kept until a newer
+    /// config displaces it or the cache is trimmed.
+    pub fn set_keep_alive_grace(&self, grace: Option<Duration>) {
+        self.state.write().unwrap().keep_alive_grace = grace;
+    }
+
+    // Removes the entries for configs nothing holds, first releasing the kept-alive configs whose
+    // grace period has passed. The dead entries are found under the read lock, which leaves
+    // lookups running, and then removed a chunk at a time so that a large map doesn't keep `get`
+    // from installing new configs for the whole collection. Entries which die during the
+    // collection are left for the next one.
+    fn collect_garbage(&self) -> Vec<Eviction> {
+        let mut evictions = self.state.write().unwrap().release_unrequested();
+        let (dead, chunk) = {
+            let state = self.state.read().unwrap();
+            (state.dead_keys(), state.gc_chunk)
+        };
+        for keys in dead.chunks(chunk) {
+            let mut state = self.state.write().unwrap();
+            evictions.extend(keys.iter().filter_map(|key| state.remove_if_dead(key)));
+        }
+        evictions
+    }
+
+    /// Purges any config paths which no longer point to a config entry, returning how many.
+    pub fn garbage_collect(&self) -> usize {
+        let evictions = self.collect_garbage();
+        let observer = self.state.read().unwrap().observer.clone();
+        let purged = evictions.iter().filter(|(_, reason)| *reason == EvictionReason::Dead).count();
+        self.report(observer, evictions);
+        purged
+    }
+
+    /// Runs `garbage_collect` every `interval` on a thread of its own, until the returned handle
+    /// is dropped. The thread only holds the cache while collecting, and exits early once every
+    /// other handle to it is gone.
+    pub fn spawn_gc(&self, interval: Duration) -> io::Result<GcHandle> {
+        use std::sync::mpsc::{self, RecvTimeoutError};
+        let cache =
+            WeakCache { state: Arc::downgrade(&self.state), builds: Arc::downgrade(&self.builds) };
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread =
+            std::thread::Builder::new().name("doh-config-gc".to_string()).spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    match cache.upgrade() {
+                        Some(cache) => {
+                            let purged = cache.garbage_collect();
+                            debug!("Background garbage collection purged {} configs", purged);
+                        }
+                        None => break,
+                    }
+                }
+            })?;
+        Ok(GcHandle { stop: Some(stop), thread: Some(thread) })
+    }
+
+    /// Lets go of everything not in use, for when memory is short: the configs kept alive for
+    /// reuse, and then entries for configs nothing holds. Returns how many configs were dropped
+    /// from the cache.
+    pub fn trim(&self) -> usize {
+        let released = std::mem::take(&mut self.state.write().unwrap().kept_alive);
+        // Collected here so the released configs are dropped before the garbage collection.
+        let released: Vec<Eviction> = released
+            .into_iter()
+            .map(|kept| (kept.key.cert_path, EvictionReason::Trimmed))
+            .collect();
+        let dead = self.collect_garbage();
+        let dropped = dead.iter().filter(|(_, reason)| *reason == EvictionReason::Dead).count();
+        let observer = self.state.read().unwrap().observer.clone();
+        self.report(observer, released.into_iter().chain(dead).collect());
+        dropped
+    }
+
+    /// Forgets the config for `key`, so the next `get` for it builds a fresh one. Configs
+    /// already handed out keep working.
+    #[cfg(test)]
+    pub fn invalidate(&self, key: &Key) -> Result<()> {
+        let key = key.normalized()?;
+        self.invalidate_where(|cached| *cached == key);
+        Ok(())
+    }
+
+    /// Forgets the configs for every key with this `cert_path`, whatever their other
+    /// parameters, so that new requests reparse a trust store updated in place. Configs already
+    /// handed out keep working.
+    pub fn invalidate_cert_path(&self, cert_path: &Option<String>) -> Result<()> {
+        let cert_path = cert_path.as_deref().map(normalize_cert_path).transpose()?;
+        self.invalidate_where(|cached| cached.cert_path == cert_path);
+        Ok(())
+    }
+
+    /// Forgets every config, as `invalidate` does for one.
+    pub fn invalidate_all(&self) {
+        self.invalidate_where(|_| true);
+    }
+
+    fn invalidate_where(&self, doomed: impl Fn(&Key) -> bool) {
+        let mut state = self.state.write().unwrap();
+        let evictions = state.invalidate(doomed);
+        let observer = state.observer.clone();
+        drop(state);
+        self.report(observer, evictions);
     }
 }
 
 #[test]
 fn create_quiche_config() {
+    assert!(Config::from_key(&test_key()).is_ok(), "quiche config without cert creating failed");
     assert!(
-        Config::from_key(&Key { cert_path: None, max_idle_timeout: 1000 }).is_ok(),
-        "quiche config without cert creating failed"
+        Config::from_key(&Key { cert_path: Some("data/local/tmp/".to_string()), ..test_key() })
+            .is_ok(),
+        "quiche config with cert creating failed"
     );
+}
+
+#[tokio::test]
+async fn extra_application_protos() {
+    use crate::connection::loopback;
+    let key = |extra_application_protos| Key { extra_application_protos, ..test_key() };
+    assert_eq!(application_protos(&key(vec![])).unwrap(), h3::APPLICATION_PROTOCOL);
+    let vendor = key(vec![b"vendor-doh".to_vec()]);
+    let protos = application_protos(&vendor).unwrap();
+    assert!(protos.starts_with(h3::APPLICATION_PROTOCOL));
+    assert_eq!(&protos[h3::APPLICATION_PROTOCOL.len()..], b"\x0avendor-doh");
+
+    let cache = Cache::new();
+    let mut config = cache.get(&vendor).unwrap();
+    assert!(!Arc::ptr_eq(&config.0, &cache.get(&key(vec![])).unwrap().0));
+    // A server which only speaks h3 still picks it.
+    let (client, _) = loopback::handshake(&mut config, None).await.unwrap();
+    assert_eq!(client.application_proto(), b"h3");
+
+    for unusable in [vec![], vec![b'x'; 256]] {
+        let result = Config::from_key(&key(vec![unusable]));
+        assert!(matches!(result, Err(ConfigError::ApplicationProtos(_))));
+    }
+}
+
+#[tokio::test]
+async fn try_take() {
+    let mut config = Config::from_key(&test_key()).unwrap();
+    let mut other = config.clone();
+    let taken = config.take().await;
+    assert!(other.try_take().is_none());
+    drop(taken);
+    assert!(other.try_take().is_some());
+}
+
+#[test]
+fn verifies_peer() {
+    let key =
+        |cert_path: Option<&str>| Key { cert_path: cert_path.map(str::to_string), ..test_key() };
+    assert!(!Config::from_key(&key(None)).unwrap().verifies_peer());
+    assert!(Config::from_key(&key(Some("data/local/tmp/"))).unwrap().verifies_peer());
+    // Configs shared through the cache keep the setting they were built with.
+    let cache = Cache::new();
+    assert!(cache.get(&key(Some("data/local/tmp/"))).unwrap().verifies_peer());
+    assert!(!cache.get(&key(None)).unwrap().verifies_peer());
+}
+
+#[tokio::test]
+async fn pem_trust_anchors() {
+    use crate::connection::loopback;
+    let key = |cert_pem: &[u8]| Key { cert_pem: Some(cert_pem.into()), ..test_key() };
+    let trusted = key(loopback::SERVER_CERT.as_bytes());
+    let cache = Cache::new();
+    let mut config = cache.get(&trusted).unwrap();
+    assert!(config.verifies_peer());
+    // The certificates themselves tell configs apart.
+    let other = cache.get(&key(format!("{}\n", loopback::SERVER_CERT).as_bytes())).unwrap();
+    assert!(!Arc::ptr_eq(&config.0, &other.0));
+
+    // Servers are verified against them as they would be against a directory holding them.
+    for (server_name, verified) in [("dns.example.com", true), ("other.example.com", false)] {
+        let handshake = loopback::handshake(&mut config, Some(server_name)).await;
+        let established = handshake.map_or(false, |(client, _)| client.is_established());
+        assert_eq!(established, verified, "{}", server_name);
+    }
+
+    for unusable in [&b""[..], b"not a certificate", b"-----BEGIN CERTIFICATE-----\nbad\n"] {
+        assert!(matches!(Config::from_key(&key(unusable)), Err(ConfigError::CertPem(_))));
+    }
+    // A bundle larger than a pipe holds by default still loads.
+    let bundle = loopback::SERVER_CERT.repeat(128);
+    assert!(bundle.len() > 64 * 1024);
+    assert!(Config::from_cert_pem(&bundle.as_bytes().into(), &test_key()).unwrap().verifies_peer());
+    // The certificates can't be combined with a directory.
+    let both = Key { cert_path: Some("data/local/tmp/".to_string()), ..trusted };
+    assert!(matches!(both.cert_source(), Err(ConfigError::CertSources)));
+    assert!(matches!(Config::from_key(&both), Err(ConfigError::CertSources)));
+    let pem = both.cert_pem.clone().unwrap();
+    assert!(matches!(Config::from_cert_pem(&pem, &both), Err(ConfigError::CertSources)));
+}
+
+#[tokio::test]
+async fn keylog() {
+    use crate::connection::loopback;
+    let path = std::env::temp_dir().join(format!("doh_keylog_{}", std::process::id()));
+    let path = path.to_str().unwrap();
+    let _ = fs::remove_file(path);
+    let mut config = Config::from_key(&test_key()).unwrap();
+    config.set_keylog(open_keylog(path).unwrap()).unwrap();
+    let handshake = loopback::handshake(&mut config, None).await;
+    let logged = fs::metadata(path).map(|metadata| metadata.len());
+    // Once a connection may have been built without it, the log can't be set any more.
+    let set_again = config.set_keylog(open_keylog(path).unwrap());
+    // The file goes before anything is checked, so that no test run leaves it behind.
+    fs::remove_file(path).unwrap();
+
+    assert!(handshake.unwrap().0.is_established());
+    assert!(logged.unwrap() > 0);
+    assert!(matches!(set_again, Err(ConfigError::InUse)));
+}
+
+#[test]
+fn cache_keylog() {
+    let path = std::env::temp_dir().join(format!("doh_cache_keylog_{}", std::process::id()));
+    let path = path.to_str().unwrap();
+    let cache = Cache::new();
+    let before = cache.get(&test_key()).unwrap();
+    cache.set_keylog_path(Some(path)).unwrap();
+    // The config already built is handed out again as it was.
+    assert!(cache.get(&test_key()).unwrap().debug_logs().keylog.is_none());
+    drop(before);
+    cache.invalidate_all();
+    assert!(cache.get(&test_key()).unwrap().debug_logs().keylog.is_some());
+
+    // A path which can't be opened fails up front, leaving the log as it was.
+    let missing = "/nonexistent/doh_keylog";
+    assert!(matches!(cache.set_keylog_path(Some(missing)), Err(ConfigError::DebugLog(..))));
+    let other = Key { max_idle_timeout: 20, ..test_key() };
+    assert!(cache.get(&other).unwrap().debug_logs().keylog.is_some());
+
+    cache.set_keylog_path(None).unwrap();
+    let other = Key { max_idle_timeout: 10, ..test_key() };
+    assert!(cache.get(&other).unwrap().debug_logs().keylog.is_none());
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn empty_trust_store() {
+    let dir = std::env::temp_dir().join(format!("doh_empty_trust_store_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let result =
+        Config::from_key(&Key { cert_path: Some(dir.to_str().unwrap().to_string()), ..test_key() });
+    fs::remove_dir(&dir).unwrap();
+    assert!(matches!(result, Err(ConfigError::EmptyTrustStore(_))));
+}
+
+#[test]
+fn validate_cert_path() {
+    use crate::connection::loopback;
+    assert!(Config::validate_cert_path(None).is_ok());
+    let missing = Config::validate_cert_path(Some("/nonexistent/cacerts"));
+    assert!(matches!(missing, Err(ConfigError::MissingTrustStore(_))));
+
+    let dir = std::env::temp_dir().join(format!("doh_validate_cert_path_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.to_str().unwrap();
+    let empty = Config::validate_cert_path(Some(path));
+    fs::write(dir.join("server.pem"), loopback::SERVER_CERT).unwrap();
+    let trusted = Config::validate_cert_path(Some(path));
+    let file = Config::validate_cert_path(Some(dir.join("server.pem").to_str().unwrap()));
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(matches!(empty, Err(ConfigError::EmptyTrustStore(_))));
+    assert!(trusted.is_ok());
+    assert!(matches!(file, Err(ConfigError::CertPathNotDirectory(_))));
+}
+
+#[test]
+fn cert_path_to_file() {
+    let file = std::env::temp_dir().join(format!("doh_cert_path_file_{}.pem", std::process::id()));
+    fs::write(&file, "-----BEGIN CERTIFICATE-----\n").unwrap();
+    let key = Key { cert_path: Some(file.to_str().unwrap().to_string()), ..test_key() };
+    let built = Config::from_key(&key);
+    let validated = key.validate();
+    fs::remove_file(&file).unwrap();
     assert!(
-        Config::from_key(&Key {
-            cert_path: Some("data/local/tmp/".to_string()),
-            max_idle_timeout: 1000
-        })
-        .is_ok(),
-        "quiche config with cert creating failed"
+        matches!(built, Err(ConfigError::CertPathNotDirectory(path)) if Path::new(&path) == file)
     );
+    assert!(matches!(validated, Err(ConfigError::CertPathNotDirectory(_))));
+}
+
+#[test]
+fn transient_trust_store_errors() {
+    let dir =
+        std::env::temp_dir().join(format!("doh_transient_trust_store_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.to_str().unwrap().to_string();
+    // A certificate listed in the directory but gone by the time it is read, as when the
+    // directory is being replaced.
+    let target = dir.join("target");
+    std::os::unix::fs::symlink(&target, dir.join("cert.pem")).unwrap();
+    let key = Key { cert_path: Some(path.clone()), ..test_key() };
+    // If the certificate never appears, the store is as good as empty.
+    let persistent = Config::from_key(&key);
+
+    // The update completes after the first scan, and the retry finds the certificate.
+    let mut scans = 0;
+    let retried = retry_transient(3, Duration::from_millis(0), || {
+        scans += 1;
+        let scan = is_empty_trust_store(&path);
+        fs::write(&target, "-----BEGIN CERTIFICATE-----\n").unwrap();
+        scan
+    });
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(matches!(persistent, Err(ConfigError::EmptyTrustStore(_))));
+    assert!(matches!(retried, Ok(false)));
+    assert_eq!(scans, 2);
+
+    // Permanent errors aren't retried.
+    let mut scans = 0;
+    let permanent: io::Result<()> = retry_transient(3, Duration::from_millis(0), || {
+        scans += 1;
+        Err(io::ErrorKind::PermissionDenied.into())
+    });
+    assert_eq!(permanent.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    assert_eq!(scans, 1);
+}
+
+#[test]
+fn validate_key() {
+    assert!(test_key().validate().is_ok());
+    let missing = Key { cert_path: Some("/nonexistent/cacerts".to_string()), ..test_key() };
+    assert!(matches!(missing.validate(), Err(ConfigError::MissingTrustStore(_))));
+
+    let dir = std::env::temp_dir().join(format!("doh_validate_key_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let empty = Key { cert_path: Some(dir.to_str().unwrap().to_string()), ..test_key() };
+    let result = empty.validate();
+    fs::remove_dir(&dir).unwrap();
+    assert!(matches!(result, Err(ConfigError::EmptyTrustStore(_))));
+}
+
+#[test]
+fn validate_key_ranges() {
+    let key = |transport| Key { transport, ..test_key() };
+    let limits = TransportParams {
+        initial_max_data: Some(MAX_VARINT),
+        initial_max_streams_bidi: Some(1 << 60),
+        initial_max_streams_uni: Some(1 << 60),
+        max_ack_delay: Some(16383),
+        ack_delay_exponent: Some(20),
+        ..Default::default()
+    };
+    assert!(key(limits.clone()).validate().is_ok());
+    let over = [
+        TransportParams { initial_max_data: Some(1 << 62), ..limits.clone() },
+        TransportParams { initial_max_stream_data_uni: Some(u64::MAX), ..limits.clone() },
+        TransportParams { initial_max_streams_bidi: Some((1 << 60) + 1), ..limits.clone() },
+        TransportParams { max_ack_delay: Some(16384), ..limits.clone() },
+        TransportParams { ack_delay_exponent: Some(21), ..limits },
+    ];
+    for transport in over {
+        let result = key(transport.clone()).validate();
+        assert!(matches!(result, Err(ConfigError::OutOfRange(_))), "{:?}: {:?}", transport, result);
+    }
+    let idle = Key { max_idle_timeout: 1 << 62, ..test_key() };
+    assert!(matches!(idle.validate(), Err(ConfigError::OutOfRange(_))));
 }
 
 #[test]
 fn shared_cache() {
     let cache_a = Cache::new();
     let cache_b = cache_a.clone();
-    let config_a = cache_a.get(&Key { cert_path: None, max_idle_timeout: 1000 }).unwrap();
+    let config_a = cache_a.get(&test_key()).unwrap();
     assert_eq!(Arc::strong_count(&config_a.0), 2);
-    let _config_b = cache_b.get(&Key { cert_path: None, max_idle_timeout: 1000 }).unwrap();
+    let _config_b = cache_b.get(&test_key()).unwrap();
     assert_eq!(Arc::strong_count(&config_a.0), 3);
 }
 
 #[test]
 fn different_keys() {
-    let cache = Cache::new();
-    let key_a = Key { cert_path: None, max_idle_timeout: 1000 };
-    let key_b = Key { cert_path: Some("a".to_string()), max_idle_timeout: 1000 };
-    let key_c = Key { cert_path: Some("a".to_string()), max_idle_timeout: 5000 };
+    let cache = Cache::with_capacity(1);
+    let key_a = test_key();
+    let key_b = Key { cert_path: Some("a".to_string()), ..test_key() };
+    let key_c = Key { cert_path: Some("a".to_string()), max_idle_timeout: 5000, ..test_key() };
     let config_a = cache.get(&key_a).unwrap();
     let config_b = cache.get(&key_b).unwrap();
     let _config_b = cache.get(&key_b).unwrap();
@@ -218,11 +1846,28 @@ fn different_keys() {
 }
 
 #[test]
-fn lifetimes() {
+fn relative_cert_path() {
     let cache = Cache::new();
-    let key_a = Key { cert_path: Some("a".to_string()), max_idle_timeout: 1000 };
-    let key_b = Key { cert_path: Some("b".to_string()), max_idle_timeout: 1000 };
-    let config_none = cache.get(&Key { cert_path: None, max_idle_timeout: 1000 }).unwrap();
+    let relative = Key { cert_path: Some("a".to_string()), ..test_key() };
+    let absolute = Key {
+        cert_path: Some(std::env::current_dir().unwrap().join("a").to_str().unwrap().to_string()),
+        ..test_key()
+    };
+    let dotted = Key { cert_path: Some("./a/".to_string()), ..test_key() };
+    let config = cache.get(&relative).unwrap();
+    let _config_absolute = cache.get(&absolute).unwrap();
+    let _config_dotted = cache.get(&dotted).unwrap();
+    // One handle each, plus the cache's keep-alive.
+    assert_eq!(Arc::strong_count(&config.0), 4);
+    assert_eq!(cache.state.read().unwrap().key_to_config.len(), 1);
+}
+
+#[test]
+fn lifetimes() {
+    let cache = Cache::with_capacity(1);
+    let key_a = Key { cert_path: Some("a".to_string()), ..test_key() };
+    let key_b = Key { cert_path: Some("b".to_string()), ..test_key() };
+    let config_none = cache.get(&test_key()).unwrap();
     let config_a = cache.get(&key_a).unwrap();
     let config_b = cache.get(&key_b).unwrap();
 
@@ -265,11 +1910,604 @@ fn lifetimes() {
     assert_eq!(cache.state.read().unwrap().key_to_config.len(), 2);
 }
 
+#[test]
+fn keep_alive_lru() {
+    let evictions = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let recorder = evictions.clone();
+    let cache = Cache::with_observer(Arc::new(move |cert_path, reason| {
+        recorder.lock().unwrap().push((cert_path.map(str::to_string), reason))
+    }));
+    cache.set_keep_alive_capacity(2);
+    let key = |cert_path: &str| Key { cert_path: Some(cert_path.to_string()), ..test_key() };
+    let kept = |cache: &Cache| -> Vec<String> {
+        let state = cache.state.read().unwrap();
+        let mut kept: Vec<_> =
+            state.kept_alive.iter().filter_map(|kept| kept.key.cert_path.clone()).collect();
+        kept.sort();
+        kept
+    };
+
+    // Flapping between as many keys as there are slots builds each config once.
+    for _ in 0..3 {
+        drop(cache.get(&key("/a")).unwrap());
+        drop(cache.get(&key("/b")).unwrap());
+    }
+    assert_eq!(cache.stats().constructions, 2);
+    assert_eq!(kept(&cache), vec!["/a", "/b"]);
+    assert!(evictions.lock().unwrap().is_empty());
+
+    // Asking for "/a" again makes "/b" the least recently requested, so "/c" displaces it.
+    drop(cache.get(&key("/a")).unwrap());
+    drop(cache.get(&key("/c")).unwrap());
+    assert_eq!(kept(&cache), vec!["/a", "/c"]);
+    assert_eq!(evictions.lock().unwrap()[0], (Some("/b".to_string()), EvictionReason::Capacity));
+
+    // Shrinking to a single slot keeps only the most recent.
+    evictions.lock().unwrap().clear();
+    cache.set_keep_alive_capacity(1);
+    assert_eq!(kept(&cache), vec!["/c"]);
+    assert_eq!(evictions.lock().unwrap()[0], (Some("/a".to_string()), EvictionReason::Capacity));
+}
+
+#[test]
+fn eviction_observer() {
+    let evictions = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let recorder = evictions.clone();
+    let cache = Cache::with_observer(Arc::new(move |cert_path, reason| {
+        recorder.lock().unwrap().push((cert_path.map(str::to_string), reason))
+    }));
+    cache.set_keep_alive_capacity(1);
+    let key_a = test_key();
+    let key_b = Key { cert_path: Some("/b".to_string()), ..test_key() };
+    let key_c = Key { cert_path: Some("/c".to_string()), ..test_key() };
+    drop(cache.get(&key_a).unwrap());
+    let _config_b = cache.get(&key_b).unwrap();
+    drop(cache.get(&key_c).unwrap());
+    cache.garbage_collect();
+    cache.invalidate(&key_b).unwrap();
+    // Invalidating an unknown key reports nothing.
+    cache.invalidate(&key_a).unwrap();
+
+    assert_eq!(
+        *evictions.lock().unwrap(),
+        vec![
+            (None, EvictionReason::Capacity),
+            (Some("/b".to_string()), EvictionReason::Capacity),
+            (None, EvictionReason::Dead),
+            (Some("/b".to_string()), EvictionReason::Invalidated),
+        ]
+    );
+    // "/c" is still kept alive, and a fresh "/b" can be built.
+    assert_eq!(cache.state.read().unwrap().key_to_config.len(), 1);
+    assert!(cache.get(&key_b).is_ok());
+}
+
+#[test]
+fn invalidate_cert_path() {
+    let cache = Cache::new();
+    let key = |cert_path: &str, max_idle_timeout| Key {
+        cert_path: Some(cert_path.to_string()),
+        max_idle_timeout,
+        ..test_key()
+    };
+    let stale_short = cache.get(&key("/a", 1000)).unwrap();
+    let stale_long = cache.get(&key("/a", 5000)).unwrap();
+    let other = cache.get(&key("/b", 1000)).unwrap();
+
+    // Every key for the path is rebuilt, however the path is spelled, while other paths stay.
+    cache.invalidate_cert_path(&Some("//a/.".to_string())).unwrap();
+    let fresh_short = cache.get(&key("/a", 1000)).unwrap();
+    let fresh_long = cache.get(&key("/a", 5000)).unwrap();
+    assert!(!Arc::ptr_eq(&stale_short.0, &fresh_short.0));
+    assert!(!Arc::ptr_eq(&stale_long.0, &fresh_long.0));
+    assert!(Arc::ptr_eq(&other.0, &cache.get(&key("/b", 1000)).unwrap().0));
+
+    cache.invalidate_all();
+    assert!(cache.state.read().unwrap().key_to_config.is_empty());
+    assert!(cache.state.read().unwrap().kept_alive.is_empty());
+    assert!(!Arc::ptr_eq(&other.0, &cache.get(&key("/b", 1000)).unwrap().0));
+}
+
+#[test]
+fn construction_stats() {
+    let cache = Cache::new();
+    assert_eq!(cache.stats(), CacheStats::default());
+    let _config_a = cache.get(&test_key()).unwrap();
+    let _config_a2 = cache.get(&test_key()).unwrap();
+    let _config_b = cache.get(&Key { max_idle_timeout: 5000, ..test_key() }).unwrap();
+    let stats = cache.stats();
+    // The second lookup was served from the cache.
+    assert_eq!(stats.constructions, 2);
+    assert!(stats.max_construction_time > Duration::from_secs(0));
+    assert!(stats.max_construction_time <= stats.total_construction_time);
+}
+
+#[test]
+fn lookup_stats() {
+    let cache = Cache::new();
+    let key = test_key();
+    let _config = cache.get(&key).unwrap();
+    let _config = cache.get(&key).unwrap();
+    let stats = cache.clone().stats();
+    assert_eq!((stats.hits, stats.misses, stats.races), (1, 1, 0));
+
+    // A build which loses to one installed meanwhile is a race, on top of both misses.
+    let theirs = std::cell::RefCell::new(None);
+    let _config = cache.get_or_build(&Key { max_idle_timeout: 5000, ..key }, |key| {
+        *theirs.borrow_mut() = Some(cache.get(key).unwrap());
+        Config::from_key(key)
+    });
+    let stats = cache.stats();
+    assert_eq!((stats.hits, stats.misses, stats.races), (1, 3, 1));
+}
+
+#[test]
+fn busy_cache() {
+    use std::sync::mpsc;
+    let cache = Cache::new();
+    let key = test_key();
+    // A reader which doesn't let go keeps `get` from installing its config, but not forever.
+    let (locked_tx, locked_rx) = mpsc::channel();
+    let (release_tx, release_rx) = mpsc::channel::<()>();
+    let state = cache.state.clone();
+    let holder = std::thread::spawn(move || {
+        let _guard = state.read().unwrap();
+        locked_tx.send(()).unwrap();
+        let _ = release_rx.recv();
+    });
+    locked_rx.recv().unwrap();
+    let start = BootTime::now();
+    assert!(matches!(cache.get(&key), Err(ConfigError::CacheBusy)));
+    assert!(start.elapsed() >= Cache::LOCK_PATIENCE);
+    drop(release_tx);
+    holder.join().unwrap();
+    assert!(cache.get(&key).is_ok());
+}
+
+#[test]
+fn racing_failures() {
+    use std::sync::Barrier;
+    const RACERS: usize = 16;
+    let key = test_key();
+    let own_error = |racer: usize| ConfigError::EmptyTrustStore(racer.to_string());
+
+    // A build which fails while another caller's succeeds gives way to the shared config.
+    let cache = Cache::new();
+    let shared = std::cell::RefCell::new(None);
+    let config = cache.get_or_build(&key, |key| {
+        *shared.borrow_mut() = Some(cache.get(key).unwrap());
+        Err(own_error(0))
+    });
+    assert!(Arc::ptr_eq(&config.unwrap().0, &shared.into_inner().unwrap().0));
+
+    for round in 0..8 {
+        let cache = Cache::new();
+        let barrier = Arc::new(Barrier::new(RACERS));
+        let racers: Vec<_> = (0..RACERS)
+            .map(|racer| {
+                let (cache, key, barrier) = (cache.clone(), key.clone(), barrier.clone());
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    // Every third racer fails, and in the last round all of them do.
+                    let result = cache.get_or_build(&key, |key| {
+                        if racer % 3 == 0 || round == 7 {
+                            Err(own_error(racer))
+                        } else {
+                            Config::from_key(key)
+                        }
+                    });
+                    (racer, result)
+                })
+            })
+            .collect();
+        let mut shared: Option<Config> = None;
+        for racer in racers {
+            match racer.join().unwrap() {
+                (_, Ok(config)) => match &shared {
+                    Some(shared) => assert!(Arc::ptr_eq(&shared.0, &config.0)),
+                    None => shared = Some(config),
+                },
+                (racer, Err(ConfigError::EmptyTrustStore(failed))) => {
+                    assert_eq!(failed, racer.to_string())
+                }
+                (_, Err(e)) => panic!("Unexpected error {:?}", e),
+            }
+        }
+        let entries = cache.state.read().unwrap().key_to_config.len();
+        assert_eq!(entries, if shared.is_some() { 1 } else { 0 });
+        assert_eq!(shared.is_none(), round == 7);
+    }
+}
+
 #[tokio::test]
 async fn quiche_connect() {
     use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
-    let mut config = Config::from_key(&Key { cert_path: None, max_idle_timeout: 10 }).unwrap();
+    let mut config = Config::from_key(&Key { max_idle_timeout: 10, ..test_key() }).unwrap();
     let socket_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 42));
     let conn_id = quiche::ConnectionId::from_ref(&[]);
     quiche::connect(None, &conn_id, socket_addr, config.take().await.deref_mut()).unwrap();
 }
+
+#[test]
+fn response_window() {
+    // Even if every concurrent stream is abandoned at the cap and the server keeps sending to the
+    // end of its window, the connection window isn't used up.
+    let window = stream_window(DEFAULT_MAX_RESPONSE_SIZE);
+    assert!(window > DEFAULT_MAX_RESPONSE_SIZE as u64);
+    assert!(MAX_CONCURRENT_STREAM_SIZE * window < MAX_INCOMING_BUFFER_SIZE_WHOLE);
+    assert_eq!(stream_window(usize::MAX), u64::MAX);
+
+    let cache = Cache::new();
+    let default = test_key();
+    let large = Key { max_response_size: Some(1 << 20), ..default.clone() };
+    let config_default = cache.get(&default).unwrap();
+    let config_large = cache.get(&large).unwrap();
+    assert!(!Arc::ptr_eq(&config_default.0, &config_large.0));
+}
+
+#[test]
+fn quic_version_allowlist() {
+    const DRAFT_29: u32 = 0xff00_001d;
+    let default = test_key();
+    for unusable in [vec![], vec![0x1234_5678], vec![quiche::PROTOCOL_VERSION, 0x1234_5678]] {
+        let result = Config::from_key(&Key { quic_versions: unusable.clone(), ..default.clone() });
+        // The versions are reported, so the one at fault can be found.
+        assert!(
+            matches!(result, Err(ConfigError::ProtocolVersion(versions)) if versions == unusable)
+        );
+    }
+
+    let cache = Cache::new();
+    let draft = Key { quic_versions: vec![DRAFT_29, quiche::PROTOCOL_VERSION], ..default.clone() };
+    let config_default = cache.get(&default).unwrap();
+    let config_draft = cache.get(&draft).unwrap();
+    assert!(!Arc::ptr_eq(&config_default.0, &config_draft.0));
+}
+
+#[test]
+fn trim() {
+    let evictions = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let recorder = evictions.clone();
+    let cache = Cache::with_observer(Arc::new(move |cert_path, reason| {
+        recorder.lock().unwrap().push((cert_path.map(str::to_string), reason))
+    }));
+    cache.set_keep_alive_capacity(1);
+    let key = |cert_path: &str| Key { cert_path: Some(cert_path.to_string()), ..test_key() };
+    let _config_a = cache.get(&key("/a")).unwrap();
+    drop(cache.get(&key("/b")).unwrap());
+    evictions.lock().unwrap().clear();
+
+    // "/b" was only kept alive by the cache, so it goes. "/a" is still in use.
+    assert_eq!(cache.trim(), 1);
+    assert_eq!(
+        *evictions.lock().unwrap(),
+        vec![
+            (Some("/b".to_string()), EvictionReason::Trimmed),
+            (Some("/b".to_string()), EvictionReason::Dead),
+        ]
+    );
+    assert_eq!(cache.state.read().unwrap().key_to_config.len(), 1);
+    assert_eq!(cache.trim(), 0);
+}
+
+#[test]
+fn incremental_garbage_collect() {
+    let evictions = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let recorder = evictions.clone();
+    let cache = Cache::with_observer(Arc::new(move |cert_path, reason| {
+        recorder.lock().unwrap().push((cert_path.map(str::to_string), reason))
+    }));
+    cache.set_keep_alive_capacity(1);
+    cache.set_gc_chunk(2);
+    let key = |cert_path: &str| Key { cert_path: Some(cert_path.to_string()), ..test_key() };
+    for path in ["/a", "/b", "/c", "/d", "/e"] {
+        drop(cache.get(&key(path)).unwrap());
+    }
+    let _config_f = cache.get(&key("/f")).unwrap();
+    evictions.lock().unwrap().clear();
+    // Five dead entries take three chunks.
+    assert_eq!(cache.garbage_collect(), 5);
+    let mut collected = evictions.lock().unwrap().clone();
+    collected.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let dead =
+        ["/a", "/b", "/c", "/d", "/e"].map(|path| (Some(path.to_string()), EvictionReason::Dead));
+    assert_eq!(collected, dead);
+    assert_eq!(cache.state.read().unwrap().key_to_config.len(), 1);
+
+    // Between finding entries dead and removing them, one is rebuilt and one is invalidated.
+    // "/c" stays alive as the latest config.
+    for path in ["/a", "/b", "/c"] {
+        drop(cache.get(&key(path)).unwrap());
+    }
+    let dead = cache.state.read().unwrap().dead_keys();
+    assert_eq!(dead.len(), 2);
+    let _config_a = cache.get(&key("/a")).unwrap();
+    cache.invalidate(&key("/b")).unwrap();
+    let mut state = cache.state.write().unwrap();
+    let removed: Vec<_> = dead.iter().filter_map(|key| state.remove_if_dead(key)).collect();
+    assert!(removed.is_empty());
+    assert_eq!(state.key_to_config.len(), 3);
+}
+
+#[test]
+fn background_garbage_collect() {
+    let cache = Cache::with_capacity(1);
+    let key = |cert_path: &str| Key { cert_path: Some(cert_path.to_string()), ..test_key() };
+    let gc = cache.spawn_gc(Duration::from_millis(10)).unwrap();
+    for cert_path in ["/a", "/b", "/c"] {
+        drop(cache.get(&key(cert_path)).unwrap());
+    }
+    // Only "/c" is kept alive, so the entries for the others go without anyone asking.
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    while cache.state.read().unwrap().key_to_config.len() > 1 {
+        assert!(std::time::Instant::now() < deadline, "Entries were never collected");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    // The collector doesn't keep the cache alive, and stops with its handle.
+    let state = Arc::downgrade(&cache.state);
+    drop(cache);
+    drop(gc);
+    assert!(state.upgrade().is_none());
+}
+
+#[test]
+fn keep_alive_grace() {
+    let clock = crate::boot_time::MockClock::new();
+    let cache = Cache::with_clock(clock.clone());
+    let key = |cert_path: &str| Key { cert_path: Some(cert_path.to_string()), ..test_key() };
+    drop(cache.get(&key("/a")).unwrap());
+    clock.advance(Duration::from_secs(3600));
+    // Without a grace period, the latest config is kept however long it goes unused.
+    assert_eq!(cache.garbage_collect(), 0);
+    assert!(cache.state.read().unwrap().kept_alive.len() == 1);
+
+    cache.set_keep_alive_grace(Some(Duration::from_secs(60)));
+    // A request restarts the grace period, and a config still in use is kept regardless.
+    let config = cache.get(&key("/a")).unwrap();
+    clock.advance(Duration::from_secs(61));
+    assert_eq!(cache.garbage_collect(), 0);
+    drop(config);
+    clock.advance(Duration::from_secs(59));
+    drop(cache.get(&key("/a")).unwrap());
+    clock.advance(Duration::from_secs(59));
+    assert_eq!(cache.garbage_collect(), 0);
+    assert!(cache.state.read().unwrap().kept_alive.len() == 1);
+
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(cache.garbage_collect(), 1);
+    let state = cache.state.read().unwrap();
+    assert!(state.kept_alive.is_empty());
+    assert!(state.key_to_config.is_empty());
+}
+
+#[test]
+fn resident_ages() {
+    let clock = crate::boot_time::MockClock::new();
+    let cache = Cache::with_clock(clock.clone());
+    cache.set_keep_alive_capacity(1);
+    let key = |cert_path: &str| Key { cert_path: Some(cert_path.to_string()), ..test_key() };
+    let a = cache.get(&key("/a")).unwrap();
+    clock.advance(Duration::from_secs(30));
+    drop(cache.get(&key("/b")).unwrap());
+    clock.advance(Duration::from_secs(10));
+    drop(cache.get(&key("/c")).unwrap());
+    clock.advance(Duration::from_secs(5));
+    // A lookup served from the cache doesn't make the config any younger.
+    drop(cache.get(&key("/a")).unwrap());
+    // "/b" is held by nothing, so it is no longer resident even before garbage collection.
+    assert_eq!(
+        cache.resident(),
+        vec![
+            ResidentConfig {
+                cert_path: Some("/a".to_string()),
+                age: Duration::from_secs(45),
+                kept_alive: false,
+            },
+            ResidentConfig {
+                cert_path: Some("/c".to_string()),
+                age: Duration::from_secs(5),
+                kept_alive: true,
+            },
+        ]
+    );
+    drop(a);
+    assert_eq!(cache.resident().len(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn get_async() {
+    let cache = Cache::new();
+    let key = test_key();
+    // Concurrent callers for one key share a single build.
+    let configs = futures::future::join_all((0..8).map(|_| cache.get_async(&key))).await;
+    let configs: Vec<_> = configs.into_iter().map(|config| config.unwrap()).collect();
+    assert!(configs.iter().all(|config| Arc::ptr_eq(&config.0, &configs[0].0)));
+    assert_eq!(cache.stats().constructions, 1);
+    // The synchronous lookup finds the same config.
+    assert!(Arc::ptr_eq(&cache.get(&key).unwrap().0, &configs[0].0));
+    assert!(cache.builds.lock().unwrap().values().all(|lock| lock.strong_count() == 0));
+
+    let bad_key = Key { quic_versions: vec![0xbabababa], ..key };
+    assert!(matches!(cache.get_async(&bad_key).await, Err(ConfigError::ProtocolVersion(_))));
+}
+
+#[tokio::test]
+async fn disable_active_migration() {
+    use crate::connection::loopback;
+    // Transport parameter ID, RFC 9000 section 18.2.
+    const DISABLE_ACTIVE_MIGRATION: u64 = 0x0c;
+    for disable in [true, false] {
+        let mut config =
+            Config::from_key(&Key { disable_active_migration: disable, ..test_key() }).unwrap();
+        let mut client = loopback::client(&mut config, None).await.unwrap();
+        let initial = &loopback::datagrams(&mut client).unwrap()[0];
+        let params = loopback::client_transport_parameters(initial).unwrap();
+        // Other parameters are there either way, so the packet was read correctly.
+        assert!(params.iter().any(|(id, _)| *id != DISABLE_ACTIVE_MIGRATION));
+        assert_eq!(params.iter().any(|(id, _)| *id == DISABLE_ACTIVE_MIGRATION), disable);
+    }
+}
+
+#[tokio::test]
+async fn early_data() {
+    use crate::connection::loopback;
+    let mut server_config = loopback::server_config().unwrap();
+    server_config.enable_early_data();
+    let client_addr = loopback::CLIENT_ADDR.parse().unwrap();
+    for enable in [true, false] {
+        let mut config =
+            Config::from_key(&Key { enable_early_data: enable, ..test_key() }).unwrap();
+        let mut session: Option<Vec<u8>> = None;
+        // Only a resumed connection can send early data.
+        for resumed in [false, true] {
+            let mut client = loopback::client(&mut config, None).await.unwrap();
+            if let Some(session) = &session {
+                client.set_session(session).unwrap();
+            }
+            // The client hello goes out with the first datagrams, which decides on early data.
+            let mut to_server = loopback::datagrams(&mut client).unwrap();
+            assert_eq!(client.is_in_early_data(), enable && resumed);
+            let mut server = quiche::accept(
+                &quiche::ConnectionId::from_ref(&[0xcd; quiche::MAX_CONN_ID_LEN]),
+                None,
+                client_addr,
+                &mut server_config,
+            )
+            .unwrap();
+            for datagram in &mut to_server {
+                server.recv(datagram, quiche::RecvInfo { from: client_addr }).unwrap();
+            }
+            loopback::exchange(&mut client, &mut server).unwrap();
+            assert!(client.is_established());
+            session = client.session();
+        }
+    }
+}
+
+#[tokio::test]
+async fn transport_params_keep_configs_apart() {
+    use crate::connection::loopback;
+    // Transport parameter ID, RFC 9000 section 18.2.
+    const INITIAL_MAX_STREAMS_BIDI: u64 = 0x08;
+    let cache = Cache::new();
+    // Two servers sharing a cert path, one of which allows fewer streams.
+    let key = |transport| Key { transport, ..test_key() };
+    let default_key = key(Default::default());
+    let tuned_key =
+        key(TransportParams { initial_max_streams_bidi: Some(4), ..Default::default() });
+    let default = cache.get(&default_key).unwrap();
+    let mut tuned = cache.get(&tuned_key).unwrap();
+    assert!(!Arc::ptr_eq(&default.0, &tuned.0));
+    assert_eq!(cache.resident().len(), 2);
+    // Each is shared by whoever asks with the same parameters.
+    assert!(Arc::ptr_eq(&default.0, &cache.get(&default_key).unwrap().0));
+    assert!(Arc::ptr_eq(&tuned.0, &cache.get(&tuned_key).unwrap().0));
+
+    // And the tuned config carries its parameter.
+    let mut client = loopback::client(&mut tuned, None).await.unwrap();
+    let initial = &loopback::datagrams(&mut client).unwrap()[0];
+    let params = loopback::client_transport_parameters(initial).unwrap();
+    assert!(params.contains(&(INITIAL_MAX_STREAMS_BIDI, vec![4])));
+}
+
+#[test]
+fn congestion_control_keeps_configs_apart() {
+    let cache = Cache::new();
+    let key = |congestion_control| Key {
+        transport: TransportParams { congestion_control, ..Default::default() },
+        ..test_key()
+    };
+    let default = cache.get(&key(None)).unwrap();
+    let reno = cache.get(&key(Some(CongestionControl::Reno))).unwrap();
+    assert!(!Arc::ptr_eq(&default.0, &reno.0));
+    assert_eq!(default.congestion_control(), CongestionControl::Cubic);
+    assert_eq!(reno.congestion_control(), CongestionControl::Reno);
+}
+
+#[tokio::test]
+async fn max_datagram_size() {
+    use crate::connection::loopback;
+    // Transport parameter ID, RFC 9000 section 18.2.
+    const MAX_UDP_PAYLOAD_SIZE: u64 = 0x03;
+    let cache = Cache::new();
+    let key = |max_datagram_size| Key {
+        transport: TransportParams { max_datagram_size, ..Default::default() },
+        ..test_key()
+    };
+    let default = cache.get(&key(None)).unwrap();
+    let mut small = cache.get(&key(Some(1280))).unwrap();
+    assert!(!Arc::ptr_eq(&default.0, &small.0));
+    assert_eq!(default.max_datagram_size(), MAX_DATAGRAM_SIZE);
+    assert_eq!(small.max_datagram_size(), 1280);
+    // No path may carry less than QUIC requires.
+    let tiny = cache.get(&key(Some(500))).unwrap();
+    assert_eq!(tiny.max_datagram_size(), quiche::MIN_CLIENT_INITIAL_LEN);
+
+    let mut client = loopback::client(&mut small, None).await.unwrap();
+    let initial = &loopback::datagrams(&mut client).unwrap()[0];
+    let params = loopback::client_transport_parameters(initial).unwrap();
+    // 1280 as a QUIC variable-length integer.
+    assert!(params.contains(&(MAX_UDP_PAYLOAD_SIZE, vec![0x45, 0x00])));
+}
+
+#[test]
+fn with_max_entries() {
+    let key = |i: usize| Key { cert_path: Some(format!("/{}", i)), ..test_key() };
+    // Churning through keys leaves dead entries behind until garbage collection.
+    let unbounded = Cache::new();
+    for i in 0..10 {
+        unbounded.get(&key(i)).unwrap();
+    }
+    assert_eq!(unbounded.state.read().unwrap().key_to_config.len(), 10);
+
+    // Kept-alive configs are live, so the cap sits above the keep-alive slots.
+    let cache = Cache::with_max_entries(Cache::DEFAULT_KEEP_ALIVE_CAPACITY + 1);
+    for i in 0..10 {
+        cache.get(&key(i)).unwrap();
+        assert!(
+            cache.state.read().unwrap().key_to_config.len()
+                <= Cache::DEFAULT_KEEP_ALIVE_CAPACITY + 1
+        );
+    }
+    assert!(cache.state.read().unwrap().key_to_config.contains_key(&key(9)));
+}
+
+#[test]
+fn entry_limit() {
+    let evictions = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let recorder = evictions.clone();
+    let cache = Cache::with_observer(Arc::new(move |cert_path, reason| {
+        recorder.lock().unwrap().push((cert_path.map(str::to_string), reason))
+    }));
+    cache.set_keep_alive_capacity(1);
+    let key = |i: usize| Key { cert_path: Some(format!("/path{}", i)), ..test_key() };
+    cache.set_max_entries(4);
+    // Every config is held, so none can go, and the cache grows past its limit rather than
+    // forgetting a config in use.
+    let configs: Vec<_> = (0..10).map(|i| cache.get(&key(i)).unwrap()).collect();
+    assert_eq!(cache.state.read().unwrap().key_to_config.len(), 10);
+    assert!(evictions.lock().unwrap().iter().all(|(_, reason)| *reason != EvictionReason::Dead));
+    // Each is still shared with a fresh lookup.
+    assert!(Arc::ptr_eq(&cache.get(&key(0)).unwrap().0, &configs[0].0));
+
+    // Once configs die, they go. Dropping all but "/path1" leaves the others dead, but for
+    // "/path0" in the keep-alive slot, which dies when "/path10" takes it.
+    let mut configs = configs;
+    let _held = configs.swap_remove(1);
+    drop(configs);
+    evictions.lock().unwrap().clear();
+    let _config = cache.get(&key(10)).unwrap();
+    let mut dead: Vec<_> = evictions
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, reason)| *reason == EvictionReason::Dead)
+        .map(|(cert_path, _)| cert_path.clone().unwrap())
+        .collect();
+    dead.sort();
+    let mut expected: Vec<_> = (2..10).chain([0]).map(|i| format!("/path{}", i)).collect();
+    expected.sort();
+    assert_eq!(dead, expected);
+    assert_eq!(cache.state.read().unwrap().key_to_config.len(), 2);
+}
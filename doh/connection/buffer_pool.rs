@@ -0,0 +1,179 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Packet buffers shared between connections
+
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Size of each buffer, enough for any UDP datagram.
+pub const BUFFER_SIZE: usize = 65536;
+
+/// Handle to a pool used by every connection of a dispatcher
+pub type SharedBufferPool = Arc<BufferPool>;
+
+/// Reusable packet buffers.
+///
+/// Connections borrow a buffer only while reading or writing packets, so the memory in use is
+/// bounded by how many connections are doing so at once rather than by how many exist. At most
+/// `max_free` returned buffers are kept for reuse; any beyond that are freed.
+#[derive(Debug)]
+pub struct BufferPool {
+    free: Mutex<Vec<Box<[u8]>>>,
+    max_free: usize,
+    in_use: AtomicUsize,
+    high_water_mark: AtomicUsize,
+    allocations: AtomicU64,
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_MAX_FREE)
+    }
+}
+
+impl BufferPool {
+    // The dispatcher runs connections on a single thread, so more than a couple of buffers are
+    // rarely borrowed at once.
+    const DEFAULT_MAX_FREE: usize = 4;
+
+    /// Creates an empty pool which keeps up to `max_free` buffers for reuse.
+    pub fn new(max_free: usize) -> Self {
+        Self {
+            free: Mutex::new(Vec::new()),
+            max_free,
+            in_use: AtomicUsize::new(0),
+            high_water_mark: AtomicUsize::new(0),
+            allocations: AtomicU64::new(0),
+        }
+    }
+
+    /// Borrows a buffer, which goes back to the pool when dropped.
+    pub fn get(self: &Arc<Self>) -> PooledBuffer {
+        let buffer = self.free.lock().unwrap().pop().unwrap_or_else(|| {
+            self.allocations.fetch_add(1, Ordering::Relaxed);
+            vec![0; BUFFER_SIZE].into_boxed_slice()
+        });
+        let in_use = self.in_use.fetch_add(1, Ordering::Relaxed) + 1;
+        self.high_water_mark.fetch_max(in_use, Ordering::Relaxed);
+        PooledBuffer { buffer: Some(buffer), pool: self.clone() }
+    }
+
+    /// Number of buffers currently borrowed.
+    #[cfg(test)]
+    pub fn in_use(&self) -> usize {
+        self.in_use.load(Ordering::Relaxed)
+    }
+
+    /// Most buffers ever borrowed at once.
+    pub fn high_water_mark(&self) -> usize {
+        self.high_water_mark.load(Ordering::Relaxed)
+    }
+
+    /// Number of buffers allocated because none was free.
+    #[cfg(test)]
+    pub fn allocations(&self) -> u64 {
+        self.allocations.load(Ordering::Relaxed)
+    }
+
+    /// Frees the buffers kept for reuse, returning how many there were. Borrowed buffers are
+    /// unaffected, and are kept for reuse again when returned.
+    pub fn trim(&self) -> usize {
+        std::mem::take(&mut *self.free.lock().unwrap()).len()
+    }
+
+    fn put(&self, buffer: Box<[u8]>) {
+        self.in_use.fetch_sub(1, Ordering::Relaxed);
+        let mut free = self.free.lock().unwrap();
+        if free.len() < self.max_free {
+            free.push(buffer);
+        }
+    }
+}
+
+/// Buffer borrowed from a `BufferPool`
+pub struct PooledBuffer {
+    // Only `None` while being dropped.
+    buffer: Option<Box<[u8]>>,
+    pool: SharedBufferPool,
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        self.buffer.as_deref().unwrap()
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.buffer.as_deref_mut().unwrap()
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        if let Some(buffer) = self.buffer.take() {
+            self.pool.put(buffer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BufferPool, BUFFER_SIZE};
+    use std::sync::Arc;
+
+    #[test]
+    fn reuses_buffers() {
+        let pool = Arc::new(BufferPool::new(1));
+        for _ in 0..100 {
+            let buffer = pool.get();
+            assert_eq!(buffer.len(), BUFFER_SIZE);
+        }
+        // One buffer served every borrow.
+        assert_eq!(pool.allocations(), 1);
+        assert_eq!(pool.high_water_mark(), 1);
+        assert_eq!(pool.in_use(), 0);
+    }
+
+    #[test]
+    fn bounds_free_buffers() {
+        let pool = Arc::new(BufferPool::new(1));
+        let buffers: Vec<_> = (0..3).map(|_| pool.get()).collect();
+        assert_eq!(pool.in_use(), 3);
+        assert_eq!(pool.high_water_mark(), 3);
+        drop(buffers);
+        assert_eq!(pool.in_use(), 0);
+        assert_eq!(pool.free.lock().unwrap().len(), 1);
+        let _buffer = pool.get();
+        assert_eq!(pool.allocations(), 3);
+        assert_eq!(pool.high_water_mark(), 3);
+    }
+
+    #[test]
+    fn trim_frees_spare_buffers() {
+        let pool = Arc::new(BufferPool::new(2));
+        let buffers: Vec<_> = (0..3).map(|_| pool.get()).collect();
+        drop(buffers);
+        let borrowed = pool.get();
+        assert_eq!(pool.trim(), 1);
+        assert_eq!(pool.trim(), 0);
+        drop(borrowed);
+        assert_eq!(pool.free.lock().unwrap().len(), 1);
+    }
+}
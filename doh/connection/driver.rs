@@ -13,23 +13,33 @@
 * limitations under the License.
 */
 
-//! Defines a backing task to keep a HTTP/3 connection running
+//! Defines a backing task to keep a QUIC connection running, carrying DNS over HTTP/3 or streams
 
 use crate::boot_time;
-use crate::boot_time::BootTime;
+use crate::boot_time::{BootTime, Clock, SharedClock};
+use crate::certificate::{self, CertInfo, CertObserver, CertOutcome, HandshakeReport};
+use crate::config::StreamMode;
+use crate::dispatcher::{ConnectFailure, DispatcherMetrics};
+use crate::encoding;
 use log::{debug, warn};
 use quiche::h3;
 use std::collections::HashMap;
 use std::default::Default;
 use std::future;
 use std::io;
+use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 use thiserror::Error;
 use tokio::net::UdpSocket;
 use tokio::select;
 use tokio::sync::{mpsc, oneshot, watch};
 
-use super::Status;
+use super::buffer_pool::SharedBufferPool;
+use super::handshake_limiter::HandshakeSlot;
+use super::packet_tape::{Direction, SharedPacketTape};
+use super::trace::{Section, Span};
+use super::{Environment, Options, PacketSize, PacketSizeObserver, Peer, Status};
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -40,9 +50,25 @@ pub enum Error {
     #[error("HTTP/3 error: {0}")]
     H3(#[from] h3::Error),
     #[error("Response delivery error: {0}")]
-    StreamSend(#[from] mpsc::error::SendError<Stream>),
+    StreamSend(#[from] Box<mpsc::error::SendError<Stream>>),
     #[error("Connection closed")]
     Closed,
+    #[error("Connection closed by the idle timeout with {0} requests in flight")]
+    IdleWithRequests(usize),
+    #[error("No progress for {0:?} with requests in flight")]
+    Stalled(boot_time::Duration),
+    #[error("Server negotiated QUIC version {0:#x}, which is not acceptable")]
+    VersionNotAcceptable(u32),
+    #[error("Handshake not complete after {0:?}")]
+    HandshakeTimeout(boot_time::Duration),
+    #[error("Stuck {phase} for {stuck:?}")]
+    Zombie { phase: &'static str, stuck: boot_time::Duration },
+    #[error("{0} packets lost, over the connection's retransmission budget")]
+    RetransmissionBudget(usize),
+    #[error("Unable to decode QPACK header block, so no headers on the connection can be trusted")]
+    Qpack,
+    #[error("Server certificate is for {presented:?}, not {expected}")]
+    ServerNameMismatch { expected: String, presented: Vec<String> },
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -52,10 +78,18 @@ pub type Result<T> = std::result::Result<T, Error>;
 pub struct Request {
     /// Request headers
     pub headers: Vec<h3::Header>,
+    /// Request body, sent after the headers. DoH only sends GET requests, so this is empty
+    /// except for requests written by hand.
+    pub body: Vec<u8>,
+    /// When the requestor asked for the request, which may be well before it reached the
+    /// connection
+    pub submitted: BootTime,
     /// Expiry time for the request, relative to `CLOCK_BOOTTIME`
     pub expiry: Option<BootTime>,
     /// Channel to send the response to
     pub response_tx: oneshot::Sender<Stream>,
+    /// Largest response body to accumulate before abandoning the stream
+    pub max_response_size: usize,
 }
 
 #[derive(Debug)]
@@ -67,43 +101,426 @@ pub struct Stream {
     pub data: Vec<u8>,
     /// Error code if stream was reset
     pub error: Option<u64>,
+    /// Whether the body was abandoned for exceeding the request's `max_response_size`
+    pub too_large: bool,
+    /// Whether the request was abandoned at its expiry without a complete response
+    pub expired: bool,
+    /// How the transport fared while the request was in flight
+    pub stats: QueryStats,
 }
 
 impl Stream {
     fn new(headers: Vec<h3::Header>) -> Self {
-        Self { headers, data: Vec::new(), error: None }
+        Self {
+            headers,
+            data: Vec::new(),
+            error: None,
+            too_large: false,
+            expired: false,
+            stats: Default::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+/// Transport statistics for a single request
+pub struct QueryStats {
+    /// Time from the request being submitted until it went on the wire, spent in queues and
+    /// waiting for the connection to be ready. Everything after that is network time.
+    pub queue_wait: boot_time::Duration,
+    /// The connection's RTT estimate when the response completed
+    pub rtt: boot_time::Duration,
+    /// The connection's delivery rate estimate, in bytes per second, when the response completed
+    pub delivery_rate: u64,
+    /// Time from issuing the request until its response headers arrived
+    pub time_to_first_byte: Option<boot_time::Duration>,
+    /// Packets the connection declared lost while the request was in flight. quiche retransmits
+    /// the frames of every lost packet, so this also counts retransmissions, though on a busy
+    /// connection some of them may have carried other requests' data. The quiche version we
+    /// build against does not track spurious losses, so those are not reported.
+    pub packets_lost: usize,
+    /// Bytes of UDP payload sent for the request, QUIC and HTTP/3 framing included. Packets sent
+    /// while several requests were in flight are split evenly between them.
+    pub request_wire_bytes: u64,
+    /// Bytes of UDP payload received for the response, split the same way
+    pub response_wire_bytes: u64,
+    /// Why the request went on the connection it did. Only the network knows, so it is filled in
+    /// there once the response completes.
+    pub connection_source: ConnectionSource,
+}
+
+/// Whether a request went on its network's existing connection, and if not, why a new one was
+/// made for it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConnectionSource {
+    /// Sent on the connection already in use, including the one a probe made
+    #[default]
+    Reused,
+    /// The connection in use had closed, whether idle or failed
+    NewBecauseNoneLive,
+    /// The connection in use had carried `ServerInfo::max_queries_per_connection` requests
+    NewBecauseExhausted,
+    /// Connection reuse is disabled, so each request after the first gets a connection of its own
+    NewBecauseReuseDisabled,
+    /// The query asked for a full handshake, so it got a connection of its own which resumed no
+    /// session
+    NewForFullHandshake,
+}
+
+// Running totals of the bytes each open request is owed. Every packet is split evenly between
+// the requests open when it went by, so a request's wire bytes are how far the totals grew while
+// it was open.
+#[derive(Clone, Copy, Debug, Default)]
+struct WireShare {
+    sent: f64,
+    received: f64,
+}
+
+impl WireShare {
+    fn add(&mut self, direction: Direction, len: usize, open_requests: usize) {
+        if open_requests == 0 {
+            return;
+        }
+        let share = len as f64 / open_requests as f64;
+        match direction {
+            Direction::Outbound => self.sent += share,
+            Direction::Inbound => self.received += share,
+        }
+    }
+}
+
+// Connection state when a request was issued, for working out its `QueryStats`.
+#[derive(Debug)]
+struct RequestStart {
+    at: BootTime,
+    queue_wait: boot_time::Duration,
+    lost: usize,
+    wire: WireShare,
+    // Ends with the request, answered or not. Only held to be dropped.
+    _section: Section,
+}
+
+impl RequestStart {
+    fn new(clock: &dyn Clock, stats: &quiche::Stats, wire: WireShare, submitted: BootTime) -> Self {
+        Self {
+            at: clock.now(),
+            queue_wait: clock.elapsed(submitted),
+            lost: stats.lost,
+            wire,
+            _section: Section::begin(Span::Request),
+        }
+    }
+
+    // Completes `query_stats` once the response is done.
+    fn finish(&self, stats: &quiche::Stats, wire: WireShare, query_stats: &mut QueryStats) {
+        query_stats.queue_wait = self.queue_wait;
+        query_stats.rtt = stats.rtt;
+        query_stats.delivery_rate = stats.delivery_rate;
+        query_stats.packets_lost = stats.lost.saturating_sub(self.lost);
+        query_stats.request_wire_bytes = (wire.sent - self.wire.sent).round() as u64;
+        query_stats.response_wire_bytes = (wire.received - self.wire.received).round() as u64;
+    }
+}
+
+/// Response size limit used when the requestor does not specify one.
+pub const DEFAULT_MAX_RESPONSE_SIZE: usize = 64 * 1024;
+
+// QUIC transport error for the TLS bad_certificate alert (RFC 9001, section 4.8).
+const CRYPTO_ERROR_BAD_CERTIFICATE: u64 = 0x100 + 42;
+
+// HTTP/3 error code used to stop reading a response we no longer want.
+const H3_REQUEST_CANCELLED: u64 = 0x10c;
+
+// DNS over QUIC error code for the same (RFC 9250, section 8.4).
+const DOQ_REQUEST_CANCELLED: u64 = 0x3;
+
+/// What the handshake settled on
+///
+/// The idle timeout isn't included. It is the minimum of ours and the server's, but the quiche
+/// version we build against keeps the server's transport parameters to itself, so all we know
+/// is that it is at most `config::Key::max_idle_timeout`. Nothing schedules against it yet, as
+/// there is no keep-alive; see the note where the config sets the timeout.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Negotiated {
+    /// QUIC version the connection runs
+    pub version: u32,
+    /// ALPN protocol the server picked, such as `h3`
+    pub alpn: Vec<u8>,
+}
+
+/// What the driver last saw of its connection
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Activity {
+    /// Requests issued on the connection and not yet answered
+    pub open_streams: usize,
+    /// When quiche's next timer fires, which for a connection with nothing in flight is when it
+    /// closes for being idle. `None` if no timer is armed.
+    pub idle_deadline: Option<BootTime>,
+    /// Response body bytes held for requests in flight
+    pub buffered_bytes: usize,
+    /// Packets declared lost over the life of the connection
+    pub packets_lost: usize,
+    /// Filled in once the handshake completes
+    pub negotiated: Option<Negotiated>,
+}
+
+/// `Activity` shared with the `Connection` handle.
+pub type SharedActivity = Arc<Mutex<Activity>>;
+
+/// Maps quiche's `Done`, which only means there is nothing more to do for now, to `None` so that
+/// only real failures are propagated.
+pub fn quic_step<T>(result: quiche::Result<T>) -> quiche::Result<Option<T>> {
+    match result {
+        Err(quiche::Error::Done) => Ok(None),
+        result => result.map(Some),
+    }
+}
+
+/// Hands a packet received from `from` to quiche. Every packet goes to quiche whatever order it
+/// arrives in: quiche copes with reordering itself, and drops duplicates and packets it can't yet
+/// decrypt without that being an error.
+pub fn deliver(
+    quiche_conn: &mut quiche::Connection,
+    packet: &mut [u8],
+    from: SocketAddr,
+) -> quiche::Result<()> {
+    quic_step(quiche_conn.recv(packet, quiche::RecvInfo { from }))?;
+    Ok(())
+}
+
+/// As `quic_step`, for the HTTP/3 layer.
+pub fn h3_step<T>(result: h3::Result<T>) -> h3::Result<Option<T>> {
+    match result {
+        Err(h3::Error::Done) => Ok(None),
+        result => result.map(Some),
+    }
+}
+
+/// Sends `packet`, unless the socket refuses it as too large for the path. In that case
+/// `max_send_size` comes down to the smallest datagram every QUIC path must carry and `Ok(false)`
+/// is returned: the packet is lost, and quiche sends its frames again, within the new limit, once
+/// it declares them so. A datagram already that small which is still refused means the path can't
+/// carry QUIC at all, so the error is returned.
+pub async fn send_within_path_limit(
+    socket: &UdpSocket,
+    packet: &[u8],
+    to: SocketAddr,
+    max_send_size: &mut usize,
+) -> io::Result<bool> {
+    match socket.send_to(packet, to).await {
+        Err(e)
+            if e.raw_os_error() == Some(libc::EMSGSIZE)
+                && packet.len() > quiche::MIN_CLIENT_INITIAL_LEN =>
+        {
+            warn!(
+                "Datagram of {} bytes too large to send to {}, capping datagrams at {} bytes",
+                packet.len(),
+                to,
+                quiche::MIN_CLIENT_INITIAL_LEN
+            );
+            *max_send_size = quiche::MIN_CLIENT_INITIAL_LEN;
+            Ok(false)
+        }
+        result => result.map(|_| true),
+    }
+}
+
+/// Why a connection whose handshake never completed ended with `error`. `peer_closed` is whether
+/// the server closed the connection, and `socket_error` whether reading from the socket failed.
+pub fn connect_failure(error: &Error, peer_closed: bool, socket_error: bool) -> ConnectFailure {
+    match error {
+        Error::Network(_) => ConnectFailure::Unreachable,
+        Error::Quic(quiche::Error::UnknownVersion) | Error::VersionNotAcceptable(_) => {
+            ConnectFailure::VersionNegotiation
+        }
+        Error::Quic(quiche::Error::TlsFail) | Error::ServerNameMismatch { .. } => {
+            ConnectFailure::TlsVerify
+        }
+        // Closing quietly means the idle timeout fired. If the socket reported errors along the
+        // way, they say why nothing got through.
+        Error::Closed if !peer_closed && socket_error => ConnectFailure::Unreachable,
+        Error::Closed if !peer_closed => ConnectFailure::HandshakeTimeout,
+        Error::HandshakeTimeout(_) | Error::Zombie { .. } => ConnectFailure::HandshakeTimeout,
+        _ => ConnectFailure::Other,
+    }
+}
+
+/// Whether a handshake which failed with `error` looks like it was intercepted, as captive portals
+/// do, rather than reaching a server with a bad certificate: TLS failed, and `leaf`, the
+/// certificate presented, isn't valid for `server_name` at all. An interstitial's certificate is
+/// for the portal's own host. Without a server name to check against, nothing looks intercepted.
+pub fn looks_intercepted(
+    error: &Error,
+    leaf: Option<&CertInfo>,
+    server_name: Option<&str>,
+) -> bool {
+    match (error, leaf, server_name) {
+        (Error::Quic(quiche::Error::TlsFail), Some(leaf), Some(name)) => {
+            !certificate::matches_name(leaf, name)
+        }
+        _ => false,
+    }
+}
+
+/// The version quiche will switch `quiche_conn`, still using `version`, to on receiving `packet`,
+/// or `None` if `packet` is not a version negotiation packet quiche would act on. This mirrors
+/// quiche's own checks, so that the switch can be vetted before quiche makes it.
+pub fn negotiated_version(
+    quiche_conn: &quiche::Connection,
+    packet: &mut [u8],
+    version: u32,
+) -> Option<u32> {
+    let header = quiche::Header::from_slice(packet, quiche::MAX_CONN_ID_LEN).ok()?;
+    if header.ty != quiche::Type::VersionNegotiation
+        || quiche_conn.stats().recv > 0
+        || header.dcid != quiche_conn.source_id()
+        || header.scid != quiche_conn.destination_id()
+    {
+        return None;
+    }
+    let offered = header.versions?;
+    if offered.contains(&version) {
+        return None;
     }
+    let supported: Vec<u32> =
+        offered.into_iter().filter(|&v| quiche::version_is_supported(v)).collect();
+    if supported.is_empty() {
+        // quiche fails the connection with `UnknownVersion` itself.
+        None
+    } else if supported.contains(&quiche::PROTOCOL_VERSION) {
+        // The final version takes precedence over drafts.
+        Some(quiche::PROTOCOL_VERSION)
+    } else {
+        supported.into_iter().max().map(|v| v.max(version))
+    }
+}
+
+// Whether a request's deadline, if it has one, has passed.
+fn is_expired(clock: &dyn Clock, expiry: Option<BootTime>) -> bool {
+    matches!(expiry, Some(expiry) if clock.now() > expiry)
+}
+
+// Time left before a watchdog of `timeout` fires for a connection which last made progress at
+// `last_progress`.
+fn watchdog_remaining(
+    clock: &dyn Clock,
+    timeout: Option<boot_time::Duration>,
+    last_progress: BootTime,
+) -> Option<boot_time::Duration> {
+    Some(timeout?.checked_sub(clock.elapsed(last_progress)).unwrap_or_default())
 }
 
-const MAX_UDP_PACKET_SIZE: usize = 65536;
+// Whether a deadline whose sleep has just ended has really passed, going by the clock rather than
+// by the sleep. A wakeup can come early, and a connection torn down on one would take its queries
+// with it; the driver instead goes round again and sleeps for what is left.
+fn due(remaining: Option<boot_time::Duration>) -> bool {
+    remaining == Some(boot_time::Duration::from_secs(0))
+}
+
+/// The driver's end of the channels and state it shares with its `Connection`
+pub struct Handles {
+    pub request_rx: mpsc::Receiver<Request>,
+    pub status_tx: watch::Sender<Status>,
+    pub packet_tape: SharedPacketTape,
+    pub activity: SharedActivity,
+}
 
-struct Driver {
+/// Drives a connection through its handshake, then hands it to a `StreamDriver` while it is
+/// established.
+pub struct Driver {
     request_rx: mpsc::Receiver<Request>,
     status_tx: watch::Sender<Status>,
     quiche_conn: Pin<Box<quiche::Connection>>,
     socket: UdpSocket,
-    // This buffer is large, boxing it will keep it
-    // off the stack and prevent it being copied during
-    // moves of the driver.
-    buffer: Box<[u8; MAX_UDP_PACKET_SIZE]>,
+    // Packet buffers are borrowed from here only while packets are read or written, so idle
+    // connections don't each hold one.
+    buffer_pool: SharedBufferPool,
     net_id: u32,
+    // Name the certificate is expected to be for, if the server was configured with one.
+    server_name: Option<String>,
     // Used to check if the connection has entered closing or draining state. A connection can
     // enter closing state if the sender of request_rx's channel has been dropped.
     // Note that we can't check if a receiver is dead without potentially receiving a message, and
     // if we poll on a dead receiver in a select! it will immediately return None. As a result, we
     // need this to gate whether or not to include .recv() in our select!
     closing: bool,
+    // Watchdog and HTTP/3 settings.
+    options: Options,
+    // Last time a packet was sent or received, or a request was issued.
+    last_progress: BootTime,
+    // What last woke up the driver, for diagnosing wedged connections.
+    last_event: &'static str,
+    clock: SharedClock,
+    metrics: Arc<DispatcherMetrics>,
+    // Set once the attempt to establish the connection has been counted as a success or failure.
+    attempt_settled: bool,
+    // Whether reading from the socket has failed, which is how ICMP unreachables show up.
+    socket_error: bool,
+    // Taken when the handshake is reported, so each connection reports at most once.
+    cert_observer: Option<CertObserver>,
+    // Told about every packet, for the whole life of the connection.
+    packet_size_observer: Option<PacketSizeObserver>,
+    // Records packets until the attempt settles.
+    packet_tape: SharedPacketTape,
+    activity: SharedActivity,
+    // The version the connection started with, until version negotiation has happened. quiche
+    // ignores any further negotiation.
+    version_before_negotiation: Option<u32>,
+    // The version the connection runs, once negotiation is over.
+    quic_version: u32,
+    // Held until the handshake has completed or failed, as is its trace section.
+    handshake_slot: Option<HandshakeSlot>,
+    handshake_section: Option<Section>,
+    // Begun once the connection starts closing.
+    close_section: Option<Section>,
+    // Requests in flight, kept up to date by the `StreamDriver`, and their share of the traffic.
+    open_requests: usize,
+    wire_share: WireShare,
+    // Set when the last read used up `Options::recv_batch`, so more packets may be waiting.
+    recv_backlog: bool,
+    // When the handshake has gone on for `Options::handshake_timeout`, until it completes.
+    handshake_deadline: Option<BootTime>,
+    // When the handshake, or closing, has gone on for `Options::zombie_timeout`, and the start
+    // of it. Unset while the connection is established and open.
+    zombie_deadline: Option<(BootTime, BootTime)>,
+    // Largest datagram handed to the socket, to begin with the config's `max_datagram_size`.
+    // quiche 0.9 can't be told the path MTU, so packets are kept within it by the size of the
+    // buffer quiche writes them into.
+    max_send_size: usize,
+}
+
+// How an established connection's requests are written to their streams and answers read back.
+enum Framing {
+    H3(Box<h3::Connection>),
+    // Raw DNS over QUIC. With no HTTP/3 layer to open streams, the driver numbers them itself. It
+    // also keeps the message ID each query was asked with, as they are all sent with ID 0.
+    RawDns { next_stream_id: u64, message_ids: HashMap<u64, [u8; 2]> },
+}
+
+// What became of a request written to a stream of its own.
+enum Opened {
+    Stream(u64),
+    // No stream can be opened until the server allows more, or has made room.
+    Blocked,
+    // The request was given up on, and is answered with this stream error.
+    Cancelled(u64),
 }
 
-struct H3Driver {
+// Drives an established connection, whichever `Framing` its requests take.
+struct StreamDriver {
     driver: Driver,
-    // h3_conn sometimes can't "fit" a request in its available windows.
+    // The connection sometimes can't "fit" a request in its available windows.
     // This value holds a peeked request in that case, waiting for
     // transmission to become possible.
     buffered_request: Option<Request>,
-    h3_conn: h3::Connection,
+    framing: Framing,
     requests: HashMap<u64, Request>,
     streams: HashMap<u64, Stream>,
+    started: HashMap<u64, RequestStart>,
+    // Set once the `Connection` handle is gone. The connection closes when the requests already
+    // in flight have been answered, or at `drain_deadline` if that comes first.
+    retiring: bool,
+    drain_deadline: Option<BootTime>,
 }
 
 async fn optional_timeout(timeout: Option<boot_time::Duration>, net_id: u32) {
@@ -114,47 +531,225 @@ async fn optional_timeout(timeout: Option<boot_time::Duration>, net_id: u32) {
     }
 }
 
-/// Creates a future which when polled will handle events related to a HTTP/3 connection.
-/// The returned error code will explain why the connection terminated.
-pub async fn drive(
-    request_rx: mpsc::Receiver<Request>,
-    status_tx: watch::Sender<Status>,
-    quiche_conn: Pin<Box<quiche::Connection>>,
-    socket: UdpSocket,
-    net_id: u32,
-) -> Result<()> {
-    Driver::new(request_rx, status_tx, quiche_conn, socket, net_id).drive().await
-}
-
 impl Driver {
-    fn new(
-        request_rx: mpsc::Receiver<Request>,
-        status_tx: watch::Sender<Status>,
+    /// A driver for `quiche_conn`, sending on `socket` datagrams of at most `max_send_size`.
+    pub fn new(
         quiche_conn: Pin<Box<quiche::Connection>>,
         socket: UdpSocket,
-        net_id: u32,
+        peer: Peer<'_>,
+        options: Options,
+        handles: Handles,
+        env: Environment,
+        max_send_size: usize,
     ) -> Self {
-        Self {
+        let version_before_negotiation = options.quic_versions.first().copied();
+        let Handles { request_rx, status_tx, packet_tape, activity } = handles;
+        let mut driver = Self {
             request_rx,
             status_tx,
             quiche_conn,
             socket,
-            buffer: Box::new([0; MAX_UDP_PACKET_SIZE]),
-            net_id,
+            buffer_pool: env.metrics.buffer_pool(),
+            net_id: peer.net_id,
+            server_name: peer.server_name.map(str::to_string),
             closing: false,
+            options,
+            last_progress: env.clock.now(),
+            last_event: "start",
+            clock: env.clock,
+            metrics: env.metrics,
+            attempt_settled: false,
+            socket_error: false,
+            cert_observer: env.cert_observer,
+            packet_size_observer: env.packet_size_observer,
+            packet_tape,
+            activity,
+            version_before_negotiation,
+            quic_version: version_before_negotiation.unwrap_or(quiche::PROTOCOL_VERSION),
+            handshake_slot: None,
+            handshake_section: None,
+            close_section: None,
+            open_requests: 0,
+            wire_share: WireShare::default(),
+            recv_backlog: false,
+            max_send_size,
+            handshake_deadline: None,
+            zombie_deadline: None,
+        };
+        driver.start_handshake_clocks();
+        driver
+    }
+
+    // Starts the handshake's timeouts from now.
+    fn start_handshake_clocks(&mut self) {
+        self.last_progress = self.clock.now();
+        self.handshake_deadline = self
+            .options
+            .handshake_timeout
+            .and_then(|timeout| self.clock.now().checked_add(timeout));
+        self.arm_zombie_deadline();
+    }
+
+    // Gives the phase the connection is entering `Options::zombie_timeout` to end.
+    fn arm_zombie_deadline(&mut self) {
+        let now = self.clock.now();
+        self.zombie_deadline = self
+            .options
+            .zombie_timeout
+            .and_then(|timeout| now.checked_add(timeout))
+            .map(|deadline| (deadline, now));
+    }
+
+    fn handshake_remaining(&self) -> Option<boot_time::Duration> {
+        let deadline = self.handshake_deadline?;
+        Some(deadline.checked_duration_since(self.clock.now()).unwrap_or_default())
+    }
+
+    fn zombie_remaining(&self) -> Option<boot_time::Duration> {
+        let (deadline, _) = self.zombie_deadline?;
+        Some(deadline.checked_duration_since(self.clock.now()).unwrap_or_default())
+    }
+
+    fn handshake_timer_fired(&mut self) -> Result<()> {
+        if !due(self.handshake_remaining()) {
+            self.last_event = "early handshake timer";
+            return Ok(());
+        }
+        Err(Error::HandshakeTimeout(self.options.handshake_timeout.unwrap_or_default()))
+    }
+
+    fn zombie_timer_fired(&mut self, phase: &'static str) -> Result<()> {
+        if !due(self.zombie_remaining()) {
+            self.last_event = "early zombie timer";
+            return Ok(());
+        }
+        Err(self.reap(phase))
+    }
+
+    // Tears down a connection stuck in `phase`.
+    fn reap(&mut self, phase: &'static str) -> Error {
+        let stuck = self.zombie_deadline.map(|(_, since)| self.clock.elapsed(since));
+        let stuck = stuck.unwrap_or_default();
+        warn!(
+            "Connection {} on network {} stuck {} for {:?}, reaping it. last_event={}, stats={:?}",
+            self.quiche_conn.trace_id(),
+            self.net_id,
+            phase,
+            stuck,
+            self.last_event,
+            self.quiche_conn.stats()
+        );
+        self.metrics.zombie_reaped();
+        Error::Zombie { phase, stuck }
+    }
+
+    fn progress(&mut self, event: &'static str) {
+        self.last_progress = self.clock.now();
+        self.last_event = event;
+    }
+
+    // Counts an error as the outcome of the connection attempt, unless the handshake completed.
+    fn settle_attempt<T>(&mut self, result: Result<T>) -> Result<T> {
+        if let Err(e) = &result {
+            if !self.attempt_settled {
+                self.attempt_settled = true;
+                self.handshake_slot = None;
+                self.handshake_section = None;
+                self.packet_tape.dump(self.quiche_conn.trace_id());
+                let leaf = self.quiche_conn.peer_cert().and_then(|der| certificate::parse(&der));
+                let failure = if looks_intercepted(e, leaf.as_ref(), self.server_name.as_deref()) {
+                    warn!(
+                        "Connection on network {} looks intercepted by a captive portal",
+                        self.net_id
+                    );
+                    ConnectFailure::CaptivePortal
+                } else {
+                    connect_failure(e, self.quiche_conn.peer_error().is_some(), self.socket_error)
+                };
+                self.metrics.connection_failed(failure);
+            }
         }
+        result
     }
 
-    async fn drive(mut self) -> Result<()> {
+    /// Runs the connection until it closes. The error says why it did.
+    pub async fn drive(mut self) -> Result<()> {
+        // Queue behind other connections' handshakes before sending anything, so that time spent
+        // waiting doesn't count against the handshake's own timeouts.
+        self.handshake_slot = Some(self.metrics.handshake_limiter().acquire().await);
+        self.handshake_section = Some(Section::begin(Span::Handshake));
+        self.start_handshake_clocks();
+        self.metrics.connection_attempted();
         // Prime connection
-        self.flush_tx().await?;
+        let primed = self.flush_tx().await;
+        self.settle_attempt(primed)?;
         loop {
             self = self.drive_once().await?
         }
     }
 
-    fn handle_closed(&self) -> Result<()> {
+    // Fails the connection if `Options::expected_server_name` is set and the server's certificate
+    // isn't valid for it, however well it verified.
+    async fn check_server_name(&mut self) -> Result<()> {
+        let expected = match self.options.expected_server_name.clone() {
+            Some(expected) => expected,
+            None => return Ok(()),
+        };
+        let leaf = self.quiche_conn.peer_cert().and_then(|der| certificate::parse(&der));
+        if matches!(&leaf, Some(leaf) if certificate::matches_name(leaf, &expected)) {
+            return Ok(());
+        }
+        let error = Error::ServerNameMismatch {
+            expected,
+            presented: leaf.as_ref().map(certificate::presented_names).unwrap_or_default(),
+        };
+        warn!("{} on network {}", error, self.net_id);
+        self.report_handshake(CertOutcome::Rejected);
+        // Tell the server why, with the bad_certificate TLS alert, before dropping the connection.
+        self.quiche_conn.close(false, CRYPTO_ERROR_BAD_CERTIFICATE, b"")?;
+        self.flush_tx().await?;
+        Err(error)
+    }
+
+    // Tells the observer, if any, what became of the server's certificate.
+    // Tears down the connection once it has lost more packets than its retransmission budget.
+    async fn check_retransmissions(&mut self) -> Result<()> {
+        let budget = match self.options.lost_packet_budget() {
+            Some(budget) => budget,
+            None => return Ok(()),
+        };
+        let lost = self.quiche_conn.stats().lost;
+        if lost <= budget {
+            return Ok(());
+        }
+        warn!(
+            "Connection {} on network {} lost {} packets, over its budget of {}, tearing it down",
+            self.quiche_conn.trace_id(),
+            self.net_id,
+            lost,
+            budget
+        );
+        if self.quiche_conn.close(false, 0, b"RETRANSMISSION BUDGET").is_ok() {
+            let _ = self.flush_tx().await;
+        }
+        Err(Error::RetransmissionBudget(lost))
+    }
+
+    fn report_handshake(&mut self, outcome: CertOutcome) {
+        if let Some(observer) = self.cert_observer.take() {
+            let leaf = self.quiche_conn.peer_cert().and_then(|der| certificate::parse(&der));
+            observer(&HandshakeReport { net_id: self.net_id, outcome, leaf });
+        }
+    }
+
+    fn handle_closed(&mut self) -> Result<()> {
         if self.quiche_conn.is_closed() {
+            // A connection which closes with a certificate in hand but before the handshake
+            // completed has most likely failed verification. Once established, the observer has
+            // already been told.
+            if self.quiche_conn.peer_cert().is_some() {
+                self.report_handshake(CertOutcome::Rejected);
+            }
             // TODO: Also log local_error() once Quiche 0.10.0 is available.
             debug!(
                 "Connection {} closed on network {}, peer_error={:x?}",
@@ -170,6 +765,14 @@ impl Driver {
         }
     }
 
+    // The connection stays closing, and its close section open, until the driver ends.
+    fn enter_closing(&mut self) {
+        self.closing = true;
+        if self.close_section.is_none() {
+            self.close_section = Some(Section::begin(Span::Close));
+        }
+    }
+
     fn handle_draining(&mut self) {
         if self.quiche_conn.is_draining() && !self.closing {
             // TODO: Also log local_error() once Quiche 0.10.0 is available.
@@ -186,29 +789,46 @@ impl Driver {
             // Drain the pending DNS requests from the queue to make their corresponding future
             // tasks return some error quickly rather than timeout. However, the DNS requests
             // that has been sent will still time out.
-            // TODO: re-issue the outstanding DNS requests, such as passing H3Driver.requests
+            // TODO: re-issue the outstanding DNS requests, such as passing StreamDriver.requests
             // along with Status::Dead to the `Network` that can re-issue the DNS requests.
             while self.request_rx.try_recv().is_ok() {}
-            self.closing = true;
+            self.enter_closing();
         }
     }
 
     async fn drive_once(mut self) -> Result<Self> {
         let timer = optional_timeout(self.quiche_conn.timeout(), self.net_id);
+        let zombie = optional_timeout(self.zombie_remaining(), self.net_id);
+        let handshake = optional_timeout(self.handshake_remaining(), self.net_id);
         select! {
-            // If a quiche timer would fire, call their callback
+            // If a quiche timer would fire, call their callback. Quiche checks its own timers
+            // against the time, so an early wakeup does nothing.
             _ = timer => {
                 debug!("Driver: Timer expired on network {}", self.net_id);
+                self.last_event = "timer";
                 self.quiche_conn.on_timeout()
             }
             // If we got packets from our peer, pass them to quiche
-            Ok((size, from)) = self.socket.recv_from(self.buffer.as_mut()) => {
-                self.quiche_conn.recv(&mut self.buffer[..size], quiche::RecvInfo { from })?;
-                debug!("Received {} bytes on network {}", size, self.net_id);
+            Ok(()) = self.socket.readable() => {
+                let received = self.recv();
+                self.settle_attempt(received)?
+            }
+            // The handshake has taken longer than it was allowed
+            _ = handshake => {
+                let fired = self.handshake_timer_fired();
+                self.settle_attempt(fired)?
+            }
+            // The handshake has neither completed nor failed in all this time
+            _ = zombie => {
+                let fired = self.zombie_timer_fired("handshaking");
+                self.settle_attempt(fired)?
             }
         };
         // Any of the actions in the select could require us to send packets to the peer
-        self.flush_tx().await?;
+        let flushed = self.flush_tx().await;
+        self.settle_attempt(flushed)?;
+        let within_budget = self.check_retransmissions().await;
+        self.settle_attempt(within_budget)?;
 
         // If the QUIC connection is live, but the HTTP/3 is not, try to bring it up
         if self.quiche_conn.is_established() {
@@ -217,9 +837,34 @@ impl Driver {
                 self.quiche_conn.trace_id(),
                 self.net_id
             );
-            let h3_config = h3::Config::new()?;
-            let h3_conn = h3::Connection::with_transport(&mut self.quiche_conn, &h3_config)?;
-            self = H3Driver::new(self, h3_conn).drive().await?;
+            self.activity.lock().unwrap().negotiated = Some(Negotiated {
+                version: self.quic_version,
+                alpn: self.quiche_conn.application_proto().to_vec(),
+            });
+            let checked = self.check_server_name().await;
+            self.settle_attempt(checked)?;
+            self.report_handshake(CertOutcome::Accepted);
+            if !self.attempt_settled {
+                self.attempt_settled = true;
+                self.handshake_slot = None;
+                self.handshake_section = None;
+                self.packet_tape.dump(self.quiche_conn.trace_id());
+                self.handshake_deadline = None;
+                self.metrics.connection_established();
+            }
+            // There is no warmup PING to measure the path with, as the quiche version we build
+            // against can't send one. The handshake has already given quiche an RTT sample, which
+            // the first answer reports in `QueryStats::rtt`.
+            let stream_driver = match self.options.stream_mode {
+                StreamMode::Http3 => {
+                    let h3_config = self.options.h3_config()?;
+                    let h3_conn =
+                        h3::Connection::with_transport(&mut self.quiche_conn, &h3_config)?;
+                    StreamDriver::new(self, h3_conn)
+                }
+                StreamMode::RawDns => StreamDriver::raw_dns(self),
+            };
+            self = stream_driver.drive().await?;
             let _ = self.status_tx.send(Status::QUIC);
         }
 
@@ -230,19 +875,100 @@ impl Driver {
         self.handle_draining();
 
         // If the connection has closed, tear down
-        self.handle_closed()?;
+        let closed = self.handle_closed();
+        self.settle_attempt(closed)?;
 
+        self.yield_if_backlogged().await;
         Ok(self)
     }
 
+    // Reads the packets waiting now that the socket is readable, up to `Options::recv_batch`.
+    fn recv(&mut self) -> Result<()> {
+        let mut buffer = self.buffer_pool.get();
+        let batch = self.options.recv_batch.max(1);
+        for _ in 0..batch {
+            match self.socket.try_recv_from(&mut buffer) {
+                Ok((size, from)) => {
+                    if !self.attempt_settled {
+                        let now = self.clock.now();
+                        self.packet_tape.record(Direction::Inbound, now, from, &buffer[..size]);
+                    }
+                    self.observe_packet_size(Direction::Inbound, size);
+                    self.vet_version_negotiation(&mut buffer[..size])?;
+                    deliver(&mut self.quiche_conn, &mut buffer[..size], from)?;
+                    self.progress("recv");
+                    debug!("Received {} bytes on network {}", size, self.net_id);
+                }
+                // Readiness can be spurious, and otherwise this is the end of the backlog.
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                // As before, a failed read is skipped. Anything that matters, such as the server
+                // being unreachable, shows up as the connection timing out.
+                Err(e) => {
+                    debug!("Unable to receive on network {}: {:?}", self.net_id, e);
+                    self.socket_error = true;
+                    return Ok(());
+                }
+            }
+        }
+        self.recv_backlog = true;
+        Ok(())
+    }
+
+    // Lets other tasks run before reading on, if a whole batch of packets was just read. The
+    // socket is likely still readable, so without this the driver would carry straight on.
+    async fn yield_if_backlogged(&mut self) {
+        if std::mem::take(&mut self.recv_backlog) {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    fn observe_packet_size(&mut self, direction: Direction, len: usize) {
+        self.wire_share.add(direction, len, self.open_requests);
+        if let Some(observer) = &self.packet_size_observer {
+            let trace_id = self.quiche_conn.trace_id();
+            observer(&PacketSize { net_id: self.net_id, trace_id, direction, len });
+        }
+    }
+
+    // Fails the connection if `packet` would make quiche switch to a version we don't accept.
+    fn vet_version_negotiation(&mut self, packet: &mut [u8]) -> Result<()> {
+        let version = match self.version_before_negotiation {
+            Some(version) => version,
+            None => return Ok(()),
+        };
+        if let Some(negotiated) = negotiated_version(&self.quiche_conn, packet, version) {
+            if !self.options.quic_versions.contains(&negotiated) {
+                return Err(Error::VersionNotAcceptable(negotiated));
+            }
+            debug!("Negotiated QUIC version {:#x} on network {}", negotiated, self.net_id);
+            self.version_before_negotiation = None;
+            self.quic_version = negotiated;
+        }
+        Ok(())
+    }
+
     async fn flush_tx(&mut self) -> Result<()> {
-        let send_buf = self.buffer.as_mut();
+        let mut buffer = self.buffer_pool.get();
         loop {
-            match self.quiche_conn.send(send_buf) {
-                Err(quiche::Error::Done) => return Ok(()),
-                Err(e) => return Err(e.into()),
-                Ok((valid_len, send_info)) => {
-                    self.socket.send_to(&send_buf[..valid_len], send_info.to).await?;
+            let send_buf = &mut buffer[..self.max_send_size];
+            match quic_step(self.quiche_conn.send(send_buf))? {
+                None => return Ok(()),
+                Some((valid_len, send_info)) => {
+                    if !self.attempt_settled {
+                        let (now, packet) = (self.clock.now(), &send_buf[..valid_len]);
+                        self.packet_tape.record(Direction::Outbound, now, send_info.to, packet);
+                    }
+                    // quiche has already committed to this packet. `send_to` holds on to it
+                    // while the kernel send buffer is full, waiting for the socket to become
+                    // writable, and further output stays inside quiche until it is sent.
+                    let packet = &send_buf[..valid_len];
+                    let (socket, max_send_size) = (&self.socket, &mut self.max_send_size);
+                    if !send_within_path_limit(socket, packet, send_info.to, max_send_size).await? {
+                        continue;
+                    }
+                    self.observe_packet_size(Direction::Outbound, valid_len);
+                    self.last_progress = self.clock.now();
+                    self.last_event = "send";
                     debug!("Sent {} bytes on network {}", valid_len, self.net_id);
                 }
             }
@@ -250,14 +976,28 @@ impl Driver {
     }
 }
 
-impl H3Driver {
+impl StreamDriver {
     fn new(driver: Driver, h3_conn: h3::Connection) -> Self {
+        Self::with_framing(driver, Framing::H3(Box::new(h3_conn)))
+    }
+
+    // Sends requests as raw DNS over QUIC instead, skipping HTTP/3 altogether.
+    fn raw_dns(driver: Driver) -> Self {
+        let framing = Framing::RawDns { next_stream_id: 0, message_ids: HashMap::new() };
+        Self::with_framing(driver, framing)
+    }
+
+    fn with_framing(mut driver: Driver, framing: Framing) -> Self {
+        driver.zombie_deadline = None;
         Self {
             driver,
-            h3_conn,
+            framing,
             requests: HashMap::new(),
             streams: HashMap::new(),
+            started: HashMap::new(),
             buffered_request: None,
+            retiring: false,
+            drain_deadline: None,
         }
     }
 
@@ -269,7 +1009,7 @@ impl H3Driver {
                     .driver
                     .status_tx
                     .send(Status::Dead { session: self.driver.quiche_conn.session() });
-                return Err(e)
+                return Err(e);
             }
         }
     }
@@ -283,32 +1023,54 @@ impl H3Driver {
         if let Some(request) = self.buffered_request.take() {
             self.handle_request(request)?;
         }
+        let watchdog = optional_timeout(self.watchdog_remaining(), self.driver.net_id);
+        let drain = optional_timeout(self.drain_remaining(), self.driver.net_id);
+        let expiry = optional_timeout(self.expiry_remaining(), self.driver.net_id);
+        if self.driver.closing && self.driver.zombie_deadline.is_none() {
+            self.driver.arm_zombie_deadline();
+        }
+        let zombie = optional_timeout(self.driver.zombie_remaining(), self.driver.net_id);
         select! {
             // Only attempt to enqueue new requests if we have no buffered request and aren't
             // closing
             msg = self.driver.request_rx.recv(), if !self.driver.closing && self.buffered_request.is_none() => {
                 match msg {
                     Some(request) => self.handle_request(request)?,
-                    None => self.shutdown(true, b"DONE").await?,
+                    None => self.retire()?,
                 }
             },
             // If a quiche timer would fire, call their callback
             _ = timer => {
-                debug!("H3Driver: Timer expired on network {}", self.driver.net_id);
+                debug!("StreamDriver: Timer expired on network {}", self.driver.net_id);
+                self.driver.last_event = "timer";
                 self.driver.quiche_conn.on_timeout()
             }
             // If we got packets from our peer, pass them to quiche
-            Ok((size, from)) = self.driver.socket.recv_from(self.driver.buffer.as_mut()) => {
-                self.driver.quiche_conn.recv(&mut self.driver.buffer[..size], quiche::RecvInfo { from }).map(|_| ())?;
-                debug!("Received {} bytes on network {}", size, self.driver.net_id);
-            }
+            Ok(()) = self.driver.socket.readable() => self.driver.recv()?,
+            // If requests are waiting on a connection which has gone quiet, the driver is wedged
+            _ = watchdog => return self.watchdog_timer_fired().await,
+            // A retired connection may have run out of time to receive what is still in flight,
+            // which `ready_to_close` checks against the clock
+            _ = drain => self.driver.last_event = "drain deadline",
+            // A request has reached its expiry, whether or not anything is arriving for it
+            _ = expiry => self.expire_requests()?,
+            // The connection has been closing for far longer than quiche should take
+            _ = zombie => return self.driver.zombie_timer_fired("closing"),
         };
 
         // Any of the actions in the select could require us to send packets to the peer
         self.driver.flush_tx().await?;
+        self.driver.check_retransmissions().await?;
 
-        // Process any incoming HTTP/3 events
-        self.flush_h3().await?;
+        // Process whatever has arrived on the request streams
+        self.flush_streams().await?;
+
+        // Once a retired connection has nothing left in flight, or no time left, close it
+        if self.ready_to_close() {
+            self.retiring = false;
+            self.shutdown(false, b"DONE").await?;
+            self.driver.flush_tx().await?;
+        }
 
         // If the connection has entered draining state (the server is closing the connection),
         // tell the status watcher not to use the connection. Besides, per Quiche document,
@@ -316,58 +1078,222 @@ impl H3Driver {
         // This tokio task will become unowned and get dropped when is_closed() returns true.
         self.driver.handle_draining();
 
+        self.report_activity();
+
         // If the connection has closed, tear down
-        self.driver.handle_closed()
+        self.handle_closed()?;
+
+        self.driver.yield_if_backlogged().await;
+        Ok(())
+    }
+
+    // As `Driver::handle_closed`, telling apart a connection the idle timeout closed under
+    // requests still in flight. Closing idle with nothing outstanding is routine, but this loses
+    // the requests, which then fail for their requestors to retry.
+    fn handle_closed(&mut self) -> Result<()> {
+        let in_flight = self.requests.len() + usize::from(self.buffered_request.is_some());
+        // Every other close is ours, and sets `closing`, or the server's, and carries its error.
+        let idle = !self.driver.closing && self.driver.quiche_conn.peer_error().is_none();
+        match self.driver.handle_closed() {
+            Err(Error::Closed) if idle && in_flight > 0 => {
+                warn!(
+                    "Connection {} on network {} closed by the idle timeout with {} requests \
+                     in flight, stats={:?}",
+                    self.driver.quiche_conn.trace_id(),
+                    self.driver.net_id,
+                    in_flight,
+                    self.driver.quiche_conn.stats()
+                );
+                Err(Error::IdleWithRequests(in_flight))
+            }
+            closed => closed,
+        }
+    }
+
+    // Publishes what is in flight, for `Connection::info`.
+    fn report_activity(&self) {
+        let now = self.driver.clock.now();
+        let idle_deadline =
+            self.driver.quiche_conn.timeout().and_then(|timeout| now.checked_add(timeout));
+        let buffered_bytes = self.streams.values().map(|stream| stream.data.len()).sum();
+        let mut activity = self.driver.activity.lock().unwrap();
+        activity.open_streams = self.requests.len();
+        activity.idle_deadline = idle_deadline;
+        activity.buffered_bytes = buffered_bytes;
+        activity.packets_lost = self.driver.quiche_conn.stats().lost;
     }
 
     fn handle_request(&mut self, request: Request) -> Result<()> {
         debug!("Handling DNS request on network {}, stats={:?}, peer_streams_left_bidi={}, peer_streams_left_uni={}",
                 self.driver.net_id, self.driver.quiche_conn.stats(), self.driver.quiche_conn.peer_streams_left_bidi(), self.driver.quiche_conn.peer_streams_left_uni());
         // If the request has already timed out, don't issue it to the server.
-        if let Some(expiry) = request.expiry {
-            if BootTime::now() > expiry {
-                warn!("Abandoning expired DNS request");
+        if is_expired(self.driver.clock.as_ref(), request.expiry) {
+            warn!("Abandoning expired DNS request");
+            let _ = request.response_tx.send(Stream { expired: true, ..Stream::new(Vec::new()) });
+            return Ok(());
+        }
+        let stream_id = match self.open_stream(&request)? {
+            Opened::Stream(stream_id) => stream_id,
+            Opened::Blocked => {
+                // We only call handle_request on a value that has just come out of
+                // buffered_request, or when buffered_request is empty. This assert just
+                // validates that we don't break that assumption later, as it could result in
+                // requests being dropped on the floor under high load.
+                debug!("Stream has become blocked, buffering one request.");
+                assert!(self.buffered_request.is_none());
+                self.buffered_request = Some(request);
                 return Ok(());
             }
-        }
-        let stream_id =
-            // If h3_conn says the stream is blocked, this error is recoverable just by trying
-            // again once the stream has made progress. Buffer the request for a later retry.
-            match self.h3_conn.send_request(&mut self.driver.quiche_conn, &request.headers, true) {
-                Err(h3::Error::StreamBlocked) | Err(h3::Error::TransportError(quiche::Error::StreamLimit)) => {
-                    // We only call handle_request on a value that has just come out of
-                    // buffered_request, or when buffered_request is empty. This assert just
-                    // validates that we don't break that assumption later, as it could result in
-                    // requests being dropped on the floor under high load.
-                    debug!("Stream has become blocked, buffering one request.");
-                    assert!(self.buffered_request.is_none());
-                    self.buffered_request = Some(request);
-                    return Ok(())
-                }
-                result => result?,
-            };
+            Opened::Cancelled(error) => {
+                let error = Some(error);
+                let _ = request.response_tx.send(Stream { error, ..Stream::new(Vec::new()) });
+                return Ok(());
+            }
+        };
+        // Order our own sending by the same priority the request asks of the server. Streams are
+        // always set explicitly, since quiche's own default would rank below every request.
+        let priority = encoding::Priority::of_request(&request.headers);
+        self.driver.quiche_conn.stream_priority(
+            stream_id,
+            priority.urgency,
+            priority.incremental,
+        )?;
         debug!(
             "Handled DNS request: stream ID {}, network {}, stream_capacity={:?}",
             stream_id,
             self.driver.net_id,
             self.driver.quiche_conn.stream_capacity(stream_id)
         );
+        self.started.insert(
+            stream_id,
+            RequestStart::new(
+                self.driver.clock.as_ref(),
+                &self.driver.quiche_conn.stats(),
+                self.driver.wire_share,
+                request.submitted,
+            ),
+        );
         self.requests.insert(stream_id, request);
+        self.driver.open_requests = self.requests.len();
+        self.driver.progress("request");
+        Ok(())
+    }
+
+    // Writes `request` onto a stream of its own.
+    fn open_stream(&mut self, request: &Request) -> Result<Opened> {
+        let quiche_conn = &mut self.driver.quiche_conn;
+        let h3_conn = match &mut self.framing {
+            Framing::H3(h3_conn) => h3_conn,
+            Framing::RawDns { next_stream_id, message_ids } => {
+                return send_raw_dns(quiche_conn, next_stream_id, message_ids, request)
+            }
+        };
+        let fin = request.body.is_empty();
+        // If h3_conn says the stream is blocked, this error is recoverable just by trying
+        // again once the stream has made progress.
+        let stream_id = match h3_conn.send_request(quiche_conn, &request.headers, fin) {
+            Err(h3::Error::StreamBlocked)
+            | Err(h3::Error::TransportError(quiche::Error::StreamLimit)) => {
+                return Ok(Opened::Blocked)
+            }
+            result => result?,
+        };
+        if !request.body.is_empty() {
+            // Hand-written bodies are small, so one that doesn't fit the stream's initial window
+            // is given up on rather than buffered.
+            match h3_conn.send_body(quiche_conn, stream_id, &request.body, true) {
+                Ok(written) if written == request.body.len() => (),
+                result => {
+                    warn!("Unable to send {}-byte request body: {:?}", request.body.len(), result);
+                    cancel_stream(quiche_conn, stream_id, H3_REQUEST_CANCELLED);
+                    return Ok(Opened::Cancelled(H3_REQUEST_CANCELLED));
+                }
+            }
+        }
+        Ok(Opened::Stream(stream_id))
+    }
+
+    // Stream error code for telling the server a response is no longer wanted.
+    fn cancel_code(&self) -> u64 {
+        match self.framing {
+            Framing::H3(_) => H3_REQUEST_CANCELLED,
+            Framing::RawDns { .. } => DOQ_REQUEST_CANCELLED,
+        }
+    }
+
+    // DNS over QUIC has no GOAWAY: a raw DNS connection just closes once its answers are in.
+    fn send_goaway(&mut self) -> Result<()> {
+        if let Framing::H3(h3_conn) = &mut self.framing {
+            h3_conn.send_goaway(&mut self.driver.quiche_conn, 0)?;
+        }
         Ok(())
     }
 
+    // Time left before the watchdog fires, or `None` if there is nothing for it to guard.
+    fn watchdog_remaining(&self) -> Option<boot_time::Duration> {
+        if self.requests.is_empty() {
+            return None;
+        }
+        watchdog_remaining(
+            self.driver.clock.as_ref(),
+            self.driver.options.watchdog_timeout,
+            self.driver.last_progress,
+        )
+    }
+
+    async fn watchdog_timer_fired(&mut self) -> Result<()> {
+        if !due(self.watchdog_remaining()) {
+            self.driver.last_event = "early watchdog timer";
+            return Ok(());
+        }
+        self.watchdog_expired().await
+    }
+
+    async fn watchdog_expired(&mut self) -> Result<()> {
+        let stalled = self.driver.clock.elapsed(self.driver.last_progress);
+        let mut stream_ids: Vec<_> = self.requests.keys().collect();
+        stream_ids.sort();
+        warn!(
+            "Connection {} on network {} made no progress for {:?}, tearing it down. \
+             last_event={}, requests={:?}, partial_responses={}, buffered_request={}, stats={:?}",
+            self.driver.quiche_conn.trace_id(),
+            self.driver.net_id,
+            stalled,
+            self.driver.last_event,
+            stream_ids,
+            self.streams.len(),
+            self.buffered_request.is_some(),
+            self.driver.quiche_conn.stats()
+        );
+        // Let the server know, if the socket still works. Either way, returning an error drops
+        // the in-flight requests so their queries fail now rather than timing out.
+        if self.driver.quiche_conn.close(false, 0, b"WATCHDOG").is_ok() {
+            let _ = self.driver.flush_tx().await;
+        }
+        Err(Error::Stalled(stalled))
+    }
+
     async fn recv_body(&mut self, stream_id: u64) -> Result<()> {
         const STREAM_READ_CHUNK: usize = 4096;
+        let max_response_size = self
+            .requests
+            .get(&stream_id)
+            .map_or(DEFAULT_MAX_RESPONSE_SIZE, |request| request.max_response_size);
         if let Some(stream) = self.streams.get_mut(&stream_id) {
             loop {
                 let base_len = stream.data.len();
                 stream.data.resize(base_len + STREAM_READ_CHUNK, 0);
-                match self.h3_conn.recv_body(
-                    &mut self.driver.quiche_conn,
-                    stream_id,
-                    &mut stream.data[base_len..],
-                ) {
-                    Err(h3::Error::Done) => {
+                let received = match &mut self.framing {
+                    Framing::H3(h3_conn) => h3_conn.recv_body(
+                        &mut self.driver.quiche_conn,
+                        stream_id,
+                        &mut stream.data[base_len..],
+                    ),
+                    // Raw DNS streams are read by `recv_raw_dns`.
+                    Framing::RawDns { .. } => Err(h3::Error::Done),
+                };
+                match h3_step(received) {
+                    Ok(None) => {
                         stream.data.truncate(base_len);
                         return Ok(());
                     }
@@ -376,7 +1302,7 @@ impl H3Driver {
                         stream.data.truncate(base_len);
                         return Err(e.into());
                     }
-                    Ok(recvd) => {
+                    Ok(Some(recvd)) => {
                         stream.data.truncate(base_len + recvd);
                         debug!(
                             "Got {} bytes of response data from stream ID {} on network {}",
@@ -384,17 +1310,42 @@ impl H3Driver {
                         );
                     }
                 }
+                if stream.data.len() > max_response_size {
+                    break;
+                }
             }
         } else {
             warn!("Received body for untracked stream ID {}", stream_id);
+            return Ok(());
+        }
+        // The response has outgrown what the requestor is willing to accept. Stop the server
+        // from sending the rest of it and hand back what we know rather than buffering more.
+        warn!(
+            "Response on stream ID {} exceeded {} bytes on network {}, abandoning it",
+            stream_id, max_response_size, self.driver.net_id
+        );
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            stream.data.clear();
+            stream.too_large = true;
         }
+        // `Done` means the stream is already gone, which is as good as having stopped it.
+        quic_step(self.driver.quiche_conn.stream_shutdown(
+            stream_id,
+            quiche::Shutdown::Read,
+            H3_REQUEST_CANCELLED,
+        ))?;
+        self.respond(stream_id);
         Ok(())
     }
 
     fn discard_datagram(&mut self, _flow_id: u64) -> Result<()> {
+        let mut buffer = self.driver.buffer_pool.get();
         loop {
-            match self.h3_conn.recv_dgram(&mut self.driver.quiche_conn, self.driver.buffer.as_mut())
-            {
+            let h3_conn = match &mut self.framing {
+                Framing::H3(h3_conn) => h3_conn,
+                Framing::RawDns { .. } => return Ok(()),
+            };
+            match h3_conn.recv_dgram(&mut self.driver.quiche_conn, &mut buffer) {
                 Err(h3::Error::Done) => return Ok(()),
                 Err(e) => return Err(e.into()),
                 _ => (),
@@ -402,13 +1353,33 @@ impl H3Driver {
         }
     }
 
+    async fn flush_streams(&mut self) -> Result<()> {
+        match self.framing {
+            Framing::H3(_) => self.flush_h3().await,
+            Framing::RawDns { .. } => self.flush_raw_dns(),
+        }
+    }
+
     async fn flush_h3(&mut self) -> Result<()> {
         loop {
-            match self.h3_conn.poll(&mut self.driver.quiche_conn) {
-                Err(h3::Error::Done) => return Ok(()),
-                Err(e) => return Err(e.into()),
-                Ok((stream_id, event)) => self.process_h3_event(stream_id, event).await?,
-            }
+            let h3_conn = match &mut self.framing {
+                Framing::H3(h3_conn) => h3_conn,
+                Framing::RawDns { .. } => return Ok(()),
+            };
+            let (stream_id, event) = match h3_conn.poll(&mut self.driver.quiche_conn) {
+                // quiche has already closed the connection. Requests in flight fail with it
+                // rather than wait for headers decoded against state which may be corrupt, and
+                // the network connects afresh for the next query.
+                Err(h3::Error::QpackDecompressionFailed) => {
+                    warn!("QPACK decoding failed on network {}", self.driver.net_id);
+                    return Err(Error::Qpack);
+                }
+                result => match h3_step(result)? {
+                    Some(polled) => polled,
+                    None => return Ok(()),
+                },
+            };
+            self.process_h3_event(stream_id, event).await?;
         }
     }
 
@@ -422,10 +1393,19 @@ impl H3Driver {
                     "process_h3_event: h3::Event::Headers on stream ID {}, network {}",
                     stream_id, self.driver.net_id
                 );
-                let stream = Stream::new(list);
-                if self.streams.insert(stream_id, stream).is_some() {
-                    warn!("Re-using stream ID {} before it was completed.", stream_id)
+                if self.streams.contains_key(&stream_id) {
+                    // A HEADERS frame following the headers and body of a response already being
+                    // received is a trailer section. DoH has no use for trailers, but some proxies
+                    // send them, so they are dropped rather than mistaken for the start of another
+                    // response, which is delivered once the stream finishes.
+                    debug!("Ignoring trailers on stream ID {}", stream_id);
+                    return Ok(());
+                }
+                let mut stream = Stream::new(list);
+                if let Some(start) = self.started.get(&stream_id) {
+                    stream.stats.time_to_first_byte = Some(self.driver.clock.elapsed(start.at));
                 }
+                self.streams.insert(stream_id, stream);
                 if !has_body {
                     self.respond(stream_id);
                 }
@@ -460,6 +1440,103 @@ impl H3Driver {
         Ok(())
     }
 
+    // Stops taking requests but lets those already sent finish before the connection is closed.
+    fn retire(&mut self) -> Result<()> {
+        debug!(
+            "Retiring connection {} on network {} with {} requests in flight",
+            self.driver.quiche_conn.trace_id(),
+            self.driver.net_id,
+            self.requests.len()
+        );
+        self.driver.enter_closing();
+        self.retiring = true;
+        // Requests whose requestor has gone, such as the losing attempts of a hedged query, have
+        // nobody to answer, so they are cancelled rather than holding the connection open.
+        let abandoned: Vec<u64> = self
+            .requests
+            .iter()
+            .filter(|(_, request)| request.response_tx.is_closed())
+            .map(|(&stream_id, _)| stream_id)
+            .collect();
+        let cancel_code = self.cancel_code();
+        for stream_id in abandoned {
+            debug!("Cancelling abandoned request on stream ID {}", stream_id);
+            cancel_stream(&mut self.driver.quiche_conn, stream_id, cancel_code);
+            self.streams.entry(stream_id).or_insert_with(|| Stream::new(Vec::new()));
+            self.respond(stream_id);
+        }
+        self.drain_deadline = self
+            .driver
+            .options
+            .drain_timeout
+            .and_then(|timeout| self.driver.clock.now().checked_add(timeout));
+        self.send_goaway()
+    }
+
+    // Time until the first request in flight expires, or `None` if none of them has an expiry.
+    fn expiry_remaining(&self) -> Option<boot_time::Duration> {
+        let first = self.requests.values().filter_map(|request| request.expiry).min()?;
+        Some(first.checked_duration_since(self.driver.clock.now()).unwrap_or_default())
+    }
+
+    // Abandons the requests in flight whose expiry has passed, so that they don't hold on to
+    // their streams until the server answers or the connection goes. The server is asked to stop
+    // sending their responses, and the requestors are told they timed out.
+    fn expire_requests(&mut self) -> Result<()> {
+        self.driver.last_event = "expiry";
+        let clock = self.driver.clock.clone();
+        let expired: Vec<u64> = self
+            .requests
+            .iter()
+            .filter(|(_, request)| is_expired(clock.as_ref(), request.expiry))
+            .map(|(&stream_id, _)| stream_id)
+            .collect();
+        let cancel_code = self.cancel_code();
+        for stream_id in expired {
+            debug!("Request on stream ID {} expired on network {}", stream_id, self.driver.net_id);
+            // `Done` means the stream is already gone, which is as good as having stopped it.
+            quic_step(self.driver.quiche_conn.stream_shutdown(
+                stream_id,
+                quiche::Shutdown::Read,
+                cancel_code,
+            ))?;
+            let stream = self.streams.entry(stream_id).or_insert_with(|| Stream::new(Vec::new()));
+            stream.data.clear();
+            stream.expired = true;
+            self.respond(stream_id);
+        }
+        Ok(())
+    }
+
+    // Time left for a retired connection to receive the answers still in flight, or `None` if
+    // there is no deadline.
+    fn drain_remaining(&self) -> Option<boot_time::Duration> {
+        let deadline = self.drain_deadline.filter(|_| self.retiring)?;
+        Some(deadline.checked_duration_since(self.driver.clock.now()).unwrap_or_default())
+    }
+
+    // Whether a retired connection should now close, because it has nothing left in flight or
+    // its drain deadline has passed.
+    fn ready_to_close(&self) -> bool {
+        if !self.retiring {
+            return false;
+        }
+        if self.requests.is_empty() && self.buffered_request.is_none() {
+            return true;
+        }
+        let now = self.driver.clock.now();
+        if !matches!(self.drain_deadline, Some(deadline) if now >= deadline) {
+            return false;
+        }
+        warn!(
+            "Connection {} on network {} closing with {} requests unanswered",
+            self.driver.quiche_conn.trace_id(),
+            self.driver.net_id,
+            self.requests.len()
+        );
+        true
+    }
+
     async fn shutdown(&mut self, send_goaway: bool, msg: &[u8]) -> Result<()> {
         debug!(
             "Closing connection {} on network {} with msg {:?}",
@@ -469,9 +1546,9 @@ impl H3Driver {
         );
         self.driver.request_rx.close();
         while self.driver.request_rx.recv().await.is_some() {}
-        self.driver.closing = true;
+        self.driver.enter_closing();
         if send_goaway {
-            self.h3_conn.send_goaway(&mut self.driver.quiche_conn, 0)?;
+            self.send_goaway()?;
         }
         if self.driver.quiche_conn.close(true, 0, msg).is_err() {
             warn!("Trying to close already closed QUIC connection");
@@ -479,12 +1556,88 @@ impl H3Driver {
         Ok(())
     }
 
+    // Reads the raw DNS streams quiche has data for.
+    fn flush_raw_dns(&mut self) -> Result<()> {
+        let readable: Vec<u64> = self.driver.quiche_conn.readable().collect();
+        for stream_id in readable {
+            self.recv_raw_dns(stream_id)?;
+        }
+        Ok(())
+    }
+
+    // Buffers what has arrived on a raw DNS stream, and hands the answer over once the stream
+    // finishes. Nothing is streamed to the requestor before then, as only at the end does the
+    // length prefix tell whether the answer is whole.
+    fn recv_raw_dns(&mut self, stream_id: u64) -> Result<()> {
+        let max_response_size = match self.requests.get(&stream_id) {
+            Some(request) => request.max_response_size,
+            None => {
+                warn!("Received data for untracked stream ID {}", stream_id);
+                quic_step(self.driver.quiche_conn.stream_shutdown(
+                    stream_id,
+                    quiche::Shutdown::Read,
+                    DOQ_REQUEST_CANCELLED,
+                ))?;
+                return Ok(());
+            }
+        };
+        let time_to_first_byte =
+            self.started.get(&stream_id).map(|start| self.driver.clock.elapsed(start.at));
+        let stream = self.streams.entry(stream_id).or_insert_with(|| Stream {
+            stats: QueryStats { time_to_first_byte, ..Default::default() },
+            ..Stream::new(Vec::new())
+        });
+        let mut buffer = self.driver.buffer_pool.get();
+        let mut finished = false;
+        // The answer may be as large as the requestor accepts, after its two-byte prefix.
+        while !finished && stream.data.len() <= max_response_size + 2 {
+            match quic_step(self.driver.quiche_conn.stream_recv(stream_id, &mut buffer))? {
+                Some((read, fin)) => {
+                    stream.data.extend_from_slice(&buffer[..read]);
+                    finished = fin;
+                }
+                None => return Ok(()),
+            }
+        }
+        if stream.data.len() > max_response_size + 2 {
+            warn!(
+                "Response on stream ID {} exceeded {} bytes on network {}, abandoning it",
+                stream_id, max_response_size, self.driver.net_id
+            );
+            stream.data.clear();
+            stream.too_large = true;
+            quic_step(self.driver.quiche_conn.stream_shutdown(
+                stream_id,
+                quiche::Shutdown::Read,
+                DOQ_REQUEST_CANCELLED,
+            ))?;
+        } else {
+            let message_id = match &self.framing {
+                Framing::RawDns { message_ids, .. } => message_ids.get(&stream_id).copied(),
+                Framing::H3(_) => None,
+            };
+            stream.data = unframe_raw_dns(&stream.data, message_id);
+        }
+        self.respond(stream_id);
+        Ok(())
+    }
+
     fn respond(&mut self, stream_id: u64) {
-        match (self.streams.remove(&stream_id), self.requests.remove(&stream_id)) {
-            (Some(stream), Some(request)) => {
+        if let Framing::RawDns { message_ids, .. } = &mut self.framing {
+            message_ids.remove(&stream_id);
+        }
+        let start = self.started.remove(&stream_id);
+        let removed = (self.streams.remove(&stream_id), self.requests.remove(&stream_id));
+        self.driver.open_requests = self.requests.len();
+        match removed {
+            (Some(mut stream), Some(request)) => {
+                if let Some(start) = start {
+                    let stats = self.driver.quiche_conn.stats();
+                    start.finish(&stats, self.driver.wire_share, &mut stream.stats);
+                }
                 debug!(
-                    "Sending answer back to resolv, stream ID: {}, network {}",
-                    stream_id, self.driver.net_id
+                    "Sending answer back to resolv, stream ID: {}, network {}, stats={:?}",
+                    stream_id, self.driver.net_id, stream.stats
                 );
                 // We don't care about the error, because it means the requestor has left.
                 let _ = request.response_tx.send(stream);
@@ -494,3 +1647,1146 @@ impl H3Driver {
         }
     }
 }
+
+// Stops both directions of a stream we are giving up on. Either may already be gone.
+fn cancel_stream(quiche_conn: &mut quiche::Connection, stream_id: u64, error: u64) {
+    let _ = quiche_conn.stream_shutdown(stream_id, quiche::Shutdown::Write, error);
+    let _ = quiche_conn.stream_shutdown(stream_id, quiche::Shutdown::Read, error);
+}
+
+// Sends a query as raw DNS over QUIC: the whole of a new stream is the message, prefixed by its
+// length, with its ID zeroed as RFC 9250 requires. The ID the query was asked with is kept, to be
+// put back into the answer.
+fn send_raw_dns(
+    quiche_conn: &mut quiche::Connection,
+    next_stream_id: &mut u64,
+    message_ids: &mut HashMap<u64, [u8; 2]>,
+    request: &Request,
+) -> Result<Opened> {
+    // The network writes queries as DoH requests. A request written by hand carries the message
+    // as its body instead.
+    let message = if request.body.is_empty() {
+        encoding::query_of_request(&request.headers)
+    } else {
+        Some(request.body.clone())
+    };
+    let message =
+        match message.filter(|message| (2..=usize::from(u16::MAX)).contains(&message.len())) {
+            Some(message) => message,
+            None => {
+                warn!("Request carries no DNS message to send as raw DNS");
+                return Ok(Opened::Cancelled(DOQ_REQUEST_CANCELLED));
+            }
+        };
+    let mut framed = Vec::with_capacity(message.len() + 2);
+    framed.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    framed.extend_from_slice(&[0, 0]);
+    framed.extend_from_slice(&message[2..]);
+    let stream_id = *next_stream_id;
+    // `Done` means the stream has no room yet, and it is written from the start next time.
+    let written = match quiche_conn.stream_send(stream_id, &framed, true) {
+        Err(quiche::Error::StreamLimit) | Err(quiche::Error::Done) => return Ok(Opened::Blocked),
+        result => result?,
+    };
+    // Client-initiated bidirectional streams are every fourth ID.
+    *next_stream_id += 4;
+    if written < framed.len() {
+        warn!("Unable to send {}-byte query in one go", framed.len());
+        cancel_stream(quiche_conn, stream_id, DOQ_REQUEST_CANCELLED);
+        return Ok(Opened::Cancelled(DOQ_REQUEST_CANCELLED));
+    }
+    message_ids.insert(stream_id, [message[0], message[1]]);
+    Ok(Opened::Stream(stream_id))
+}
+
+// Takes the answer out of a raw DNS stream: the message after its length prefix, with the ID of
+// the query put back. A stream which doesn't hold exactly one message yields nothing, which the
+// requestor sees as a malformed response.
+fn unframe_raw_dns(data: &[u8], message_id: Option<[u8; 2]>) -> Vec<u8> {
+    match data {
+        [high, low, message @ ..]
+            if message.len() >= 2
+                && usize::from(u16::from_be_bytes([*high, *low])) == message.len() =>
+        {
+            let mut answer = message.to_vec();
+            if let Some(message_id) = message_id {
+                answer[..2].copy_from_slice(&message_id);
+            }
+            answer
+        }
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        connect_failure, deliver, h3_step, is_expired, looks_intercepted, negotiated_version,
+        quic_step, send_within_path_limit, unframe_raw_dns, watchdog_remaining, Driver, Error,
+        Handles, QueryStats, Request, RequestStart, Stream, StreamDriver, WireShare,
+        DEFAULT_MAX_RESPONSE_SIZE,
+    };
+    use crate::boot_time::{Clock, Duration, MockClock};
+    use crate::certificate::CertInfo;
+    use crate::config::{stream_window, test_key, Config, Key, StreamMode, MAX_DATAGRAM_SIZE};
+    use crate::connection::loopback::{
+        connection_pair, connection_pair_idle_after, connection_pair_with_key, datagrams, exchange,
+        raw_dns_connection_pair, CLIENT_ADDR, SERVER_ADDR,
+    };
+    use crate::connection::packet_tape::{Direction, PacketTape};
+    use crate::connection::{
+        Environment, Options, Peer, SocketBinding, Status, METERED_LOST_PACKET_BUDGET,
+    };
+    use crate::dispatcher::{ConnectFailure, DispatcherMetrics};
+    use crate::encoding;
+    use quiche::h3;
+    use std::ops::DerefMut;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use tokio::net::UdpSocket;
+    use tokio::sync::{mpsc, oneshot, watch};
+
+    #[test]
+    fn request_expiry() {
+        let clock = MockClock::new();
+        let expiry = clock.now().checked_add(Duration::from_secs(5));
+        assert!(!is_expired(clock.as_ref(), None));
+        assert!(!is_expired(clock.as_ref(), expiry));
+        clock.advance(Duration::from_secs(5));
+        assert!(!is_expired(clock.as_ref(), expiry));
+        clock.advance(Duration::from_millis(1));
+        assert!(is_expired(clock.as_ref(), expiry));
+    }
+
+    #[test]
+    fn watchdog_deadline() {
+        let clock = MockClock::new();
+        let last_progress = clock.now();
+        let timeout = Some(Duration::from_secs(30));
+        assert_eq!(watchdog_remaining(clock.as_ref(), None, last_progress), None);
+        assert_eq!(watchdog_remaining(clock.as_ref(), timeout, last_progress), timeout);
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(
+            watchdog_remaining(clock.as_ref(), timeout, last_progress),
+            Some(Duration::from_secs(10))
+        );
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(
+            watchdog_remaining(clock.as_ref(), timeout, last_progress),
+            Some(Duration::from_secs(0))
+        );
+    }
+
+    #[test]
+    fn query_stats() {
+        let clock = MockClock::new();
+        let mut conn_stats = quiche::Stats {
+            recv: 10,
+            sent: 10,
+            lost: 2,
+            rtt: Duration::from_millis(50),
+            cwnd: 12000,
+            delivery_rate: 0,
+        };
+        let submitted = clock.now();
+        clock.advance(Duration::from_millis(30));
+        let mut wire = WireShare::default();
+        wire.add(Direction::Outbound, 1200, 1);
+        let start = RequestStart::new(clock.as_ref(), &conn_stats, wire, submitted);
+        clock.advance(Duration::from_millis(80));
+        assert_eq!(clock.elapsed(start.at), Duration::from_millis(80));
+        conn_stats.lost = 5;
+        conn_stats.rtt = Duration::from_millis(60);
+        conn_stats.delivery_rate = 1_000_000;
+        // A packet each way for this request alone, then one each way shared with another.
+        wire.add(Direction::Outbound, 100, 1);
+        wire.add(Direction::Inbound, 300, 1);
+        wire.add(Direction::Outbound, 50, 2);
+        wire.add(Direction::Inbound, 900, 2);
+        // Nothing was open for this one.
+        wire.add(Direction::Inbound, 60, 0);
+        let mut stats = QueryStats::default();
+        start.finish(&conn_stats, wire, &mut stats);
+        assert_eq!(stats.queue_wait, Duration::from_millis(30));
+        assert_eq!(stats.packets_lost, 3);
+        assert_eq!(stats.rtt, Duration::from_millis(60));
+        assert_eq!(stats.delivery_rate, 1_000_000);
+        assert_eq!(stats.request_wire_bytes, 125);
+        assert_eq!(stats.response_wire_bytes, 750);
+    }
+
+    #[test]
+    fn retransmission_budget() {
+        assert_eq!(Options::default().lost_packet_budget(), None);
+        let metered = Options { metered: true, ..Default::default() };
+        assert_eq!(metered.lost_packet_budget(), Some(METERED_LOST_PACKET_BUDGET));
+        let capped = Options { max_lost_packets: Some(3), ..metered };
+        assert_eq!(capped.lost_packet_budget(), Some(3));
+        assert_eq!(
+            connect_failure(&Error::RetransmissionBudget(4), false, false),
+            ConnectFailure::Other
+        );
+    }
+
+    #[test]
+    fn done_is_not_an_error() {
+        // No more packets to send or process.
+        assert_eq!(quic_step::<usize>(Err(quiche::Error::Done)), Ok(None));
+        assert_eq!(quic_step(Ok(1200)), Ok(Some(1200)));
+        assert_eq!(
+            quic_step::<usize>(Err(quiche::Error::InvalidPacket)),
+            Err(quiche::Error::InvalidPacket)
+        );
+        // No more HTTP/3 events or body.
+        assert_eq!(h3_step::<u64>(Err(h3::Error::Done)), Ok(None));
+        assert_eq!(h3_step(Ok(4)), Ok(Some(4)));
+        assert_eq!(h3_step::<u64>(Err(h3::Error::FrameError)), Err(h3::Error::FrameError));
+        assert_eq!(
+            h3_step::<u64>(Err(h3::Error::TransportError(quiche::Error::StreamLimit))),
+            Err(h3::Error::TransportError(quiche::Error::StreamLimit))
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_send_lowers_limit() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let to = socket.local_addr().unwrap();
+        let mut max_send_size = MAX_DATAGRAM_SIZE;
+        let packet = [0; MAX_DATAGRAM_SIZE];
+        assert!(send_within_path_limit(&socket, &packet, to, &mut max_send_size).await.unwrap());
+        assert_eq!(max_send_size, MAX_DATAGRAM_SIZE);
+        // No UDP datagram over IPv4 is this large, so the socket refuses it as it would one over
+        // the path MTU.
+        let packet = vec![0; 65536];
+        assert!(!send_within_path_limit(&socket, &packet, to, &mut max_send_size).await.unwrap());
+        assert_eq!(max_send_size, quiche::MIN_CLIENT_INITIAL_LEN);
+        let packet = &packet[..max_send_size];
+        assert!(send_within_path_limit(&socket, packet, to, &mut max_send_size).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn full_send_buffer_waits_to_send() {
+        use std::io::Read;
+        use std::os::unix::io::{FromRawFd, IntoRawFd};
+        // Linux hands loopback datagrams straight to the receiver, so a UDP socket's send buffer
+        // never fills. A TCP connection stands in for it: `send_to` ignores the address on one,
+        // and its send buffer fills while the peer isn't reading.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let to = listener.local_addr().unwrap();
+        let stream = std::net::TcpStream::connect(to).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let fd = stream.into_raw_fd();
+        let socket = UdpSocket::from_std(unsafe { std::net::UdpSocket::from_raw_fd(fd) }).unwrap();
+
+        // Data moves on into the peer's receive buffer as it is acknowledged, so keep sending
+        // until the socket stays unwritable for a while.
+        let filler = [0; MAX_DATAGRAM_SIZE];
+        let mut queued = 0;
+        while tokio::time::timeout(Duration::from_millis(20), socket.writable()).await.is_ok() {
+            match socket.try_send_to(&filler, to) {
+                Ok(sent) => queued += sent,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => (),
+                Err(e) => panic!("Unexpected {:?}", e),
+            }
+        }
+
+        // The packet waits for room rather than failing or being dropped.
+        let packet = [0xab; MAX_DATAGRAM_SIZE];
+        let mut max_send_size = MAX_DATAGRAM_SIZE;
+        let send = send_within_path_limit(&socket, &packet, to, &mut max_send_size);
+        tokio::pin!(send);
+        let pending = tokio::time::timeout(Duration::from_millis(50), &mut send).await;
+        assert!(pending.is_err());
+
+        // Once the peer drains the buffer, the packet goes out after everything queued before it.
+        let reader = std::thread::spawn(move || {
+            let mut received = vec![0; queued + MAX_DATAGRAM_SIZE];
+            peer.read_exact(&mut received).unwrap();
+            received.split_off(queued)
+        });
+        assert!(send.await.unwrap());
+        assert_eq!(reader.join().unwrap(), packet);
+    }
+
+    #[test]
+    fn intercepted_handshakes() {
+        let tls = Error::Quic(quiche::Error::TlsFail);
+        let portal = CertInfo {
+            subject_cn: Some("portal.hotel.example".into()),
+            subject_alt_names: vec!["portal.hotel.example".into()],
+            not_after: None,
+        };
+        let server = CertInfo {
+            subject_cn: None,
+            subject_alt_names: vec!["*.dns.example".into()],
+            not_after: None,
+        };
+        // An interstitial's certificate names the portal, not the server asked for.
+        assert!(looks_intercepted(&tls, Some(&portal), Some("doh.dns.example")));
+        // The server's own certificate failing, say because it expired, is its failure.
+        assert!(!looks_intercepted(&tls, Some(&server), Some("doh.dns.example")));
+        // Without a certificate, or a name to hold it to, there is nothing to go on.
+        assert!(!looks_intercepted(&tls, None, Some("doh.dns.example")));
+        assert!(!looks_intercepted(&tls, Some(&portal), None));
+        // Nor does anything but a TLS failure look intercepted.
+        assert!(!looks_intercepted(&Error::Closed, Some(&portal), Some("doh.dns.example")));
+    }
+
+    #[test]
+    fn connect_failure_causes() {
+        let refused = || Error::Network(std::io::ErrorKind::ConnectionRefused.into());
+        assert_eq!(connect_failure(&refused(), false, false), ConnectFailure::Unreachable);
+        let version = Error::Quic(quiche::Error::UnknownVersion);
+        assert_eq!(connect_failure(&version, false, false), ConnectFailure::VersionNegotiation);
+        let tls = Error::Quic(quiche::Error::TlsFail);
+        assert_eq!(connect_failure(&tls, false, false), ConnectFailure::TlsVerify);
+        let mismatch =
+            Error::ServerNameMismatch { expected: "a.example".into(), presented: Vec::new() };
+        assert_eq!(connect_failure(&mismatch, false, false), ConnectFailure::TlsVerify);
+        assert_eq!(connect_failure(&Error::Closed, false, false), ConnectFailure::HandshakeTimeout);
+        assert_eq!(connect_failure(&Error::Closed, false, true), ConnectFailure::Unreachable);
+        assert_eq!(connect_failure(&Error::Closed, true, true), ConnectFailure::Other);
+        let unacceptable = Error::VersionNotAcceptable(0xff00_001d);
+        assert_eq!(
+            connect_failure(&unacceptable, false, false),
+            ConnectFailure::VersionNegotiation
+        );
+    }
+
+    fn version_negotiation(dcid: &[u8], scid: &[u8], versions: &[u32]) -> Vec<u8> {
+        let mut packet = vec![0x80, 0, 0, 0, 0, dcid.len() as u8];
+        packet.extend_from_slice(dcid);
+        packet.push(scid.len() as u8);
+        packet.extend_from_slice(scid);
+        versions.iter().for_each(|v| packet.extend_from_slice(&v.to_be_bytes()));
+        packet
+    }
+
+    #[tokio::test]
+    async fn version_negotiation_outcome() {
+        const DRAFT_27: u32 = 0xff00_001b;
+        const DRAFT_29: u32 = 0xff00_001d;
+        let key = Key { quic_versions: vec![DRAFT_29], ..test_key() };
+        let mut config = Config::from_key(&key).unwrap();
+        let scid = super::super::new_scid();
+        let conn = quiche::connect(
+            None,
+            &quiche::ConnectionId::from_ref(&scid),
+            "192.0.2.1:443".parse().unwrap(),
+            config.take().await.deref_mut(),
+        )
+        .unwrap();
+        let dcid = conn.destination_id().as_ref().to_vec();
+        let offer = |versions: &[u32]| version_negotiation(&scid, &dcid, versions);
+
+        assert_eq!(negotiated_version(&conn, &mut offer(&[DRAFT_27]), DRAFT_29), Some(DRAFT_29));
+        assert_eq!(
+            negotiated_version(&conn, &mut offer(&[DRAFT_27, quiche::PROTOCOL_VERSION]), DRAFT_29),
+            Some(quiche::PROTOCOL_VERSION)
+        );
+        // Offers quiche would ignore, or fail the connection over itself.
+        assert_eq!(negotiated_version(&conn, &mut offer(&[DRAFT_29]), DRAFT_29), None);
+        assert_eq!(negotiated_version(&conn, &mut offer(&[0x1234_5678]), DRAFT_29), None);
+        let mut misaddressed = version_negotiation(&dcid, &scid, &[quiche::PROTOCOL_VERSION]);
+        assert_eq!(negotiated_version(&conn, &mut misaddressed, DRAFT_29), None);
+        assert_eq!(negotiated_version(&conn, &mut [0x40, 1, 2, 3], DRAFT_29), None);
+    }
+
+    // Every packet `conn` has to send, with coalesced packets split apart.
+    fn flight(conn: &mut quiche::Connection) -> Vec<Vec<u8>> {
+        datagrams(conn).unwrap().iter().flat_map(|datagram| split_coalesced(datagram)).collect()
+    }
+
+    fn split_coalesced(mut datagram: &[u8]) -> Vec<Vec<u8>> {
+        fn varint(bytes: &[u8]) -> (usize, usize) {
+            let len = 1 << (bytes[0] >> 6);
+            let value = bytes[1..len]
+                .iter()
+                .fold(usize::from(bytes[0] & 0x3f), |v, &b| v << 8 | b as usize);
+            (value, len)
+        }
+        let mut packets = Vec::new();
+        while let Some(&first) = datagram.first() {
+            // A short header packet runs to the end of the datagram.
+            let len = if first & 0x80 == 0 {
+                datagram.len()
+            } else {
+                let mut pos = 6 + datagram[5] as usize;
+                pos += 1 + datagram[pos] as usize;
+                // Only Initial packets carry a token.
+                if first & 0x30 == 0 {
+                    let (token_len, n) = varint(&datagram[pos..]);
+                    pos += n + token_len;
+                }
+                let (payload_len, n) = varint(&datagram[pos..]);
+                pos + n + payload_len
+            };
+            packets.push(datagram[..len].to_vec());
+            datagram = &datagram[len..];
+        }
+        packets
+    }
+
+    #[tokio::test]
+    async fn handshake_packets_out_of_order() {
+        let client_addr = CLIENT_ADDR.parse().unwrap();
+        let server_addr = SERVER_ADDR.parse().unwrap();
+        let (mut client, mut server) = connection_pair().await.unwrap();
+
+        let mut reordered = 0;
+        for _ in 0..10 {
+            if client.is_established() && server.is_established() {
+                break;
+            }
+            for mut packet in flight(&mut client) {
+                deliver(&mut server, &mut packet, client_addr).unwrap();
+            }
+            // The server's packets arrive last first, then all over again. Those which come
+            // before the keys to decrypt them, and the duplicates, must not upset the client.
+            let mut packets = flight(&mut server);
+            packets.reverse();
+            reordered += packets.len().saturating_sub(1);
+            for mut packet in packets.clone().into_iter().chain(packets.into_iter().rev()) {
+                deliver(&mut client, &mut packet, server_addr).unwrap();
+            }
+        }
+        assert!(reordered > 0, "the server's packets were never reordered");
+        assert!(client.is_established());
+        assert!(server.is_established());
+    }
+
+    #[tokio::test]
+    async fn initial_datagrams_padded() {
+        let client_addr = CLIENT_ADDR.parse().unwrap();
+        let server_addr = SERVER_ADDR.parse().unwrap();
+        let (mut client, mut server) = connection_pair().await.unwrap();
+        let mut initials = 0;
+        while !client.is_established() {
+            for mut datagram in datagrams(&mut client).unwrap() {
+                // Long header of type Initial
+                if datagram[0] & 0xb0 == 0x80 {
+                    initials += 1;
+                    assert!(datagram.len() >= quiche::MIN_CLIENT_INITIAL_LEN, "{}", datagram.len());
+                }
+                deliver(&mut server, &mut datagram, client_addr).unwrap();
+            }
+            for mut datagram in datagrams(&mut server).unwrap() {
+                deliver(&mut client, &mut datagram, server_addr).unwrap();
+            }
+        }
+        assert!(initials > 0);
+    }
+
+    #[tokio::test]
+    async fn extra_headers_sent() {
+        let (mut client, mut server) = connection_pair().await.unwrap();
+        exchange(&mut client, &mut server).unwrap();
+        assert!(client.is_established());
+        let h3_config = h3::Config::new().unwrap();
+        let mut client_h3 = h3::Connection::with_transport(&mut client, &h3_config).unwrap();
+        let mut server_h3 = h3::Connection::with_transport(&mut server, &h3_config).unwrap();
+
+        let url = url::Url::parse("https://mylocal.com/dns-query").unwrap();
+        let mut request = encoding::dns_request(&encoding::probe_query().unwrap(), &url).unwrap();
+        let extra = [
+            h3::Header::new(b"authorization", b"Bearer abc"),
+            h3::Header::new(b"x-device-id", b"1234"),
+        ];
+        encoding::check_extra_headers(&extra).unwrap();
+        request.extend(extra.iter().cloned());
+        client_h3.send_request(&mut client, &request, true).unwrap();
+        exchange(&mut client, &mut server).unwrap();
+
+        let list = loop {
+            match server_h3.poll(&mut server) {
+                Ok((_, h3::Event::Headers { list, .. })) => break list,
+                Ok(_) => continue,
+                Err(e) => panic!("no request headers: {:?}", e),
+            }
+        };
+        assert_eq!(list, request);
+        assert!(list.ends_with(&extra));
+    }
+
+    // A driver for `client` starting out in `status`, whose `Connection` end is the test.
+    async fn test_driver(
+        client: Pin<Box<quiche::Connection>>,
+        status: Status,
+        options: Options,
+        clock: Arc<MockClock>,
+        metrics: Arc<DispatcherMetrics>,
+    ) -> Driver {
+        let handles = Handles {
+            request_rx: mpsc::channel(1).1,
+            status_tx: watch::channel(status).0,
+            packet_tape: Arc::new(PacketTape::new(false)),
+            activity: Default::default(),
+        };
+        let peer = Peer {
+            server_name: None,
+            addr: SERVER_ADDR.parse().unwrap(),
+            net_id: 1,
+            binding: &SocketBinding::Mark(0),
+        };
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let env = Environment::for_test(clock, metrics);
+        Driver::new(client, socket, peer, options, handles, env, MAX_DATAGRAM_SIZE)
+    }
+
+    // A `StreamDriver` for the client end of a loopback connection, and the server end.
+    async fn loopback_h3_driver(
+        options: Options,
+        clock: Arc<MockClock>,
+    ) -> (StreamDriver, Pin<Box<quiche::Connection>>, h3::Connection) {
+        h3_driver_over(connection_pair().await.unwrap(), options, clock).await
+    }
+
+    // As `loopback_h3_driver`, over a connection pair already made.
+    async fn h3_driver_over(
+        (mut client, mut server): (Pin<Box<quiche::Connection>>, Pin<Box<quiche::Connection>>),
+        options: Options,
+        clock: Arc<MockClock>,
+    ) -> (StreamDriver, Pin<Box<quiche::Connection>>, h3::Connection) {
+        exchange(&mut client, &mut server).unwrap();
+        let h3_config = h3::Config::new().unwrap();
+        let client_h3 = h3::Connection::with_transport(&mut client, &h3_config).unwrap();
+        let server_h3 = h3::Connection::with_transport(&mut server, &h3_config).unwrap();
+        let driver = test_driver(client, Status::H3, options, clock, Default::default()).await;
+        (StreamDriver::new(driver, client_h3), server, server_h3)
+    }
+
+    // Issues `count` probe requests, returning where their responses will arrive, and the stream
+    // IDs the server sees them on.
+    fn send_probes(
+        stream_driver: &mut StreamDriver,
+        server: &mut quiche::Connection,
+        server_h3: &mut h3::Connection,
+        count: usize,
+    ) -> (Vec<oneshot::Receiver<Stream>>, Vec<u64>) {
+        let url = url::Url::parse("https://mylocal.com/dns-query").unwrap();
+        let headers = encoding::dns_request(&encoding::probe_query().unwrap(), &url).unwrap();
+        let mut response_rxs = Vec::new();
+        for _ in 0..count {
+            let (response_tx, response_rx) = oneshot::channel();
+            stream_driver
+                .handle_request(Request {
+                    headers: headers.clone(),
+                    body: Vec::new(),
+                    submitted: stream_driver.driver.clock.now(),
+                    expiry: None,
+                    response_tx,
+                    max_response_size: DEFAULT_MAX_RESPONSE_SIZE,
+                })
+                .unwrap();
+            response_rxs.push(response_rx);
+        }
+        exchange(&mut stream_driver.driver.quiche_conn, server).unwrap();
+        let mut stream_ids = Vec::new();
+        while let Ok((stream_id, event)) = server_h3.poll(server) {
+            if let h3::Event::Headers { .. } = event {
+                stream_ids.push(stream_id);
+            }
+        }
+        stream_ids.sort_unstable();
+        assert_eq!(stream_ids.len(), count);
+        (response_rxs, stream_ids)
+    }
+
+    // Passes along whatever the server has sent and lets the driver process it.
+    async fn step(stream_driver: &mut StreamDriver, server: &mut quiche::Connection) {
+        exchange(&mut stream_driver.driver.quiche_conn, server).unwrap();
+        stream_driver.flush_h3().await.unwrap();
+    }
+
+    // The server answers two requests a piece of each at a time, finishing the second first.
+    #[tokio::test]
+    async fn interleaved_responses() {
+        let (mut stream_driver, mut server, mut server_h3) =
+            loopback_h3_driver(Options::default(), MockClock::new()).await;
+        let (mut response_rxs, stream_ids) =
+            send_probes(&mut stream_driver, &mut server, &mut server_h3, 2);
+
+        let bodies = [vec![0xaa; 3000], vec![0xbb; 2000]];
+        let response_headers = [h3::Header::new(b":status", b"200")];
+        for (&stream_id, body) in stream_ids.iter().zip(&bodies) {
+            server_h3.send_response(&mut server, stream_id, &response_headers, false).unwrap();
+            server_h3.send_body(&mut server, stream_id, &body[..1000], false).unwrap();
+        }
+        step(&mut stream_driver, &mut server).await;
+        assert!(response_rxs.iter_mut().all(|rx| rx.try_recv().is_err()));
+        assert_eq!(stream_driver.streams[&stream_ids[0]].data, bodies[0][..1000]);
+        assert_eq!(stream_driver.streams[&stream_ids[1]].data, bodies[1][..1000]);
+
+        server_h3.send_body(&mut server, stream_ids[0], &bodies[0][1000..2000], false).unwrap();
+        server_h3.send_body(&mut server, stream_ids[1], &bodies[1][1000..], true).unwrap();
+        step(&mut stream_driver, &mut server).await;
+        let second = response_rxs[1].try_recv().unwrap();
+        assert_eq!(second.data, bodies[1]);
+        assert!(response_rxs[0].try_recv().is_err());
+
+        server_h3.send_body(&mut server, stream_ids[0], &bodies[0][2000..], true).unwrap();
+        step(&mut stream_driver, &mut server).await;
+        let first = response_rxs[0].try_recv().unwrap();
+        assert_eq!(first.data, bodies[0]);
+        assert!(stream_driver.requests.is_empty() && stream_driver.streams.is_empty());
+    }
+
+    // A proxy sends a trailer section after the body. It is dropped, and the response is delivered
+    // whole when the stream finishes.
+    #[tokio::test]
+    async fn trailers_after_body() {
+        let (mut stream_driver, mut server, mut server_h3) =
+            loopback_h3_driver(Options::default(), MockClock::new()).await;
+        let (mut response_rxs, stream_ids) =
+            send_probes(&mut stream_driver, &mut server, &mut server_h3, 1);
+
+        let response_headers = [h3::Header::new(b":status", b"200")];
+        server_h3.send_response(&mut server, stream_ids[0], &response_headers, false).unwrap();
+        server_h3.send_body(&mut server, stream_ids[0], &[0xaa; 100], false).unwrap();
+        step(&mut stream_driver, &mut server).await;
+        assert!(response_rxs[0].try_recv().is_err());
+
+        let trailers = [h3::Header::new(b"x-trailer", b"1")];
+        server_h3.send_response(&mut server, stream_ids[0], &trailers, true).unwrap();
+        step(&mut stream_driver, &mut server).await;
+        let response = response_rxs[0].try_recv().unwrap();
+        assert_eq!(response.headers, response_headers);
+        assert_eq!(response.data, [0xaa; 100]);
+        assert!(stream_driver.requests.is_empty() && stream_driver.streams.is_empty());
+    }
+
+    // With nothing more to send, no more HTTP/3 events, and no more of a body yet, quiche says it
+    // is done for now. That is flow control, so neither the request waiting for the rest of its
+    // body nor the connection fails.
+    #[tokio::test]
+    async fn done_is_flow_control() {
+        let (mut stream_driver, mut server, mut server_h3) =
+            loopback_h3_driver(Options::default(), MockClock::new()).await;
+        // Once what the client has to send has gone, there is nothing more.
+        exchange(&mut stream_driver.driver.quiche_conn, &mut server).unwrap();
+        stream_driver.driver.flush_tx().await.unwrap();
+        stream_driver.flush_h3().await.unwrap();
+        let (mut response_rxs, stream_ids) =
+            send_probes(&mut stream_driver, &mut server, &mut server_h3, 1);
+
+        let response_headers = [h3::Header::new(b":status", b"200")];
+        server_h3.send_response(&mut server, stream_ids[0], &response_headers, false).unwrap();
+        server_h3.send_body(&mut server, stream_ids[0], &[0xaa; 100], false).unwrap();
+        step(&mut stream_driver, &mut server).await;
+        step(&mut stream_driver, &mut server).await;
+        exchange(&mut stream_driver.driver.quiche_conn, &mut server).unwrap();
+        stream_driver.driver.flush_tx().await.unwrap();
+        assert!(response_rxs[0].try_recv().is_err());
+        assert_eq!(stream_driver.streams[&stream_ids[0]].data, [0xaa; 100]);
+        assert!(!stream_driver.driver.quiche_conn.is_closed());
+
+        server_h3.send_body(&mut server, stream_ids[0], &[0xbb; 100], true).unwrap();
+        step(&mut stream_driver, &mut server).await;
+        let response = response_rxs[0].try_recv().unwrap();
+        assert_eq!(response.data[..100], [0xaa; 100]);
+        assert_eq!(response.data[100..], [0xbb; 100]);
+    }
+
+    // A server which takes its time answering leaves the connection quiet, with a request in
+    // flight, for far longer than any watchdog would allow. Unless one is asked for, the
+    // connection is left to wait for the answer.
+    #[tokio::test]
+    async fn slow_server_outlasts_default_watchdog() {
+        let clock = MockClock::new();
+        let (mut stream_driver, mut server, mut server_h3) =
+            loopback_h3_driver(Options::default(), clock.clone()).await;
+        let (mut response_rxs, stream_ids) =
+            send_probes(&mut stream_driver, &mut server, &mut server_h3, 1);
+        clock.advance(Duration::from_secs(300));
+        assert_eq!(stream_driver.watchdog_remaining(), None);
+        stream_driver.expire_requests().unwrap();
+
+        let response_headers = [h3::Header::new(b":status", b"200")];
+        server_h3.send_response(&mut server, stream_ids[0], &response_headers, false).unwrap();
+        server_h3.send_body(&mut server, stream_ids[0], &[0xaa; 100], true).unwrap();
+        step(&mut stream_driver, &mut server).await;
+        assert_eq!(response_rxs[0].try_recv().unwrap().data, [0xaa; 100]);
+        assert!(!stream_driver.driver.quiche_conn.is_closed());
+    }
+
+    #[tokio::test]
+    async fn retired_connection_drains() {
+        let clock = MockClock::new();
+        let options = Options { drain_timeout: Some(Duration::from_secs(1)), ..Default::default() };
+        let (mut stream_driver, mut server, mut server_h3) =
+            loopback_h3_driver(options, clock.clone()).await;
+        let (mut response_rxs, stream_ids) =
+            send_probes(&mut stream_driver, &mut server, &mut server_h3, 2);
+        stream_driver.retire().unwrap();
+        assert!(stream_driver.driver.closing);
+
+        // Answers to requests already in flight are still received.
+        let response_headers = [h3::Header::new(b":status", b"200")];
+        server_h3.send_response(&mut server, stream_ids[0], &response_headers, false).unwrap();
+        server_h3.send_body(&mut server, stream_ids[0], &[0xaa; 100], true).unwrap();
+        step(&mut stream_driver, &mut server).await;
+        assert_eq!(response_rxs[0].try_recv().unwrap().data, [0xaa; 100]);
+        assert!(!stream_driver.ready_to_close());
+
+        // Once the deadline passes, the connection closes without the other answer.
+        clock.advance(Duration::from_millis(999));
+        assert_eq!(stream_driver.drain_remaining(), Some(Duration::from_millis(1)));
+        assert!(!stream_driver.ready_to_close());
+        clock.advance(Duration::from_millis(1));
+        assert!(stream_driver.ready_to_close());
+        stream_driver.shutdown(false, b"DONE").await.unwrap();
+        exchange(&mut stream_driver.driver.quiche_conn, &mut server).unwrap();
+        assert!(server.peer_error().is_some());
+        drop(stream_driver);
+        assert!(response_rxs[1].try_recv().is_err());
+    }
+
+    // Runs quiche's timers, in real time as quiche keeps its own, until the client gives up on
+    // the server.
+    async fn wait_for_idle_close(stream_driver: &mut StreamDriver) {
+        let conn = &mut stream_driver.driver.quiche_conn;
+        while !conn.is_closed() {
+            tokio::time::sleep(conn.timeout().unwrap()).await;
+            conn.on_timeout();
+        }
+    }
+
+    #[tokio::test]
+    async fn idle_close_with_requests_in_flight() {
+        let clock = MockClock::new();
+        let pair = connection_pair_idle_after(100).await.unwrap();
+        let (mut stream_driver, mut server, mut server_h3) =
+            h3_driver_over(pair, Default::default(), clock.clone()).await;
+        let (mut response_rxs, _) = send_probes(&mut stream_driver, &mut server, &mut server_h3, 1);
+        wait_for_idle_close(&mut stream_driver).await;
+        assert!(matches!(stream_driver.handle_closed(), Err(Error::IdleWithRequests(1))));
+        drop(stream_driver);
+        assert!(response_rxs[0].try_recv().is_err());
+
+        // With nothing in flight, the close is unremarkable.
+        let pair = connection_pair_idle_after(100).await.unwrap();
+        let (mut stream_driver, ..) = h3_driver_over(pair, Default::default(), clock).await;
+        wait_for_idle_close(&mut stream_driver).await;
+        assert!(matches!(stream_driver.handle_closed(), Err(Error::Closed)));
+    }
+
+    #[tokio::test]
+    async fn request_abandoned_at_expiry() {
+        use crate::connection::stream_response;
+        use crate::dispatcher::{QueryError, Response};
+        let clock = MockClock::new();
+        let (mut stream_driver, mut server, mut server_h3) =
+            loopback_h3_driver(Default::default(), clock.clone()).await;
+        let url = url::Url::parse("https://mylocal.com/dns-query").unwrap();
+        let (response_tx, mut response_rx) = oneshot::channel();
+        stream_driver
+            .handle_request(Request {
+                headers: encoding::dns_request(&encoding::probe_query().unwrap(), &url).unwrap(),
+                body: Vec::new(),
+                submitted: clock.now(),
+                expiry: clock.now().checked_add(Duration::from_secs(2)),
+                response_tx,
+                max_response_size: DEFAULT_MAX_RESPONSE_SIZE,
+            })
+            .unwrap();
+        exchange(&mut stream_driver.driver.quiche_conn, &mut server).unwrap();
+        let (stream_id, _) = server_h3.poll(&mut server).unwrap();
+
+        // The server never answers, so nothing but the expiry wakes the driver before the idle
+        // timeout, five seconds away.
+        assert_eq!(stream_driver.expiry_remaining(), Some(Duration::from_secs(2)));
+        clock.advance(Duration::from_secs(2));
+        stream_driver.expire_requests().unwrap();
+        assert!(response_rx.try_recv().is_err());
+        clock.advance(Duration::from_millis(1));
+        stream_driver.expire_requests().unwrap();
+        let stream = response_rx.try_recv().unwrap();
+        assert_eq!(stream_response(Some(stream)), Response::Error { error: QueryError::Timeout });
+        assert!(stream_driver.requests.is_empty());
+        assert_eq!(stream_driver.expiry_remaining(), None);
+        assert!(!stream_driver.driver.quiche_conn.is_closed());
+
+        // The server is told to stop sending the response, and gives up the stream.
+        assert!(server.stream_capacity(stream_id).is_ok());
+        exchange(&mut stream_driver.driver.quiche_conn, &mut server).unwrap();
+        assert!(server.stream_capacity(stream_id).is_err());
+    }
+
+    #[tokio::test]
+    async fn request_with_body() {
+        let clock = MockClock::new();
+        let (mut stream_driver, mut server, mut server_h3) =
+            loopback_h3_driver(Default::default(), clock.clone()).await;
+        let headers = vec![
+            h3::Header::new(b":method", b"POST"),
+            h3::Header::new(b":scheme", b"https"),
+            h3::Header::new(b":authority", b"mylocal.com"),
+            h3::Header::new(b":path", b"/dns-query"),
+        ];
+        let (response_tx, _response_rx) = oneshot::channel();
+        stream_driver
+            .handle_request(Request {
+                headers: headers.clone(),
+                body: vec![1, 2, 3],
+                submitted: clock.now(),
+                expiry: None,
+                response_tx,
+                max_response_size: DEFAULT_MAX_RESPONSE_SIZE,
+            })
+            .unwrap();
+        exchange(&mut stream_driver.driver.quiche_conn, &mut server).unwrap();
+
+        // The server gets the headers as given, then the body, then the end of the stream.
+        let (stream_id, event) = server_h3.poll(&mut server).unwrap();
+        match event {
+            h3::Event::Headers { list, has_body } => {
+                assert_eq!(list, headers);
+                assert!(has_body);
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+        assert!(
+            matches!(server_h3.poll(&mut server), Ok((id, h3::Event::Data)) if id == stream_id)
+        );
+        let mut body = [0; 16];
+        assert_eq!(server_h3.recv_body(&mut server, stream_id, &mut body), Ok(3));
+        assert_eq!(&body[..3], &[1, 2, 3]);
+        assert!(matches!(server_h3.poll(&mut server), Ok((_, h3::Event::Finished))));
+    }
+
+    // Has the server send `body` on `stream_id` for as long as flow control lets it, without the
+    // client reading any of it, returning how much got through.
+    fn push_unread(
+        stream_driver: &mut StreamDriver,
+        server: &mut quiche::Connection,
+        server_h3: &mut h3::Connection,
+        stream_id: u64,
+        body: &[u8],
+    ) -> usize {
+        let mut pushed = 0;
+        let mut idle_rounds = 0;
+        // A round can also come up empty while the congestion window fills, so only a second
+        // one in a row means the stream window is used up.
+        while idle_rounds < 2 {
+            match server_h3.send_body(server, stream_id, &body[pushed..], false) {
+                Ok(written) if written > 0 => {
+                    pushed += written;
+                    idle_rounds = 0;
+                }
+                Ok(_) | Err(h3::Error::Done) => idle_rounds += 1,
+                Err(e) => panic!("Unable to send body: {:?}", e),
+            }
+            exchange(&mut stream_driver.driver.quiche_conn, server).unwrap();
+        }
+        pushed
+    }
+
+    // The window request streams start with takes a response of the default size cap, but
+    // not much more.
+    #[tokio::test]
+    async fn request_stream_window() {
+        let (mut stream_driver, mut server, mut server_h3) =
+            loopback_h3_driver(Default::default(), MockClock::new()).await;
+        let (_response_rxs, stream_ids) =
+            send_probes(&mut stream_driver, &mut server, &mut server_h3, 1);
+        let response_headers = [h3::Header::new(b":status", b"200")];
+        server_h3.send_response(&mut server, stream_ids[0], &response_headers, false).unwrap();
+        let window = stream_window(DEFAULT_MAX_RESPONSE_SIZE) as usize;
+        let body = vec![0xaa; 2 * window];
+        let pushed =
+            push_unread(&mut stream_driver, &mut server, &mut server_h3, stream_ids[0], &body);
+        assert!(pushed > DEFAULT_MAX_RESPONSE_SIZE);
+        assert!(pushed < window);
+    }
+
+    // A response just over the stream window fails the request as too large, and stops the
+    // server sending it without holding up the rest of the connection.
+    #[tokio::test]
+    async fn response_over_the_window() {
+        use crate::connection::stream_response;
+        use crate::dispatcher::{QueryError, Response};
+        const MAX_RESPONSE_SIZE: usize = 4096;
+        let key = Key { max_response_size: Some(MAX_RESPONSE_SIZE), ..test_key() };
+        let pair = connection_pair_with_key(&key).await.unwrap();
+        let clock = MockClock::new();
+        let (mut stream_driver, mut server, mut server_h3) =
+            h3_driver_over(pair, Default::default(), clock.clone()).await;
+        let url = url::Url::parse("https://mylocal.com/dns-query").unwrap();
+        let (response_tx, mut response_rx) = oneshot::channel();
+        stream_driver
+            .handle_request(Request {
+                headers: encoding::dns_request(&encoding::probe_query().unwrap(), &url).unwrap(),
+                body: Vec::new(),
+                submitted: clock.now(),
+                expiry: None,
+                response_tx,
+                max_response_size: MAX_RESPONSE_SIZE,
+            })
+            .unwrap();
+        exchange(&mut stream_driver.driver.quiche_conn, &mut server).unwrap();
+        let (stream_id, _) = server_h3.poll(&mut server).unwrap();
+
+        let response_headers = [h3::Header::new(b":status", b"200")];
+        server_h3.send_response(&mut server, stream_id, &response_headers, false).unwrap();
+        let body = vec![0xaa; stream_window(MAX_RESPONSE_SIZE) as usize + 1];
+        push_unread(&mut stream_driver, &mut server, &mut server_h3, stream_id, &body);
+        stream_driver.flush_h3().await.unwrap();
+        let stream = response_rx.try_recv().unwrap();
+        assert_eq!(
+            stream_response(Some(stream)),
+            Response::Error { error: QueryError::ResponseTooLarge }
+        );
+        assert!(stream_driver.requests.is_empty() && stream_driver.streams.is_empty());
+        exchange(&mut stream_driver.driver.quiche_conn, &mut server).unwrap();
+        assert!(server.stream_capacity(stream_id).is_err());
+
+        // The next request is answered as usual.
+        let (mut response_rxs, stream_ids) =
+            send_probes(&mut stream_driver, &mut server, &mut server_h3, 1);
+        server_h3.send_response(&mut server, stream_ids[0], &response_headers, false).unwrap();
+        server_h3.send_body(&mut server, stream_ids[0], &[0xbb; 100], true).unwrap();
+        step(&mut stream_driver, &mut server).await;
+        assert_eq!(response_rxs[0].try_recv().unwrap().data, [0xbb; 100]);
+    }
+
+    #[tokio::test]
+    async fn qpack_error_closes_connection() {
+        let (mut stream_driver, mut server, mut server_h3) =
+            loopback_h3_driver(Default::default(), MockClock::new()).await;
+        let (mut response_rxs, stream_ids) =
+            send_probes(&mut stream_driver, &mut server, &mut server_h3, 1);
+        // A HEADERS frame whose field line refers to the dynamic table, which doesn't exist.
+        const HEADERS: u8 = 0x01;
+        let frame = [HEADERS, 3, 0, 0, 0x80];
+        server.stream_send(stream_ids[0], &frame, true).unwrap();
+        exchange(&mut stream_driver.driver.quiche_conn, &mut server).unwrap();
+        assert!(matches!(stream_driver.flush_h3().await, Err(Error::Qpack)));
+        assert!(response_rxs[0].try_recv().is_err());
+
+        // The server is told why.
+        exchange(&mut stream_driver.driver.quiche_conn, &mut server).unwrap();
+        const QPACK_DECOMPRESSION_FAILED: u64 = 0x200;
+        assert_eq!(server.peer_error().unwrap().error_code, QPACK_DECOMPRESSION_FAILED);
+    }
+
+    #[tokio::test]
+    async fn recv_in_batches() {
+        let options = Options { recv_batch: 2, ..Default::default() };
+        let (mut stream_driver, mut server, mut server_h3) =
+            loopback_h3_driver(options, MockClock::new()).await;
+        let (_response_rxs, stream_ids) =
+            send_probes(&mut stream_driver, &mut server, &mut server_h3, 3);
+        let response_headers = [h3::Header::new(b":status", b"200")];
+        for &stream_id in &stream_ids {
+            server_h3.send_response(&mut server, stream_id, &response_headers, false).unwrap();
+            server_h3.send_body(&mut server, stream_id, &[0xaa; 1000], true).unwrap();
+        }
+        let to_client = datagrams(&mut server).unwrap();
+        assert!(to_client.len() >= 3);
+        let sender = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client_addr = stream_driver.driver.socket.local_addr().unwrap();
+        for datagram in &to_client {
+            sender.send_to(datagram, client_addr).unwrap();
+        }
+        stream_driver.driver.socket.readable().await.unwrap();
+
+        // A full batch leaves the driver to yield before reading on.
+        let received = stream_driver.driver.quiche_conn.stats().recv;
+        stream_driver.driver.recv().unwrap();
+        assert_eq!(stream_driver.driver.quiche_conn.stats().recv, received + 2);
+        assert!(stream_driver.driver.recv_backlog);
+        stream_driver.driver.yield_if_backlogged().await;
+        assert!(!stream_driver.driver.recv_backlog);
+        while stream_driver.driver.quiche_conn.stats().recv < received + to_client.len() {
+            stream_driver.driver.socket.readable().await.unwrap();
+            stream_driver.driver.recv().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn reap_stuck_handshake() {
+        let clock = MockClock::new();
+        let metrics = Arc::new(DispatcherMetrics::default());
+        // The server never answers, so the client stays handshaking.
+        let (client, _server) = connection_pair().await.unwrap();
+        let options =
+            Options { zombie_timeout: Some(Duration::from_secs(10)), ..Default::default() };
+        let driver =
+            test_driver(client, Status::QUIC, options, clock.clone(), metrics.clone()).await;
+        assert_eq!(driver.zombie_remaining(), Some(Duration::from_secs(10)));
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(driver.zombie_remaining(), Some(Duration::from_secs(0)));
+        match driver.drive_once().await {
+            Err(Error::Zombie { phase: "handshaking", stuck }) => {
+                assert_eq!(stuck, Duration::from_secs(10))
+            }
+            Err(e) => panic!("Unexpected error: {:?}", e),
+            Ok(_) => panic!("Stuck handshake wasn't reaped"),
+        }
+        assert_eq!(metrics.zombies_reaped(), 1);
+        assert_eq!(metrics.connection_failures(ConnectFailure::HandshakeTimeout), 1);
+    }
+
+    #[tokio::test]
+    async fn handshake_timeout() {
+        let clock = MockClock::new();
+        let metrics = Arc::new(DispatcherMetrics::default());
+        // The server never answers, so the client stays handshaking.
+        let (client, _server) = connection_pair().await.unwrap();
+        let options =
+            Options { handshake_timeout: Some(Duration::from_secs(5)), ..Default::default() };
+        let driver =
+            test_driver(client, Status::QUIC, options, clock.clone(), metrics.clone()).await;
+        assert_eq!(driver.handshake_remaining(), Some(Duration::from_secs(5)));
+        clock.advance(Duration::from_secs(5));
+        match driver.drive_once().await {
+            Err(Error::HandshakeTimeout(timeout)) => assert_eq!(timeout, Duration::from_secs(5)),
+            Err(e) => panic!("Unexpected error: {:?}", e),
+            Ok(_) => panic!("Handshake outlived its timeout"),
+        }
+        assert_eq!(metrics.zombies_reaped(), 0);
+        assert_eq!(metrics.connection_failures(ConnectFailure::HandshakeTimeout), 1);
+    }
+
+    #[tokio::test]
+    async fn early_handshake_wakeups_ignored() {
+        let clock = MockClock::new();
+        let metrics = Arc::new(DispatcherMetrics::default());
+        let (client, _server) = connection_pair().await.unwrap();
+        let options = Options {
+            handshake_timeout: Some(Duration::from_secs(5)),
+            zombie_timeout: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        let mut driver =
+            test_driver(client, Status::QUIC, options, clock.clone(), metrics.clone()).await;
+        // Both timers wake before either deadline, and the handshake carries on.
+        clock.advance(Duration::from_secs(1));
+        driver.handshake_timer_fired().unwrap();
+        driver.zombie_timer_fired("handshaking").unwrap();
+        assert_eq!(driver.handshake_remaining(), Some(Duration::from_secs(4)));
+        assert_eq!(driver.zombie_remaining(), Some(Duration::from_secs(9)));
+        assert!(!driver.quiche_conn.is_closed());
+        assert_eq!(metrics.zombies_reaped(), 0);
+        assert_eq!(metrics.connection_failures(ConnectFailure::HandshakeTimeout), 0);
+
+        clock.advance(Duration::from_secs(4));
+        match driver.handshake_timer_fired() {
+            Err(Error::HandshakeTimeout(timeout)) => assert_eq!(timeout, Duration::from_secs(5)),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn early_watchdog_wakeup_ignored() {
+        let clock = MockClock::new();
+        let options =
+            Options { watchdog_timeout: Some(Duration::from_secs(5)), ..Default::default() };
+        let (mut stream_driver, mut server, _server_h3) =
+            loopback_h3_driver(options, clock.clone()).await;
+        let url = url::Url::parse("https://mylocal.com/dns-query").unwrap();
+        let (response_tx, mut response_rx) = oneshot::channel();
+        stream_driver
+            .handle_request(Request {
+                headers: encoding::dns_request(&encoding::probe_query().unwrap(), &url).unwrap(),
+                body: Vec::new(),
+                submitted: clock.now(),
+                expiry: clock.now().checked_add(Duration::from_secs(10)),
+                response_tx,
+                max_response_size: DEFAULT_MAX_RESPONSE_SIZE,
+            })
+            .unwrap();
+        exchange(&mut stream_driver.driver.quiche_conn, &mut server).unwrap();
+
+        // The watchdog and the expiry both wake before their deadlines; the query is still
+        // waiting on its answer.
+        clock.advance(Duration::from_secs(1));
+        stream_driver.watchdog_timer_fired().await.unwrap();
+        stream_driver.expire_requests().unwrap();
+        assert_eq!(stream_driver.requests.len(), 1);
+        assert!(matches!(response_rx.try_recv(), Err(oneshot::error::TryRecvError::Empty)));
+        assert!(!stream_driver.driver.quiche_conn.is_closed());
+        assert_eq!(stream_driver.watchdog_remaining(), Some(Duration::from_secs(4)));
+
+        clock.advance(Duration::from_secs(4));
+        match stream_driver.watchdog_timer_fired().await {
+            Err(Error::Stalled(stalled)) => assert_eq!(stalled, Duration::from_secs(5)),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn raw_dns_request() {
+        let clock = MockClock::new();
+        let (mut client, mut server) = raw_dns_connection_pair().await.unwrap();
+        exchange(&mut client, &mut server).unwrap();
+        assert_eq!(client.application_proto(), b"doq");
+        let driver = test_driver(
+            client,
+            Status::H3,
+            Options { stream_mode: StreamMode::RawDns, ..Default::default() },
+            clock.clone(),
+            Default::default(),
+        )
+        .await;
+        let mut stream_driver = StreamDriver::raw_dns(driver);
+
+        // Queries reach the connection as DoH requests, as the network writes them.
+        let mut query =
+            base64::decode_config(encoding::probe_query().unwrap(), base64::URL_SAFE_NO_PAD)
+                .unwrap();
+        query[..2].copy_from_slice(&[0x12, 0x34]);
+        let base64_query = base64::encode_config(&query, base64::URL_SAFE_NO_PAD);
+        let url = url::Url::parse("https://mylocal.com/dns-query").unwrap();
+        let (response_tx, mut response_rx) = oneshot::channel();
+        stream_driver
+            .handle_request(Request {
+                headers: encoding::dns_request(&base64_query, &url).unwrap(),
+                body: Vec::new(),
+                submitted: clock.now(),
+                expiry: None,
+                response_tx,
+                max_response_size: DEFAULT_MAX_RESPONSE_SIZE,
+            })
+            .unwrap();
+        exchange(&mut stream_driver.driver.quiche_conn, &mut server).unwrap();
+
+        // The server sees just the message, with its ID zeroed and its length in front.
+        let mut sent = Vec::new();
+        let mut buffer = [0; 512];
+        let (read, fin) = server.stream_recv(0, &mut buffer).unwrap();
+        sent.extend_from_slice(&buffer[..read]);
+        assert!(fin);
+        assert_eq!(sent[..2], (query.len() as u16).to_be_bytes());
+        assert_eq!(sent[2..4], [0, 0]);
+        assert_eq!(sent[4..], query[2..]);
+
+        // The answer comes back with the ID the query was asked with.
+        let mut answer = sent.clone();
+        answer[4] |= 0x80;
+        server.stream_send(0, &answer, true).unwrap();
+        exchange(&mut stream_driver.driver.quiche_conn, &mut server).unwrap();
+        stream_driver.flush_streams().await.unwrap();
+        let stream = response_rx.try_recv().unwrap();
+        assert_eq!(stream.data[..2], [0x12, 0x34]);
+        assert_eq!(stream.data[2..], answer[4..]);
+        assert!(stream.headers.is_empty());
+        assert!(stream_driver.requests.is_empty());
+    }
+
+    #[test]
+    fn raw_dns_answers_unframed() {
+        let message = [0, 0, 0x81, 0x80, 0, 0];
+        let mut framed = vec![0, 6];
+        framed.extend_from_slice(&message);
+        assert_eq!(unframe_raw_dns(&framed, Some([0xab, 0xcd])), [0xab, 0xcd, 0x81, 0x80, 0, 0]);
+        assert_eq!(unframe_raw_dns(&framed, None), message);
+        // A prefix that doesn't match the rest of the stream spoils the answer.
+        assert!(unframe_raw_dns(&framed[..7], None).is_empty());
+        framed.push(0);
+        assert!(unframe_raw_dns(&framed, None).is_empty());
+        assert!(unframe_raw_dns(&[0, 1, 0], None).is_empty());
+        assert!(unframe_raw_dns(&[], None).is_empty());
+    }
+}
@@ -0,0 +1,110 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Limit on the handshakes running at once across a dispatcher
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Slots for in-progress handshakes, shared by every connection of a dispatcher.
+///
+/// Bringing up many connections at once, such as when several networks connect together, can
+/// otherwise spend a burst of CPU on the handshakes' crypto. Connections beyond the limit wait
+/// for a slot before sending their first packet. Without a limit, slots are only counted.
+#[derive(Clone, Debug, Default)]
+pub struct HandshakeLimiter {
+    semaphore: Option<Arc<Semaphore>>,
+    in_progress: Arc<AtomicUsize>,
+}
+
+impl HandshakeLimiter {
+    /// Creates a limiter letting up to `limit` handshakes run at once, or any number if `None`.
+    /// A limit of 0 is treated as 1, so that connections can still be established.
+    pub fn new(limit: Option<usize>) -> Self {
+        Self {
+            semaphore: limit.map(|limit| Arc::new(Semaphore::new(limit.max(1)))),
+            in_progress: Default::default(),
+        }
+    }
+
+    /// Waits for a free slot. The slot is held until the returned `HandshakeSlot` is dropped.
+    pub async fn acquire(&self) -> HandshakeSlot {
+        let permit = match &self.semaphore {
+            // The semaphore is never closed, so acquiring only fails if that changes.
+            Some(semaphore) => semaphore.clone().acquire_owned().await.ok(),
+            None => None,
+        };
+        self.in_progress.fetch_add(1, Ordering::Relaxed);
+        HandshakeSlot { _permit: permit, in_progress: self.in_progress.clone() }
+    }
+
+    /// Number of slots currently held.
+    pub fn in_progress(&self) -> usize {
+        self.in_progress.load(Ordering::Relaxed)
+    }
+}
+
+/// A handshake's slot in its `HandshakeLimiter`, freed when dropped
+#[derive(Debug)]
+pub struct HandshakeSlot {
+    _permit: Option<OwnedSemaphorePermit>,
+    in_progress: Arc<AtomicUsize>,
+}
+
+impl Drop for HandshakeSlot {
+    fn drop(&mut self) {
+        self.in_progress.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    #[tokio::test]
+    async fn excess_handshakes_wait() {
+        let limiter = HandshakeLimiter::new(Some(2));
+        let first = limiter.acquire().await;
+        let _second = limiter.acquire().await;
+        assert_eq!(limiter.in_progress(), 2);
+
+        let mut third = Box::pin(limiter.acquire());
+        assert!((&mut third).now_or_never().is_none());
+        assert_eq!(limiter.in_progress(), 2);
+
+        drop(first);
+        assert_eq!(limiter.in_progress(), 1);
+        let _third = third.now_or_never().expect("slot freed");
+        assert_eq!(limiter.in_progress(), 2);
+    }
+
+    #[tokio::test]
+    async fn unlimited_handshakes_counted() {
+        let limiter = HandshakeLimiter::new(None);
+        let slots: Vec<_> = futures::future::join_all((0..10).map(|_| limiter.acquire())).await;
+        assert_eq!(limiter.in_progress(), 10);
+        drop(slots);
+        assert_eq!(limiter.in_progress(), 0);
+    }
+
+    #[tokio::test]
+    async fn zero_limit_allows_one() {
+        let limiter = HandshakeLimiter::new(Some(0));
+        let _slot = limiter.acquire().now_or_never().expect("one slot");
+    }
+}
@@ -15,22 +15,38 @@
 
 //! Module providing an async abstraction around a quiche HTTP/3 connection
 
-use crate::boot_time::BootTime;
+use crate::boot_time::{self, BootTime, Duration, SharedClock};
+use crate::certificate::CertObserver;
+use crate::config::{Config, CongestionControl, StreamMode};
+use crate::dispatcher::{DispatcherMetrics, QueryError, Response};
+use crate::encoding;
 use crate::network::SocketTagger;
 use log::{debug, error, warn};
 use quiche::h3;
 use std::future::Future;
 use std::io;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use thiserror::Error;
 use tokio::net::UdpSocket;
 use tokio::sync::{mpsc, oneshot, watch};
 use tokio::task;
+use url::Url;
 
+mod buffer_pool;
 mod driver;
+mod handshake_limiter;
+#[cfg(any(test, feature = "self_test"))]
+pub mod loopback;
+mod packet_tape;
+mod trace;
 
-pub use driver::Stream;
-use driver::{drive, Request};
+pub use buffer_pool::SharedBufferPool;
+use driver::{Activity, Driver, Handles, Request, SharedActivity};
+pub use driver::{ConnectionSource, Negotiated, QueryStats, Stream, DEFAULT_MAX_RESPONSE_SIZE};
+pub use handshake_limiter::HandshakeLimiter;
+pub use packet_tape::{Direction, PacketTape};
 
 #[derive(Debug, Clone)]
 pub enum Status {
@@ -42,10 +58,376 @@ pub enum Status {
     },
 }
 
+/// How a connection's socket is kept on its network
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SocketBinding {
+    /// Mark the socket with this fwmark (`SO_MARK`), which is how netd routes Android networks.
+    /// Failing to set the mark is logged, and the socket is used unmarked.
+    Mark(u32),
+    /// Bind the socket to the interface with this name (`SO_BINDTODEVICE`), for platforms which
+    /// route by interface rather than by mark. Failing to bind fails the connection.
+    Device(String),
+    /// Use a socket which is already set up, such as one a privileged component has marked or
+    /// bound, rather than creating one.
+    Provided(ProvidedSocket),
+}
+
+/// A UDP socket set up outside the resolver, for `SocketBinding::Provided`.
+///
+/// Each connection made with it gets a duplicate of the descriptor, and the socket is closed once
+/// the last of them and of the clones of this handle are gone. A network opens a fresh connection
+/// whenever its last one has closed, so the descriptor is not used up by the first. Connections
+/// overlap only briefly, while one is retired, but in that time either may read a datagram meant
+/// for the other. quiche discards it for its connection ID, and the sender retransmits.
+#[derive(Clone, Debug)]
+pub struct ProvidedSocket(Arc<std::net::UdpSocket>);
+
+impl ProvidedSocket {
+    /// Takes ownership of `fd`, which must be a UDP socket over IPv4 or IPv6. It may already be
+    /// bound, and connected, in which case it must be connected to the server's address. Fails
+    /// with `ErrorKind::InvalidInput` if `fd` is not such a socket, and closes it either way.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open descriptor which nothing else owns, as it is closed when the socket
+    /// is dropped.
+    pub unsafe fn from_raw_fd(fd: std::os::unix::io::RawFd) -> io::Result<Self> {
+        use std::os::unix::io::FromRawFd;
+        let socket = std::net::UdpSocket::from_raw_fd(fd);
+        let invalid = |what: String| io::Error::new(io::ErrorKind::InvalidInput, what);
+        let domain = socket_option(&socket, libc::SO_DOMAIN)
+            .map_err(|e| invalid(format!("fd {} is not a socket: {}", fd, e)))?;
+        if domain != libc::AF_INET && domain != libc::AF_INET6 {
+            return Err(invalid(format!("fd {} is not an IP socket (domain {})", fd, domain)));
+        }
+        let (ty, protocol) =
+            (socket_option(&socket, libc::SO_TYPE)?, socket_option(&socket, libc::SO_PROTOCOL)?);
+        if ty != libc::SOCK_DGRAM || protocol != libc::IPPROTO_UDP {
+            return Err(invalid(format!(
+                "fd {} is not a UDP socket (type {}, protocol {})",
+                fd, ty, protocol
+            )));
+        }
+        // The duplicates share this, as it belongs to the open file rather than the descriptor.
+        socket.set_nonblocking(true)?;
+        Ok(Self(Arc::new(socket)))
+    }
+
+    // Connects a descriptor of its own for a connection to `peer_addr`, once `tag_socket` has
+    // seen it.
+    async fn connect(&self, peer_addr: SocketAddr, tag_socket: &SocketTagger) -> Result<UdpSocket> {
+        let std_socket = self.0.try_clone()?;
+        tag_socket(&std_socket).await;
+        let socket = UdpSocket::from_std(std_socket)?;
+        match socket.peer_addr() {
+            Ok(connected) if connected == peer_addr => {}
+            Ok(connected) => {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("provided socket is connected to {}, not {}", connected, peer_addr),
+                )))
+            }
+            Err(_) => socket.connect(peer_addr).await?,
+        }
+        Ok(socket)
+    }
+}
+
+// Handles are only equal if they share the socket, so that networks given the same descriptor
+// keep matching `ServerInfo`s.
+impl PartialEq for ProvidedSocket {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for ProvidedSocket {}
+
+// Reads an integer `SOL_SOCKET` option of `socket`.
+fn socket_option(socket: &std::net::UdpSocket, option: libc::c_int) -> io::Result<libc::c_int> {
+    use std::os::unix::io::AsRawFd;
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // The pointers passed are to `value` and `len`, which outlive the call, and `len` is the size
+    // of `value`.
+    if unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            option,
+            &mut value as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    } == 0
+    {
+        Ok(value)
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Handed to a `PacketSizeObserver` for each packet a connection sends or receives
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PacketSize<'a> {
+    pub net_id: u32,
+    /// Trace ID of the connection, for telling connections on the same network apart
+    pub trace_id: &'a str,
+    pub direction: Direction,
+    /// UDP payload size in bytes
+    pub len: usize,
+}
+
+/// Told the size of every packet connections send or receive, for aggregating into histograms.
+/// It runs on the connection's driver task, so it should be quick.
+pub type PacketSizeObserver = Arc<dyn Fn(&PacketSize) + Send + Sync>;
+
+/// What the connections a dispatcher makes share: how their sockets are tagged, the clock and
+/// metrics they go by, and who is told about their handshakes and packets.
+#[derive(Clone)]
+pub struct Environment {
+    pub tag_socket: SocketTagger,
+    pub clock: SharedClock,
+    pub metrics: Arc<DispatcherMetrics>,
+    pub cert_observer: Option<CertObserver>,
+    pub packet_size_observer: Option<PacketSizeObserver>,
+}
+
+impl Environment {
+    /// An environment for tests going by `clock`, which leaves sockets untagged and tells no
+    /// observers.
+    #[cfg(any(test, feature = "self_test"))]
+    pub fn for_test(clock: SharedClock, metrics: Arc<DispatcherMetrics>) -> Self {
+        Self {
+            tag_socket: Arc::new(|_| Box::pin(async {})),
+            clock,
+            metrics,
+            cert_observer: None,
+            packet_size_observer: None,
+        }
+    }
+}
+
+/// The server a connection is made to, and the network it is reached over
+#[derive(Clone, Copy, Debug)]
+pub struct Peer<'a> {
+    /// Name sent in the TLS SNI extension, and which the certificate is verified against.
+    pub server_name: Option<&'a str>,
+    pub addr: SocketAddr,
+    pub net_id: u32,
+    pub binding: &'a SocketBinding,
+}
+
+/// Tunables for a `Connection`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Options {
+    /// Tear the connection down if requests are in flight but nothing has been sent or received
+    /// for this long. `None`, the default, disables the watchdog. A connection which is slow but
+    /// working, such as one on a lossy path waiting out retransmissions, can go quiet for a while,
+    /// so this should be longer than the queries sent on it are allowed to take.
+    pub watchdog_timeout: Option<Duration>,
+    /// Cap on response bodies for requests which don't set their own. `None` means
+    /// `DEFAULT_MAX_RESPONSE_SIZE`. The config the connection is built from should have a matching
+    /// `config::Key::max_response_size`, so the stream flow-control window fits the cap.
+    pub max_response_size: Option<usize>,
+    /// QPACK dynamic table capacity to advertise to the server, and how many streams may block
+    /// waiting on table updates. DNS responses carry only a handful of headers, so these rarely
+    /// matter, but some servers interoperate better with particular values. `None` keeps quiche's
+    /// default of no dynamic table. The quiche version we build against only uses the static
+    /// table for what it sends, so these only affect how the server may encode its responses.
+    pub qpack_max_table_capacity: Option<u64>,
+    pub qpack_blocked_streams: Option<u64>,
+    /// QUIC versions the connection may use, most preferred first. If the server answers with
+    /// version negotiation and quiche would switch to a version not listed here, the connection
+    /// fails instead. The config should have a matching `config::Key::quic_versions`, as that
+    /// decides which version the connection starts with.
+    pub quic_versions: Vec<u32>,
+    /// Connection flow-control window to advertise in place of the config's. Configs are shared,
+    /// so the window is only swapped in while this connection is created. `None` keeps the
+    /// config's window.
+    pub connection_window: Option<u64>,
+    /// Name the server's certificate must be valid for, such as the name of a pinned server. If
+    /// it isn't, the connection fails with a distinct error once the handshake completes, even
+    /// if the certificate verified, for instance because no server name was sent to verify it
+    /// against. `None` leaves the check to verification.
+    pub expected_server_name: Option<String>,
+    /// Cap on the response body bytes buffered across the requests in flight, so that a stalled
+    /// server can't make the connection hold on to ever more memory. Once it is reached, new
+    /// requests fail with `Error::Saturated` until responses complete. Each response is also
+    /// bounded by its own `max_response_size`. `None` means no cap.
+    pub max_buffered_response_bytes: Option<usize>,
+    /// Whether the connection is on a metered network. Metered connections use Reno congestion
+    /// control whatever their config's algorithm, as it backs off further on loss than CUBIC,
+    /// and get a `max_lost_packets` budget if none is given. This trades reliability for data:
+    /// on a lossy path a query which enough retransmissions would have got through fails
+    /// instead, and the resolver falls back to another transport.
+    pub metered: bool,
+    /// Packets the connection may declare lost before it is torn down with
+    /// `Error::RetransmissionBudget`, failing the requests in flight. quiche retransmits what
+    /// every lost packet carried, and the version we build against counts packets rather than
+    /// bytes, so this bounds the retransmitted data at about this many full-sized packets.
+    /// `None` means no limit, or `METERED_LOST_PACKET_BUDGET` for a metered connection.
+    pub max_lost_packets: Option<usize>,
+    /// How long a connection closing gracefully, once its `Connection` handle is gone or retired,
+    /// keeps receiving answers to the requests already in flight. It sends no new requests in
+    /// the meantime. When the time is up it closes anyway, and requests still unanswered fail.
+    /// `None` waits for all of them, each of which is still bounded by its expiry and the
+    /// watchdog.
+    pub drain_timeout: Option<Duration>,
+    /// Most datagrams the driver reads each time the socket becomes readable, before it sends
+    /// what they call for and lets other connections' drivers run. Larger batches cost fewer
+    /// wakeups and flushes under a burst, but hold up acknowledgements, timers and requests until
+    /// the whole batch has been handed to quiche, and on a busy runtime let one connection keep
+    /// its thread from others for longer. A batch of 1 reads a packet at a time. 0 is taken as 1.
+    pub recv_batch: usize,
+    /// Longest the connection may spend handshaking, or closing, before it is reaped: torn down
+    /// and counted in `DispatcherMetrics::zombies_reaped`. quiche's own timers end both well
+    /// within the default, the handshake at the idle timeout and closing after a few PTOs, so
+    /// this only catches a connection whose state has wedged and which would otherwise linger.
+    /// Closing includes a retired connection draining its requests. `None` never reaps.
+    pub zombie_timeout: Option<Duration>,
+    /// Whether the connection may move to another network path. The driver doesn't migrate
+    /// connections yet, so for now this only stops the config from telling the server that they
+    /// won't: a config built for the connection must have `config::Key::disable_active_migration`
+    /// set to the opposite, which is how the dispatcher builds its keys.
+    pub active_migration: bool,
+    /// Longest the handshake may take before the connection fails with
+    /// `Error::HandshakeTimeout`, counted as a `ConnectFailure::HandshakeTimeout`. `None` leaves
+    /// it to quiche, which gives up on the handshake at the idle timeout.
+    pub handshake_timeout: Option<Duration>,
+    /// Whether requests go out as HTTP/3 or, experimentally, as raw DNS over QUIC streams. The
+    /// config must have a matching `config::Key::stream_mode`, so the server is offered the
+    /// right ALPN protocol; the dispatcher builds its keys to match. HTTP/3 settings such as
+    /// the QPACK ones are ignored for raw DNS, and the headers of a request only serve to carry
+    /// the DNS message.
+    pub stream_mode: StreamMode,
+}
+
+/// Lost packets a metered connection may retransmit when `Options::max_lost_packets` isn't set,
+/// about 20 KiB at full size. A healthy connection answering DNS queries loses few if any.
+pub const METERED_LOST_PACKET_BUDGET: usize = 16;
+
+impl Options {
+    // Enough for the few packets a DNS answer spans to be taken in one go, while a flood can't
+    // keep the driver from the rest of its work for long.
+    const DEFAULT_RECV_BATCH: usize = 16;
+    const DEFAULT_ZOMBIE_TIMEOUT: Duration = Duration::from_secs(300);
+
+    fn lost_packet_budget(&self) -> Option<usize> {
+        match self.max_lost_packets {
+            None if self.metered => Some(METERED_LOST_PACKET_BUDGET),
+            budget => budget,
+        }
+    }
+
+    fn h3_config(&self) -> h3::Result<h3::Config> {
+        let mut config = h3::Config::new()?;
+        if let Some(capacity) = self.qpack_max_table_capacity {
+            config.set_qpack_max_table_capacity(capacity);
+        }
+        if let Some(streams) = self.qpack_blocked_streams {
+            config.set_qpack_blocked_streams(streams);
+        }
+        Ok(config)
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            watchdog_timeout: None,
+            max_response_size: None,
+            qpack_max_table_capacity: None,
+            qpack_blocked_streams: None,
+            quic_versions: vec![quiche::PROTOCOL_VERSION],
+            connection_window: None,
+            expected_server_name: None,
+            max_buffered_response_bytes: None,
+            metered: false,
+            max_lost_packets: None,
+            drain_timeout: None,
+            recv_batch: Self::DEFAULT_RECV_BATCH,
+            zombie_timeout: Some(Self::DEFAULT_ZOMBIE_TIMEOUT),
+            active_migration: false,
+            handshake_timeout: None,
+            stream_mode: StreamMode::Http3,
+        }
+    }
+}
+
+/// Snapshot of a connection, for diagnostics
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// The id quiche uses for the connection in its logs
+    pub trace_id: String,
+    pub net_id: u32,
+    pub peer_addr: SocketAddr,
+    /// Time since the connection was created
+    pub age: Duration,
+    /// Requests sent on the connection so far
+    pub queries: u64,
+    /// Requests issued and not yet answered
+    pub open_streams: usize,
+    /// When quiche's next timer fires, which for a connection with nothing in flight is when it
+    /// closes for being idle
+    pub idle_deadline: Option<BootTime>,
+    /// Response body bytes buffered for requests not yet answered
+    pub buffered_bytes: usize,
+    /// Packets the connection has declared lost, and so retransmitted, so far
+    pub packets_lost: usize,
+    /// The QUIC version and ALPN protocol, once the handshake has completed
+    pub negotiated: Option<Negotiated>,
+    /// Whether the server's certificate is verified. A connection which doesn't verify it will
+    /// talk to whoever answers at the server's address.
+    pub verifies_peer: bool,
+}
+
+/// Describes a `Connection` on demand, and can be kept by whoever lists connections without
+/// holding on to the connection itself.
+#[derive(Clone)]
+pub struct Monitor {
+    trace_id: String,
+    net_id: u32,
+    peer_addr: SocketAddr,
+    created: BootTime,
+    queries: Arc<AtomicU64>,
+    activity: SharedActivity,
+    clock: SharedClock,
+    verifies_peer: bool,
+}
+
+impl Monitor {
+    /// The connection as it is now.
+    pub fn info(&self) -> ConnectionInfo {
+        let Activity { open_streams, idle_deadline, buffered_bytes, packets_lost, negotiated } =
+            self.activity.lock().unwrap().clone();
+        ConnectionInfo {
+            trace_id: self.trace_id.clone(),
+            net_id: self.net_id,
+            peer_addr: self.peer_addr,
+            age: self.clock.elapsed(self.created),
+            queries: self.queries.load(Ordering::Relaxed),
+            open_streams,
+            idle_deadline,
+            buffered_bytes,
+            packets_lost,
+            negotiated,
+            verifies_peer: self.verifies_peer,
+        }
+    }
+}
+
 /// Quiche HTTP/3 connection
 pub struct Connection {
     request_tx: mpsc::Sender<Request>,
     status_rx: watch::Receiver<Status>,
+    trace_id: String,
+    clock: SharedClock,
+    driver: task::JoinHandle<driver::Result<()>>,
+    default_max_response_size: usize,
+    max_buffered_response_bytes: Option<usize>,
+    monitor: Monitor,
+    // Set by `retire`.
+    retired: bool,
 }
 
 fn new_scid() -> [u8; quiche::MAX_CONN_ID_LEN] {
@@ -78,11 +460,51 @@ fn mark_socket(socket: &std::net::UdpSocket, socket_mark: u32) -> io::Result<()>
     }
 }
 
+fn bind_to_device(socket: &std::net::UdpSocket, device: &str) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    // The kernel takes at most IFNAMSIZ bytes including the terminating NUL, and would silently
+    // bind to a prefix of a longer name.
+    if device.is_empty() || device.len() >= libc::IFNAMSIZ || device.contains('\0') {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid interface name"));
+    }
+    // As in `mark_socket`, the only pointer passed is to `device`, which outlives the call.
+    if unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            device.as_ptr() as *const libc::c_void,
+            device.len() as libc::socklen_t,
+        )
+    } == 0
+    {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+fn bind_socket(socket: &std::net::UdpSocket, binding: &SocketBinding) -> Result<()> {
+    match binding {
+        SocketBinding::Mark(mark) => {
+            mark_socket(socket, *mark).unwrap_or_else(|e| error!("Unable to mark socket : {:?}", e))
+        }
+        SocketBinding::Device(device) => bind_to_device(socket, device)
+            .map_err(|source| Error::BindToDevice { device: device.clone(), source })?,
+        // Set up by whoever provided it.
+        SocketBinding::Provided(_) => {}
+    }
+    Ok(())
+}
+
 async fn build_socket(
     peer_addr: SocketAddr,
-    socket_mark: u32,
+    binding: &SocketBinding,
     tag_socket: &SocketTagger,
-) -> io::Result<UdpSocket> {
+) -> Result<UdpSocket> {
+    if let SocketBinding::Provided(provided) = binding {
+        return provided.connect(peer_addr, tag_socket).await;
+    }
     let bind_addr = match peer_addr {
         SocketAddr::V4(_) => "0.0.0.0:0",
         SocketAddr::V6(_) => "[::]:0",
@@ -90,8 +512,7 @@ async fn build_socket(
 
     let socket = UdpSocket::bind(bind_addr).await?;
     let std_socket = socket.into_std()?;
-    mark_socket(&std_socket, socket_mark)
-        .unwrap_or_else(|e| error!("Unable to mark socket : {:?}", e));
+    bind_socket(&std_socket, binding)?;
     tag_socket(&std_socket).await;
     let socket = UdpSocket::from_std(std_socket)?;
     socket.connect(peer_addr).await?;
@@ -115,52 +536,252 @@ pub enum Error {
     /// UDP socket for use by the connection.
     #[error("IO error: {0}")]
     Io(#[from] io::Error),
+    /// The socket could not be bound to its interface, typically because the process lacks the
+    /// permission `SO_BINDTODEVICE` needs, or because there is no interface of that name.
+    #[error("Unable to bind socket to {device}: {source}")]
+    BindToDevice { device: String, source: io::Error },
     /// The request is no longer being serviced. This could mean that the
     /// request was dropped for an unspecified reason, or that the connection
     /// was closed prematurely and it can no longer be serviced.
     #[error("Driver dropped request")]
     RecvResponse(#[from] oneshot::error::RecvError),
+    /// The DNS query could not be turned into a DoH request.
+    #[error("Unable to encode request: {0}")]
+    Encode(anyhow::Error),
+    /// The connection is buffering `Options::max_buffered_response_bytes` or more.
+    #[error("Connection is buffering {0} response bytes")]
+    Saturated(usize),
 }
 
 /// Common result type for working with a HTTP/3 connection
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Converts the outcome of a DoH request into the response for its requestor. `None` means the
+/// connection went away before the request completed.
+///
+/// Any DNS message is an answer, whatever its RCODE: a SERVFAIL or FORMERR from the server is for
+/// the resolver to act on. An empty body or one which isn't a DNS message means the server failed
+/// to answer at all, so it is an error, and the resolver falls back as it would for others. So is a
+/// 5xx status, whatever the body, which is reported along with the status and any `Retry-After`
+/// so that the network can retry or hold off. A reset stream, by contrast, is a transport failure.
+/// A 404, or a success with some other content type than a DNS message, says the server doesn't
+/// serve DoH at all, which is worth telling the user apart from a server which is failing.
+/// A redirect, or an HTML page in place of the answer, is what captive portals send, so it is
+/// reported as one for the resolver to prompt a sign-in. A request the driver abandoned at its
+/// expiry times out.
+pub fn stream_response(stream: Option<Stream>) -> Response {
+    match stream {
+        None => {
+            debug!("Connection died while processing request");
+            Response::Error { error: QueryError::ConnectionError }
+        }
+        Some(stream) if stream.expired => Response::Error { error: QueryError::Timeout },
+        Some(stream) if stream.too_large => Response::Error { error: QueryError::ResponseTooLarge },
+        Some(Stream { error: Some(err), .. }) => Response::Error { error: QueryError::Reset(err) },
+        Some(stream) => match server_error(&stream.headers)
+            .or_else(|| captive_portal(&stream.headers, &stream.data))
+            .or_else(|| not_doh(&stream.headers))
+        {
+            Some(error) => {
+                debug!("Server failed to answer: {:?}", error);
+                Response::Error { error }
+            }
+            None => match encoding::response_metadata(&stream.data) {
+                Ok(metadata) => Response::Success { answer: stream.data, metadata },
+                Err(_) => {
+                    debug!("Response body of {} bytes is not a DNS message", stream.data.len());
+                    Response::Error { error: QueryError::MalformedResponse }
+                }
+            },
+        },
+    }
+}
+
+fn server_error(headers: &[h3::Header]) -> Option<QueryError> {
+    let status = encoding::status_code(headers).filter(|status| (500..600).contains(status))?;
+    Some(QueryError::ServerError { status, retry_after: encoding::retry_after(headers) })
+}
+
+// A redirect other than 304 Not Modified, which is how ETag revalidations are answered, or an
+// HTML page where the answer should be. An HTML content type alone isn't enough, as the body may
+// still be a DNS message, and is left for `not_doh`.
+fn captive_portal(headers: &[h3::Header], body: &[u8]) -> Option<QueryError> {
+    const HTTP_NOT_MODIFIED: u16 = 304;
+    let status = encoding::status_code(headers)?;
+    let redirect = (300..400).contains(&status) && status != HTTP_NOT_MODIFIED;
+    let page = (200..300).contains(&status) && encoding::looks_like_html(body);
+    if !(redirect || page) {
+        return None;
+    }
+    let location = encoding::header_value(headers, b"location")
+        .map(|value| String::from_utf8_lossy(value).into_owned());
+    Some(QueryError::CaptivePortal { status, location })
+}
+
+// A response without a content type is given the benefit of the doubt, and judged by its body.
+fn not_doh(headers: &[h3::Header]) -> Option<QueryError> {
+    const HTTP_NOT_FOUND: u16 = 404;
+    let status = encoding::status_code(headers)?;
+    let content_type = encoding::header_value(headers, b"content-type");
+    let wrong_type = match content_type {
+        Some(content_type) => {
+            (200..300).contains(&status) && !encoding::is_dns_message_type(content_type)
+        }
+        None => false,
+    };
+    if status != HTTP_NOT_FOUND && !wrong_type {
+        return None;
+    }
+    let content_type = content_type.map(|value| String::from_utf8_lossy(value).into_owned());
+    Some(QueryError::NotADohEndpoint { status, content_type })
+}
+
 impl Connection {
     const MAX_PENDING_REQUESTS: usize = 10;
-    /// Create a new connection with a background task handling IO.
+    /// Create a new connection with a background task handling IO. `config` is only locked
+    /// while the quiche connection is created, and is left as it was.
     pub async fn new(
-        server_name: Option<&str>,
-        to: SocketAddr,
-        socket_mark: u32,
-        net_id: u32,
-        tag_socket: &SocketTagger,
-        config: &mut quiche::Config,
+        peer: Peer<'_>,
+        config: &mut Config,
         session: Option<Vec<u8>>,
+        options: Options,
+        env: &Environment,
     ) -> Result<Self> {
         let (request_tx, request_rx) = mpsc::channel(Self::MAX_PENDING_REQUESTS);
         let (status_tx, status_rx) = watch::channel(Status::QUIC);
+        // The source connection ID stays fixed for the connection's lifetime. The quiche version
+        // we build against discards NEW_CONNECTION_ID and RETIRE_CONNECTION_ID frames and has no
+        // API for issuing further IDs, so rotation has to wait for a quiche upgrade.
         let scid = new_scid();
-        let mut quiche_conn =
-            quiche::connect(server_name, &quiche::ConnectionId::from_ref(&scid), to, config)?;
+        let verifies_peer = config.verifies_peer();
+        let congestion_control = config.congestion_control();
+        let max_send_size = config.max_datagram_size();
+        let debug_logs = config.debug_logs();
+        let quiche_conn = {
+            let mut config = config.take().await;
+            if let Some(window) = options.connection_window {
+                config.set_initial_max_data(window);
+            }
+            if options.metered {
+                config.set_cc_algorithm(CongestionControl::Reno.algorithm());
+            }
+            let quiche_conn = quiche::connect(
+                peer.server_name,
+                &quiche::ConnectionId::from_ref(&scid),
+                peer.addr,
+                &mut config,
+            );
+            if options.connection_window.is_some() {
+                // Put back the window every config is built with, for the connections sharing it.
+                config.set_initial_max_data(crate::config::MAX_INCOMING_BUFFER_SIZE_WHOLE);
+            }
+            if options.metered {
+                config.set_cc_algorithm(congestion_control.algorithm());
+            }
+            quiche_conn
+        };
+        let mut quiche_conn = quiche_conn?;
+        debug_logs.install(&mut quiche_conn);
         if let Some(session) = session {
             debug!("Setting session");
-            quiche_conn.set_session(&session)?;
+            // A session from an older quiche may no longer parse. It only saves a round trip, so
+            // fall back to a full handshake rather than failing the connection.
+            if let Err(e) = quiche_conn.set_session(&session) {
+                warn!("Discarding session which can't be resumed: {:?}", e);
+            }
         }
+        let trace_id = quiche_conn.trace_id().to_string();
 
-        let socket = build_socket(to, socket_mark, tag_socket).await?;
+        let socket = build_socket(peer.addr, peer.binding, &env.tag_socket).await?;
+        let clock = env.clock.clone();
+        let monitor = Monitor {
+            trace_id: trace_id.clone(),
+            net_id: peer.net_id,
+            peer_addr: peer.addr,
+            created: clock.now(),
+            queries: Default::default(),
+            activity: Default::default(),
+            clock: clock.clone(),
+            verifies_peer,
+        };
+        let handles = Handles {
+            request_rx,
+            status_tx,
+            packet_tape: PacketTape::for_connection(),
+            activity: monitor.activity.clone(),
+        };
+        let default_max_response_size =
+            options.max_response_size.unwrap_or(DEFAULT_MAX_RESPONSE_SIZE);
+        let max_buffered_response_bytes = options.max_buffered_response_bytes;
+        let driver =
+            Driver::new(quiche_conn, socket, peer, options, handles, env.clone(), max_send_size);
+        let driver_trace_id = trace_id.clone();
         let driver = async move {
-            let result = drive(request_rx, status_tx, quiche_conn, socket, net_id).await;
+            let result = driver.drive().await;
             if let Err(ref e) = result {
-                warn!("Connection driver returns some Err: {:?}", e);
+                warn!("[{}] Connection driver returns some Err: {:?}", driver_trace_id, e);
             }
             result
         };
-        task::spawn(driver);
-        Ok(Self { request_tx, status_rx })
+        let driver = task::spawn(env.metrics.track(driver));
+        Ok(Self {
+            request_tx,
+            status_rx,
+            trace_id,
+            clock,
+            driver,
+            default_max_response_size,
+            max_buffered_response_bytes,
+            monitor,
+            retired: false,
+        })
+    }
+
+    /// The id quiche uses for this connection in its own logs and qlog output.
+    pub fn trace_id(&self) -> &str {
+        &self.trace_id
+    }
+
+    /// Describes this connection, now or later.
+    pub fn monitor(&self) -> Monitor {
+        self.monitor.clone()
+    }
+
+    /// Whether the connection is buffering as many response bytes as
+    /// `Options::max_buffered_response_bytes` allows, so that new requests would be refused.
+    pub fn saturated(&self) -> bool {
+        self.buffered_response_bytes().is_some()
+    }
+
+    // The response bytes buffered, if they are at the cap.
+    fn buffered_response_bytes(&self) -> Option<usize> {
+        let max = self.max_buffered_response_bytes?;
+        let buffered = self.monitor.activity.lock().unwrap().buffered_bytes;
+        Some(buffered).filter(|&buffered| buffered >= max)
+    }
+
+    /// Tears the connection down at once, without telling the server. Requests in flight fail as
+    /// if the connection had died. Meant for when the network underneath is gone, so a graceful
+    /// close could never complete.
+    pub fn abort(&self) {
+        debug!("[{}] Aborting connection", self.trace_id);
+        self.driver.abort();
+    }
+
+    /// Closes the connection once the requests in flight have been answered, as dropping the
+    /// handle would. From then on, the connection counts as dead and refuses new requests.
+    pub fn retire(&mut self) {
+        debug!("[{}] Retiring connection", self.trace_id);
+        // The driver retires the connection once the request channel has no senders left.
+        self.request_tx = mpsc::channel(1).0;
+        self.retired = true;
     }
 
     /// Waits until we're either fully alive or dead
     pub async fn wait_for_live(&mut self) -> bool {
+        if self.retired {
+            return false;
+        }
         // Once sc-mainline-prod updates to modern tokio, use
         // borrow_and_update here.
         match &*self.status_rx.borrow() {
@@ -194,13 +815,116 @@ impl Connection {
     /// Send a query, produce a future which will provide a response.
     /// The future is separately returned rather than awaited to allow it to be waited on without
     /// keeping the `Connection` itself borrowed.
+    /// If the response body grows beyond `max_response_size` (`Options::max_response_size` if
+    /// unspecified), the stream is abandoned and the returned `Stream` is marked `too_large`.
+    /// `submitted` is when the query was first asked for, from which its `QueryStats::queue_wait`
+    /// is measured.
     pub async fn query(
         &self,
         headers: Vec<h3::Header>,
+        submitted: BootTime,
         expiry: Option<BootTime>,
+        max_response_size: Option<usize>,
     ) -> Result<impl Future<Output = Option<Stream>>> {
-        let (response_tx, response_rx) = oneshot::channel();
-        self.request_tx.send(Request { headers, response_tx, expiry }).await?;
+        let response_rx =
+            self.send_request(headers, Vec::new(), submitted, expiry, max_response_size).await?;
         Ok(async move { response_rx.await.ok() })
     }
+
+    /// Sends a request made of exactly `headers` and `body`, and returns the response as the
+    /// server sent it. Nothing about the request is filled in or checked, so interop tests can
+    /// reproduce what particular servers do with unusual requests; resolving should go through
+    /// `query` or `dns_query`. The body must fit in the stream's initial flow-control window, or
+    /// the request is reset and the response carries `H3_REQUEST_CANCELLED`.
+    #[cfg(test)]
+    pub async fn send_raw_request(
+        &self,
+        headers: Vec<h3::Header>,
+        body: Vec<u8>,
+    ) -> Result<impl Future<Output = Option<Stream>>> {
+        let now = self.clock.now();
+        let response_rx = self.send_request(headers, body, now, None, None).await?;
+        Ok(async move { response_rx.await.ok() })
+    }
+
+    async fn send_request(
+        &self,
+        headers: Vec<h3::Header>,
+        body: Vec<u8>,
+        submitted: BootTime,
+        expiry: Option<BootTime>,
+        max_response_size: Option<usize>,
+    ) -> Result<oneshot::Receiver<Stream>> {
+        let (response_tx, response_rx) = oneshot::channel();
+        let max_response_size = max_response_size.unwrap_or(self.default_max_response_size);
+        let request = Request { headers, body, submitted, response_tx, expiry, max_response_size };
+        self.send(request).await?;
+        Ok(response_rx)
+    }
+
+    async fn send(&self, request: Request) -> Result<()> {
+        if let Some(buffered) = self.buffered_response_bytes() {
+            return Err(Error::Saturated(buffered));
+        }
+        self.request_tx.send(request).await?;
+        self.monitor.queries.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+    /// Send a wire-format DNS query as a DoH request for `url` on this specific connection,
+    /// bypassing the network's connection management. The returned future resolves to the
+    /// answer, or to `QueryError::Timeout` if the server hasn't answered within `timeout`.
+    pub async fn dns_query(
+        &self,
+        url: &Url,
+        wire: &[u8],
+        timeout: Duration,
+    ) -> Result<impl Future<Output = Response>> {
+        let base64_query = base64::encode_config(wire, base64::URL_SAFE_NO_PAD);
+        let headers = encoding::dns_request(&base64_query, url).map_err(Error::Encode)?;
+        let now = self.clock.now();
+        let stream_fut = self.query(headers, now, now.checked_add(timeout), None).await?;
+        Ok(async move {
+            boot_time::timeout(timeout, stream_fut)
+                .await
+                .map_or(Response::Error { error: QueryError::Timeout }, stream_response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boot_time::system_clock;
+    use crate::config::test_key;
+    use crate::connection::loopback::{DohServer, Reply};
+
+    // A connection to `server`, which doesn't verify its certificate.
+    async fn connect(server: &DohServer) -> Connection {
+        let mut config = Config::from_key(&test_key()).unwrap();
+        let binding = SocketBinding::Mark(0);
+        let peer = Peer { server_name: None, addr: server.addr, net_id: 1, binding: &binding };
+        let env = Environment::for_test(system_clock(), Default::default());
+        Connection::new(peer, &mut config, None, Options::default(), &env).await.unwrap()
+    }
+
+    // Headers of a DoH request for a probe query, and the DNS answer a `DohServer` gives it.
+    fn probe_request() -> (Vec<h3::Header>, Vec<u8>) {
+        let url = Url::parse("https://mylocal.com/dns-query").unwrap();
+        let query = encoding::probe_query().unwrap();
+        let mut answer = base64::decode_config(&query, base64::URL_SAFE_NO_PAD).unwrap();
+        answer[2] |= 0x80;
+        (encoding::dns_request(&query, &url).unwrap(), answer)
+    }
+
+    #[tokio::test]
+    async fn send_raw_request() {
+        let server = DohServer::start(Box::new(|_, _| Reply::After(Duration::ZERO))).unwrap();
+        let connection = connect(&server).await;
+        // The request goes out exactly as given, here with the headers in reverse.
+        let (mut headers, answer) = probe_request();
+        headers.reverse();
+        let stream = connection.send_raw_request(headers, Vec::new()).await.unwrap().await.unwrap();
+        assert!(stream.headers.contains(&h3::Header::new(b":status", b"200")));
+        assert_eq!(stream.data, answer);
+    }
 }
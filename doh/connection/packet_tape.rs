@@ -0,0 +1,202 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Records the packets of a connection's handshake, so a failure seen in the field can be
+//! replayed in a test
+
+#[cfg(test)]
+use super::driver::deliver;
+use crate::boot_time::BootTime;
+use log::debug;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+/// Most packets kept on a tape. The first flights of a handshake say the most about why it failed,
+/// so recording stops here rather than dropping early packets.
+pub const MAX_PACKETS: usize = 256;
+
+/// Which way a recorded packet went
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// A packet as it crossed the socket
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Packet {
+    pub direction: Direction,
+    pub at: BootTime,
+    /// Where the packet came from or went to
+    pub peer: SocketAddr,
+    pub bytes: Vec<u8>,
+}
+
+/// Handle to a tape, shared between a `Connection` and its driver.
+pub type SharedPacketTape = Arc<PacketTape>;
+
+/// Packets of a connection until its handshake settles, in the order they were sent or received.
+///
+/// Connections only record when built with the `packet_tape` feature, meant for eng builds.
+/// Nothing is recorded once the handshake completes, so DNS traffic is never kept.
+#[derive(Debug)]
+pub struct PacketTape {
+    enabled: bool,
+    packets: Mutex<Vec<Packet>>,
+}
+
+impl PacketTape {
+    pub fn new(enabled: bool) -> Self {
+        Self { enabled, packets: Mutex::new(Vec::new()) }
+    }
+
+    /// A tape for a new connection, which records if the `packet_tape` feature is enabled.
+    pub fn for_connection() -> SharedPacketTape {
+        Arc::new(Self::new(cfg!(feature = "packet_tape")))
+    }
+
+    pub fn record(&self, direction: Direction, at: BootTime, peer: SocketAddr, bytes: &[u8]) {
+        if !self.enabled {
+            return;
+        }
+        let mut packets = self.packets.lock().unwrap();
+        if packets.len() < MAX_PACKETS {
+            packets.push(Packet { direction, at, peer, bytes: bytes.to_vec() });
+        }
+    }
+
+    pub fn packets(&self) -> Vec<Packet> {
+        self.packets.lock().unwrap().clone()
+    }
+
+    /// Logs every packet recorded, base64-encoded, for turning back into a tape to `replay`.
+    pub fn dump(&self, trace_id: &str) {
+        let packets = self.packets();
+        let start = match packets.first() {
+            Some(first) => first.at,
+            None => return,
+        };
+        debug!("[{}] Handshake failed after {} packets", trace_id, packets.len());
+        for packet in &packets {
+            debug!(
+                "[{}] {:?} {} +{:?} {}",
+                trace_id,
+                packet.direction,
+                packet.peer,
+                packet.at.checked_duration_since(start).unwrap_or_default(),
+                base64::encode(&packet.bytes)
+            );
+        }
+    }
+}
+
+/// Feeds the inbound packets of a tape to `quiche_conn`, a fresh connection standing in for the
+/// recorded one, through the driver's receive path. Returns how many packets were delivered, or
+/// the first error, as the driver would have hit it.
+///
+/// Connection IDs are random, so packets are rewritten to carry the replaying connection's IDs in
+/// place of the recorded ones. Packet protection keys derive from the original IDs and TLS key
+/// shares, though, so only what quiche checks before decrypting replays faithfully: version
+/// negotiation, and malformed or unexpected packets. Encrypted handshake packets are dropped as
+/// undecryptable, as quiche would drop forged ones.
+#[cfg(test)]
+pub fn replay(packets: &[Packet], quiche_conn: &mut quiche::Connection) -> quiche::Result<usize> {
+    let scid = quiche_conn.source_id().to_vec();
+    let dcid = quiche_conn.destination_id().to_vec();
+    let mut delivered = 0;
+    for packet in packets.iter().filter(|packet| packet.direction == Direction::Inbound) {
+        let mut bytes = packet.bytes.clone();
+        rewrite_ids(&mut bytes, &scid, &dcid);
+        deliver(quiche_conn, &mut bytes, packet.peer)?;
+        delivered += 1;
+    }
+    Ok(delivered)
+}
+
+// Points an inbound packet at the connection with source ID `scid`. A version negotiation packet
+// also echoes the destination ID the connection first used, `dcid`. IDs of a different length
+// from the replaying connection's are left alone.
+#[cfg(test)]
+fn rewrite_ids(packet: &mut [u8], scid: &[u8], dcid: &[u8]) -> Option<()> {
+    const LONG_HEADER: u8 = 0x80;
+    if packet.first()? & LONG_HEADER == 0 {
+        packet.get_mut(1..1 + scid.len())?.copy_from_slice(scid);
+        return Some(());
+    }
+    // Flags and version precede the length-prefixed destination and source IDs.
+    let is_version_negotiation = packet.get(1..5)? == [0; 4];
+    let dcid_len = *packet.get(5)? as usize;
+    if dcid_len == scid.len() {
+        packet.get_mut(6..6 + dcid_len)?.copy_from_slice(scid);
+    }
+    let scid_pos = 6 + dcid_len;
+    let scid_len = *packet.get(scid_pos)? as usize;
+    if is_version_negotiation && scid_len == dcid.len() {
+        packet.get_mut(scid_pos + 1..scid_pos + 1 + scid_len)?.copy_from_slice(dcid);
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{replay, Direction, PacketTape, MAX_PACKETS};
+    use crate::boot_time::BootTime;
+    use crate::config::{test_key, Config};
+    use std::net::SocketAddr;
+    use std::ops::DerefMut;
+
+    #[test]
+    fn records_up_to_limit() {
+        let peer: SocketAddr = "192.0.2.1:443".parse().unwrap();
+        let disabled = PacketTape::new(false);
+        disabled.record(Direction::Outbound, BootTime::now(), peer, b"initial");
+        assert!(disabled.packets().is_empty());
+
+        let tape = PacketTape::new(true);
+        for _ in 0..MAX_PACKETS + 1 {
+            tape.record(Direction::Inbound, BootTime::now(), peer, b"packet");
+        }
+        assert_eq!(tape.packets().len(), MAX_PACKETS);
+    }
+
+    #[tokio::test]
+    async fn replay_version_negotiation() {
+        let peer: SocketAddr = "192.0.2.1:443".parse().unwrap();
+        let key = test_key();
+        let mut config = Config::from_key(&key).unwrap();
+        let connect = |config: &mut quiche::Config| {
+            let scid = super::super::new_scid();
+            quiche::connect(None, &quiche::ConnectionId::from_ref(&scid), peer, config).unwrap()
+        };
+
+        // The recorded connection was offered only a version we don't support.
+        let recorded = connect(config.take().await.deref_mut());
+        let mut negotiation = vec![0x80, 0, 0, 0, 0];
+        for id in [recorded.source_id(), recorded.destination_id()].iter() {
+            negotiation.push(id.len() as u8);
+            negotiation.extend_from_slice(id);
+        }
+        negotiation.extend_from_slice(&0x1a2a_3a4a_u32.to_be_bytes());
+        let tape = PacketTape::new(true);
+        tape.record(Direction::Outbound, BootTime::now(), peer, b"client initial");
+        tape.record(Direction::Inbound, BootTime::now(), peer, &negotiation);
+
+        // A fresh connection, with its own IDs, fails the same way.
+        let mut replaying = connect(config.take().await.deref_mut());
+        let result = replay(&tape.packets(), &mut replaying);
+        assert!(matches!(result, Err(quiche::Error::UnknownVersion)), "{:?}", result);
+    }
+}
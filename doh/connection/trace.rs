@@ -0,0 +1,124 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Sections on the Android system trace for the phases of a connection and its requests, so
+//! that DoH latency can be lined up with the rest of the system in systrace or Perfetto
+//!
+//! Sections are only written when built with the `atrace` feature, which calls into libcutils;
+//! `libnetd_resolv`, which links this library, already depends on it. Without the feature a
+//! `Section` is empty and beginning or ending one does nothing.
+
+/// What a section covers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Span {
+    /// From the first flight until the handshake completes or fails
+    Handshake,
+    /// From sending a request until its answer is handed back
+    Request,
+    /// From the connection starting to close until its driver ends
+    Close,
+}
+
+/// A section of the trace, which ends when dropped.
+///
+/// These are asynchronous sections, told apart by a cookie, as the driver may move between
+/// threads at every await and sections of different connections overlap.
+#[derive(Debug)]
+pub struct Section {
+    #[cfg(feature = "atrace")]
+    begun: Option<(Span, i32)>,
+}
+
+#[cfg(feature = "atrace")]
+mod sys {
+    use super::Span;
+    use libc::c_char;
+    use std::sync::atomic::{AtomicI32, Ordering};
+
+    // ATRACE_TAG_NETWORK in cutils/trace.h
+    const ATRACE_TAG_NETWORK: u64 = 1 << 21;
+
+    extern "C" {
+        fn atrace_get_enabled_tags() -> u64;
+        fn atrace_async_begin_body(name: *const c_char, cookie: i32);
+        fn atrace_async_end_body(name: *const c_char, cookie: i32);
+    }
+
+    static NEXT_COOKIE: AtomicI32 = AtomicI32::new(0);
+
+    fn name(span: Span) -> &'static [u8] {
+        match span {
+            Span::Handshake => b"DoH handshake\0",
+            Span::Request => b"DoH request\0",
+            Span::Close => b"DoH close\0",
+        }
+    }
+
+    // Returns the cookie the section was begun with, or `None` if network tracing is off.
+    pub fn begin(span: Span) -> Option<i32> {
+        // Safety: libcutils initializes tracing on first use, and the names are static
+        // NUL-terminated strings.
+        unsafe {
+            if atrace_get_enabled_tags() & ATRACE_TAG_NETWORK == 0 {
+                return None;
+            }
+            let cookie = NEXT_COOKIE.fetch_add(1, Ordering::Relaxed);
+            atrace_async_begin_body(name(span).as_ptr() as *const c_char, cookie);
+            Some(cookie)
+        }
+    }
+
+    pub fn end(span: Span, cookie: i32) {
+        // Safety: as for `begin`.
+        unsafe { atrace_async_end_body(name(span).as_ptr() as *const c_char, cookie) }
+    }
+}
+
+impl Section {
+    /// Begins a section covering `span`, if network tracing is on.
+    #[cfg(feature = "atrace")]
+    pub fn begin(span: Span) -> Self {
+        Self { begun: sys::begin(span).map(|cookie| (span, cookie)) }
+    }
+
+    /// Begins a section covering `span`, if network tracing is on.
+    #[cfg(not(feature = "atrace"))]
+    pub fn begin(_span: Span) -> Self {
+        Self {}
+    }
+}
+
+#[cfg(feature = "atrace")]
+impl Drop for Section {
+    fn drop(&mut self) {
+        // A section which was begun is ended even if tracing has since been turned off, so a
+        // trace started again later doesn't show it as still open.
+        if let Some((span, cookie)) = self.begun {
+            sys::end(span, cookie);
+        }
+    }
+}
+
+#[cfg(all(test, not(feature = "atrace")))]
+mod tests {
+    use super::{Section, Span};
+
+    #[test]
+    fn disabled_sections_are_free() {
+        assert_eq!(std::mem::size_of::<Section>(), 0);
+        let _section = Section::begin(Span::Handshake);
+    }
+}
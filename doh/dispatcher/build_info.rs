@@ -0,0 +1,123 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Which QUIC and TLS stack the resolver is running on, for matching field reports to a build
+
+use libc::{c_char, c_int};
+use std::ffi::CStr;
+use std::fmt;
+
+extern "C" {
+    // Provided by the TLS library quiche links against, whichever it is.
+    fn OpenSSL_version(which: c_int) -> *const c_char;
+    fn FIPS_mode() -> c_int;
+}
+
+const OPENSSL_VERSION: c_int = 0;
+
+// Versions quiche has spoken in any release we might be linked with, newest first.
+const KNOWN_QUIC_VERSIONS: [u32; 4] = [0x0000_0001, 0xff00_001d, 0xff00_001c, 0xff00_001b];
+
+/// Description of the QUIC stack linked into the resolver, as returned by `build_info`
+///
+/// quiche 0.9 only reports its own crate version through its C API, which the Rust library
+/// doesn't build, so the QUIC versions it speaks stand in for it: they change with most quiche
+/// releases that matter for interoperability. quiche has no optional features which change its
+/// behaviour at run time, so only this crate's are listed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BuildInfo {
+    /// QUIC version connections offer unless configured otherwise
+    pub quic_version: u32,
+    /// Every QUIC version the linked quiche accepts, newest first
+    pub quic_versions: Vec<u32>,
+    /// Name the TLS library gives itself, such as "BoringSSL"
+    pub tls_library: String,
+    /// Whether the TLS library's cryptography runs in FIPS mode
+    pub fips: bool,
+    /// Optional features this crate was built with
+    pub features: Vec<&'static str>,
+}
+
+/// Describes the QUIC stack, asking the linked libraries rather than relying on the versions this
+/// crate was written against.
+pub fn build_info() -> BuildInfo {
+    // Safety: OpenSSL_version returns a pointer to a static NUL-terminated string, and FIPS_mode
+    // only reads global state.
+    let (tls_library, fips) = unsafe {
+        (
+            CStr::from_ptr(OpenSSL_version(OPENSSL_VERSION)).to_string_lossy().into_owned(),
+            FIPS_mode() != 0,
+        )
+    };
+    BuildInfo {
+        quic_version: quiche::PROTOCOL_VERSION,
+        quic_versions: KNOWN_QUIC_VERSIONS
+            .iter()
+            .copied()
+            .filter(|&version| quiche::version_is_supported(version))
+            .collect(),
+        tls_library,
+        fips,
+        features: enabled_features(),
+    }
+}
+
+fn enabled_features() -> Vec<&'static str> {
+    let mut features = Vec::new();
+    if cfg!(feature = "metrics_text") {
+        features.push("metrics_text");
+    }
+    if cfg!(feature = "packet_tape") {
+        features.push("packet_tape");
+    }
+    if cfg!(feature = "self_test") {
+        features.push("self_test");
+    }
+    if cfg!(feature = "atrace") {
+        features.push("atrace");
+    }
+    features
+}
+
+impl fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "quiche QUIC {:#x} (accepts", self.quic_version)?;
+        for version in &self.quic_versions {
+            write!(f, " {:#x}", version)?;
+        }
+        write!(f, "), {}", self.tls_library)?;
+        if self.fips {
+            write!(f, " FIPS")?;
+        }
+        write!(f, ", features [{}]", self.features.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::build_info;
+
+    #[test]
+    fn describes_linked_stack() {
+        let info = build_info();
+        assert_eq!(info.quic_version, quiche::PROTOCOL_VERSION);
+        assert!(info.quic_versions.contains(&quiche::PROTOCOL_VERSION));
+        assert_eq!(info.tls_library, "BoringSSL");
+        let text = info.to_string();
+        assert!(text.starts_with("quiche QUIC 0x"), "{}", text);
+        assert!(text.contains("BoringSSL"), "{}", text);
+    }
+}
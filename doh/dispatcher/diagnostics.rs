@@ -0,0 +1,260 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Step-by-step check of a DoH server, for showing the user where reaching it breaks down
+
+use crate::boot_time::{self, Duration, SharedClock};
+use crate::certificate::{CertInfo, CertObserver, CertOutcome, HandshakeReport};
+use crate::config::{Config, ConfigError};
+use crate::connection::{
+    stream_response, Connection, Direction, Environment, Negotiated, PacketSizeObserver,
+};
+use crate::encoding;
+use crate::network::ServerInfo;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use super::{ConnectFailure, DispatcherMetrics, Response};
+
+/// How one step of `Dispatcher::submit_diagnose` went
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Step<T> {
+    Passed(T),
+    /// Why the step failed, fit to show the user
+    Failed(String),
+    /// An earlier step failed, so this one wasn't tried
+    Skipped,
+}
+
+/// Details of a completed handshake
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HandshakeDiagnostics {
+    /// Time from setting up the socket until the handshake completed, which is at least a round
+    /// trip to the server
+    pub duration: Duration,
+    pub negotiated: Negotiated,
+}
+
+/// Details of the answer to the sample query
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueryDiagnostics {
+    /// Time from sending the query until the answer was complete
+    pub duration: Duration,
+    /// The connection's RTT estimate once the answer arrived
+    pub rtt: Duration,
+    pub rcode: u8,
+    pub answer_count: u16,
+}
+
+/// Outcome of `Dispatcher::submit_diagnose`, with a field for each step in the order they run
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostics {
+    /// Where the server was tried. Servers are configured by address, so there is no DNS lookup
+    /// of their name to report.
+    pub peer_addr: SocketAddr,
+    /// Whether anything came back from the server over UDP
+    pub reachability: Step<()>,
+    pub handshake: Step<HandshakeDiagnostics>,
+    /// The server's certificate, or `None` if it couldn't be parsed. As for queries, a server
+    /// without a cert path passes without its certificate being checked against a trust store.
+    pub certificate: Step<Option<CertInfo>>,
+    /// Answer to a probe query like the one validating a server sends
+    pub query: Step<QueryDiagnostics>,
+}
+
+impl Diagnostics {
+    fn new(peer_addr: SocketAddr) -> Self {
+        Self {
+            peer_addr,
+            reachability: Step::Skipped,
+            handshake: Step::Skipped,
+            certificate: Step::Skipped,
+            query: Step::Skipped,
+        }
+    }
+
+    /// Diagnostics for a server whose config couldn't be built, so nothing could be tried.
+    pub(super) fn no_config(peer_addr: SocketAddr, error: &ConfigError) -> Self {
+        Self {
+            handshake: Step::Failed(format!("Unable to configure the connection: {}", error)),
+            ..Self::new(peer_addr)
+        }
+    }
+}
+
+/// A line per step, for showing the user as is.
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Server {}", self.peer_addr)?;
+        write_step(f, "Reachability", &self.reachability, |()| String::new())?;
+        write_step(f, "Handshake", &self.handshake, |handshake| {
+            format!(" in {:?}, {:?}", handshake.duration, handshake.negotiated)
+        })?;
+        write_step(f, "Certificate", &self.certificate, |leaf| match leaf {
+            Some(leaf) => format!(", {:?}", leaf),
+            None => ", which could not be parsed".to_string(),
+        })?;
+        write_step(f, "Query", &self.query, |query| {
+            format!(
+                " in {:?}, RTT {:?}, rcode {}, {} answers",
+                query.duration, query.rtt, query.rcode, query.answer_count
+            )
+        })
+    }
+}
+
+fn write_step<T>(
+    f: &mut fmt::Formatter,
+    name: &str,
+    step: &Step<T>,
+    details: impl FnOnce(&T) -> String,
+) -> fmt::Result {
+    match step {
+        Step::Passed(value) => writeln!(f, "{}: passed{}", name, details(value)),
+        Step::Failed(reason) => writeln!(f, "{}: failed: {}", name, reason),
+        Step::Skipped => writeln!(f, "{}: skipped", name),
+    }
+}
+
+fn describe(failure: ConnectFailure) -> &'static str {
+    match failure {
+        ConnectFailure::Unreachable => "The server could not be reached",
+        ConnectFailure::HandshakeTimeout => {
+            "The handshake did not complete before the idle timeout"
+        }
+        ConnectFailure::TlsVerify => "The server's certificate did not verify",
+        ConnectFailure::VersionNegotiation => "The server supports none of our QUIC versions",
+        ConnectFailure::CaptivePortal => {
+            "The connection was intercepted, most likely by a captive portal to sign in to"
+        }
+        ConnectFailure::Other => "The connection closed during the handshake",
+    }
+}
+
+/// Runs each step against `info` on a connection of its own, which is closed on return. Steps
+/// share `timeout`; one that runs out of it fails, and those after it are skipped.
+pub(super) async fn diagnose(
+    info: ServerInfo,
+    mut config: Config,
+    env: Environment,
+    timeout: Duration,
+) -> Diagnostics {
+    let mut diagnostics = Diagnostics::new(info.peer_addr);
+    // Metrics of its own tell why this connection's handshake failed.
+    let metrics = Arc::new(DispatcherMetrics::default());
+    let received = Arc::new(AtomicUsize::new(0));
+    let packet_size_observer: PacketSizeObserver = {
+        let received = received.clone();
+        Arc::new(move |packet| {
+            if packet.direction == Direction::Inbound {
+                received.fetch_add(1, Ordering::Relaxed);
+            }
+        })
+    };
+    let report = Arc::new(Mutex::new(None));
+    let cert_observer: CertObserver = {
+        let report = report.clone();
+        Arc::new(move |handshake: &HandshakeReport| {
+            *report.lock().unwrap() = Some(handshake.clone());
+        })
+    };
+
+    let env = Environment {
+        metrics: metrics.clone(),
+        cert_observer: Some(cert_observer),
+        packet_size_observer: Some(packet_size_observer),
+        ..env
+    };
+    let clock = env.clock.clone();
+    let started = clock.now();
+    let options = info.connection_options.clone();
+    let connection = Connection::new(info.peer(), &mut config, None, options, &env).await;
+    let mut connection = match connection {
+        Ok(connection) => connection,
+        Err(e) => {
+            diagnostics.reachability = Step::Failed(format!("Unable to set up a socket: {}", e));
+            return diagnostics;
+        }
+    };
+    let live = boot_time::timeout(timeout, connection.wait_for_live()).await;
+    let handshake_time = clock.elapsed(started);
+    diagnostics.reachability = if received.load(Ordering::Relaxed) > 0 {
+        Step::Passed(())
+    } else {
+        Step::Failed("Nothing came back from the server".to_string())
+    };
+    let failure =
+        ConnectFailure::ALL.iter().copied().find(|&cause| metrics.connection_failures(cause) > 0);
+    diagnostics.certificate = match report.lock().unwrap().take() {
+        Some(HandshakeReport { outcome: CertOutcome::Accepted, leaf, .. }) => Step::Passed(leaf),
+        Some(HandshakeReport { outcome: CertOutcome::Rejected, .. }) => {
+            Step::Failed(describe(ConnectFailure::TlsVerify).to_string())
+        }
+        None if failure == Some(ConnectFailure::TlsVerify) => {
+            Step::Failed(describe(ConnectFailure::TlsVerify).to_string())
+        }
+        None => Step::Skipped,
+    };
+    diagnostics.handshake = match (live, connection.monitor().info().negotiated) {
+        (Ok(true), Some(negotiated)) => {
+            Step::Passed(HandshakeDiagnostics { duration: handshake_time, negotiated })
+        }
+        (Err(_), _) => Step::Failed(format!("The handshake took longer than {:?}", timeout)),
+        _ => Step::Failed(describe(failure.unwrap_or(ConnectFailure::Other)).to_string()),
+    };
+    if !matches!(diagnostics.handshake, Step::Passed(_)) {
+        return diagnostics;
+    }
+
+    let remaining = timeout.checked_sub(clock.elapsed(started)).unwrap_or_default();
+    diagnostics.query =
+        match encoding::probe_query().and_then(|query| encoding::dns_request(&query, &info.url)) {
+            Ok(request) => query(&connection, request, &clock, remaining).await,
+            Err(e) => Step::Failed(format!("Unable to build the query: {}", e)),
+        };
+    diagnostics
+}
+
+async fn query(
+    connection: &Connection,
+    request: encoding::DnsRequest,
+    clock: &SharedClock,
+    timeout: Duration,
+) -> Step<QueryDiagnostics> {
+    let submitted = clock.now();
+    let stream =
+        match connection.query(request, submitted, submitted.checked_add(timeout), None).await {
+            Ok(stream) => stream,
+            Err(e) => return Step::Failed(format!("Unable to send the query: {}", e)),
+        };
+    let stream = match boot_time::timeout(timeout, stream).await {
+        Ok(stream) => stream,
+        Err(_) => return Step::Failed(format!("No answer within {:?}", timeout)),
+    };
+    let duration = clock.elapsed(submitted);
+    let rtt = stream.as_ref().map(|stream| stream.stats.rtt).unwrap_or_default();
+    match stream_response(stream) {
+        Response::Success { metadata, .. } => Step::Passed(QueryDiagnostics {
+            duration,
+            rtt,
+            rcode: metadata.rcode,
+            answer_count: metadata.answer_count,
+        }),
+        Response::Error { error } => Step::Failed(format!("The query failed: {:?}", error)),
+    }
+}
@@ -16,22 +16,34 @@
 
 //! Provides a backing task to implement a Dispatcher
 
-use crate::boot_time::{BootTime, Duration};
+use crate::boot_time::{self, Duration};
 use anyhow::{bail, Result};
+use futures::stream::{FuturesUnordered, StreamExt};
 use log::{debug, trace, warn};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 use tokio::sync::{mpsc, oneshot};
+use tokio::task;
 
-use super::{Command, QueryError, Response};
-use crate::network::{Network, ServerInfo, SocketTagger, ValidationReporter};
-use crate::{config, network};
+use super::diagnostics::{self, Diagnostics};
+use super::{Command, QueryError, Response, TrimSummary};
+use crate::config::Config;
+use crate::connection::{Connection, Environment};
+use crate::network::{Network, ServerInfo, SessionStore, ValidationReporter};
+use crate::{config, encoding, network};
 
 pub struct Driver {
     command_rx: mpsc::Receiver<Command>,
+    // Each `Network` owns its connection, so keying by net_id keeps connections from being
+    // shared across networks even when their `Config` is the same cache entry.
     networks: HashMap<u32, Network>,
+    // Networks reported lost which haven't been probed since.
+    lost_networks: HashSet<u32>,
     validation: ValidationReporter,
-    tagger: SocketTagger,
+    env: Environment,
     config_cache: config::Cache,
+    session_store: Arc<SessionStore>,
+    fresh_connection_cert_paths: HashSet<String>,
 }
 
 fn debug_err(r: Result<()>) {
@@ -40,18 +52,78 @@ fn debug_err(r: Result<()>) {
     }
 }
 
+pub(super) fn config_key(info: &ServerInfo) -> config::Key {
+    config::Key {
+        cert_path: info.cert_path.clone(),
+        cert_pem: info.cert_pem.clone(),
+        max_idle_timeout: info.idle_timeout_ms,
+        max_response_size: info.connection_options.max_response_size,
+        quic_versions: info.connection_options.quic_versions.clone(),
+        disable_active_migration: !info.connection_options.active_migration,
+        enable_early_data: false,
+        transport: info.transport_params.clone(),
+        stream_mode: info.connection_options.stream_mode,
+        extra_application_protos: info.extra_application_protos.clone(),
+    }
+}
+
+async fn one_shot_query(
+    info: ServerInfo,
+    mut config: Config,
+    env: Environment,
+    mut query: Vec<u8>,
+    timeout: Duration,
+) -> Response {
+    let message_id = match encoding::set_message_id(&mut query, 0) {
+        Ok(message_id) => message_id,
+        Err(e) => {
+            debug!("Unable to read the ID of one-shot query: {:?}", e);
+            return Response::Error { error: QueryError::MalformedQuery };
+        }
+    };
+    let options = info.connection_options.clone();
+    let connection = Connection::new(info.peer(), &mut config, None, options, &env).await;
+    let mut connection = match connection {
+        Ok(connection) => connection,
+        Err(e) => {
+            debug!("Unable to connect for one-shot query: {:?}", e);
+            return Response::Error { error: QueryError::ConnectionError };
+        }
+    };
+    if !connection.wait_for_live().await {
+        return Response::Error { error: QueryError::ConnectionError };
+    }
+    // The connection is dropped on return, which shuts it down and releases an ephemeral config.
+    match connection.dns_query(&info.url, &query, timeout).await {
+        Ok(response) => {
+            let response = network::check_truncation(info.fail_truncated_answers, response.await);
+            network::restore_message_id(message_id, response)
+        }
+        Err(e) => {
+            debug!("Unable to send one-shot query: {:?}", e);
+            Response::Error { error: QueryError::ConnectionError }
+        }
+    }
+}
+
 impl Driver {
     pub fn new(
         command_rx: mpsc::Receiver<Command>,
         validation: ValidationReporter,
-        tagger: SocketTagger,
+        env: Environment,
+        session_store: Arc<SessionStore>,
+        config_cache: config::Cache,
+        fresh_connection_cert_paths: HashSet<String>,
     ) -> Self {
         Self {
             command_rx,
             networks: HashMap::new(),
+            lost_networks: HashSet::new(),
             validation,
-            tagger,
-            config_cache: config::Cache::new(),
+            env,
+            config_cache,
+            session_store,
+            fresh_connection_cert_paths,
         }
     }
 
@@ -66,13 +138,68 @@ impl Driver {
             trace!("dispatch command: {:?}", command);
             match command {
                 Command::Probe { info, timeout } => debug_err(self.probe(info, timeout).await),
-                Command::Query { net_id, base64_query, expired_time, resp } => {
-                    debug_err(self.query(net_id, base64_query, expired_time, resp).await)
+                Command::Query {
+                    net_id,
+                    base64_query,
+                    submitted,
+                    expired_time,
+                    max_response_size,
+                    priority,
+                    extra_headers,
+                    message_id,
+                    force_full_handshake,
+                    queued,
+                    resp,
+                } => {
+                    drop(queued);
+                    let query = network::Query {
+                        query: base64_query,
+                        response: resp,
+                        submitted,
+                        expiry: expired_time,
+                        max_response_size,
+                        priority,
+                        extra_headers,
+                        is_retry: false,
+                        message_id,
+                        force_full_handshake,
+                    };
+                    debug_err(self.query(net_id, query).await)
+                }
+                Command::OneShot { info, config, query, timeout, resp } => {
+                    self.one_shot(info, config, query, timeout, resp)
+                }
+                Command::Hedged { servers, hedge_delay, query, timeout, resp } => {
+                    self.hedged(servers, hedge_delay, query, timeout, resp)
                 }
+                Command::Diagnose { info, timeout, resp } => self.diagnose(info, timeout, resp),
                 Command::Clear { net_id } => {
                     self.networks.remove(&net_id);
+                    self.lost_networks.remove(&net_id);
+                    self.session_store.clear_network(net_id);
+                    self.config_cache.garbage_collect();
+                }
+                Command::NetworkLost { net_id } => {
+                    if let Some(network) = self.networks.remove(&net_id) {
+                        network.lost();
+                    }
+                    self.lost_networks.insert(net_id);
+                    self.session_store.clear_network(net_id);
                     self.config_cache.garbage_collect();
                 }
+                Command::ListConnections { resp } => {
+                    let mut connections: Vec<_> =
+                        self.networks.values().map(Network::connection_info).collect();
+                    connections.sort_by_key(|connection| connection.net_id);
+                    // We don't care if the requestor has gone.
+                    let _ = resp.send(connections);
+                }
+                Command::TrimMemory { aggressive, resp } => {
+                    let summary = self.trim_memory(aggressive);
+                    debug!("Trimmed memory: {:?}", summary);
+                    // We don't care if the requestor has gone.
+                    let _ = resp.send(summary);
+                }
                 Command::Exit => {
                     bail!("Death due to Exit")
                 }
@@ -83,45 +210,177 @@ impl Driver {
         }
     }
 
-    async fn query(
-        &mut self,
-        net_id: u32,
-        query: String,
-        expiry: BootTime,
-        response: oneshot::Sender<Response>,
-    ) -> Result<()> {
+    fn trim_memory(&mut self, aggressive: bool) -> TrimSummary {
+        let buffers_freed = self.env.metrics.buffer_pool().trim();
+        if !aggressive {
+            let configs_dropped = self.config_cache.garbage_collect();
+            return TrimSummary { configs_dropped, buffers_freed, ..Default::default() };
+        }
+        let connections_retired =
+            self.networks.values().filter(|network| network.retire_if_idle()).count();
+        let configs_dropped = self.config_cache.trim();
+        TrimSummary { connections_retired, configs_dropped, buffers_freed }
+    }
+
+    async fn query(&mut self, net_id: u32, query: network::Query) -> Result<()> {
         if let Some(network) = self.networks.get_mut(&net_id) {
-            network.query(network::Query { query, response, expiry }).await?;
+            network.query(query).await?;
+        } else if self.lost_networks.contains(&net_id) {
+            debug!("Failing query for lost network net_id={}", net_id);
+            let _ = query.response.send(Response::Error { error: QueryError::NetworkLost });
         } else {
             warn!("Tried to send a query to non-existent network net_id={}", net_id);
-            response.send(Response::Error { error: QueryError::Unexpected }).unwrap_or_else(|_| {
-                warn!("Unable to send reply for non-existent network net_id={}", net_id);
-            })
+            query.response.send(Response::Error { error: QueryError::Unexpected }).unwrap_or_else(
+                |_| {
+                    warn!("Unable to send reply for non-existent network net_id={}", net_id);
+                },
+            )
         }
         Ok(())
     }
 
+    fn one_shot(
+        &self,
+        info: ServerInfo,
+        config: Option<Config>,
+        query: Vec<u8>,
+        timeout: Duration,
+        response: oneshot::Sender<Response>,
+    ) {
+        // Deliberately bypass the cache so a one-off query doesn't displace configs in use.
+        let config = match config {
+            Some(config) => Ok(config),
+            None => Config::from_key(&config_key(&info)),
+        };
+        let config = match config {
+            Ok(config) => config,
+            Err(e) => {
+                warn!("Unable to build config for one-shot query: {:?}", e);
+                let _ = response.send(Response::Error { error: QueryError::BrokenServer });
+                return;
+            }
+        };
+        let env = self.env.clone();
+        // The query runs in its own task so the dispatcher can keep serving other commands
+        // while the connection is set up.
+        task::spawn(self.env.metrics.track(async move {
+            let query = one_shot_query(info, config, env, query, timeout);
+            let result = boot_time::timeout(timeout, query)
+                .await
+                .unwrap_or(Response::Error { error: QueryError::Timeout });
+            // We don't care if the response is gone.
+            let _ = response.send(result);
+        }));
+    }
+
+    fn hedged(
+        &self,
+        servers: Vec<ServerInfo>,
+        hedge_delay: Duration,
+        query: Vec<u8>,
+        timeout: Duration,
+        response: oneshot::Sender<Response>,
+    ) {
+        let mut attempts = Vec::with_capacity(servers.len());
+        for info in servers {
+            // As for one-shot queries, the cache is bypassed.
+            let config = match Config::from_key(&config_key(&info)) {
+                Ok(config) => config,
+                Err(e) => {
+                    warn!("Unable to build config for hedged query to {}: {:?}", info.peer_addr, e);
+                    continue;
+                }
+            };
+            let delay = hedge_delay * attempts.len() as u32;
+            let attempt = one_shot_query(info, config, self.env.clone(), query.clone(), timeout);
+            attempts.push(async move {
+                boot_time::sleep(delay).await;
+                attempt.await
+            });
+        }
+        // `Dispatcher::submit_hedged` turns away empty lists, so this is every config failing.
+        if attempts.is_empty() {
+            let _ = response.send(Response::Error { error: QueryError::BrokenServer });
+            return;
+        }
+        task::spawn(self.env.metrics.track(async move {
+            let race = async {
+                let mut attempts: FuturesUnordered<_> = attempts.into_iter().collect();
+                let mut result = Response::Error { error: QueryError::BrokenServer };
+                while let Some(attempt) = attempts.next().await {
+                    result = attempt;
+                    if let Response::Success { .. } = result {
+                        break;
+                    }
+                }
+                // Dropping the attempts still running closes their connections, and those yet
+                // to start never connect.
+                result
+            };
+            let result = boot_time::timeout(timeout, race)
+                .await
+                .unwrap_or(Response::Error { error: QueryError::Timeout });
+            // We don't care if the response is gone.
+            let _ = response.send(result);
+        }));
+    }
+
+    fn diagnose(
+        &self,
+        info: ServerInfo,
+        timeout: Duration,
+        response: oneshot::Sender<Diagnostics>,
+    ) {
+        // As for one-shot queries, the cache is bypassed.
+        let config = match Config::from_key(&config_key(&info)) {
+            Ok(config) => config,
+            Err(e) => {
+                let _ = response.send(Diagnostics::no_config(info.peer_addr, &e));
+                return;
+            }
+        };
+        let env = self.env.clone();
+        task::spawn(self.env.metrics.track(async move {
+            let diagnostics = diagnostics::diagnose(info, config, env, timeout).await;
+            debug!("Diagnosed server: {:?}", diagnostics);
+            // We don't care if the response is gone.
+            let _ = response.send(diagnostics);
+        }));
+    }
+
     async fn probe(&mut self, info: ServerInfo, timeout: Duration) -> Result<()> {
         use std::collections::hash_map::Entry;
+        // Probing provides a server for the network, so it is no longer lost.
+        self.lost_networks.remove(&info.net_id);
         if !self.networks.get(&info.net_id).map_or(true, |net| net.get_info() == &info) {
             // If we have a network registered to the provided net_id, but the server info doesn't
             // match, our API has been used incorrectly. Attempt to recover by deleting the old
-            // network and recreating it according to the probe request.
+            // network and recreating it according to the probe request. This also covers a
+            // changed socket binding, so a connection is never reused with different routing.
             warn!("Probing net_id={} with mismatched server info {:?}", info.net_id, info);
             self.networks.remove(&info.net_id);
         }
+        let reuse_connections = !matches!(
+            &info.cert_path,
+            Some(path) if self.fresh_connection_cert_paths.contains(path)
+        );
         // Can't use or_insert_with because creating a network may fail
         let net = match self.networks.entry(info.net_id) {
             Entry::Occupied(network) => network.into_mut(),
             Entry::Vacant(vacant) => {
-                let key = config::Key {
-                    cert_path: info.cert_path.clone(),
-                    max_idle_timeout: info.idle_timeout_ms,
-                };
-                let config = self.config_cache.get(&key)?;
+                // The trust store is parsed on the blocking pool, so that the runtime's thread
+                // keeps driving the other networks' connections meanwhile.
+                let config = self.config_cache.get_async(&config_key(&info)).await?;
                 vacant.insert(
-                    Network::new(info, config, self.validation.clone(), self.tagger.clone())
-                        .await?,
+                    Network::new(
+                        info,
+                        config,
+                        self.validation.clone(),
+                        self.env.clone(),
+                        self.session_store.clone(),
+                        reuse_connections,
+                    )
+                    .await?,
                 )
             }
         };
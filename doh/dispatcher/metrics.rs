@@ -0,0 +1,505 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Counters describing the work handled by a Dispatcher
+
+#[cfg(feature = "metrics_text")]
+use crate::config::CacheStats;
+use crate::connection::{HandshakeLimiter, SharedBufferPool};
+#[cfg(feature = "metrics_text")]
+use std::fmt::Write;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Why an attempt to establish a connection failed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectFailure {
+    /// Sending or receiving failed, typically because the server's host or port is unreachable
+    Unreachable,
+    /// Nothing stopped the handshake from completing, but it didn't before the idle timeout
+    HandshakeTimeout,
+    /// The TLS handshake failed, most commonly because the server's certificate didn't verify
+    TlsVerify,
+    /// The server supports none of our QUIC versions
+    VersionNegotiation,
+    /// The TLS handshake failed with a certificate for another name than the server's, which is
+    /// what a captive portal intercepting the connection presents. Signing in to the portal
+    /// should let the server be reached.
+    CaptivePortal,
+    /// Anything else, such as the server closing the connection during the handshake
+    Other,
+}
+
+impl ConnectFailure {
+    const COUNT: usize = 6;
+
+    pub(super) const ALL: [ConnectFailure; Self::COUNT] = [
+        Self::Unreachable,
+        Self::HandshakeTimeout,
+        Self::TlsVerify,
+        Self::VersionNegotiation,
+        Self::CaptivePortal,
+        Self::Other,
+    ];
+
+    #[cfg(feature = "metrics_text")]
+    fn label(self) -> &'static str {
+        match self {
+            Self::Unreachable => "unreachable",
+            Self::HandshakeTimeout => "handshake_timeout",
+            Self::TlsVerify => "tls_verify",
+            Self::VersionNegotiation => "version_negotiation",
+            Self::CaptivePortal => "captive_portal",
+            Self::Other => "other",
+        }
+    }
+}
+
+/// Every counter of a `DispatcherMetrics`, read together by `DispatcherMetrics::snapshot`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub queued_queries: usize,
+    pub overloaded_queries: u64,
+    pub connection_rotations: u64,
+    pub connection_attempts: u64,
+    pub connection_successes: u64,
+    connection_failures: [u64; ConnectFailure::COUNT],
+    pub zombies_reaped: u64,
+    pub response_cache_entries: usize,
+    pub response_cache_bytes: usize,
+    pub buffer_high_water_mark: usize,
+    pub handshakes_in_progress: usize,
+}
+
+impl MetricsSnapshot {
+    /// As `DispatcherMetrics::connection_failures`.
+    pub fn connection_failures(&self, cause: ConnectFailure) -> u64 {
+        self.connection_failures[cause as usize]
+    }
+}
+
+/// Dispatcher-wide counters, shared between the `Dispatcher` handle and its driver task.
+#[derive(Debug, Default)]
+pub struct DispatcherMetrics {
+    queued_queries: AtomicUsize,
+    overloaded_queries: AtomicU64,
+    connection_rotations: AtomicU64,
+    connection_attempts: AtomicU64,
+    connection_successes: AtomicU64,
+    // Indexed by `ConnectFailure`.
+    connection_failures: [AtomicU64; ConnectFailure::COUNT],
+    zombies_reaped: AtomicU64,
+    // Summed over every network's response cache.
+    response_cache_entries: AtomicUsize,
+    response_cache_bytes: AtomicUsize,
+    // Lives here so its counters are reported with the rest.
+    buffer_pool: SharedBufferPool,
+    handshake_limiter: HandshakeLimiter,
+    // Tasks wrapped by `track` which haven't finished.
+    active_tasks: AtomicUsize,
+}
+
+// Counts a tracked task for as long as its future is alive.
+struct ActiveTask(Arc<DispatcherMetrics>);
+
+impl Drop for ActiveTask {
+    fn drop(&mut self) {
+        self.0.active_tasks.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts a submitted query in `queued_queries` for as long as it is alive, so a query is no
+/// longer counted once the driver picks it up, or once it is dropped without being picked up.
+#[derive(Debug)]
+pub struct QueuedQuery(Arc<DispatcherMetrics>);
+
+impl Drop for QueuedQuery {
+    fn drop(&mut self) {
+        self.0.queued_queries.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl DispatcherMetrics {
+    /// Creates counters for a dispatcher letting up to `max_concurrent_handshakes` of its
+    /// connections handshake at once, or any number if `None`.
+    pub(crate) fn new(max_concurrent_handshakes: Option<usize>) -> Self {
+        Self {
+            handshake_limiter: HandshakeLimiter::new(max_concurrent_handshakes),
+            ..Default::default()
+        }
+    }
+
+    /// Number of queries submitted but not yet picked up by the driver.
+    pub fn queued_queries(&self) -> usize {
+        self.queued_queries.load(Ordering::Relaxed)
+    }
+
+    /// Number of queries rejected because the submission queue was full.
+    pub fn overloaded_queries(&self) -> u64 {
+        self.overloaded_queries.load(Ordering::Relaxed)
+    }
+
+    /// Number of connections retired for reaching `ServerInfo::max_queries_per_connection`.
+    pub fn connection_rotations(&self) -> u64 {
+        self.connection_rotations.load(Ordering::Relaxed)
+    }
+
+    /// Number of connections the dispatcher has tried to establish. Attempts abandoned before
+    /// their handshake succeeded or failed, such as on a network which was lost, count here
+    /// without an outcome.
+    pub fn connection_attempts(&self) -> u64 {
+        self.connection_attempts.load(Ordering::Relaxed)
+    }
+
+    /// Number of connections whose handshake completed.
+    pub fn connection_successes(&self) -> u64 {
+        self.connection_successes.load(Ordering::Relaxed)
+    }
+
+    /// Number of connection attempts which failed for `cause`.
+    pub fn connection_failures(&self, cause: ConnectFailure) -> u64 {
+        self.connection_failures[cause as usize].load(Ordering::Relaxed)
+    }
+
+    /// Number of connections torn down for being stuck handshaking or closing for longer than
+    /// `connection::Options::zombie_timeout`. A stuck handshake also counts as a failed attempt.
+    pub fn zombies_reaped(&self) -> u64 {
+        self.zombies_reaped.load(Ordering::Relaxed)
+    }
+
+    /// Number of ETag-validated answers held for conditional requests, across all networks.
+    pub fn response_cache_entries(&self) -> usize {
+        self.response_cache_entries.load(Ordering::Relaxed)
+    }
+
+    /// Bytes those answers take up, with their queries and ETags, as counted against
+    /// `ResponseCacheLimits::max_bytes`.
+    pub fn response_cache_bytes(&self) -> usize {
+        self.response_cache_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Most packet buffers borrowed at once by the dispatcher's connections.
+    pub fn buffer_high_water_mark(&self) -> usize {
+        self.buffer_pool.high_water_mark()
+    }
+
+    /// Number of connections whose handshake is in progress, not counting those still waiting
+    /// for a slot.
+    pub fn handshakes_in_progress(&self) -> usize {
+        self.handshake_limiter.in_progress()
+    }
+
+    /// Reads every counter at once, so that totals reported together agree with each other.
+    ///
+    /// The counters are updated independently, so reading them one after the other could see
+    /// some of the updates made meanwhile and not others, such as an attempt's failure but not
+    /// the attempt. The snapshot is only returned once two reads in a row have found the same
+    /// values, which means nothing changed in between and the values all held at once. A busy
+    /// dispatcher may keep changing them, so after a few tries the last read is returned anyway,
+    /// with each counter still correct but possibly a few updates apart from the others.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        const MAX_READS: usize = 4;
+        let mut snapshot = self.read_all();
+        for _ in 1..MAX_READS {
+            let again = self.read_all();
+            if again == snapshot {
+                break;
+            }
+            snapshot = again;
+        }
+        snapshot
+    }
+
+    fn read_all(&self) -> MetricsSnapshot {
+        let mut connection_failures = [0; ConnectFailure::COUNT];
+        for cause in ConnectFailure::ALL {
+            connection_failures[cause as usize] = self.connection_failures(cause);
+        }
+        MetricsSnapshot {
+            queued_queries: self.queued_queries(),
+            overloaded_queries: self.overloaded_queries(),
+            connection_rotations: self.connection_rotations(),
+            connection_attempts: self.connection_attempts(),
+            connection_successes: self.connection_successes(),
+            connection_failures,
+            zombies_reaped: self.zombies_reaped(),
+            response_cache_entries: self.response_cache_entries(),
+            response_cache_bytes: self.response_cache_bytes(),
+            buffer_high_water_mark: self.buffer_high_water_mark(),
+            handshakes_in_progress: self.handshakes_in_progress(),
+        }
+    }
+
+    /// Number of the dispatcher's tasks which are still running or waiting to run: its driver,
+    /// and the network, connection and query tasks it has spawned. A task which outlives the
+    /// dispatcher's shutdown keeps this above zero. Only read by tests, which check that shutdown
+    /// leaves nothing running.
+    #[cfg(test)]
+    pub fn active_tasks(&self) -> usize {
+        self.active_tasks.load(Ordering::Relaxed)
+    }
+
+    /// Wraps a future about to be spawned as one of the dispatcher's tasks, so that it is
+    /// counted in `active_tasks` until it completes or is dropped.
+    pub(crate) fn track<F: Future>(self: &Arc<Self>, future: F) -> impl Future<Output = F::Output> {
+        self.active_tasks.fetch_add(1, Ordering::Relaxed);
+        let task = ActiveTask(self.clone());
+        async move {
+            let _task = task;
+            future.await
+        }
+    }
+
+    /// Pool the dispatcher's connections borrow packet buffers from.
+    pub(crate) fn buffer_pool(&self) -> SharedBufferPool {
+        self.buffer_pool.clone()
+    }
+
+    /// Slots the dispatcher's connections wait for before handshaking.
+    pub(crate) fn handshake_limiter(&self) -> &HandshakeLimiter {
+        &self.handshake_limiter
+    }
+
+    pub(super) fn query_queued(self: &Arc<Self>) -> QueuedQuery {
+        self.queued_queries.fetch_add(1, Ordering::Relaxed);
+        QueuedQuery(self.clone())
+    }
+
+    pub(super) fn query_overloaded(&self) {
+        self.overloaded_queries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_rotated(&self) {
+        self.connection_rotations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_attempted(&self) {
+        self.connection_attempts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_established(&self) {
+        self.connection_successes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_failed(&self, cause: ConnectFailure) {
+        self.connection_failures[cause as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn zombie_reaped(&self) {
+        self.zombies_reaped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn response_cached(&self, bytes: usize) {
+        self.response_cache_entries.fetch_add(1, Ordering::Relaxed);
+        self.response_cache_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn response_uncached(&self, bytes: usize) {
+        self.response_cache_entries.fetch_sub(1, Ordering::Relaxed);
+        self.response_cache_bytes.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+#[cfg(feature = "metrics_text")]
+impl DispatcherMetrics {
+    /// Renders these counters, and the config cache's `cache`, in the Prometheus text exposition
+    /// format. Only built with the `metrics_text` feature, for monitoring outside Android.
+    pub fn render_text(&self, cache: &CacheStats) -> String {
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, String)]| {
+            // Writing to a `String` can't fail.
+            let _ = writeln!(text, "# HELP doh_{} {}\n# TYPE doh_{} {}", name, help, name, kind);
+            for (labels, value) in samples {
+                let _ = writeln!(text, "doh_{}{} {}", name, labels, value);
+            }
+        };
+        let single = |value: String| [(String::new(), value)];
+        let snapshot = self.snapshot();
+        metric(
+            "queued_queries",
+            "gauge",
+            "Queries submitted but not yet picked up by the driver.",
+            &single(snapshot.queued_queries.to_string()),
+        );
+        metric(
+            "overloaded_queries_total",
+            "counter",
+            "Queries rejected because the submission queue was full.",
+            &single(snapshot.overloaded_queries.to_string()),
+        );
+        metric(
+            "connection_rotations_total",
+            "counter",
+            "Connections retired for reaching their query limit.",
+            &single(snapshot.connection_rotations.to_string()),
+        );
+        metric(
+            "connection_attempts_total",
+            "counter",
+            "Connections the dispatcher has tried to establish.",
+            &single(snapshot.connection_attempts.to_string()),
+        );
+        metric(
+            "connection_successes_total",
+            "counter",
+            "Connections whose handshake completed.",
+            &single(snapshot.connection_successes.to_string()),
+        );
+        let failures: Vec<_> = ConnectFailure::ALL
+            .iter()
+            .map(|&cause| {
+                let labels = format!("{{cause=\"{}\"}}", cause.label());
+                (labels, snapshot.connection_failures(cause).to_string())
+            })
+            .collect();
+        metric(
+            "connection_failures_total",
+            "counter",
+            "Connection attempts which failed, by cause.",
+            &failures,
+        );
+        metric(
+            "zombies_reaped_total",
+            "counter",
+            "Connections torn down for being stuck handshaking or closing.",
+            &single(snapshot.zombies_reaped.to_string()),
+        );
+        metric(
+            "response_cache_entries",
+            "gauge",
+            "Answers held for conditional requests.",
+            &single(snapshot.response_cache_entries.to_string()),
+        );
+        metric(
+            "response_cache_bytes",
+            "gauge",
+            "Bytes taken up by answers held for conditional requests.",
+            &single(snapshot.response_cache_bytes.to_string()),
+        );
+        metric(
+            "buffer_high_water_mark",
+            "gauge",
+            "Most packet buffers borrowed at once.",
+            &single(snapshot.buffer_high_water_mark.to_string()),
+        );
+        metric(
+            "handshakes_in_progress",
+            "gauge",
+            "Connections whose handshake is in progress.",
+            &single(snapshot.handshakes_in_progress.to_string()),
+        );
+        metric(
+            "config_constructions_total",
+            "counter",
+            "QUIC configs built by the config cache.",
+            &single(cache.constructions.to_string()),
+        );
+        metric(
+            "config_construction_seconds_total",
+            "counter",
+            "Time spent building QUIC configs.",
+            &single(cache.total_construction_time.as_secs_f64().to_string()),
+        );
+        metric(
+            "config_construction_seconds_max",
+            "gauge",
+            "Longest time spent building a single QUIC config.",
+            &single(cache.max_construction_time.as_secs_f64().to_string()),
+        );
+        metric(
+            "config_lookups_total",
+            "counter",
+            "Config cache lookups, by whether a live config was found.",
+            &[
+                ("{result=\"hit\"}".to_string(), cache.hits.to_string()),
+                ("{result=\"miss\"}".to_string(), cache.misses.to_string()),
+            ],
+        );
+        metric(
+            "config_build_races_total",
+            "counter",
+            "QUIC configs discarded for an identical one built at the same time.",
+            &single(cache.races.to_string()),
+        );
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConnectFailure, DispatcherMetrics};
+    use std::sync::Arc;
+
+    #[cfg(feature = "metrics_text")]
+    #[test]
+    fn text_exposition() {
+        use crate::config::CacheStats;
+        use std::time::Duration;
+        let metrics = DispatcherMetrics::default();
+        metrics.connection_attempted();
+        metrics.connection_attempted();
+        metrics.connection_failed(ConnectFailure::TlsVerify);
+        let cache = CacheStats {
+            constructions: 3,
+            total_construction_time: Duration::from_millis(1500),
+            max_construction_time: Duration::from_millis(750),
+            hits: 5,
+            ..Default::default()
+        };
+        let text = metrics.render_text(&cache);
+        let lines: Vec<_> = text.lines().collect();
+        assert!(lines.contains(&"# TYPE doh_connection_attempts_total counter"));
+        assert!(lines.contains(&"doh_connection_attempts_total 2"));
+        assert!(lines.contains(&"doh_connection_failures_total{cause=\"tls_verify\"} 1"));
+        assert!(lines.contains(&"doh_connection_failures_total{cause=\"unreachable\"} 0"));
+        assert!(lines.contains(&"doh_handshakes_in_progress 0"));
+        assert!(lines.contains(&"doh_zombies_reaped_total 0"));
+        assert!(lines.contains(&"doh_response_cache_bytes 0"));
+        assert!(lines.contains(&"doh_config_constructions_total 3"));
+        assert!(lines.contains(&"doh_config_construction_seconds_total 1.5"));
+        assert!(lines.contains(&"doh_config_construction_seconds_max 0.75"));
+        assert!(lines.contains(&"doh_config_lookups_total{result=\"hit\"} 5"));
+        assert!(lines.contains(&"doh_config_build_races_total 0"));
+        // Every sample follows the HELP and TYPE lines of its metric.
+        assert!(lines.iter().all(|line| line.starts_with("# ") || line.starts_with("doh_")));
+    }
+
+    #[test]
+    fn snapshot() {
+        let metrics = Arc::new(DispatcherMetrics::new(Some(2)));
+        let queued = metrics.query_queued();
+        metrics.connection_attempted();
+        metrics.connection_attempted();
+        metrics.connection_established();
+        metrics.connection_failed(ConnectFailure::Unreachable);
+        metrics.zombie_reaped();
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.queued_queries, 1);
+        assert_eq!(snapshot.connection_attempts, 2);
+        assert_eq!(snapshot.connection_successes, 1);
+        assert_eq!(snapshot.connection_failures(ConnectFailure::Unreachable), 1);
+        assert_eq!(snapshot.connection_failures(ConnectFailure::TlsVerify), 0);
+        assert_eq!(snapshot.zombies_reaped, 1);
+        assert_eq!(snapshot.overloaded_queries, 0);
+        // A snapshot is a copy, unaffected by later updates.
+        drop(queued);
+        assert_eq!(metrics.queued_queries(), 0);
+        metrics.connection_attempted();
+        assert_eq!(snapshot.connection_attempts, 2);
+        assert_eq!(metrics.snapshot().connection_attempts, 3);
+    }
+}
@@ -14,20 +14,42 @@
  * limitations under the License.
  */
 
-use crate::boot_time::{BootTime, Duration};
+use crate::boot_time::{self, timeout, BootTime, Duration, SharedClock};
+use crate::config;
+use crate::connection::Environment;
+use crate::encoding;
 use anyhow::Result;
-use log::error;
+use log::{debug, error, info, warn};
+use quiche::h3;
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::Arc;
+use thiserror::Error;
 use tokio::runtime::{Builder, Runtime};
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::{mpsc, oneshot};
 use tokio::task;
 
-pub use crate::network::{ServerInfo, SocketTagger, ValidationReporter};
+pub use crate::certificate::CertObserver;
+pub use crate::config::{CacheStats, Config, ResidentConfig, StreamMode};
+pub use crate::connection::{ConnectionInfo, PacketSizeObserver};
+pub use crate::encoding::{Edns, Priority};
+pub use crate::network::{
+    ProvidedSocket, ServerInfo, SessionStore, SocketBinding, SocketTagger, ValidationReporter,
+};
 
 const MAX_BUFFERED_CMD_COUNT: usize = 400;
 
+mod build_info;
+mod diagnostics;
 mod driver;
+mod metrics;
 use driver::Driver;
 
+pub use build_info::build_info;
+pub use diagnostics::Diagnostics;
+pub use metrics::{ConnectFailure, DispatcherMetrics, MetricsSnapshot};
+
 #[derive(Eq, PartialEq, Debug)]
 /// Error response to a query
 pub enum QueryError {
@@ -39,14 +61,103 @@ pub enum QueryError {
     ServerNotReady,
     /// Server reset HTTP/3 stream
     Reset(u64),
-    /// Tried to query non-existent network
+    /// Response exceeded the size limit requested for the query
+    ResponseTooLarge,
+    /// No response arrived before the query's deadline
+    Timeout,
+    /// The query is not a DNS message that could be adjusted as requested
+    MalformedQuery,
+    /// An extra request header is malformed, or is one DoH sets itself
+    InvalidHeader,
+    /// The answer is not a DNS message whose metadata could be read, such as an empty body
+    MalformedResponse,
+    /// The server answered with an HTTP 5xx status, and the delay it asked for with
+    /// `Retry-After`, if any
+    ServerError { status: u16, retry_after: Option<Duration> },
+    /// The server answered like a web server rather than a DoH one, most likely because the
+    /// configured URL points at the wrong server: with a 404 Not Found, or with a successful
+    /// response whose `Content-Type` is this rather than `application/dns-message`.
+    NotADohEndpoint { status: u16, content_type: Option<String> },
+    /// The answer looks like it came from a captive portal rather than the server: a redirect,
+    /// with the `Location` it pointed to if any, or a successful response with an HTML page for
+    /// a body. Signing in to the portal should let queries through.
+    CaptivePortal { status: u16, location: Option<String> },
+    /// The network's connection is already buffering as many response bytes as
+    /// `connection::Options::max_buffered_response_bytes` allows, so the query wasn't sent
+    ConnectionSaturated,
+    /// The answer has the TC bit set, and `ServerInfo::fail_truncated_answers` asked for that to
+    /// fail the query
+    Truncated,
+    /// The query could not be handed to the dispatcher
+    NotSent(SendError),
+    /// The network was reported lost, and no server has been provided for it since
+    NetworkLost,
+    /// A hedged query was given no servers to race
+    NoServers,
+    /// Tried to query non-existent network, or the query was dropped before being answered
     Unexpected,
 }
 
+impl QueryError {
+    /// Whether the query couldn't be resolved at all, as opposed to being malformed or failing in
+    /// a way that asks the caller to try differently, such as over another transport or after
+    /// signing in to a captive portal. These are the failures `Options::synthesize_servfail`
+    /// answers with a SERVFAIL.
+    pub fn is_terminal(&self) -> bool {
+        !matches!(
+            self,
+            Self::MalformedQuery
+                | Self::InvalidHeader
+                | Self::ResponseTooLarge
+                | Self::Truncated
+                | Self::CaptivePortal { .. }
+                | Self::NoServers
+        )
+    }
+}
+
+/// Error returned when a command can't be handed to the dispatcher
+#[derive(Debug, Error, Eq, PartialEq)]
+pub enum SendError {
+    /// Too many commands are already waiting for the driver
+    #[error("Dispatcher is overloaded")]
+    Overloaded,
+    /// The driver task has exited
+    #[error("Dispatcher has exited")]
+    Closed,
+}
+
+/// Per-query settings for `Dispatcher::submit_query`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QueryOptions {
+    /// Answers larger than this are abandoned with `QueryError::ResponseTooLarge`.
+    /// If `None`, the connection's default safety cap applies.
+    pub max_response_size: Option<usize>,
+    /// Scheduling priority relative to other queries sharing the connection.
+    pub priority: Priority,
+    /// EDNS(0) parameters to set on the query. If `None`, the query is sent as given, including
+    /// any OPT record it already has.
+    pub edns: Option<Edns>,
+    /// Headers to send after the standard DoH ones, such as `authorization` for a server which
+    /// requires it. Pseudo-headers and the headers DoH sets itself are rejected with
+    /// `QueryError::InvalidHeader`.
+    pub extra_headers: Vec<h3::Header>,
+    /// Sends the query on a connection of its own which neither presents nor records a session
+    /// ticket, and so can't send early data, whatever `ServerInfo::use_session_resumption` says.
+    /// The network's shared connection is left as it is.
+    pub force_full_handshake: bool,
+}
+
 #[derive(Eq, PartialEq, Debug)]
 pub enum Response {
-    Error { error: QueryError },
-    Success { answer: Vec<u8> },
+    Error {
+        error: QueryError,
+    },
+    Success {
+        answer: Vec<u8>,
+        /// Read from `answer` once, as it arrives, so nothing needs to parse its header again.
+        metadata: encoding::ResponseMetadata,
+    },
 }
 
 #[derive(Debug)]
@@ -58,46 +169,542 @@ pub enum Command {
     Query {
         net_id: u32,
         base64_query: String,
+        /// When the query was submitted, for measuring how long it waits before being sent.
+        submitted: BootTime,
         expired_time: BootTime,
+        /// Answers larger than this are abandoned with `QueryError::ResponseTooLarge`.
+        /// If `None`, the connection's default safety cap applies.
+        max_response_size: Option<usize>,
+        /// Scheduling priority relative to other queries sharing the connection.
+        priority: Priority,
+        /// Headers to append to the request, already checked.
+        extra_headers: Vec<h3::Header>,
+        /// ID the query was submitted with, which was zeroed in `base64_query`.
+        message_id: u16,
+        /// Sends the query on a fresh connection that doesn't resume a session.
+        force_full_handshake: bool,
+        /// Counts the query as queued until the driver picks it up.
+        queued: metrics::QueuedQuery,
         resp: oneshot::Sender<Response>,
     },
+    /// Send a single query over a dedicated connection which is closed once it is answered.
+    /// Registered networks and the config cache are left untouched.
+    OneShot {
+        info: ServerInfo,
+        /// Config for the connection. If `None`, an ephemeral one is built for `info` and
+        /// dropped along with the connection.
+        config: Option<Config>,
+        query: Vec<u8>,
+        timeout: Duration,
+        resp: oneshot::Sender<Response>,
+    },
+    /// Race a single query across several servers, as `Dispatcher::submit_hedged` describes.
+    Hedged {
+        servers: Vec<ServerInfo>,
+        hedge_delay: Duration,
+        query: Vec<u8>,
+        timeout: Duration,
+        resp: oneshot::Sender<Response>,
+    },
+    /// Check a server step by step over a dedicated connection, as `Dispatcher::submit_diagnose`
+    /// describes.
+    Diagnose {
+        info: ServerInfo,
+        timeout: Duration,
+        resp: oneshot::Sender<Diagnostics>,
+    },
     Clear {
         net_id: u32,
     },
+    /// Fail all queries on the network at once and drop its connection. Queries for it fail with
+    /// `QueryError::NetworkLost` until it is probed again.
+    NetworkLost {
+        net_id: u32,
+    },
+    /// Describe the connection each network is currently sending queries on.
+    ListConnections {
+        resp: oneshot::Sender<Vec<ConnectionInfo>>,
+    },
+    /// Release memory which isn't needed, as `Dispatcher::trim_memory` describes.
+    TrimMemory {
+        aggressive: bool,
+        resp: oneshot::Sender<TrimSummary>,
+    },
     Exit,
 }
 
+/// What `Dispatcher::trim_memory` let go of
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TrimSummary {
+    /// Idle connections asked to close. Each closes unless a query for its network gets to it
+    /// first, and the next query connects again.
+    pub connections_retired: usize,
+    /// Configs dropped from the config cache
+    pub configs_dropped: usize,
+    /// Spare packet buffers freed
+    pub buffers_freed: usize,
+}
+
+/// Tunables for a `Dispatcher`
+#[derive(Clone)]
+pub struct Options {
+    /// Maximum number of commands waiting for the driver. Queries submitted beyond this are
+    /// rejected with `SendError::Overloaded` rather than queued.
+    pub max_buffered_commands: usize,
+    /// Clock consulted for query deadlines. Tests can substitute one they control.
+    pub clock: SharedClock,
+    /// Told how each connection's handshake went, with details of the server's certificate.
+    pub cert_observer: Option<CertObserver>,
+    /// Told the size of every packet each connection sends or receives.
+    pub packet_size_observer: Option<PacketSizeObserver>,
+    /// Cert paths, spelled as in `ServerInfo::cert_path`, whose servers get a fresh connection
+    /// for every query, for server clusters where connection affinity causes problems. This
+    /// overrides `ServerInfo::max_queries_per_connection`, which governs reuse for other paths.
+    pub fresh_connection_cert_paths: HashSet<String>,
+    /// Maximum number of connections handshaking at once, across all networks. Further
+    /// connections wait for one of those to finish its handshake before starting their own,
+    /// which keeps bursts of new connections from spiking the CPU. `None` means no limit.
+    pub max_concurrent_handshakes: Option<usize>,
+    /// Answer queries which fail terminally, as `QueryError::is_terminal` decides, with a
+    /// SERVFAIL response echoing the query's ID and question, so callers can hand it back to
+    /// the app like any other answer. The error itself is logged. Off by default.
+    pub synthesize_servfail: bool,
+    /// Entries the QUIC config cache's garbage collection removes per hold of its write lock. See
+    /// `config::Cache::set_gc_chunk`.
+    pub config_gc_chunk: usize,
+    /// How long the QUIC config cache keeps a config alive for reuse once nothing else holds it
+    /// and it isn't requested. See `config::Cache::set_keep_alive_grace`.
+    pub config_keep_alive_grace: Option<Duration>,
+    /// Configs the QUIC config cache keeps alive for reuse while nothing else holds them. See
+    /// `config::Cache::set_keep_alive_capacity`.
+    pub config_keep_alive_capacity: usize,
+    /// Most entries the QUIC config cache holds, live or dead. See
+    /// `config::Cache::set_max_entries`. `None` leaves it unbounded.
+    pub max_cached_configs: Option<usize>,
+    /// How often a thread of its own garbage-collects the QUIC config cache, on top of the
+    /// collections the dispatcher runs as networks go away. See `config::Cache::spawn_gc`. `None`
+    /// runs no such thread.
+    pub config_gc_interval: Option<Duration>,
+    /// Longest a TLS session may be resumed after it was recorded, for privacy. See
+    /// `SessionStore::set_max_age`. `None` resumes sessions for as long as servers allow.
+    pub session_max_age: Option<Duration>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            max_buffered_commands: MAX_BUFFERED_CMD_COUNT,
+            clock: boot_time::system_clock(),
+            cert_observer: None,
+            packet_size_observer: None,
+            fresh_connection_cert_paths: HashSet::new(),
+            max_concurrent_handshakes: None,
+            synthesize_servfail: false,
+            config_gc_chunk: config::Cache::DEFAULT_GC_CHUNK,
+            config_keep_alive_grace: None,
+            config_keep_alive_capacity: config::Cache::DEFAULT_KEEP_ALIVE_CAPACITY,
+            max_cached_configs: None,
+            config_gc_interval: None,
+            session_max_age: None,
+        }
+    }
+}
+
+impl fmt::Debug for Options {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Options")
+            .field("max_buffered_commands", &self.max_buffered_commands)
+            .field("clock", &self.clock)
+            .field("cert_observer", &self.cert_observer.is_some())
+            .field("packet_size_observer", &self.packet_size_observer.is_some())
+            .field("fresh_connection_cert_paths", &self.fresh_connection_cert_paths)
+            .field("max_concurrent_handshakes", &self.max_concurrent_handshakes)
+            .field("synthesize_servfail", &self.synthesize_servfail)
+            .field("config_gc_chunk", &self.config_gc_chunk)
+            .field("config_keep_alive_grace", &self.config_keep_alive_grace)
+            .field("config_keep_alive_capacity", &self.config_keep_alive_capacity)
+            .field("max_cached_configs", &self.max_cached_configs)
+            .field("config_gc_interval", &self.config_gc_interval)
+            .field("session_max_age", &self.session_max_age)
+            .finish()
+    }
+}
+
 /// Context for a running DoH engine.
 pub struct Dispatcher {
     /// Used to submit cmds to the I/O task.
     cmd_sender: mpsc::Sender<Command>,
     join_handle: task::JoinHandle<Result<()>>,
     runtime: Runtime,
+    metrics: Arc<DispatcherMetrics>,
+    clock: SharedClock,
+    session_store: Arc<SessionStore>,
+    // Shared with the driver, which builds configs through it.
+    config_cache: config::Cache,
+    // Stops the config cache's background garbage collection when dropped.
+    _config_gc: Option<config::GcHandle>,
+    synthesize_servfail: bool,
 }
 
 impl Dispatcher {
     const DOH_THREADS: usize = 1;
+    // From android.content.ComponentCallbacks2.
+    const TRIM_MEMORY_RUNNING_CRITICAL: i32 = 15;
 
     pub fn new(validation: ValidationReporter, tagger: SocketTagger) -> Result<Dispatcher> {
-        let (cmd_sender, cmd_receiver) = mpsc::channel::<Command>(MAX_BUFFERED_CMD_COUNT);
+        Self::with_options(validation, tagger, Options::default())
+    }
+
+    pub fn with_options(
+        validation: ValidationReporter,
+        tagger: SocketTagger,
+        options: Options,
+    ) -> Result<Dispatcher> {
+        let (cmd_sender, cmd_receiver) = mpsc::channel::<Command>(options.max_buffered_commands);
+        let metrics = Arc::new(DispatcherMetrics::new(options.max_concurrent_handshakes));
         let runtime = Builder::new_multi_thread()
             .worker_threads(Self::DOH_THREADS)
             .enable_all()
             .thread_name("doh-handler")
             .build()?;
-        let join_handle = runtime.spawn(async {
-            let result = Driver::new(cmd_receiver, validation, tagger).drive().await;
+        info!("Starting DoH dispatcher on {}", build_info());
+        let clock = options.clock;
+        let session_store = Arc::new(SessionStore::new(clock.clone()));
+        let config_cache = config::Cache::with_clock(clock.clone());
+        config_cache.set_observer(Arc::new(|cert_path, reason| {
+            debug!("Config cache let go of the config for {:?}: {:?}", cert_path, reason)
+        }));
+        config_cache.set_gc_chunk(options.config_gc_chunk);
+        config_cache.set_keep_alive_grace(options.config_keep_alive_grace);
+        config_cache.set_keep_alive_capacity(options.config_keep_alive_capacity);
+        if let Some(entries) = options.max_cached_configs {
+            config_cache.set_max_entries(entries);
+        }
+        let config_gc = options
+            .config_gc_interval
+            .map(|interval| config_cache.spawn_gc(interval))
+            .transpose()?;
+        session_store.set_max_age(options.session_max_age);
+        let env = Environment {
+            tag_socket: tagger,
+            clock: clock.clone(),
+            metrics: metrics.clone(),
+            cert_observer: options.cert_observer,
+            packet_size_observer: options.packet_size_observer,
+        };
+        let driver = Driver::new(
+            cmd_receiver,
+            validation,
+            env,
+            session_store.clone(),
+            config_cache.clone(),
+            options.fresh_connection_cert_paths,
+        );
+        let join_handle = runtime.spawn(metrics.track(async {
+            let result = driver.drive().await;
             if let Err(ref e) = result { error!("Dispatcher driver exited due to {:?}", e) }
             result
-        });
-        Ok(Dispatcher { cmd_sender, join_handle, runtime })
+        }));
+        Ok(Dispatcher {
+            cmd_sender,
+            join_handle,
+            runtime,
+            metrics,
+            clock,
+            session_store,
+            config_cache,
+            _config_gc: config_gc,
+            synthesize_servfail: options.synthesize_servfail,
+        })
     }
 
-    pub fn send_cmd(&self, cmd: Command) -> Result<()> {
-        self.cmd_sender.blocking_send(cmd)?;
+    /// Hands a command to the driver. Queries are rejected with `SendError::Overloaded` if the
+    /// driver has fallen too far behind; other commands wait for room in the queue.
+    pub fn send_cmd(&self, cmd: Command) -> std::result::Result<(), SendError> {
+        if let Command::Query { .. } = cmd {
+            // A query refused here is dropped along with its `QueuedQuery`.
+            return self.cmd_sender.try_send(cmd).map_err(|e| match e {
+                TrySendError::Full(_) => {
+                    self.metrics.query_overloaded();
+                    SendError::Overloaded
+                }
+                TrySendError::Closed(_) => SendError::Closed,
+            });
+        }
+        self.cmd_sender.blocking_send(cmd).map_err(|_| SendError::Closed)
+    }
+
+    /// Submits a wire-format DNS query for `net_id` without waiting for the answer, which will be
+    /// delivered on the returned channel.
+    pub fn submit_query(
+        &self,
+        net_id: u32,
+        query: &[u8],
+        timeout: Duration,
+        options: QueryOptions,
+    ) -> std::result::Result<oneshot::Receiver<Response>, QueryError> {
+        if !self.synthesize_servfail {
+            return self.enqueue_query(net_id, query, timeout, options);
+        }
+        let servfail = match encoding::servfail(query) {
+            Ok(servfail) => servfail,
+            Err(e) => {
+                debug!("Unable to build a SERVFAIL for query: {:?}", e);
+                return self.enqueue_query(net_id, query, timeout, options);
+            }
+        };
+        let (resp, resp_rx) = oneshot::channel();
+        match self.enqueue_query(net_id, query, timeout, options) {
+            Ok(answer_rx) => {
+                self.runtime.spawn(self.metrics.track(async move {
+                    let response = answer_rx
+                        .await
+                        .unwrap_or(Response::Error { error: QueryError::Unexpected });
+                    // We don't care if the response is gone.
+                    let _ = resp.send(servfail_if_terminal(response, servfail));
+                }));
+            }
+            Err(error) if error.is_terminal() => {
+                let _ = resp.send(servfail_if_terminal(Response::Error { error }, servfail));
+            }
+            Err(error) => return Err(error),
+        }
+        Ok(resp_rx)
+    }
+
+    fn enqueue_query(
+        &self,
+        net_id: u32,
+        query: &[u8],
+        timeout: Duration,
+        options: QueryOptions,
+    ) -> std::result::Result<oneshot::Receiver<Response>, QueryError> {
+        let submitted = self.clock.now();
+        let expired_time = submitted.checked_add(timeout).ok_or_else(|| {
+            error!("Bad timeout parameter: {:?}", timeout);
+            QueryError::Unexpected
+        })?;
+        encoding::check_extra_headers(&options.extra_headers).map_err(|e| {
+            error!("Unable to add headers to query: {:?}", e);
+            QueryError::InvalidHeader
+        })?;
+        let mut query = query.to_vec();
+        let message_id = encoding::set_message_id(&mut query, 0).map_err(|e| {
+            error!("Unable to read the ID of query: {:?}", e);
+            QueryError::MalformedQuery
+        })?;
+        if let Some(edns) = options.edns {
+            query = encoding::set_edns(&query, edns).map_err(|e| {
+                error!("Unable to set EDNS on query: {:?}", e);
+                QueryError::MalformedQuery
+            })?;
+        }
+        let base64_query = base64::encode_config(query, base64::URL_SAFE_NO_PAD);
+        let (resp, resp_rx) = oneshot::channel();
+        self.send_cmd(Command::Query {
+            net_id,
+            base64_query,
+            submitted,
+            expired_time,
+            max_response_size: options.max_response_size,
+            priority: options.priority,
+            extra_headers: options.extra_headers,
+            message_id,
+            force_full_handshake: options.force_full_handshake,
+            queued: self.metrics.query_queued(),
+            resp,
+        })
+        .map_err(QueryError::NotSent)?;
+        Ok(resp_rx)
+    }
+
+    /// Resolves a wire-format DNS query, blocking the calling thread until the answer arrives or
+    /// `timeout` passes. Only built for tests: `doh_query` is the blocking call for C callers,
+    /// and submits through `submit_query` so that it only holds the dispatcher while submitting.
+    #[cfg(test)]
+    pub fn resolve(
+        &self,
+        net_id: u32,
+        query: &[u8],
+        timeout: Duration,
+    ) -> std::result::Result<Vec<u8>, QueryError> {
+        wait_for_answer(self.submit_query(net_id, query, timeout, Default::default())?, timeout)
+    }
+
+    /// Submits a wire-format DNS query for `info` over a fresh connection without waiting for the
+    /// answer, which will be delivered on the returned channel. Unlike `submit_query`, the server
+    /// need not be probed first, and nothing about the connection outlives the query.
+    pub fn submit_once(
+        &self,
+        info: ServerInfo,
+        config: Option<Config>,
+        query: &[u8],
+        timeout: Duration,
+    ) -> std::result::Result<oneshot::Receiver<Response>, QueryError> {
+        let (resp, resp_rx) = oneshot::channel();
+        self.send_cmd(Command::OneShot { info, config, query: query.to_vec(), timeout, resp })
+            .map_err(QueryError::NotSent)?;
+        Ok(resp_rx)
+    }
+
+    /// Submits a wire-format DNS query to be raced across `servers`, each over a fresh
+    /// connection as for `submit_once`. The first server is tried at once and each of the others
+    /// `hedge_delay` after the one before it, until one of them answers. The first successful
+    /// answer is sent and the remaining attempts are abandoned, closing their connections; if
+    /// none succeeds, the error from the last one to fail is sent. Fails with
+    /// `QueryError::NoServers` if `servers` is empty.
+    ///
+    /// This trades bandwidth and battery for latency: every attempt started costs a handshake
+    /// and a query, and with a zero `hedge_delay` every server is always contacted. Keep it for
+    /// queries where a slow answer costs more than the extra traffic, and prefer a delay around
+    /// the usual answer time so the hedge only starts when the first server is being slow.
+    pub fn submit_hedged(
+        &self,
+        servers: Vec<ServerInfo>,
+        hedge_delay: Duration,
+        query: &[u8],
+        timeout: Duration,
+    ) -> std::result::Result<oneshot::Receiver<Response>, QueryError> {
+        if servers.is_empty() {
+            return Err(QueryError::NoServers);
+        }
+        let (resp, resp_rx) = oneshot::channel();
+        self.send_cmd(Command::Hedged {
+            servers,
+            hedge_delay,
+            query: query.to_vec(),
+            timeout,
+            resp,
+        })
+        .map_err(QueryError::NotSent)?;
+        Ok(resp_rx)
+    }
+
+    /// Checks the server `info` describes one step at a time, for a "test connection" button:
+    /// whether it can be reached, whether the handshake completes and what it settles on,
+    /// whether its certificate passes, and whether it answers a query. This takes a connection
+    /// of its own, verified against `info.cert_path`, and leaves registered networks and the
+    /// config cache alone. The diagnostics are sent once every step has run or `timeout` has
+    /// passed.
+    pub fn submit_diagnose(
+        &self,
+        info: ServerInfo,
+        timeout: Duration,
+    ) -> std::result::Result<oneshot::Receiver<Diagnostics>, SendError> {
+        let (resp, resp_rx) = oneshot::channel();
+        self.send_cmd(Command::Diagnose { info, timeout, resp })?;
+        Ok(resp_rx)
+    }
+
+    /// Reports that the network `net_id` is gone. Its queries fail with `QueryError::NetworkLost`
+    /// rather than waiting for their connection to time out, as do new ones until a server is
+    /// probed for the network again.
+    pub fn network_lost(&self, net_id: u32) -> std::result::Result<(), SendError> {
+        self.send_cmd(Command::NetworkLost { net_id })
+    }
+
+    /// Snapshots of the connection each network is currently sending queries on, ordered by
+    /// network. Connections which were replaced but are still answering earlier queries are not
+    /// listed.
+    pub fn list_connections(&self) -> std::result::Result<Vec<ConnectionInfo>, SendError> {
+        let (resp, resp_rx) = oneshot::channel();
+        self.send_cmd(Command::ListConnections { resp })?;
+        self.runtime.block_on(resp_rx).map_err(|_| SendError::Closed)
+    }
+
+    /// Releases memory the dispatcher can do without, for when the system is short of it.
+    /// `level` is as passed to `ComponentCallbacks2.onTrimMemory`. At any level, spare packet
+    /// buffers are freed and configs nothing uses are dropped. From
+    /// `TRIM_MEMORY_RUNNING_CRITICAL` up, idle connections are closed and the config kept for
+    /// reuse is let go too, at the cost of a handshake on the next query.
+    pub fn trim_memory(&self, level: i32) -> std::result::Result<TrimSummary, SendError> {
+        let aggressive = level >= Self::TRIM_MEMORY_RUNNING_CRITICAL;
+        let (resp, resp_rx) = oneshot::channel();
+        self.send_cmd(Command::TrimMemory { aggressive, resp })?;
+        self.runtime.block_on(resp_rx).map_err(|_| SendError::Closed)
+    }
+
+    /// TLS sessions kept for resumption, which can be exported before a restart and imported
+    /// after it.
+    pub fn session_store(&self) -> &SessionStore {
+        &self.session_store
+    }
+
+    pub fn metrics(&self) -> &DispatcherMetrics {
+        &self.metrics
+    }
+
+    /// Checks that the server `info` describes could be connected to as configured, without
+    /// contacting it or caching anything. Its QUIC config must build and its settings be in range,
+    /// as `config::Key::validate` checks, and a handshake timeout must be shorter than the idle
+    /// timeout, which would otherwise cut the handshake short.
+    pub fn validate(info: &ServerInfo) -> std::result::Result<(), config::ConfigError> {
+        match info.connection_options.handshake_timeout {
+            Some(handshake_timeout)
+                if info.idle_timeout_ms != 0
+                    && handshake_timeout >= Duration::from_millis(info.idle_timeout_ms) =>
+            {
+                Err(config::ConfigError::OutOfRange(format!(
+                    "handshake timeout {:?} is not below the idle timeout of {} ms",
+                    handshake_timeout, info.idle_timeout_ms
+                )))
+            }
+            _ => driver::config_key(info).validate(),
+        }
+    }
+
+    /// How much work building QUIC configs has taken.
+    pub fn config_cache_stats(&self) -> CacheStats {
+        self.config_cache.stats()
+    }
+
+    /// Has servers probed from now on with `cert_path` reparse their trust store, for when its
+    /// certificates were updated in place, or every trust store if `None`. Networks already
+    /// probed keep the config they were set up with.
+    pub fn invalidate_cert_path(&self, cert_path: Option<&str>) -> Result<()> {
+        match cert_path {
+            Some(path) => self.config_cache.invalidate_cert_path(&Some(path.to_string()))?,
+            None => self.config_cache.invalidate_all(),
+        }
+        Ok(())
+    }
+
+    /// Has connections to servers probed from now on write their TLS secrets to `keylog_path`
+    /// and qlog traces to `qlog_dir`, for debugging failed handshakes, or stop where `None`.
+    /// Networks already probed keep the logs they were set up with. Fails, changing nothing, if
+    /// the keylog can't be opened, or if either log is given to a build without the `qlog`
+    /// feature, which Android builds leave out so that they never write TLS secrets.
+    pub fn set_debug_logs(&self, keylog_path: Option<&str>, qlog_dir: Option<&str>) -> Result<()> {
+        if !cfg!(feature = "qlog") && (keylog_path.is_some() || qlog_dir.is_some()) {
+            anyhow::bail!("Built without debug log support");
+        }
+        self.config_cache.set_keylog_path(keylog_path)?;
+        #[cfg(feature = "qlog")]
+        self.config_cache.set_qlog_dir(qlog_dir.map(str::to_string));
+        self.config_cache.invalidate_all();
         Ok(())
     }
 
+    /// The QUIC configs the config cache holds and how long ago each was built, oldest first.
+    pub fn resident_configs(&self) -> Vec<ResidentConfig> {
+        self.config_cache.resident()
+    }
+
+    /// The dispatcher's metrics and config cache counters in the Prometheus text exposition
+    /// format. Only built with the `metrics_text` feature, so the Android build leaves it out.
+    #[cfg(feature = "metrics_text")]
+    pub fn metrics_text(&self) -> String {
+        self.metrics.render_text(&self.config_cache_stats())
+    }
+
+    /// Number of tasks the dispatcher has running, as `DispatcherMetrics::active_tasks`. Once
+    /// `exit_handler` has returned, this should reach zero as soon as connections finish
+    /// closing.
+    #[cfg(test)]
+    pub fn active_task_count(&self) -> usize {
+        self.metrics.active_tasks()
+    }
+
     pub fn exit_handler(&mut self) {
         if self.cmd_sender.blocking_send(Command::Exit).is_err() {
             return;
@@ -105,3 +712,486 @@ impl Dispatcher {
         let _ = self.runtime.block_on(&mut self.join_handle);
     }
 }
+
+// Swaps a terminal error for `servfail`, logging the error it stands in for.
+fn servfail_if_terminal(response: Response, servfail: Vec<u8>) -> Response {
+    match response {
+        Response::Error { error } if error.is_terminal() => {
+            warn!("Answering with SERVFAIL for query which failed with {:?}", error);
+            Response::Success { answer: servfail, metadata: encoding::SERVFAIL_METADATA }
+        }
+        response => response,
+    }
+}
+
+/// Blocks the calling thread until a response from `Dispatcher::submit_query` arrives or
+/// `wait_time` passes.
+pub fn wait_for_answer(
+    resp_rx: oneshot::Receiver<Response>,
+    wait_time: Duration,
+) -> std::result::Result<Vec<u8>, QueryError> {
+    let rt = Builder::new_current_thread().enable_all().build().map_err(|e| {
+        error!("Unable to build runtime for query: {:?}", e);
+        QueryError::Unexpected
+    })?;
+    let local = task::LocalSet::new();
+    match local.block_on(&rt, async { timeout(wait_time, resp_rx).await }) {
+        Ok(Ok(Response::Success { answer, .. })) => Ok(answer),
+        Ok(Ok(Response::Error { error })) => Err(error),
+        Ok(Err(e)) => {
+            error!("no result {}", e);
+            Err(QueryError::Unexpected)
+        }
+        Err(e) => {
+            error!("timeout: {}", e);
+            Err(QueryError::Timeout)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::diagnostics::Step;
+    use super::*;
+    use futures::FutureExt;
+
+    fn new_dispatcher() -> Dispatcher {
+        let validation: ValidationReporter = Arc::new(|_, _| async {}.boxed());
+        let tagger: SocketTagger = Arc::new(|_| async {}.boxed());
+        Dispatcher::new(validation, tagger).unwrap()
+    }
+
+    #[test]
+    fn resolve_unknown_network() {
+        let mut dispatcher = new_dispatcher();
+        assert_eq!(
+            dispatcher.resolve(42, &[0; 12], Duration::from_secs(1)),
+            Err(QueryError::Unexpected)
+        );
+        assert_eq!(dispatcher.metrics().queued_queries(), 0);
+        dispatcher.exit_handler();
+    }
+
+    #[test]
+    fn resolve_lost_network() {
+        let mut dispatcher = new_dispatcher();
+        dispatcher.network_lost(42).unwrap();
+        assert_eq!(
+            dispatcher.resolve(42, &[0; 12], Duration::from_secs(1)),
+            Err(QueryError::NetworkLost)
+        );
+        // Forgetting the network forgets that it was lost.
+        dispatcher.send_cmd(Command::Clear { net_id: 42 }).unwrap();
+        assert_eq!(
+            dispatcher.resolve(42, &[0; 12], Duration::from_secs(1)),
+            Err(QueryError::Unexpected)
+        );
+        dispatcher.exit_handler();
+    }
+
+    #[test]
+    fn forget_sessions_with_network() {
+        let mut dispatcher = new_dispatcher();
+        const SERVER: &str = "https://dns.example.com/dns-query";
+        for net_id in 42..45 {
+            dispatcher.session_store().insert(net_id, SERVER, vec![net_id as u8]);
+        }
+        dispatcher.send_cmd(Command::Clear { net_id: 42 }).unwrap();
+        dispatcher.network_lost(43).unwrap();
+        // Commands are handled in order, so once a query is answered the others have been too.
+        assert_eq!(
+            dispatcher.resolve(99, &[0; 12], Duration::from_secs(1)),
+            Err(QueryError::Unexpected)
+        );
+        assert_eq!(dispatcher.session_store().get(42, SERVER), None);
+        assert_eq!(dispatcher.session_store().get(43, SERVER), None);
+        assert_eq!(dispatcher.session_store().get(44, SERVER), Some(vec![44]));
+        dispatcher.exit_handler();
+    }
+
+    #[test]
+    fn synthesized_servfail() {
+        let validation: ValidationReporter = Arc::new(|_, _| async {}.boxed());
+        let tagger: SocketTagger = Arc::new(|_| async {}.boxed());
+        let options = Options { synthesize_servfail: true, ..Default::default() };
+        let mut dispatcher = Dispatcher::with_options(validation, tagger, options).unwrap();
+        let query =
+            base64::decode_config(encoding::probe_query().unwrap(), base64::URL_SAFE_NO_PAD)
+                .unwrap();
+        // The network is unknown, so the query fails, and is answered with a SERVFAIL instead.
+        let answer = dispatcher.resolve(42, &query, Duration::from_secs(1)).unwrap();
+        assert_eq!(answer, encoding::servfail(&query).unwrap());
+        assert_eq!(answer[..2], query[..2]);
+        assert_eq!(encoding::response_metadata(&answer).unwrap().rcode, 2);
+        // A query which isn't a DNS message still fails as it did.
+        assert_eq!(
+            dispatcher.resolve(42, &[0], Duration::from_secs(1)),
+            Err(QueryError::MalformedQuery)
+        );
+        dispatcher.exit_handler();
+    }
+
+    #[test]
+    fn no_tasks_outlive_shutdown() {
+        // Polls `done` until it holds, for up to `deadline`.
+        fn wait_until(deadline: Duration, done: impl Fn() -> bool) -> bool {
+            let start = BootTime::now();
+            while !done() {
+                if start.elapsed() >= deadline {
+                    return false;
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            true
+        }
+
+        let server = crate::connection::loopback::HandshakeServer::start().unwrap();
+        let mut dispatcher = new_dispatcher();
+        let info = ServerInfo { idle_timeout_ms: 5000, ..ServerInfo::for_test(server.addr) };
+        // The server never answers the probe, but the connection is established all the same.
+        dispatcher.send_cmd(Command::Probe { info, timeout: Duration::from_secs(1) }).unwrap();
+        let metrics = dispatcher.metrics.clone();
+        assert!(wait_until(Duration::from_secs(5), || metrics.connection_successes() == 1));
+        assert!(dispatcher.active_task_count() > 0);
+
+        dispatcher.exit_handler();
+        assert!(
+            wait_until(Duration::from_secs(5), || dispatcher.active_task_count() == 0),
+            "{} tasks outlived the dispatcher",
+            dispatcher.active_task_count()
+        );
+    }
+
+    #[test]
+    fn fresh_connections_for_listed_cert_paths() {
+        use crate::connection::loopback::{self, DohServer, Reply};
+        use std::sync::mpsc::sync_channel;
+
+        let dir =
+            std::env::temp_dir().join(format!("doh_fresh_connections_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // Named by the subject hash BoringSSL looks trust anchors up by in a directory.
+        std::fs::write(dir.join("64e6d868.0"), loopback::SERVER_CERT).unwrap();
+        let cert_path = dir.to_str().unwrap().to_string();
+        let query =
+            base64::decode_config(encoding::probe_query().unwrap(), base64::URL_SAFE_NO_PAD)
+                .unwrap();
+        // The probe and the first query share the first connection either way. Only for a listed
+        // cert path does the second query get a connection of its own.
+        for (listed, connections) in [(false, 1), (true, 2)] {
+            let server = DohServer::start(Box::new(|_, _| Reply::After(Duration::ZERO))).unwrap();
+            let (validated_tx, validated_rx) = sync_channel(1);
+            let validation: ValidationReporter = Arc::new(move |_, valid| {
+                let _ = validated_tx.try_send(valid);
+                async {}.boxed()
+            });
+            let tagger: SocketTagger = Arc::new(|_| async {}.boxed());
+            let fresh_connection_cert_paths =
+                if listed { std::iter::once(cert_path.clone()).collect() } else { HashSet::new() };
+            let options = Options { fresh_connection_cert_paths, ..Default::default() };
+            let mut dispatcher = Dispatcher::with_options(validation, tagger, options).unwrap();
+            let info = ServerInfo {
+                domain: Some("dns.example.com".to_string()),
+                cert_path: Some(cert_path.clone()),
+                ..ServerInfo::for_test(server.addr)
+            };
+            let net_id = info.net_id;
+            dispatcher.send_cmd(Command::Probe { info, timeout: Duration::from_secs(5) }).unwrap();
+            assert_eq!(validated_rx.recv_timeout(Duration::from_secs(5)), Ok(true));
+            for _ in 0..2 {
+                let answer = dispatcher.resolve(net_id, &query, Duration::from_secs(5)).unwrap();
+                assert_eq!(answer[12..], query[12..]);
+            }
+            assert_eq!(server.connections(), connections, "listed={}", listed);
+            dispatcher.exit_handler();
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Two callers which happen to pick the same DNS ID each get back their own answer with that
+    // ID, though both went out on the wire with ID 0.
+    #[test]
+    fn concurrent_queries_sharing_an_id() {
+        use crate::connection::loopback::{DohServer, Reply};
+        use std::sync::mpsc::sync_channel;
+
+        let (wire_ids_tx, wire_ids_rx) = sync_channel(2);
+        let server = DohServer::start(Box::new(move |_, query| {
+            let _ = wire_ids_tx.try_send(query[..2].to_vec());
+            // Leave both in flight at once.
+            Reply::After(Duration::from_millis(100))
+        }))
+        .unwrap();
+        let (validated_tx, validated_rx) = sync_channel(1);
+        let validation: ValidationReporter = Arc::new(move |_, valid| {
+            let _ = validated_tx.try_send(valid);
+            async {}.boxed()
+        });
+        let tagger: SocketTagger = Arc::new(|_| async {}.boxed());
+        let mut dispatcher = Dispatcher::new(validation, tagger).unwrap();
+        let info = ServerInfo::for_test(server.addr);
+        let net_id = info.net_id;
+        dispatcher.send_cmd(Command::Probe { info, timeout: Duration::from_secs(5) }).unwrap();
+        assert_eq!(validated_rx.recv_timeout(Duration::from_secs(5)), Ok(true));
+        // Drop the probe's ID from what the server saw.
+        wire_ids_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        let mut a =
+            base64::decode_config(encoding::probe_query().unwrap(), base64::URL_SAFE_NO_PAD)
+                .unwrap();
+        a[..2].copy_from_slice(&[0x12, 0x34]);
+        // The same ID, asking for another type of record.
+        let mut b = a.clone();
+        let qtype = b.len() - 3;
+        b[qtype] ^= 0xff;
+        let timeout = Duration::from_secs(5);
+        let a_rx = dispatcher.submit_query(net_id, &a, timeout, Default::default()).unwrap();
+        let b_rx = dispatcher.submit_query(net_id, &b, timeout, Default::default()).unwrap();
+        let (a_answer, b_answer) =
+            (wait_for_answer(a_rx, timeout).unwrap(), wait_for_answer(b_rx, timeout).unwrap());
+        // The server echoes each query back, marked as a response.
+        assert_eq!((&a_answer[..2], &a_answer[3..]), (&a[..2], &a[3..]));
+        assert_eq!((&b_answer[..2], &b_answer[3..]), (&b[..2], &b[3..]));
+        for _ in 0..2 {
+            assert_eq!(wire_ids_rx.recv_timeout(Duration::from_secs(5)).unwrap(), [0, 0]);
+        }
+        dispatcher.exit_handler();
+    }
+
+    // However a query leaves the queue, whether picked up by the driver, refused as overloaded,
+    // or picked up for a network which has been cleared, it is no longer counted.
+    #[test]
+    fn queued_queries_return_to_zero() {
+        use crate::connection::loopback::{DohServer, Reply};
+        use std::sync::mpsc::sync_channel;
+
+        let server = DohServer::start(Box::new(|count, _| {
+            // Answer the probe, and leave the queries in flight.
+            if count == 0 {
+                Reply::After(Duration::ZERO)
+            } else {
+                Reply::Ignore
+            }
+        }))
+        .unwrap();
+        let (validated_tx, validated_rx) = sync_channel(1);
+        let validation: ValidationReporter = Arc::new(move |_, valid| {
+            let _ = validated_tx.try_send(valid);
+            async {}.boxed()
+        });
+        let tagger: SocketTagger = Arc::new(|_| async {}.boxed());
+        let options = Options { max_buffered_commands: 1, ..Default::default() };
+        let mut dispatcher = Dispatcher::with_options(validation, tagger, options).unwrap();
+        let info = ServerInfo::for_test(server.addr);
+        let net_id = info.net_id;
+        dispatcher.send_cmd(Command::Probe { info, timeout: Duration::from_secs(5) }).unwrap();
+        assert_eq!(validated_rx.recv_timeout(Duration::from_secs(5)), Ok(true));
+
+        let query =
+            base64::decode_config(encoding::probe_query().unwrap(), base64::URL_SAFE_NO_PAD)
+                .unwrap();
+        let timeout = Duration::from_secs(5);
+        let mut resp_rxs = Vec::new();
+        while dispatcher.metrics().overloaded_queries() == 0 {
+            assert!(resp_rxs.len() < 10000, "The queue never overflowed");
+            match dispatcher.submit_query(net_id, &query, timeout, Default::default()) {
+                Ok(resp_rx) => resp_rxs.push(resp_rx),
+                Err(error) => assert_eq!(error, QueryError::NotSent(SendError::Overloaded)),
+            }
+        }
+        // The query still in the queue, if any, is picked up after the network is gone.
+        dispatcher.send_cmd(Command::Clear { net_id }).unwrap();
+        let start = BootTime::now();
+        while dispatcher.metrics().queued_queries() != 0 && start.elapsed() < timeout {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(dispatcher.metrics().queued_queries(), 0);
+        dispatcher.exit_handler();
+        assert_eq!(dispatcher.metrics().queued_queries(), 0);
+    }
+
+    #[test]
+    fn submit_once_unreachable_server() {
+        let mut dispatcher = new_dispatcher();
+        // Nothing listens on the discard port, so the handshake can't complete.
+        let info = ServerInfo::for_test("127.0.0.1:9".parse().unwrap());
+        let timeout = Duration::from_millis(100);
+        let result = wait_for_answer(
+            dispatcher.submit_once(info, None, &[0; 12], timeout).unwrap(),
+            timeout,
+        );
+        assert!(
+            matches!(result, Err(QueryError::Timeout) | Err(QueryError::ConnectionError)),
+            "unexpected one-shot result {:?}",
+            result
+        );
+        dispatcher.exit_handler();
+    }
+
+    #[test]
+    fn submit_hedged_unreachable_servers() {
+        let mut dispatcher = new_dispatcher();
+        let info = ServerInfo::for_test("127.0.0.1:9".parse().unwrap());
+        let other = ServerInfo { peer_addr: "[::1]:9".parse().unwrap(), ..info.clone() };
+        let timeout = Duration::from_millis(100);
+        let resp_rx = dispatcher
+            .submit_hedged(vec![info, other], Duration::from_millis(10), &[0; 12], timeout)
+            .unwrap();
+        let result = wait_for_answer(resp_rx, timeout);
+        assert!(
+            matches!(result, Err(QueryError::Timeout) | Err(QueryError::ConnectionError)),
+            "unexpected hedged result {:?}",
+            result
+        );
+        assert_eq!(
+            dispatcher.submit_hedged(Vec::new(), Duration::ZERO, &[0; 12], timeout).unwrap_err(),
+            QueryError::NoServers
+        );
+        dispatcher.exit_handler();
+    }
+
+    #[test]
+    fn submit_hedged_first_answer_wins() {
+        use crate::connection::loopback::{DohServer, Reply};
+        let mut dispatcher = new_dispatcher();
+        let slow = DohServer::start(Box::new(|_, _| Reply::Ignore)).unwrap();
+        let fast = DohServer::start(Box::new(|_, _| Reply::After(Duration::ZERO))).unwrap();
+        let spare = DohServer::start(Box::new(|_, _| Reply::After(Duration::ZERO))).unwrap();
+        let servers =
+            [&slow, &fast, &spare].iter().map(|server| ServerInfo::for_test(server.addr)).collect();
+        let query = encoding::probe_query().unwrap();
+        let query = base64::decode_config(query, base64::URL_SAFE_NO_PAD).unwrap();
+        let timeout = Duration::from_secs(5);
+        // The slow server is tried at once and the fast one after the delay, which answers
+        // before the spare one is due.
+        let hedge_delay = Duration::from_millis(300);
+        let resp_rx = dispatcher.submit_hedged(servers, hedge_delay, &query, timeout).unwrap();
+        let answer = wait_for_answer(resp_rx, timeout).unwrap();
+        // The server echoes the query back, marked as a response.
+        assert_eq!(answer[3..], query[3..]);
+        assert_eq!((slow.requests(), fast.requests()), (1, 1));
+        // The losing attempt was torn down well before its idle timeout would have closed it,
+        // and the one still to start never connected.
+        let start = BootTime::now();
+        while slow.closed() == 0 && start.elapsed() < Duration::from_millis(500) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(slow.closed(), 1);
+        std::thread::sleep(hedge_delay);
+        assert_eq!(spare.connections(), 0);
+        dispatcher.exit_handler();
+    }
+
+    #[test]
+    fn diagnose_unreachable_server() {
+        let mut dispatcher = new_dispatcher();
+        let timeout = Duration::from_millis(200);
+        let info = ServerInfo::for_test("127.0.0.1:9".parse().unwrap());
+        let diagnostics =
+            dispatcher.submit_diagnose(info, timeout).unwrap().blocking_recv().unwrap();
+        assert_eq!(diagnostics.peer_addr, "127.0.0.1:9".parse().unwrap());
+        assert!(matches!(diagnostics.reachability, Step::Failed(_)), "{:?}", diagnostics);
+        assert!(matches!(diagnostics.handshake, Step::Failed(_)), "{:?}", diagnostics);
+        // Nothing was presented, so there was no certificate to check.
+        assert_eq!(diagnostics.certificate, Step::Skipped);
+        assert_eq!(diagnostics.query, Step::Skipped);
+        let report = diagnostics.to_string();
+        assert!(report.starts_with("Server 127.0.0.1:9\nReachability: failed: "), "{}", report);
+        assert!(report.ends_with("Certificate: skipped\nQuery: skipped\n"), "{}", report);
+
+        // A file rather than a directory of certificates.
+        let broken = ServerInfo {
+            cert_path: Some(std::env::current_exe().unwrap().to_str().unwrap().to_string()),
+            ..ServerInfo::for_test("127.0.0.1:9".parse().unwrap())
+        };
+        let diagnostics =
+            dispatcher.submit_diagnose(broken, timeout).unwrap().blocking_recv().unwrap();
+        assert!(matches!(diagnostics.handshake, Step::Failed(_)), "{:?}", diagnostics);
+        assert_eq!(diagnostics.reachability, Step::Skipped);
+        dispatcher.exit_handler();
+    }
+
+    #[test]
+    fn validate() {
+        let info = ServerInfo {
+            idle_timeout_ms: 1000,
+            ..ServerInfo::for_test("127.0.0.1:9".parse().unwrap())
+        };
+        assert!(Dispatcher::validate(&info).is_ok());
+        let with_timeout = |ms| {
+            let connection_options = crate::connection::Options {
+                handshake_timeout: Some(Duration::from_millis(ms)),
+                ..Default::default()
+            };
+            ServerInfo { connection_options, ..info.clone() }
+        };
+        assert!(Dispatcher::validate(&with_timeout(999)).is_ok());
+        let result = Dispatcher::validate(&with_timeout(1000));
+        assert!(matches!(result, Err(config::ConfigError::OutOfRange(_))), "{:?}", result);
+        // There is no idle timeout to cut the handshake short.
+        let no_idle_timeout = ServerInfo { idle_timeout_ms: 0, ..with_timeout(1000) };
+        assert!(Dispatcher::validate(&no_idle_timeout).is_ok());
+
+        let missing = ServerInfo { cert_path: Some("/nonexistent/cacerts".to_string()), ..info };
+        let result = Dispatcher::validate(&missing);
+        assert!(matches!(result, Err(config::ConfigError::MissingTrustStore(_))), "{:?}", result);
+    }
+
+    #[test]
+    fn list_connections() {
+        let mut dispatcher = new_dispatcher();
+        assert_eq!(dispatcher.list_connections(), Ok(Vec::new()));
+        for net_id in [43, 42] {
+            let info =
+                ServerInfo { net_id, ..ServerInfo::for_test("127.0.0.1:9".parse().unwrap()) };
+            let timeout = Duration::from_millis(100);
+            dispatcher.send_cmd(Command::Probe { info, timeout }).unwrap();
+        }
+        let connections = dispatcher.list_connections().unwrap();
+        let net_ids: Vec<_> = connections.iter().map(|connection| connection.net_id).collect();
+        assert_eq!(net_ids, [42, 43]);
+        for connection in &connections {
+            assert_eq!(connection.peer_addr, "127.0.0.1:9".parse().unwrap());
+            assert!(!connection.trace_id.is_empty());
+        }
+        dispatcher.network_lost(42).unwrap();
+        let connections = dispatcher.list_connections().unwrap();
+        assert_eq!(connections.iter().map(|c| c.net_id).collect::<Vec<_>>(), [43]);
+        dispatcher.exit_handler();
+    }
+    #[test]
+    fn invalidate_cert_path() {
+        let mut dispatcher = new_dispatcher();
+        let key = |cert_path: &str| config::Key {
+            cert_path: Some(cert_path.to_string()),
+            ..config::test_key()
+        };
+        let _a = dispatcher.config_cache.get(&key("/a")).unwrap();
+        let _b = dispatcher.config_cache.get(&key("/b")).unwrap();
+        dispatcher.invalidate_cert_path(Some("/a")).unwrap();
+        let _a = dispatcher.config_cache.get(&key("/a")).unwrap();
+        let _b = dispatcher.config_cache.get(&key("/b")).unwrap();
+        // Only `/a` was built again.
+        assert_eq!(dispatcher.config_cache_stats().misses, 3);
+        dispatcher.invalidate_cert_path(None).unwrap();
+        let _a = dispatcher.config_cache.get(&key("/a")).unwrap();
+        let _b = dispatcher.config_cache.get(&key("/b")).unwrap();
+        assert_eq!(dispatcher.config_cache_stats().misses, 5);
+        dispatcher.exit_handler();
+    }
+
+    #[test]
+    fn trim_memory() {
+        let mut dispatcher = new_dispatcher();
+        let info = ServerInfo::for_test("127.0.0.1:9".parse().unwrap());
+        let timeout = Duration::from_millis(100);
+        dispatcher.send_cmd(Command::Probe { info, timeout }).unwrap();
+        // TRIM_MEMORY_RUNNING_MODERATE leaves connections alone.
+        assert_eq!(dispatcher.trim_memory(5).unwrap().connections_retired, 0);
+        // TRIM_MEMORY_COMPLETE closes the network's idle connection. Its config is still held by
+        // the network, so it stays cached.
+        let summary = dispatcher.trim_memory(80).unwrap();
+        assert_eq!((summary.connections_retired, summary.configs_dropped), (1, 0));
+        dispatcher.exit_handler();
+    }
+}
@@ -17,9 +17,12 @@
 //! DoH backend for the Android DnsResolver module.
 
 pub mod boot_time;
+mod certificate;
 mod config;
 mod connection;
 mod dispatcher;
 mod encoding;
 mod ffi;
 mod network;
+#[cfg(any(test, feature = "self_test"))]
+pub mod self_test;
@@ -16,15 +16,34 @@
 
 //! Format DoH requests
 
+use crate::boot_time::Duration;
 use anyhow::{anyhow, Context, Result};
-use quiche::h3;
+use quiche::h3::{self, NameValue};
 use ring::rand::SecureRandom;
+use std::convert::TryFrom;
 use url::Url;
 
 pub type DnsRequest = Vec<quiche::h3::Header>;
 
 const NS_T_AAAA: u8 = 28;
 const NS_C_IN: u8 = 1;
+const NS_T_OPT: u16 = 41;
+const DNS_HEADER_SIZE: usize = 12;
+// High bit of the EDNS flags, which occupy the low half of the OPT record's TTL.
+const EDNS_DO_BIT: u8 = 0x80;
+// Truncation (TC) bit and RCODE mask of the header flags.
+const DNS_TC_BIT: u16 = 0x0200;
+const DNS_RCODE_MASK: u16 = 0x000f;
+// Response (QR) bit, OPCODE mask and Recursion Desired (RD) bit of the header flags.
+const DNS_QR_BIT: u16 = 0x8000;
+const DNS_OPCODE_MASK: u16 = 0x7800;
+const DNS_RD_BIT: u16 = 0x0100;
+const DNS_RCODE_SERVFAIL: u16 = 2;
+// EDNS option code of DNS Cookies (RFC 7873).
+const EDNS_OPT_COOKIE: u16 = 10;
+// UDP payload size for an OPT record added only to carry options. It is meaningless over DoH, so
+// this is just the common choice.
+const DEFAULT_UDP_PAYLOAD_SIZE: u16 = 1232;
 // Used to randomly generate query prefix and query id.
 const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
                          abcdefghijklmnopqrstuvwxyz\
@@ -56,12 +75,43 @@ pub fn probe_query() -> Result<String> {
     Ok(base64::encode_config(query, base64::URL_SAFE_NO_PAD))
 }
 
+// Expressions of an RFC 8484 URI template which add the query as the `dns` parameter, the second
+// for a template which already has query parameters of its own.
+const DNS_TEMPLATE_EXPRESSIONS: &[&str] = &["{?dns}", "{&dns}"];
+
+/// Parses where a DoH server takes queries, given either as a URL or as an RFC 8484 URI template
+/// such as `https://dns.example/custom/resolve{?dns}`. The template's `dns` expression must come
+/// last, and is stripped since `dns_request` always adds the parameter. The URL must be https,
+/// which also makes its path absolute: an empty one is read as `/`.
+pub fn doh_url(template: &str) -> Result<Url> {
+    let base = DNS_TEMPLATE_EXPRESSIONS
+        .iter()
+        .find_map(|expression| template.strip_suffix(expression))
+        .unwrap_or(template);
+    if base.contains(&['{', '}'][..]) {
+        return Err(anyhow!("Unsupported URI template {}", template));
+    }
+    let url = Url::parse(base)?;
+    if url.scheme() != "https" {
+        return Err(anyhow!("DoH URL {} is not https", template));
+    }
+    Ok(url)
+}
+
 /// Takes in a base64-encoded copy of a traditional DNS request and a
 /// URL at which the DoH server is running and produces a set of HTTP/3 headers
-/// corresponding to a DoH request for it.
+/// corresponding to a DoH request for it. Query parameters of the URL are kept, with the `dns`
+/// parameter after them.
 pub fn dns_request(base64_query: &str, url: &Url) -> Result<DnsRequest> {
     let mut path = String::from(url.path());
-    path.push_str("?dns=");
+    match url.query() {
+        Some(query) => {
+            path.push('?');
+            path.push_str(query);
+            path.push_str("&dns=");
+        }
+        None => path.push_str("?dns="),
+    }
     path.push_str(base64_query);
     let req = vec![
         h3::Header::new(b":method", b"GET"),
@@ -78,6 +128,431 @@ pub fn dns_request(base64_query: &str, url: &Url) -> Result<DnsRequest> {
     Ok(req)
 }
 
+/// Takes the wire-format DNS query back out of a request made by `dns_request`, or `None` if the
+/// request doesn't carry one. The last `dns` parameter is the query, as any in the URL itself
+/// come before it.
+pub fn query_of_request(headers: &[h3::Header]) -> Option<Vec<u8>> {
+    let path = std::str::from_utf8(header_value(headers, b":path")?).ok()?;
+    let (_, params) = path.split_once('?')?;
+    let query = params.split('&').rev().find_map(|param| param.strip_prefix("dns="))?;
+    base64::decode_config(query, base64::URL_SAFE_NO_PAD).ok()
+}
+
+// Headers which `dns_request` or the network set, or which would change what the request means.
+const RESERVED_HEADERS: &[&[u8]] =
+    &[b"accept", b"content-type", b"content-length", b"if-none-match", b"priority"];
+
+/// Checks headers to be appended to a DoH request: each must be a regular header with a lowercase
+/// name, as HTTP/3 requires, which isn't one that `dns_request` or the network sets, and whose
+/// value has no line breaks or NULs.
+pub fn check_extra_headers(headers: &[h3::Header]) -> Result<()> {
+    for header in headers {
+        let name = header.name();
+        let token =
+            |b: &u8| b.is_ascii_lowercase() || b.is_ascii_digit() || b"!#$%&'*+-.^_`|~".contains(b);
+        if name.is_empty() || !name.iter().all(token) {
+            return Err(anyhow!("Bad header name {:?}", String::from_utf8_lossy(name)));
+        }
+        if RESERVED_HEADERS.contains(&name) {
+            return Err(anyhow!("Header {} can't be overridden", String::from_utf8_lossy(name)));
+        }
+        if header.value().iter().any(|b| matches!(b, b'\r' | b'\n' | 0)) {
+            return Err(anyhow!("Bad value for header {}", String::from_utf8_lossy(name)));
+        }
+    }
+    Ok(())
+}
+
+/// EDNS(0) parameters (RFC 6891) carried in a DNS message's OPT record.
+///
+/// Queries pass through DoH untouched, so an OPT record the resolver added keeps its DO bit and
+/// payload size. The payload size only matters to UDP transports; DoH answers are read from the
+/// stream in full, up to the request's `max_response_size`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Edns {
+    /// Largest UDP payload the sender can reassemble.
+    pub udp_payload_size: u16,
+    /// Whether DNSSEC records are wanted.
+    pub dnssec_ok: bool,
+}
+
+fn read_u16(msg: &[u8], pos: usize) -> Result<u16> {
+    match msg.get(pos..pos + 2) {
+        Some(bytes) => Ok(u16::from_be_bytes([bytes[0], bytes[1]])),
+        None => Err(anyhow!("DNS message truncated at offset {}", pos)),
+    }
+}
+
+/// Writes `id` into the header of the wire-format DNS message `msg`, returning the ID it had.
+pub fn set_message_id(msg: &mut [u8], id: u16) -> Result<u16> {
+    let previous = read_u16(msg, 0)?;
+    msg[..2].copy_from_slice(&id.to_be_bytes());
+    Ok(previous)
+}
+
+fn read_u32(msg: &[u8], pos: usize) -> Result<u32> {
+    Ok(u32::from(read_u16(msg, pos)?) << 16 | u32::from(read_u16(msg, pos + 2)?))
+}
+
+// Returns the offset just past the (possibly compressed) name starting at `pos`.
+fn skip_name(msg: &[u8], mut pos: usize) -> Result<usize> {
+    loop {
+        let len = *msg.get(pos).ok_or_else(|| anyhow!("DNS name truncated at offset {}", pos))?;
+        match len & 0xc0 {
+            0 if len == 0 => return Ok(pos + 1),
+            0 => pos += 1 + len as usize,
+            // A compression pointer ends the name.
+            0xc0 => return Ok(pos + 2),
+            _ => return Err(anyhow!("Bad DNS label type {:#x} at offset {}", len, pos)),
+        }
+    }
+}
+
+// Finds the offset of the OPT record in the additional section, if there is one. The offset is
+// that of the record's TYPE field, following its (root) owner name.
+fn find_opt(msg: &[u8]) -> Result<Option<usize>> {
+    let count = |pos| read_u16(msg, pos).map(usize::from);
+    let (questions, answers, authorities, additionals) =
+        (count(4)?, count(6)?, count(8)?, count(10)?);
+    let mut pos = DNS_HEADER_SIZE;
+    for _ in 0..questions {
+        // QTYPE and QCLASS follow the name.
+        pos = skip_name(msg, pos)? + 4;
+    }
+    for i in 0..answers + authorities + additionals {
+        let type_pos = skip_name(msg, pos)?;
+        if i >= answers + authorities && read_u16(msg, type_pos)? == NS_T_OPT {
+            return Ok(Some(type_pos));
+        }
+        // TYPE, CLASS, TTL and RDLENGTH precede the RDATA.
+        let rdata_len = usize::from(read_u16(msg, type_pos + 8)?);
+        pos = type_pos + 10 + rdata_len;
+    }
+    if pos > msg.len() {
+        return Err(anyhow!("DNS message truncated, expected {} bytes", pos));
+    }
+    Ok(None)
+}
+
+/// Reads the EDNS(0) parameters of a wire-format DNS message, if it has an OPT record.
+#[cfg(test)]
+pub fn edns(msg: &[u8]) -> Result<Option<Edns>> {
+    Ok(match find_opt(msg)? {
+        Some(pos) => Some(Edns {
+            udp_payload_size: read_u16(msg, pos + 2)?,
+            dnssec_ok: msg.get(pos + 6).ok_or_else(|| anyhow!("OPT record truncated"))?
+                & EDNS_DO_BIT
+                != 0,
+        }),
+        None => None,
+    })
+}
+
+/// What a DNS response says about itself, read from its header and answer section without
+/// decoding the records.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResponseMetadata {
+    /// RCODE from the header. Extended RCODE bits in an OPT record are not included.
+    pub rcode: u8,
+    /// ANCOUNT from the header.
+    pub answer_count: u16,
+    /// Whether the TC bit is set.
+    pub truncated: bool,
+    /// Smallest TTL of the answer records, or `None` if there are none.
+    pub min_ttl: Option<u32>,
+}
+
+/// Reads the metadata of a wire-format DNS response.
+pub fn response_metadata(msg: &[u8]) -> Result<ResponseMetadata> {
+    let flags = read_u16(msg, 2)?;
+    let questions = read_u16(msg, 4)?;
+    let answer_count = read_u16(msg, 6)?;
+    let mut pos = DNS_HEADER_SIZE;
+    for _ in 0..questions {
+        pos = skip_name(msg, pos)? + 4;
+    }
+    let mut min_ttl: Option<u32> = None;
+    for _ in 0..answer_count {
+        let type_pos = skip_name(msg, pos)?;
+        let ttl = read_u32(msg, type_pos + 4)?;
+        min_ttl = Some(min_ttl.map_or(ttl, |min| min.min(ttl)));
+        pos = type_pos + 10 + usize::from(read_u16(msg, type_pos + 8)?);
+    }
+    if pos > msg.len() {
+        return Err(anyhow!("DNS message truncated, expected {} bytes", pos));
+    }
+    Ok(ResponseMetadata {
+        rcode: (flags & DNS_RCODE_MASK) as u8,
+        answer_count,
+        truncated: flags & DNS_TC_BIT != 0,
+        min_ttl,
+    })
+}
+
+/// Metadata of the responses `servfail` builds.
+pub const SERVFAIL_METADATA: ResponseMetadata = ResponseMetadata {
+    rcode: DNS_RCODE_SERVFAIL as u8,
+    answer_count: 0,
+    truncated: false,
+    min_ttl: None,
+};
+
+/// Builds a minimal SERVFAIL response to the wire-format DNS query `query`: its ID, OPCODE, RD
+/// bit and question section, with no records.
+pub fn servfail(query: &[u8]) -> Result<Vec<u8>> {
+    let flags = read_u16(query, 2)?;
+    let questions = read_u16(query, 4)?;
+    let mut pos = DNS_HEADER_SIZE;
+    for _ in 0..questions {
+        pos = skip_name(query, pos)? + 4;
+    }
+    if pos > query.len() {
+        return Err(anyhow!("DNS query truncated, expected {} bytes", pos));
+    }
+    let flags = DNS_QR_BIT | flags & (DNS_OPCODE_MASK | DNS_RD_BIT) | DNS_RCODE_SERVFAIL;
+    let mut response = query[..pos].to_vec();
+    response[2..4].copy_from_slice(&flags.to_be_bytes());
+    // No answer, authority or additional records.
+    response[6..DNS_HEADER_SIZE].fill(0);
+    Ok(response)
+}
+
+/// Applies `edns` to a wire-format DNS query, updating its OPT record or adding one. Everything
+/// else in an existing OPT record, such as its options, is left as it was.
+pub fn set_edns(query: &[u8], edns: Edns) -> Result<Vec<u8>> {
+    let mut query = query.to_vec();
+    let [size_hi, size_lo] = edns.udp_payload_size.to_be_bytes();
+    match find_opt(&query)? {
+        Some(pos) => {
+            if query.len() < pos + 10 {
+                return Err(anyhow!("OPT record truncated"));
+            }
+            query[pos + 2] = size_hi;
+            query[pos + 3] = size_lo;
+            if edns.dnssec_ok {
+                query[pos + 6] |= EDNS_DO_BIT;
+            } else {
+                query[pos + 6] &= !EDNS_DO_BIT;
+            }
+        }
+        None => {
+            let additionals = read_u16(&query, 10)?
+                .checked_add(1)
+                .ok_or_else(|| anyhow!("Too many additional records"))?;
+            query[10..12].copy_from_slice(&additionals.to_be_bytes());
+            let flags = if edns.dnssec_ok { EDNS_DO_BIT } else { 0 };
+            #[rustfmt::skip]
+            let opt = [
+                0,                       // root owner name
+                0,       NS_T_OPT as u8, // TYPE
+                size_hi, size_lo,        // CLASS, the UDP payload size
+                0,       0,              // extended RCODE and version
+                flags,   0,              // flags
+                0,       0,              // RDLENGTH
+            ];
+            query.extend_from_slice(&opt);
+        }
+    }
+    Ok(query)
+}
+
+/// A DNS Cookie (RFC 7873): the client's half, and the server's once one has been seen.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cookie {
+    pub client: [u8; Cookie::CLIENT_LEN],
+    /// Between `MIN_SERVER_LEN` and `MAX_SERVER_LEN` bytes, as the server sent it.
+    pub server: Option<Vec<u8>>,
+}
+
+impl Cookie {
+    pub const CLIENT_LEN: usize = 8;
+    pub const MIN_SERVER_LEN: usize = 8;
+    pub const MAX_SERVER_LEN: usize = 32;
+
+    /// Makes a random client cookie, with no server cookie yet.
+    pub fn generate() -> Result<Self> {
+        let mut client = [0; Self::CLIENT_LEN];
+        ring::rand::SystemRandom::new().fill(&mut client).context("failed to generate cookie")?;
+        Ok(Self { client, server: None })
+    }
+
+    /// Takes the server cookie from `response`'s cookie, if it echoes our client cookie. Returns
+    /// whether it did.
+    pub fn update(&mut self, response: &Cookie) -> bool {
+        if response.client != self.client || response.server.is_none() {
+            return false;
+        }
+        self.server = response.server.clone();
+        true
+    }
+
+    fn option_data(&self) -> Vec<u8> {
+        let mut data = self.client.to_vec();
+        data.extend(self.server.iter().flatten());
+        data
+    }
+}
+
+// Splits the RDATA of the OPT record whose TYPE is at `pos` into (code, data range) options.
+fn opt_options(msg: &[u8], pos: usize) -> Result<Vec<(u16, std::ops::Range<usize>)>> {
+    let end = pos + 10 + usize::from(read_u16(msg, pos + 8)?);
+    if end > msg.len() {
+        return Err(anyhow!("OPT record truncated"));
+    }
+    let mut options = Vec::new();
+    let mut option = pos + 10;
+    while option < end {
+        let code = read_u16(msg, option)?;
+        let data = option + 4..option + 4 + usize::from(read_u16(msg, option + 2)?);
+        if data.end > end {
+            return Err(anyhow!("EDNS option {} overruns its OPT record", code));
+        }
+        option = data.end;
+        options.push((code, data));
+    }
+    Ok(options)
+}
+
+/// Sets the COOKIE option of a wire-format query to `cookie`, replacing any there already. An OPT
+/// record is added to carry it if the query has none.
+pub fn set_cookie(query: &[u8], cookie: &Cookie) -> Result<Vec<u8>> {
+    let mut query = match find_opt(query)? {
+        Some(_) => query.to_vec(),
+        None => {
+            set_edns(query, Edns { udp_payload_size: DEFAULT_UDP_PAYLOAD_SIZE, dnssec_ok: false })?
+        }
+    };
+    let pos = find_opt(&query)?.ok_or_else(|| anyhow!("OPT record missing"))?;
+    let mut rdata = Vec::new();
+    for (code, data) in opt_options(&query, pos)? {
+        if code != EDNS_OPT_COOKIE {
+            rdata.extend_from_slice(&query[data.start - 4..data.end]);
+        }
+    }
+    let data = cookie.option_data();
+    rdata.extend_from_slice(&EDNS_OPT_COOKIE.to_be_bytes());
+    rdata.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    rdata.extend_from_slice(&data);
+    let rdata_len =
+        u16::try_from(rdata.len()).map_err(|_| anyhow!("OPT record too long for a cookie"))?;
+    let old_end = pos + 10 + usize::from(read_u16(&query, pos + 8)?);
+    query[pos + 8..pos + 10].copy_from_slice(&rdata_len.to_be_bytes());
+    query.splice(pos + 10..old_end, rdata);
+    Ok(query)
+}
+
+/// Reads the COOKIE option of a wire-format DNS message, if it has one.
+pub fn cookie(msg: &[u8]) -> Result<Option<Cookie>> {
+    let pos = match find_opt(msg)? {
+        Some(pos) => pos,
+        None => return Ok(None),
+    };
+    let data = match opt_options(msg, pos)?.into_iter().find(|(code, _)| *code == EDNS_OPT_COOKIE) {
+        Some((_, data)) => &msg[data],
+        None => return Ok(None),
+    };
+    let server_len = data.len().wrapping_sub(Cookie::CLIENT_LEN);
+    if data.len() != Cookie::CLIENT_LEN
+        && !(Cookie::MIN_SERVER_LEN..=Cookie::MAX_SERVER_LEN).contains(&server_len)
+    {
+        return Err(anyhow!("Bad COOKIE option length {}", data.len()));
+    }
+    let mut client = [0; Cookie::CLIENT_LEN];
+    client.copy_from_slice(&data[..Cookie::CLIENT_LEN]);
+    let server = Some(data[Cookie::CLIENT_LEN..].to_vec()).filter(|server| !server.is_empty());
+    Ok(Some(Cookie { client, server }))
+}
+
+/// HTTP extensible priority (RFC 9218) of a DoH request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Priority {
+    /// 0 (most urgent) to 7 (least urgent).
+    pub urgency: u8,
+    /// Whether the response can usefully be delivered interleaved with others of its urgency.
+    pub incremental: bool,
+}
+
+impl Priority {
+    const DEFAULT_URGENCY: u8 = 3;
+    const MAX_URGENCY: u8 = 7;
+
+    /// Produces the `priority` request header, or `None` when it would only restate the default.
+    pub fn header(&self) -> Option<h3::Header> {
+        if *self == Self::default() {
+            return None;
+        }
+        let mut value = format!("u={}", self.urgency.min(Self::MAX_URGENCY));
+        if self.incremental {
+            value.push_str(", i");
+        }
+        Some(h3::Header::new(b"priority", value.as_bytes()))
+    }
+
+    /// Reads the priority a request carries, falling back to the default for absent or malformed
+    /// parameters.
+    pub fn of_request(headers: &[h3::Header]) -> Self {
+        let mut priority = Self::default();
+        let value = match header_value(headers, b"priority").map(std::str::from_utf8) {
+            Some(Ok(value)) => value,
+            _ => return priority,
+        };
+        for param in value.split(',').map(str::trim) {
+            if param == "i" || param == "i=?1" {
+                priority.incremental = true;
+            } else if let Some(urgency) = param.strip_prefix("u=") {
+                if let Ok(urgency) = urgency.parse::<u8>() {
+                    priority.urgency = urgency.min(Self::MAX_URGENCY);
+                }
+            }
+        }
+        priority
+    }
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Self { urgency: Self::DEFAULT_URGENCY, incremental: false }
+    }
+}
+
+/// Looks up the value of the first header named `name` in a response.
+pub fn header_value<'a>(headers: &'a [h3::Header], name: &[u8]) -> Option<&'a [u8]> {
+    headers.iter().find(|h| h.name() == name).map(|h| h.value())
+}
+
+/// Extracts the HTTP status code from a response's `:status` pseudo-header.
+pub fn status_code(headers: &[h3::Header]) -> Option<u16> {
+    std::str::from_utf8(header_value(headers, b":status")?).ok()?.parse().ok()
+}
+
+/// Whether a `Content-Type` value is the `application/dns-message` media type, whatever its case
+/// and any parameters.
+pub fn is_dns_message_type(content_type: &[u8]) -> bool {
+    let media_type = content_type.split(|&b| b == b';').next().unwrap_or_default();
+    match std::str::from_utf8(media_type) {
+        Ok(media_type) => media_type.trim().eq_ignore_ascii_case("application/dns-message"),
+        Err(_) => false,
+    }
+}
+
+/// Whether a response body looks like an HTML page: after any leading whitespace, it opens with a
+/// doctype or an `<html>`, `<head>` or `<body>` tag, whatever the case.
+pub fn looks_like_html(body: &[u8]) -> bool {
+    const OPENINGS: [&[u8]; 4] = [b"<!doctype html", b"<html", b"<head", b"<body"];
+    let start = body.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(body.len());
+    let body = &body[start..];
+    OPENINGS.iter().any(|opening| {
+        body.get(..opening.len()).map(|head| head.eq_ignore_ascii_case(opening)).unwrap_or(false)
+    })
+}
+
+/// Extracts the delay a response's `Retry-After` header asks for. Only the delay-seconds form is
+/// understood; an HTTP-date is ignored, as the server's clock can't be relied on.
+pub fn retry_after(headers: &[h3::Header]) -> Option<Duration> {
+    let seconds = std::str::from_utf8(header_value(headers, b"retry-after")?).ok()?;
+    seconds.trim().parse().ok().map(Duration::from_secs)
+}
+
 #[cfg(test)]
 mod tests {
     use quiche::h3::NameValue;
@@ -112,4 +587,307 @@ mod tests {
         let bytes = base64::decode_config(probe_query, base64::URL_SAFE_NO_PAD).unwrap();
         assert_eq!(bytes.len(), PROBE_QUERY_SIZE);
     }
+
+    #[test]
+    fn response_headers() {
+        let headers = vec![
+            quiche::h3::Header::new(b":status", b"304"),
+            quiche::h3::Header::new(b"etag", b"\"abc\""),
+        ];
+        assert_eq!(super::status_code(&headers), Some(304));
+        assert_eq!(super::header_value(&headers, b"etag"), Some(&b"\"abc\""[..]));
+        assert_eq!(super::header_value(&headers, b"cache-control"), None);
+        assert_eq!(super::status_code(&headers[1..]), None);
+        assert_eq!(super::retry_after(&headers), None);
+    }
+
+    #[test]
+    fn dns_message_type() {
+        assert!(super::is_dns_message_type(b"application/dns-message"));
+        assert!(super::is_dns_message_type(b"Application/DNS-Message; charset=binary"));
+        assert!(!super::is_dns_message_type(b"text/html"));
+        assert!(!super::is_dns_message_type(b"application/dns-message-x"));
+        assert!(!super::is_dns_message_type(b""));
+    }
+
+    #[test]
+    fn query_taken_from_request() {
+        let probe = probe_bytes();
+        let base64_query = base64::encode_config(&probe, base64::URL_SAFE_NO_PAD);
+        for url in [LOCALHOST_URL, "https://mylocal.com/dns-query?dns=ignored&x=1"] {
+            let request = super::dns_request(&base64_query, &Url::parse(url).unwrap()).unwrap();
+            assert_eq!(super::query_of_request(&request), Some(probe.clone()), "{}", url);
+        }
+        let url = Url::parse(LOCALHOST_URL).unwrap();
+        let request = super::dns_request("not base64!", &url).unwrap();
+        assert_eq!(super::query_of_request(&request), None);
+        assert_eq!(super::query_of_request(&[]), None);
+    }
+
+    #[test]
+    fn html_bodies() {
+        assert!(super::looks_like_html(b"<!DOCTYPE html><html><body>Sign in</body></html>"));
+        assert!(super::looks_like_html(b"\r\n  <HTML lang=\"en\">"));
+        assert!(super::looks_like_html(b"<head><meta http-equiv=\"refresh\">"));
+        assert!(!super::looks_like_html(b"<?xml version=\"1.0\"?>"));
+        assert!(!super::looks_like_html(b"<ht"));
+        assert!(!super::looks_like_html(b""));
+    }
+
+    #[test]
+    fn extra_headers_checked() {
+        use quiche::h3::Header;
+        let good =
+            [Header::new(b"authorization", b"Bearer abc"), Header::new(b"x-device-id", b"1234")];
+        assert!(super::check_extra_headers(&good).is_ok());
+        for bad in [
+            Header::new(b":path", b"/elsewhere"),
+            Header::new(b"content-type", b"text/plain"),
+            Header::new(b"accept", b"*/*"),
+            Header::new(b"Authorization", b"Bearer abc"),
+            Header::new(b"", b"empty"),
+            Header::new(b"x-device-id", b"1234\r\nx-injected: 1"),
+        ] {
+            assert!(super::check_extra_headers(std::slice::from_ref(&bad)).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn retry_after_header() {
+        use crate::boot_time::Duration;
+        let retry_after =
+            |value: &[u8]| super::retry_after(&[quiche::h3::Header::new(b"retry-after", value)]);
+        assert_eq!(retry_after(b"120"), Some(Duration::from_secs(120)));
+        assert_eq!(retry_after(b" 0"), Some(Duration::from_secs(0)));
+        assert_eq!(retry_after(b"Wed, 21 Oct 2015 07:28:00 GMT"), None);
+        assert_eq!(retry_after(b"-1"), None);
+    }
+
+    fn probe_bytes() -> Vec<u8> {
+        base64::decode_config(super::probe_query().unwrap(), base64::URL_SAFE_NO_PAD).unwrap()
+    }
+
+    #[test]
+    fn edns_preserved_in_request() {
+        use super::Edns;
+        let edns = Edns { udp_payload_size: 4096, dnssec_ok: true };
+        let probe = probe_bytes();
+        assert_eq!(super::edns(&probe).unwrap(), None);
+        let query = super::set_edns(&probe, edns).unwrap();
+        assert_eq!(query.len(), probe.len() + 11);
+        assert_eq!(super::edns(&query).unwrap(), Some(edns));
+
+        let url = Url::parse(LOCALHOST_URL).unwrap();
+        let base64_query = base64::encode_config(&query, base64::URL_SAFE_NO_PAD);
+        let request = super::dns_request(&base64_query, &url).unwrap();
+        let path = std::str::from_utf8(request[3].value()).unwrap();
+        let sent =
+            base64::decode_config(path.split("?dns=").nth(1).unwrap(), base64::URL_SAFE_NO_PAD)
+                .unwrap();
+        assert_eq!(super::edns(&sent).unwrap(), Some(edns));
+    }
+
+    #[test]
+    fn set_edns_updates_existing_opt() {
+        use super::Edns;
+        let plain = Edns { udp_payload_size: 1232, dnssec_ok: false };
+        let query = super::set_edns(&probe_bytes(), plain).unwrap();
+        let signed = Edns { udp_payload_size: 4096, dnssec_ok: true };
+        let updated = super::set_edns(&query, signed).unwrap();
+        assert_eq!(updated.len(), query.len());
+        assert_eq!(updated[10..12], [0, 1]);
+        assert_eq!(super::edns(&updated).unwrap(), Some(signed));
+        assert_eq!(super::edns(&super::set_edns(&updated, plain).unwrap()).unwrap(), Some(plain));
+    }
+
+    #[test]
+    fn cookie_round_trip() {
+        use super::{Cookie, Edns};
+        let probe = probe_bytes();
+        assert_eq!(super::cookie(&probe).unwrap(), None);
+        let mut cookie = Cookie::generate().unwrap();
+        let query = super::set_cookie(&probe, &cookie).unwrap();
+        // An OPT record with a client cookie option.
+        assert_eq!(query.len(), probe.len() + 11 + 4 + Cookie::CLIENT_LEN);
+        assert_eq!(super::cookie(&query).unwrap(), Some(cookie.clone()));
+
+        // The server's answer echoes the client cookie and adds its own.
+        let server = Cookie { server: Some(vec![0x5a; 16]), ..cookie.clone() };
+        let mut answer = super::set_cookie(&query, &server).unwrap();
+        answer[2] |= 0x80;
+        assert!(cookie.update(&super::cookie(&answer).unwrap().unwrap()));
+        assert_eq!(cookie, server);
+        // One for another client is ignored.
+        let stranger = Cookie { client: [0; Cookie::CLIENT_LEN], server: Some(vec![1; 8]) };
+        assert!(!cookie.update(&stranger));
+        assert_eq!(cookie, server);
+
+        // The next query carries both, replacing the old option and keeping the OPT parameters.
+        let edns = Edns { udp_payload_size: 4096, dnssec_ok: true };
+        let query = super::set_cookie(&super::set_edns(&query, edns).unwrap(), &cookie).unwrap();
+        assert_eq!(query.len(), probe.len() + 11 + 4 + Cookie::CLIENT_LEN + 16);
+        assert_eq!(super::cookie(&query).unwrap(), Some(cookie));
+        assert_eq!(super::edns(&query).unwrap(), Some(edns));
+        assert_eq!(query[10..12], [0, 1]);
+    }
+
+    #[test]
+    fn bad_cookie_lengths() {
+        use super::Cookie;
+        let probe = probe_bytes();
+        let cookie = Cookie::generate().unwrap();
+        for server_len in [1, 7, 33] {
+            let bad = Cookie { server: Some(vec![0; server_len]), ..cookie.clone() };
+            let query = super::set_cookie(&probe, &bad).unwrap();
+            assert!(super::cookie(&query).is_err(), "{}", server_len);
+        }
+        let mut query = super::set_cookie(&probe, &cookie).unwrap();
+        // Claim a longer option than the record holds.
+        let len = query.len();
+        query[len - Cookie::CLIENT_LEN - 1] = 20;
+        assert!(super::cookie(&query).is_err());
+    }
+
+    #[test]
+    fn large_signed_answer() {
+        const NS_T_RRSIG: u8 = 46;
+        const RRSIG_COUNT: u8 = 20;
+        let mut answer = probe_bytes();
+        // Mark it a response carrying the signatures.
+        answer[2] |= 0x80;
+        answer[7] = RRSIG_COUNT;
+        for _ in 0..RRSIG_COUNT {
+            #[rustfmt::skip]
+            let rrsig = [
+                0xc0, 12,          // name, compressed to point at the question
+                0,    NS_T_RRSIG,  // TYPE
+                0,    1,           // CLASS
+                0,    0, 0x0e, 0x10, // TTL
+                0,    200,         // RDLENGTH
+            ];
+            answer.extend_from_slice(&rrsig);
+            answer.extend_from_slice(&[0xab; 200]);
+        }
+        let edns = super::Edns { udp_payload_size: 4096, dnssec_ok: true };
+        let answer = super::set_edns(&answer, edns).unwrap();
+        assert!(answer.len() > crate::config::MAX_DATAGRAM_SIZE * 3);
+        assert_eq!(super::edns(&answer).unwrap(), Some(edns));
+        assert!(super::edns(&answer[..answer.len() - 300]).is_err());
+    }
+
+    #[test]
+    fn response_header_and_ttls() {
+        let mut answer = probe_bytes();
+        // A truncated NXDOMAIN response, which is unusual but keeps the fields apart.
+        answer[2] |= 0x82;
+        answer[3] |= 3;
+        answer[7] = 3;
+        for ttl in [3600u32, 60, 300].iter() {
+            #[rustfmt::skip]
+            let aaaa = [
+                0xc0, 12,        // name, compressed to point at the question
+                0,    28,        // TYPE
+                0,    1,         // CLASS
+            ];
+            answer.extend_from_slice(&aaaa);
+            answer.extend_from_slice(&ttl.to_be_bytes());
+            answer.extend_from_slice(&[0, 16]);
+            answer.extend_from_slice(&[0x20; 16]);
+        }
+        let metadata = super::ResponseMetadata {
+            rcode: 3,
+            answer_count: 3,
+            truncated: true,
+            min_ttl: Some(60),
+        };
+        assert_eq!(super::response_metadata(&answer).unwrap(), metadata);
+        // EDNS records don't count, even though their TTL field is smaller.
+        let edns = super::Edns { udp_payload_size: 4096, dnssec_ok: false };
+        let answer = super::set_edns(&answer, edns).unwrap();
+        assert_eq!(super::response_metadata(&answer).unwrap(), metadata);
+        assert!(super::response_metadata(&answer[..answer.len() - 20]).is_err());
+
+        let probe = super::response_metadata(&probe_bytes()).unwrap();
+        assert_eq!((probe.rcode, probe.answer_count, probe.truncated), (0, 0, false));
+        assert_eq!(probe.min_ttl, None);
+    }
+
+    #[test]
+    fn priority_header() {
+        use super::Priority;
+        let url = Url::parse(LOCALHOST_URL).unwrap();
+        let mut request = super::dns_request(&super::probe_query().unwrap(), &url).unwrap();
+        assert!(Priority::default().header().is_none());
+        assert_eq!(Priority::of_request(&request), Priority::default());
+
+        request.extend(Priority { urgency: 0, incremental: true }.header());
+        assert_eq!(request.len(), H3_DNS_REQUEST_HEADER_SIZE + 1);
+        assert_eq!(request[6].name(), b"priority");
+        assert_eq!(request[6].value(), b"u=0, i");
+        assert_eq!(Priority::of_request(&request), Priority { urgency: 0, incremental: true });
+
+        let header = Priority { urgency: 9, incremental: false }.header().unwrap();
+        assert_eq!(header.value(), b"u=7");
+    }
+
+    #[test]
+    fn message_id() {
+        // Two queries in flight with the same ID, each carrying it alongside while they are sent
+        // as ID 0.
+        let mut queries = [probe_bytes(), probe_bytes()];
+        let mut carried = Vec::new();
+        for query in &mut queries {
+            super::set_message_id(query, 0x1234).unwrap();
+            carried.push(super::set_message_id(query, 0).unwrap());
+            assert_eq!(query[..2], [0, 0]);
+        }
+        assert_eq!(carried, [0x1234, 0x1234]);
+        // The answers come back in the other order, and each gets its query's ID back.
+        for (query, id) in queries.iter_mut().zip(&carried).rev() {
+            assert_eq!(super::set_message_id(query, *id).unwrap(), 0);
+            assert_eq!(query[..2], [0x12, 0x34]);
+        }
+        assert!(super::set_message_id(&mut [0], 0).is_err());
+    }
+
+    #[test]
+    fn custom_path() {
+        let query = super::probe_query().unwrap();
+        let path = |template: &str| {
+            let url = super::doh_url(template).unwrap();
+            let request = super::dns_request(&query, &url).unwrap();
+            String::from_utf8(request[3].value().to_vec()).unwrap()
+        };
+        let expected = format!("/custom/resolve?dns={}", query);
+        assert_eq!(path("https://mylocal.com/custom/resolve"), expected);
+        assert_eq!(path("https://mylocal.com/custom/resolve{?dns}"), expected);
+        assert_eq!(
+            path("https://mylocal.com/resolve?ct{&dns}"),
+            format!("/resolve?ct&dns={}", query)
+        );
+        assert_eq!(path("https://mylocal.com"), format!("/?dns={}", query));
+
+        assert!(super::doh_url("https://mylocal.com/{tenant}/dns-query{?dns}").is_err());
+        assert!(super::doh_url("https://mylocal.com/dns-query{?dns}/more").is_err());
+        assert!(super::doh_url("http://mylocal.com/dns-query").is_err());
+    }
+
+    #[test]
+    fn servfail() {
+        // A query for example.com AAAA with an OPT record, which the response leaves out.
+        let query = [
+            0xab, 0xcd, 0x01, 0x20, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 7, b'e', b'x',
+            b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0, 0x00, 28, 0x00, 0x01, 0, 0x00,
+            41, 0x04, 0xd0, 0, 0, 0, 0, 0, 0,
+        ];
+        let response = super::servfail(&query).unwrap();
+        // Same ID and question, with QR and RD set and RCODE SERVFAIL.
+        assert_eq!(response[..4], [0xab, 0xcd, 0x81, 0x02]);
+        assert_eq!(response[4..12], [0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(response[12..], query[12..29]);
+        assert_eq!(super::response_metadata(&response).unwrap(), super::SERVFAIL_METADATA);
+        assert_eq!(super::edns(&response).unwrap(), None);
+
+        assert!(super::servfail(&query[..20]).is_err());
+    }
 }
@@ -16,23 +16,27 @@
 
 //! C API for the DoH backend for the Android DnsResolver module.
 
-use crate::boot_time::{timeout, BootTime, Duration};
-use crate::dispatcher::{Command, Dispatcher, Response, ServerInfo};
+use crate::boot_time::Duration;
+use crate::connection;
+use crate::dispatcher::{
+    wait_for_answer, CacheStats, Command, Config, ConnectFailure, Dispatcher, MetricsSnapshot,
+    ProvidedSocket, QueryError, QueryOptions, ServerInfo, SocketBinding, StreamMode,
+};
+use crate::encoding;
 use crate::network::{SocketTagger, ValidationReporter};
 use futures::FutureExt;
 use libc::{c_char, int32_t, size_t, ssize_t, uint32_t, uint64_t};
-use log::{error, warn};
+use log::{error, info, warn};
+use std::collections::HashMap;
 use std::ffi::CString;
+use std::fmt::Write;
 use std::net::{IpAddr, SocketAddr};
 use std::ops::DerefMut;
 use std::os::unix::io::RawFd;
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::{ptr, slice};
-use tokio::runtime::Builder;
-use tokio::sync::oneshot;
 use tokio::task;
-use url::Url;
 
 pub type ValidationCallback =
     extern "C" fn(net_id: uint32_t, success: bool, ip_addr: *const c_char, host: *const c_char);
@@ -43,6 +47,109 @@ pub struct FeatureFlags {
     probe_timeout_ms: uint64_t,
     idle_timeout_ms: uint64_t,
     use_session_resumption: bool,
+    /// Longest a connection's handshake may take before it fails. 0 leaves it to the idle
+    /// timeout.
+    connect_timeout_ms: uint64_t,
+    /// How long queries wait for an answer when `doh_query` is given a timeout of 0. 0 means
+    /// `DEFAULT_QUERY_TIMEOUT`.
+    query_timeout_ms: uint64_t,
+    /// Interface to bind sockets to with `SO_BINDTODEVICE` rather than marking them with
+    /// `sk_mark`, as a null terminated string. Null or empty marks them.
+    bind_device: *const c_char,
+    /// Whether connections use `socket_fd`, a UDP socket set up by the caller, rather than
+    /// creating their own sockets. `sk_mark` and `bind_device` are then ignored.
+    use_socket_fd: bool,
+    /// Descriptor of the socket for `use_socket_fd`, which `doh_net_new` takes ownership of. It is
+    /// closed once the network is deleted, or probed again, and its connections are done with it,
+    /// or at once if it is unusable.
+    socket_fd: int32_t,
+    /// Whether to send DNS messages straight over QUIC streams, as DNS over QUIC (RFC 9250) does,
+    /// rather than over HTTP/3, for interoperability experiments. The server must then offer the
+    /// `doq` ALPN protocol, and is reached on port 853 rather than 443.
+    use_dns_over_quic: bool,
+}
+
+/// Counters describing the work a `DohDispatcher` has handled, filled in by `doh_get_metrics()`.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct DohMetrics {
+    /// Queries submitted but not yet picked up by the dispatcher.
+    queued_queries: uint64_t,
+    /// Queries rejected because the submission queue was full.
+    overloaded_queries: uint64_t,
+    /// Connections retired for reaching their query limit.
+    connection_rotations: uint64_t,
+    /// Connections the dispatcher has tried to establish.
+    connection_attempts: uint64_t,
+    /// Connections whose handshake completed.
+    connection_successes: uint64_t,
+    /// Connection attempts which failed because the server couldn't be reached.
+    connection_failures_unreachable: uint64_t,
+    /// Connection attempts whose handshake didn't complete before the idle timeout.
+    connection_failures_handshake_timeout: uint64_t,
+    /// Connection attempts whose TLS handshake failed.
+    connection_failures_tls_verify: uint64_t,
+    /// Connection attempts to servers supporting none of our QUIC versions.
+    connection_failures_version_negotiation: uint64_t,
+    /// Connection attempts presented with a certificate for another name, as by a captive portal.
+    connection_failures_captive_portal: uint64_t,
+    /// Connection attempts which failed for any other reason.
+    connection_failures_other: uint64_t,
+    /// Connections torn down for being stuck handshaking or closing.
+    zombies_reaped: uint64_t,
+    /// Answers held for conditional requests, across all networks.
+    response_cache_entries: uint64_t,
+    /// Bytes those answers take up.
+    response_cache_bytes: uint64_t,
+    /// Most packet buffers borrowed at once by the dispatcher's connections.
+    buffer_high_water_mark: uint64_t,
+    /// Connections whose handshake is in progress.
+    handshakes_in_progress: uint64_t,
+    /// Config lookups served a live config from the cache.
+    config_hits: uint64_t,
+    /// Config lookups which found no live config and built one.
+    config_misses: uint64_t,
+    /// Built configs thrown away because an identical one was installed while building.
+    config_races: uint64_t,
+    /// Number of configs built.
+    config_constructions: uint64_t,
+    /// Time spent building them, in microseconds.
+    config_construction_time_us: uint64_t,
+    /// Longest time spent building a single config, in microseconds.
+    config_max_construction_time_us: uint64_t,
+}
+
+impl DohMetrics {
+    fn new(snapshot: MetricsSnapshot, cache: CacheStats) -> Self {
+        Self {
+            queued_queries: snapshot.queued_queries as uint64_t,
+            overloaded_queries: snapshot.overloaded_queries,
+            connection_rotations: snapshot.connection_rotations,
+            connection_attempts: snapshot.connection_attempts,
+            connection_successes: snapshot.connection_successes,
+            connection_failures_unreachable: snapshot
+                .connection_failures(ConnectFailure::Unreachable),
+            connection_failures_handshake_timeout: snapshot
+                .connection_failures(ConnectFailure::HandshakeTimeout),
+            connection_failures_tls_verify: snapshot.connection_failures(ConnectFailure::TlsVerify),
+            connection_failures_version_negotiation: snapshot
+                .connection_failures(ConnectFailure::VersionNegotiation),
+            connection_failures_captive_portal: snapshot
+                .connection_failures(ConnectFailure::CaptivePortal),
+            connection_failures_other: snapshot.connection_failures(ConnectFailure::Other),
+            zombies_reaped: snapshot.zombies_reaped,
+            response_cache_entries: snapshot.response_cache_entries as uint64_t,
+            response_cache_bytes: snapshot.response_cache_bytes as uint64_t,
+            buffer_high_water_mark: snapshot.buffer_high_water_mark as uint64_t,
+            handshakes_in_progress: snapshot.handshakes_in_progress as uint64_t,
+            config_hits: cache.hits,
+            config_misses: cache.misses,
+            config_races: cache.races,
+            config_constructions: cache.constructions,
+            config_construction_time_us: cache.total_construction_time.as_micros() as uint64_t,
+            config_max_construction_time_us: cache.max_construction_time.as_micros() as uint64_t,
+        }
+    }
 }
 
 fn wrap_validation_callback(validation_fn: ValidationCallback) -> ValidationReporter {
@@ -84,16 +191,47 @@ fn wrap_tag_socket_callback(tag_socket_fn: TagSocketCallback) -> SocketTagger {
     })
 }
 
-pub struct DohDispatcher(Mutex<Dispatcher>);
+pub struct DohDispatcher {
+    dispatcher: Mutex<Dispatcher>,
+    // What `doh_net_new` was last given for each network.
+    networks: Mutex<HashMap<uint32_t, ProbedNetwork>>,
+}
+
+struct ProbedNetwork {
+    info: ServerInfo,
+    // `FeatureFlags::query_timeout_ms`, for queries which don't set their own.
+    query_timeout: Option<Duration>,
+}
 
 impl DohDispatcher {
     fn lock(&self) -> impl DerefMut<Target = Dispatcher> + '_ {
-        self.0.lock().unwrap()
+        self.dispatcher.lock().unwrap()
+    }
+
+    // How long a query given `timeout_ms` waits for its answer on the network.
+    fn query_timeout(&self, net_id: uint32_t, timeout_ms: uint64_t) -> Duration {
+        if timeout_ms != 0 {
+            return Duration::from_millis(timeout_ms);
+        }
+        let networks = self.networks.lock().unwrap();
+        networks
+            .get(&net_id)
+            .and_then(|network| network.query_timeout)
+            .unwrap_or(DEFAULT_QUERY_TIMEOUT)
+    }
+
+    // The server the network was probed with.
+    fn server(&self, net_id: uint32_t) -> Option<ServerInfo> {
+        self.networks.lock().unwrap().get(&net_id).map(|network| network.info.clone())
     }
 }
 
 const SYSTEM_CERT_PATH: &str = "/system/etc/security/cacerts";
 
+/// How long queries wait for an answer if neither `doh_query` nor the network's
+/// `FeatureFlags::query_timeout_ms` says. Matches the resolver's default DoH query timeout.
+pub const DEFAULT_QUERY_TIMEOUT: Duration = Duration::from_secs(30);
+
 /// The return code of doh_query means that there is no answer.
 pub const DOH_RESULT_INTERNAL_ERROR: ssize_t = -1;
 /// The return code of doh_query means that query can't be sent.
@@ -113,6 +251,36 @@ pub const DOH_LOG_LEVEL_DEBUG: u32 = 3;
 pub const DOH_LOG_LEVEL_TRACE: u32 = 4;
 
 const DOH_PORT: u16 = 443;
+const DOQ_PORT: u16 = 853;
+
+// How the sockets of a network's connections are kept on it, as `flags` and `sk_mark` say.
+// # Safety
+// `flags.bind_device` is null or a null terminated string. If `flags.use_socket_fd` is set,
+// `flags.socket_fd` is an open descriptor which nothing else owns.
+unsafe fn socket_binding(
+    sk_mark: uint32_t,
+    flags: &FeatureFlags,
+) -> Result<SocketBinding, int32_t> {
+    if flags.use_socket_fd {
+        return ProvidedSocket::from_raw_fd(flags.socket_fd).map(SocketBinding::Provided).map_err(
+            |e| {
+                error!("Unusable socket: {}", e);
+                -libc::EINVAL
+            },
+        );
+    }
+    if !flags.bind_device.is_null() {
+        match std::ffi::CStr::from_ptr(flags.bind_device).to_str() {
+            Ok("") => {}
+            Ok(device) => return Ok(SocketBinding::Device(device.to_string())),
+            Err(_) => {
+                error!("bad bind_device");
+                return Err(-libc::EINVAL);
+            }
+        }
+    }
+    Ok(SocketBinding::Mark(sk_mark))
+}
 
 fn level_from_u32(level: u32) -> Option<log::Level> {
     use log::Level::*;
@@ -156,7 +324,10 @@ pub extern "C" fn doh_dispatcher_new(
         wrap_validation_callback(validation_fn),
         wrap_tag_socket_callback(tag_socket_fn),
     ) {
-        Ok(c) => Box::into_raw(Box::new(DohDispatcher(Mutex::new(c)))),
+        Ok(c) => Box::into_raw(Box::new(DohDispatcher {
+            dispatcher: Mutex::new(c),
+            networks: Default::default(),
+        })),
         Err(e) => {
             error!("doh_dispatcher_new: failed: {:?}", e);
             ptr::null_mut()
@@ -178,7 +349,10 @@ pub unsafe extern "C" fn doh_dispatcher_delete(doh: *mut DohDispatcher) {
 /// # Safety
 /// `doh` must be a non-null pointer previously created by `doh_dispatcher_new()`
 /// and not yet deleted by `doh_dispatcher_delete()`.
-/// `url`, `domain`, `ip_addr`, `cert_path` are null terminated strings.
+/// `url`, `domain`, `ip_addr`, `cert_path` are null terminated strings, as is
+/// `flags.bind_device` unless it is null.
+/// If `flags.use_socket_fd` is set, `flags.socket_fd` must be an open descriptor which nothing
+/// else owns, as it is closed whatever this returns.
 #[no_mangle]
 pub unsafe extern "C" fn doh_net_new(
     doh: &DohDispatcher,
@@ -190,6 +364,117 @@ pub unsafe extern "C" fn doh_net_new(
     cert_path: *const c_char,
     flags: &FeatureFlags,
 ) -> int32_t {
+    // First, so that a provided socket is owned, and closed, however this returns.
+    let socket_binding = match socket_binding(sk_mark, flags) {
+        Ok(socket_binding) => socket_binding,
+        Err(e) => return e,
+    };
+    let info = match server_info(net_id, url, domain, ip_addr, cert_path, socket_binding, flags) {
+        Ok(info) => info,
+        Err(e) => return e,
+    };
+    let cmd = Command::Probe {
+        info: info.clone(),
+        timeout: Duration::from_millis(flags.probe_timeout_ms),
+    };
+    if let Err(e) = doh.lock().send_cmd(cmd) {
+        error!("Failed to send the probe: {:?}", e);
+        return -libc::EPIPE;
+    }
+    let query_timeout = match flags.query_timeout_ms {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    };
+    doh.networks.lock().unwrap().insert(net_id, ProbedNetwork { info, query_timeout });
+    0
+}
+
+/// Checks that the trust store at `cert_path` loads, for provisioning to reject a bad certificate
+/// directory at once. Only the certificates are loaded, and nothing is cached. As for
+/// `doh_net_new()`, an empty `cert_path` means the system trust store. Returns 0, or `-EINVAL` if
+/// the directory is missing or empty, or its certificates don't load.
+/// # Safety
+/// `cert_path` is a null terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn doh_validate_cert_path(cert_path: *const c_char) -> int32_t {
+    let cert_path = match std::ffi::CStr::from_ptr(cert_path).to_str() {
+        Ok("") => SYSTEM_CERT_PATH,
+        Ok(cert_path) => cert_path,
+        Err(_) => return -libc::EINVAL,
+    };
+    match Config::validate_cert_path(Some(cert_path)) {
+        Ok(()) => 0,
+        Err(e) => {
+            warn!("doh_validate_cert_path: {}", e);
+            -libc::EINVAL
+        }
+    }
+}
+
+/// Checks that the DoH server `doh_net_new()` would be given could be connected to as
+/// configured, without contacting it or caching anything, for configuration UIs to flag a bad
+/// setup early. The trust store at `cert_path` must load, and the settings in `flags` must be in
+/// range and consistent, such as `connect_timeout_ms` being shorter than `idle_timeout_ms`.
+/// The socket settings of `flags` are ignored, and `flags.socket_fd` is left to the caller.
+/// Returns 0, or `-EINVAL` for a setup `doh_net_new()` would fail to use.
+/// # Safety
+/// `url`, `domain`, `ip_addr`, `cert_path` are null terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn doh_validate_config(
+    url: *const c_char,
+    domain: *const c_char,
+    ip_addr: *const c_char,
+    cert_path: *const c_char,
+    flags: &FeatureFlags,
+) -> int32_t {
+    // No socket is made, so how it would be bound doesn't matter.
+    let socket_binding = SocketBinding::Mark(0);
+    let info = match server_info(0, url, domain, ip_addr, cert_path, socket_binding, flags) {
+        Ok(info) => info,
+        Err(e) => return e,
+    };
+    match Dispatcher::validate(&info) {
+        Ok(()) => 0,
+        Err(e) => {
+            warn!("doh_validate_config: {}", e);
+            -libc::EINVAL
+        }
+    }
+}
+
+/// Sends a DNS query through the DoH encoding and connection driver that queries go through to
+/// an in-process server on the loopback interface, as a sanity check at boot which needs no
+/// network. Returns 0 if the answer came back intact, or `-EIO` with the reason logged.
+/// Only present in builds with the `self_test` feature, for which `DOH_SELF_TEST` must be
+/// defined to declare it.
+#[cfg(feature = "self_test")]
+#[no_mangle]
+pub extern "C" fn doh_self_test() -> int32_t {
+    let result = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(anyhow::Error::from)
+        .and_then(|runtime| runtime.block_on(crate::self_test::self_test()));
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            error!("doh_self_test: failed: {:?}", e);
+            -libc::EIO
+        }
+    }
+}
+
+// The server `doh_net_new` describes, or the error it returns for arguments it can't use.
+// `url`, `domain`, `ip_addr` and `cert_path` are null terminated strings.
+unsafe fn server_info(
+    net_id: uint32_t,
+    url: *const c_char,
+    domain: *const c_char,
+    ip_addr: *const c_char,
+    cert_path: *const c_char,
+    socket_binding: SocketBinding,
+    flags: &FeatureFlags,
+) -> Result<ServerInfo, int32_t> {
     let (url, domain, ip_addr, cert_path) = match (
         std::ffi::CStr::from_ptr(url).to_str(),
         std::ffi::CStr::from_ptr(domain).to_str(),
@@ -212,40 +497,57 @@ pub unsafe extern "C" fn doh_net_new(
         }
         _ => {
             error!("bad input"); // Should not happen
-            return -libc::EINVAL;
+            return Err(-libc::EINVAL);
         }
     };
 
-    let (url, ip_addr) = match (Url::parse(url), IpAddr::from_str(&ip_addr)) {
+    let (url, ip_addr) = match (encoding::doh_url(url), IpAddr::from_str(&ip_addr)) {
         (Ok(url), Ok(ip_addr)) => (url, ip_addr),
         _ => {
             error!("bad ip or url"); // Should not happen
-            return -libc::EINVAL;
+            return Err(-libc::EINVAL);
         }
     };
-    let cmd = Command::Probe {
-        info: ServerInfo {
-            net_id,
-            url,
-            peer_addr: SocketAddr::new(ip_addr, DOH_PORT),
-            domain,
-            sk_mark,
-            cert_path,
-            idle_timeout_ms: flags.idle_timeout_ms,
-            use_session_resumption: flags.use_session_resumption,
-        },
-        timeout: Duration::from_millis(flags.probe_timeout_ms),
+    let (port, stream_mode) = if flags.use_dns_over_quic {
+        (DOQ_PORT, StreamMode::RawDns)
+    } else {
+        (DOH_PORT, StreamMode::Http3)
     };
-    if let Err(e) = doh.lock().send_cmd(cmd) {
-        error!("Failed to send the probe: {:?}", e);
-        return -libc::EPIPE;
-    }
-    0
+    Ok(ServerInfo {
+        net_id,
+        url,
+        peer_addr: SocketAddr::new(ip_addr, port),
+        domain,
+        socket_binding,
+        cert_path,
+        cert_pem: None,
+        idle_timeout_ms: flags.idle_timeout_ms,
+        use_session_resumption: flags.use_session_resumption,
+        connection_options: connection::Options {
+            handshake_timeout: match flags.connect_timeout_ms {
+                0 => None,
+                ms => Some(Duration::from_millis(ms)),
+            },
+            stream_mode,
+            ..Default::default()
+        },
+        max_queries_per_connection: None,
+        fallback_ports: Vec::new(),
+        connection_window_cap: None,
+        server_errors: Default::default(),
+        response_cache: Default::default(),
+        use_dns_cookies: false,
+        fail_truncated_answers: false,
+        retry_on_connection_loss: true,
+        transport_params: Default::default(),
+        extra_application_protos: Vec::new(),
+    })
 }
 
 /// Sends a DNS query via the network associated to the given |net_id| and waits for the response.
 /// The return code should be either one of the public constant DOH_RESULT_* to indicate the error
-/// or the size of the answer.
+/// or the size of the answer. A `timeout_ms` of 0 waits for the network's
+/// `FeatureFlags::query_timeout_ms`.
 /// # Safety
 /// `doh` must be a non-null pointer previously created by `doh_dispatcher_new()`
 /// and not yet deleted by `doh_dispatcher_delete()`.
@@ -262,56 +564,197 @@ pub unsafe extern "C" fn doh_query(
     timeout_ms: uint64_t,
 ) -> ssize_t {
     let q = slice::from_raw_parts_mut(dns_query, dns_query_len);
+    let t = doh.query_timeout(net_id, timeout_ms);
 
-    let (resp_tx, resp_rx) = oneshot::channel();
-    let t = Duration::from_millis(timeout_ms);
-    if let Some(expired_time) = BootTime::now().checked_add(t) {
-        let cmd = Command::Query {
-            net_id,
-            base64_query: base64::encode_config(q, base64::URL_SAFE_NO_PAD),
-            expired_time,
-            resp: resp_tx,
-        };
+    // Only hold the lock while submitting, so that queries don't wait on each other's answers.
+    // Anything larger than the caller's buffer would be rejected below anyway.
+    let options = QueryOptions { max_response_size: Some(response_len), ..Default::default() };
+    let resp_rx = match doh.lock().submit_query(net_id, q, t, options) {
+        Ok(resp_rx) => resp_rx,
+        Err(e) => {
+            error!("Failed to send the query: {:?}", e);
+            return DOH_RESULT_CAN_NOT_SEND;
+        }
+    };
 
-        if let Err(e) = doh.lock().send_cmd(cmd) {
+    copy_answer(wait_for_answer(resp_rx, t), response, response_len)
+}
+
+// Copies `answer` to the `response_len` bytes at `response`, returning the size of the answer, or
+// maps the error it failed with to the DOH_RESULT_* code for it.
+unsafe fn copy_answer(
+    answer: Result<Vec<u8>, QueryError>,
+    response: *mut u8,
+    response_len: size_t,
+) -> ssize_t {
+    match answer {
+        Ok(answer) => {
+            if answer.len() > response_len || answer.len() > isize::MAX as usize {
+                return DOH_RESULT_INTERNAL_ERROR;
+            }
+            let response = slice::from_raw_parts_mut(response, answer.len());
+            response.copy_from_slice(&answer);
+            answer.len() as ssize_t
+        }
+        Err(QueryError::Timeout) => DOH_RESULT_TIMEOUT,
+        // The resolver falls back to another transport for queries which can't be sent.
+        Err(QueryError::Truncated) => {
+            warn!("Answer truncated, falling back");
+            DOH_RESULT_CAN_NOT_SEND
+        }
+        Err(QueryError::ResponseTooLarge) => {
+            error!("Response larger than {} bytes", response_len);
+            DOH_RESULT_INTERNAL_ERROR
+        }
+        Err(e) => {
+            error!("Non-successful response: {:?}", e);
+            DOH_RESULT_CAN_NOT_SEND
+        }
+    }
+}
+
+/// Sends a DNS query to the DoH server of the network with the given |net_id| over a connection
+/// of its own, set up for this query and closed once it is answered, and waits for the response.
+/// The network's connections and cached TLS configuration are left untouched, so this is meant for
+/// one-off queries, such as for diagnostics, which shouldn't disturb them. The return code and
+/// `timeout_ms` are as for `doh_query`.
+/// # Safety
+/// `doh` must be a non-null pointer previously created by `doh_dispatcher_new()`
+/// and not yet deleted by `doh_dispatcher_delete()`.
+/// `dns_query` must point to a buffer at least `dns_query_len` in size.
+/// `response` must point to a buffer at least `response_len` in size.
+#[no_mangle]
+pub unsafe extern "C" fn doh_query_once(
+    doh: &DohDispatcher,
+    net_id: uint32_t,
+    dns_query: *const u8,
+    dns_query_len: size_t,
+    response: *mut u8,
+    response_len: size_t,
+    timeout_ms: uint64_t,
+) -> ssize_t {
+    let info = match doh.server(net_id) {
+        Some(info) => info,
+        None => {
+            error!("No DoH server for net_id={}", net_id);
+            return DOH_RESULT_CAN_NOT_SEND;
+        }
+    };
+    let query = slice::from_raw_parts(dns_query, dns_query_len);
+    let timeout = doh.query_timeout(net_id, timeout_ms);
+    // As for `doh_query`, the lock is only held while submitting.
+    let resp_rx = match doh.lock().submit_once(info, None, query, timeout) {
+        Ok(resp_rx) => resp_rx,
+        Err(e) => {
             error!("Failed to send the query: {:?}", e);
             return DOH_RESULT_CAN_NOT_SEND;
         }
-    } else {
-        error!("Bad timeout parameter: {}", timeout_ms);
-        return DOH_RESULT_CAN_NOT_SEND;
-    }
-
-    if let Ok(rt) = Builder::new_current_thread().enable_all().build() {
-        let local = task::LocalSet::new();
-        match local.block_on(&rt, async { timeout(t, resp_rx).await }) {
-            Ok(v) => match v {
-                Ok(v) => match v {
-                    Response::Success { answer } => {
-                        if answer.len() > response_len || answer.len() > isize::MAX as usize {
-                            return DOH_RESULT_INTERNAL_ERROR;
-                        }
-                        let response = slice::from_raw_parts_mut(response, answer.len());
-                        response.copy_from_slice(&answer);
-                        answer.len() as ssize_t
-                    }
-                    rsp => {
-                        error!("Non-successful response: {:?}", rsp);
-                        DOH_RESULT_CAN_NOT_SEND
-                    }
-                },
-                Err(e) => {
-                    error!("no result {}", e);
-                    DOH_RESULT_CAN_NOT_SEND
-                }
-            },
-            Err(e) => {
-                error!("timeout: {}", e);
-                DOH_RESULT_TIMEOUT
+    };
+    copy_answer(wait_for_answer(resp_rx, timeout), response, response_len)
+}
+
+/// Sends a DNS query to the DoH server the network `net_id` was probed with at each of the
+/// `ip_addrs_len` addresses in `ip_addrs`, racing them for the lowest latency. Each address is
+/// tried over a connection of its own, the first at once and each of the others `hedge_delay_ms`
+/// after the one before it, until one answers. The first answer wins, and the other attempts are
+/// abandoned. Every attempt started costs a handshake and a query, so this is for the few queries
+/// where a slow answer costs more than the extra bandwidth and battery, with a delay around the
+/// usual answer time so that the others are only tried when the first is being slow.
+/// The return code and `timeout_ms` are as for `doh_query`.
+/// # Safety
+/// `doh` must be a non-null pointer previously created by `doh_dispatcher_new()`
+/// and not yet deleted by `doh_dispatcher_delete()`.
+/// `ip_addrs` must point to `ip_addrs_len` null terminated strings.
+/// `dns_query` must point to a buffer at least `dns_query_len` in size.
+/// `response` must point to a buffer at least `response_len` in size.
+#[no_mangle]
+pub unsafe extern "C" fn doh_query_hedged(
+    doh: &DohDispatcher,
+    net_id: uint32_t,
+    ip_addrs: *const *const c_char,
+    ip_addrs_len: size_t,
+    hedge_delay_ms: uint64_t,
+    dns_query: *const u8,
+    dns_query_len: size_t,
+    response: *mut u8,
+    response_len: size_t,
+    timeout_ms: uint64_t,
+) -> ssize_t {
+    let info = match doh.server(net_id) {
+        Some(info) => info,
+        None => {
+            error!("No DoH server for net_id={}", net_id);
+            return DOH_RESULT_CAN_NOT_SEND;
+        }
+    };
+    let mut servers = Vec::with_capacity(ip_addrs_len);
+    for &ip_addr in slice::from_raw_parts(ip_addrs, ip_addrs_len) {
+        let ip_addr = std::ffi::CStr::from_ptr(ip_addr).to_str().ok();
+        match ip_addr.and_then(|ip_addr| IpAddr::from_str(ip_addr).ok()) {
+            Some(ip_addr) => {
+                let peer_addr = SocketAddr::new(ip_addr, info.peer_addr.port());
+                servers.push(ServerInfo { peer_addr, ..info.clone() });
+            }
+            None => {
+                error!("Bad address {:?} for hedged query", ip_addr);
+                return DOH_RESULT_CAN_NOT_SEND;
             }
         }
-    } else {
-        DOH_RESULT_CAN_NOT_SEND
+    }
+    let query = slice::from_raw_parts(dns_query, dns_query_len);
+    let hedge_delay = Duration::from_millis(hedge_delay_ms);
+    let timeout = doh.query_timeout(net_id, timeout_ms);
+    // As for `doh_query`, the lock is only held while submitting.
+    let resp_rx = match doh.lock().submit_hedged(servers, hedge_delay, query, timeout) {
+        Ok(resp_rx) => resp_rx,
+        Err(e) => {
+            error!("Failed to send the query: {:?}", e);
+            return DOH_RESULT_CAN_NOT_SEND;
+        }
+    };
+    copy_answer(wait_for_answer(resp_rx, timeout), response, response_len)
+}
+
+/// Checks the DoH server the network `net_id` was probed with step by step, for a "test
+/// connection" button, and writes a line per step to `out`, as text to show the user: whether
+/// the server can be reached, whether the handshake completes, whether its certificate passes,
+/// and whether it answers a query. The checks take a connection of their own and leave the
+/// network's connections and cached TLS configuration alone. Blocks until every step has run or
+/// the timeout has passed, with `timeout_ms` as for `doh_query`.
+/// Returns the size of the report, of which at most `out_len` bytes are written. Running the checks
+/// again for the rest gives a report of its own, so `out` is best made large enough at once.
+/// # Safety
+/// `doh` must be a non-null pointer previously created by `doh_dispatcher_new()`
+/// and not yet deleted by `doh_dispatcher_delete()`.
+/// `out` must point to a buffer at least `out_len` in size, or be null to write nothing.
+#[no_mangle]
+pub unsafe extern "C" fn doh_diagnose(
+    doh: &DohDispatcher,
+    net_id: uint32_t,
+    timeout_ms: uint64_t,
+    out: *mut u8,
+    out_len: size_t,
+) -> size_t {
+    let report = diagnose(doh, net_id, timeout_ms);
+    if !out.is_null() {
+        let written = report.len().min(out_len);
+        slice::from_raw_parts_mut(out, written).copy_from_slice(&report.as_bytes()[..written]);
+    }
+    report.len()
+}
+
+fn diagnose(doh: &DohDispatcher, net_id: uint32_t, timeout_ms: uint64_t) -> String {
+    let info = match doh.server(net_id) {
+        Some(info) => info,
+        None => return format!("No DoH server for net_id={}\n", net_id),
+    };
+    let timeout = doh.query_timeout(net_id, timeout_ms);
+    // As for `doh_query`, the lock is only held while submitting.
+    let submitted = doh.lock().submit_diagnose(info, timeout);
+    match submitted.map(|resp_rx| resp_rx.blocking_recv()) {
+        Ok(Ok(diagnostics)) => diagnostics.to_string(),
+        Ok(Err(_)) => "The checks were abandoned\n".to_string(),
+        Err(e) => format!("Failed to start the checks: {:?}\n", e),
     }
 }
 
@@ -321,14 +764,222 @@ pub unsafe extern "C" fn doh_query(
 /// and not yet deleted by `doh_dispatcher_delete()`.
 #[no_mangle]
 pub extern "C" fn doh_net_delete(doh: &DohDispatcher, net_id: uint32_t) {
+    doh.networks.lock().unwrap().remove(&net_id);
     if let Err(e) = doh.lock().send_cmd(Command::Clear { net_id }) {
         error!("Failed to send the query: {:?}", e);
     }
 }
 
+/// Fails all queries on the network with the given |net_id| immediately, and keeps failing new
+/// ones until a DoH server is probed for the network again.
+/// # Safety
+/// `doh` must be a non-null pointer previously created by `doh_dispatcher_new()`
+/// and not yet deleted by `doh_dispatcher_delete()`.
+#[no_mangle]
+pub extern "C" fn doh_net_lost(doh: &DohDispatcher, net_id: uint32_t) {
+    if let Err(e) = doh.lock().network_lost(net_id) {
+        error!("Failed to report the network lost: {:?}", e);
+    }
+}
+
+/// Releases memory the DoH engine can do without, for when the system is short of it. `level` is
+/// as passed to `ComponentCallbacks2.onTrimMemory`: from `TRIM_MEMORY_RUNNING_CRITICAL` up, idle
+/// connections are closed too, at the cost of a handshake on their network's next query.
+/// # Safety
+/// `doh` must be a non-null pointer previously created by `doh_dispatcher_new()`
+/// and not yet deleted by `doh_dispatcher_delete()`.
+#[no_mangle]
+pub extern "C" fn doh_trim_memory(doh: &DohDispatcher, level: int32_t) {
+    match doh.lock().trim_memory(level) {
+        Ok(summary) => info!("Trimmed memory at level {}: {:?}", level, summary),
+        Err(e) => error!("Failed to trim memory: {:?}", e),
+    }
+}
+
+/// Writes a description of each network's current connection, and of the TLS configurations
+/// cached for them, to `out`, as text for dumpsys.
+/// Returns the size of the description. If that is more than `out_len`, or `out` is null, nothing
+/// is written, and the call should be repeated with a larger buffer. The description changes as
+/// connections do, so a buffer of exactly that size may not be enough the second time.
+/// # Safety
+/// `doh` must be a non-null pointer previously created by `doh_dispatcher_new()`
+/// and not yet deleted by `doh_dispatcher_delete()`.
+/// `out` must point to a buffer at least `out_len` in size, or be null to only ask for the size.
+#[no_mangle]
+pub unsafe extern "C" fn doh_dump(doh: &DohDispatcher, out: *mut u8, out_len: size_t) -> size_t {
+    let dump = dump(&doh.lock());
+    if !out.is_null() && dump.len() <= out_len {
+        slice::from_raw_parts_mut(out, dump.len()).copy_from_slice(dump.as_bytes());
+    }
+    dump.len()
+}
+
+/// Writes the dispatcher's counters, and those of its QUIC config cache, to `out` in the
+/// Prometheus text exposition format, for monitoring outside Android. Returns the size of the
+/// text. If that is more than `out_len`, or `out` is null, nothing is written, and the call should
+/// be repeated with a larger buffer. Only present in builds with the `metrics_text` feature, for
+/// which `DOH_METRICS_TEXT` must be defined to declare it.
+/// # Safety
+/// `doh` must be a non-null pointer previously created by `doh_dispatcher_new()`
+/// and not yet deleted by `doh_dispatcher_delete()`.
+/// `out` must point to a buffer at least `out_len` in size, or be null to only ask for the size.
+#[cfg(feature = "metrics_text")]
+#[no_mangle]
+pub unsafe extern "C" fn doh_metrics_text(
+    doh: &DohDispatcher,
+    out: *mut u8,
+    out_len: size_t,
+) -> size_t {
+    let text = doh.lock().metrics_text();
+    if !out.is_null() && text.len() <= out_len {
+        slice::from_raw_parts_mut(out, text.len()).copy_from_slice(text.as_bytes());
+    }
+    text.len()
+}
+
+fn dump(dispatcher: &Dispatcher) -> String {
+    let mut text = String::new();
+    // Writing to a `String` can't fail.
+    match dispatcher.list_connections() {
+        Ok(connections) => {
+            for connection in connections {
+                let _ = writeln!(text, "{:?}", connection);
+            }
+        }
+        Err(e) => {
+            let _ = writeln!(text, "Failed to list connections: {:?}", e);
+        }
+    }
+    for config in dispatcher.resident_configs() {
+        let kept_alive = if config.kept_alive { ", kept alive" } else { "" };
+        let _ = writeln!(
+            text,
+            "Config for cert path {:?}, built {:?} ago{}",
+            config.cert_path, config.age, kept_alive
+        );
+    }
+    text
+}
+
+/// Writes the TLS sessions kept for resumption to `out`, so that `doh_session_import()` can load
+/// them after a restart. Returns the size of the export. If that is more than `out_len`, or `out`
+/// is null, nothing is written, and the call should be repeated with a buffer at least that large.
+/// # Safety
+/// `doh` must be a non-null pointer previously created by `doh_dispatcher_new()`
+/// and not yet deleted by `doh_dispatcher_delete()`.
+/// `out` must point to a buffer at least `out_len` in size, or be null to only ask for the size.
+#[no_mangle]
+pub unsafe extern "C" fn doh_session_export(
+    doh: &DohDispatcher,
+    out: *mut u8,
+    out_len: size_t,
+) -> size_t {
+    let sessions = doh.lock().session_store().export();
+    if !out.is_null() && sessions.len() <= out_len {
+        slice::from_raw_parts_mut(out, sessions.len()).copy_from_slice(&sessions);
+    }
+    sessions.len()
+}
+
+/// Loads TLS sessions written by `doh_session_export()`, returning how many were kept. Sessions
+/// this version can't resume are discarded.
+/// # Safety
+/// `doh` must be a non-null pointer previously created by `doh_dispatcher_new()`
+/// and not yet deleted by `doh_dispatcher_delete()`.
+/// `sessions` must point to a buffer at least `sessions_len` in size.
+#[no_mangle]
+pub unsafe extern "C" fn doh_session_import(
+    doh: &DohDispatcher,
+    sessions: *const u8,
+    sessions_len: size_t,
+) -> size_t {
+    let sessions = slice::from_raw_parts(sessions, sessions_len);
+    doh.lock().session_store().import(sessions)
+}
+
+/// Has servers probed from now on with `cert_path` reparse their trust store, for when its
+/// certificates were updated in place. As for `doh_net_new()`, an empty `cert_path` means the
+/// system trust store. A null `cert_path` invalidates every trust store. Networks already probed
+/// keep the trust store they were set up with. Returns 0, or `-EINVAL` for an unusable path.
+/// # Safety
+/// `doh` must be a non-null pointer previously created by `doh_dispatcher_new()`
+/// and not yet deleted by `doh_dispatcher_delete()`.
+/// `cert_path` is a null terminated string, or null.
+#[no_mangle]
+pub unsafe extern "C" fn doh_invalidate_cert_path(
+    doh: &DohDispatcher,
+    cert_path: *const c_char,
+) -> int32_t {
+    let cert_path = if cert_path.is_null() {
+        None
+    } else {
+        match std::ffi::CStr::from_ptr(cert_path).to_str() {
+            Ok("") => Some(SYSTEM_CERT_PATH),
+            Ok(cert_path) => Some(cert_path),
+            Err(_) => return -libc::EINVAL,
+        }
+    };
+    match doh.lock().invalidate_cert_path(cert_path) {
+        Ok(()) => 0,
+        Err(e) => {
+            error!("doh_invalidate_cert_path: failed: {:?}", e);
+            -libc::EINVAL
+        }
+    }
+}
+
+/// Has connections to servers probed from now on append their TLS secrets to the file at
+/// `keylog_path`, in the NSS key log format, and write a qlog trace each into the directory
+/// `qlog_dir`, for debugging failed handshakes. A null path turns that log off. Networks already
+/// probed are unaffected. Returns 0, or `-EINVAL`, changing nothing, for a path which isn't
+/// UTF-8, a `keylog_path` which can't be opened, or either path given to a build without qlog
+/// support, which Android builds leave out.
+/// # Safety
+/// `doh` must be a non-null pointer previously created by `doh_dispatcher_new()`
+/// and not yet deleted by `doh_dispatcher_delete()`.
+/// `keylog_path` and `qlog_dir` are null terminated strings, or null.
+#[no_mangle]
+pub unsafe extern "C" fn doh_set_debug_logs(
+    doh: &DohDispatcher,
+    keylog_path: *const c_char,
+    qlog_dir: *const c_char,
+) -> int32_t {
+    unsafe fn optional_str<'a>(s: *const c_char) -> std::result::Result<Option<&'a str>, ()> {
+        if s.is_null() {
+            return Ok(None);
+        }
+        std::ffi::CStr::from_ptr(s).to_str().map(Some).map_err(|_| ())
+    }
+    let (keylog_path, qlog_dir) = match (optional_str(keylog_path), optional_str(qlog_dir)) {
+        (Ok(keylog_path), Ok(qlog_dir)) => (keylog_path, qlog_dir),
+        _ => return -libc::EINVAL,
+    };
+    match doh.lock().set_debug_logs(keylog_path, qlog_dir) {
+        Ok(()) => 0,
+        Err(e) => {
+            error!("doh_set_debug_logs: failed: {:?}", e);
+            -libc::EINVAL
+        }
+    }
+}
+
+/// Reads the dispatcher's counters, and those of its QUIC config cache, into `metrics`. They are
+/// read together, so that totals such as the connection attempts and their outcomes agree with
+/// each other.
+/// # Safety
+/// `doh` must be a non-null pointer previously created by `doh_dispatcher_new()`
+/// and not yet deleted by `doh_dispatcher_delete()`.
+/// `metrics` must be a non-null pointer to a `DohMetrics`.
+#[no_mangle]
+pub extern "C" fn doh_get_metrics(doh: &DohDispatcher, metrics: &mut DohMetrics) {
+    let dispatcher = doh.lock();
+    *metrics = DohMetrics::new(dispatcher.metrics().snapshot(), dispatcher.config_cache_stats());
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use url::Url;
 
     const TEST_NET_ID: u32 = 50;
     const LOOPBACK_ADDR: &str = "127.0.0.1:443";
@@ -378,18 +1029,125 @@ mod tests {
         let info = ServerInfo {
             net_id: TEST_NET_ID,
             url: Url::parse(LOCALHOST_URL).unwrap(),
-            peer_addr: LOOPBACK_ADDR.parse().unwrap(),
-            domain: None,
-            sk_mark: 0,
-            cert_path: None,
             idle_timeout_ms: 0,
             use_session_resumption: true,
+            retry_on_connection_loss: true,
+            ..ServerInfo::for_test(LOOPBACK_ADDR.parse().unwrap())
         };
 
         wrap_validation_callback(success_cb)(&info, true).await;
         wrap_validation_callback(fail_cb)(&info, false).await;
     }
 
+    fn test_flags() -> FeatureFlags {
+        FeatureFlags {
+            probe_timeout_ms: 0,
+            idle_timeout_ms: 0,
+            use_session_resumption: false,
+            connect_timeout_ms: 0,
+            query_timeout_ms: 0,
+            bind_device: ptr::null(),
+            use_socket_fd: false,
+            socket_fd: 0,
+            use_dns_over_quic: false,
+        }
+    }
+
+    #[test]
+    fn validate_cert_path() {
+        let dir = std::env::temp_dir().join(format!("doh_ffi_cert_path_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert_path = format!("{}\0", dir.display());
+        let empty = unsafe { doh_validate_cert_path(cert_path.as_ptr() as *const c_char) };
+        std::fs::write(dir.join("server.pem"), crate::connection::loopback::SERVER_CERT).unwrap();
+        let trusted = unsafe { doh_validate_cert_path(cert_path.as_ptr() as *const c_char) };
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(empty, -libc::EINVAL);
+        assert_eq!(trusted, 0);
+        let missing = "/nonexistent/cacerts\0".as_ptr() as *const c_char;
+        assert_eq!(unsafe { doh_validate_cert_path(missing) }, -libc::EINVAL);
+    }
+
+    #[test]
+    fn validate_config() {
+        let validate = |cert_path: &str, flags: &FeatureFlags| unsafe {
+            doh_validate_config(
+                "https://mylocal.com/dns-query\0".as_ptr() as *const c_char,
+                "mylocal.com\0".as_ptr() as *const c_char,
+                "127.0.0.1\0".as_ptr() as *const c_char,
+                cert_path.as_ptr() as *const c_char,
+                flags,
+            )
+        };
+        let flags = FeatureFlags { idle_timeout_ms: 1000, ..test_flags() };
+        let dir = std::env::temp_dir().join(format!("doh_ffi_validate_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("server.pem"), crate::connection::loopback::SERVER_CERT).unwrap();
+        let cert_path = format!("{}\0", dir.display());
+        let valid = validate(&cert_path, &flags);
+        let slow_handshake =
+            validate(&cert_path, &FeatureFlags { connect_timeout_ms: 1000, ..flags });
+        // Without an idle timeout, there is nothing to cut the handshake short.
+        let no_idle_timeout =
+            validate(&cert_path, &FeatureFlags { connect_timeout_ms: 1000, ..test_flags() });
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(valid, 0);
+        assert_eq!(slow_handshake, -libc::EINVAL);
+        assert_eq!(no_idle_timeout, 0);
+        assert_eq!(validate("/nonexistent/cacerts\0", &flags), -libc::EINVAL);
+    }
+
+    #[test]
+    fn net_new_dns_over_quic() {
+        let doh = doh_dispatcher_new(ignore_validation, tag_socket_cb);
+        let mut flags = FeatureFlags { probe_timeout_ms: 100, ..test_flags() };
+        let new_net = |flags: &FeatureFlags| unsafe {
+            doh_net_new(
+                &*doh,
+                TEST_NET_ID,
+                "https://mylocal.com/dns-query\0".as_ptr() as *const c_char,
+                "\0".as_ptr() as *const c_char,
+                "127.0.0.1\0".as_ptr() as *const c_char,
+                0,
+                "\0".as_ptr() as *const c_char,
+                flags,
+            )
+        };
+        unsafe {
+            assert_eq!(new_net(&flags), 0);
+            let info = (*doh).server(TEST_NET_ID).unwrap();
+            assert_eq!(info.peer_addr.port(), DOH_PORT);
+            assert_eq!(info.connection_options.stream_mode, StreamMode::Http3);
+
+            flags.use_dns_over_quic = true;
+            assert_eq!(new_net(&flags), 0);
+            let info = (*doh).server(TEST_NET_ID).unwrap();
+            assert_eq!(info.peer_addr.port(), DOQ_PORT);
+            assert_eq!(info.connection_options.stream_mode, StreamMode::RawDns);
+            doh_dispatcher_delete(doh);
+        }
+    }
+
+    #[test]
+    fn socket_binding_from_flags() {
+        let mut flags = test_flags();
+        unsafe {
+            assert_eq!(socket_binding(7, &flags), Ok(SocketBinding::Mark(7)));
+            flags.bind_device = b"\0".as_ptr() as *const c_char;
+            assert_eq!(socket_binding(7, &flags), Ok(SocketBinding::Mark(7)));
+            flags.bind_device = b"wlan0\0".as_ptr() as *const c_char;
+            assert_eq!(socket_binding(7, &flags), Ok(SocketBinding::Device("wlan0".to_string())));
+
+            use std::os::unix::io::IntoRawFd;
+            flags.use_socket_fd = true;
+            flags.socket_fd = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().into_raw_fd();
+            assert!(matches!(socket_binding(7, &flags), Ok(SocketBinding::Provided(_))));
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            flags.socket_fd = listener.into_raw_fd();
+            assert_eq!(socket_binding(7, &flags), Err(-libc::EINVAL));
+        }
+    }
+
     extern "C" fn tag_socket_cb(raw_fd: RawFd) {
         assert!(raw_fd > 0)
     }
@@ -399,4 +1157,257 @@ mod tests {
         let sock = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
         wrap_tag_socket_callback(tag_socket_cb)(&sock).await;
     }
+
+    extern "C" fn ignore_validation(_: uint32_t, _: bool, _: *const c_char, _: *const c_char) {}
+
+    #[test]
+    fn session_export_import() {
+        let doh = doh_dispatcher_new(ignore_validation, tag_socket_cb);
+        unsafe {
+            (*doh).lock().session_store().insert(TEST_NET_ID, LOCALHOST_URL, vec![1, 2, 3]);
+            // Too small a buffer is left alone, and the size it needs is returned.
+            let mut out = [0xff; 4];
+            let len = doh_session_export(&*doh, out.as_mut_ptr(), out.len());
+            assert!(len > out.len());
+            assert_eq!(out, [0xff; 4]);
+            // As is a null buffer, whatever size it is said to be.
+            assert_eq!(doh_session_export(&*doh, ptr::null_mut(), usize::MAX), len);
+            let mut out = vec![0; len];
+            assert_eq!(doh_session_export(&*doh, out.as_mut_ptr(), out.len()), len);
+            doh_dispatcher_delete(doh);
+
+            let doh = doh_dispatcher_new(ignore_validation, tag_socket_cb);
+            assert_eq!(doh_session_import(&*doh, out.as_ptr(), out.len()), 1);
+            let imported = (*doh).lock().session_store().get(TEST_NET_ID, LOCALHOST_URL);
+            assert_eq!(imported, Some(vec![1, 2, 3]));
+            doh_dispatcher_delete(doh);
+        }
+    }
+
+    #[test]
+    fn query_once() {
+        use crate::connection::loopback::{DohServer, Reply};
+        let server = DohServer::start(Box::new(|_, _| Reply::After(Duration::ZERO))).unwrap();
+        let query = encoding::probe_query().unwrap();
+        let query = base64::decode_config(query, base64::URL_SAFE_NO_PAD).unwrap();
+        let mut response = [0; 512];
+        let doh = doh_dispatcher_new(ignore_validation, tag_socket_cb);
+        unsafe {
+            let query_once = |response: &mut [u8]| {
+                doh_query_once(
+                    &*doh,
+                    TEST_NET_ID,
+                    query.as_ptr(),
+                    query.len(),
+                    response.as_mut_ptr(),
+                    response.len(),
+                    5000,
+                )
+            };
+            // Nothing was probed for the network.
+            assert_eq!(query_once(&mut response), DOH_RESULT_CAN_NOT_SEND);
+            let info = ServerInfo { net_id: TEST_NET_ID, ..ServerInfo::for_test(server.addr) };
+            let network = ProbedNetwork { info, query_timeout: None };
+            (*doh).networks.lock().unwrap().insert(TEST_NET_ID, network);
+            // The server echoes the query back, marked as a response, as the answer.
+            assert_eq!(query_once(&mut response), query.len() as ssize_t);
+            assert_eq!(response[3..query.len()], query[3..]);
+            assert_eq!(query_once(&mut response), query.len() as ssize_t);
+            // Each query got a connection of its own.
+            assert_eq!(server.connections(), 2);
+            doh_dispatcher_delete(doh);
+        }
+    }
+
+    #[test]
+    fn invalidate_cert_path() {
+        let doh = doh_dispatcher_new(ignore_validation, tag_socket_cb);
+        unsafe {
+            assert_eq!(doh_invalidate_cert_path(&*doh, ptr::null()), 0);
+            assert_eq!(doh_invalidate_cert_path(&*doh, "\0".as_ptr() as *const c_char), 0);
+            let cert_path = "/data/misc/cacerts\0";
+            assert_eq!(doh_invalidate_cert_path(&*doh, cert_path.as_ptr() as *const c_char), 0);
+            doh_dispatcher_delete(doh);
+        }
+    }
+
+    #[test]
+    fn set_debug_logs() {
+        let doh = doh_dispatcher_new(ignore_validation, tag_socket_cb);
+        let path =
+            format!("{}/doh_ffi_keylog_{}\0", std::env::temp_dir().display(), std::process::id());
+        unsafe {
+            let expected = if cfg!(feature = "qlog") { 0 } else { -libc::EINVAL };
+            let keylog_path = path.as_ptr() as *const c_char;
+            assert_eq!(doh_set_debug_logs(&*doh, keylog_path, ptr::null()), expected);
+            assert_eq!(doh_set_debug_logs(&*doh, ptr::null(), ptr::null()), 0);
+            let qlog_dir = "/data/misc/qlog\0".as_ptr() as *const c_char;
+            assert_eq!(doh_set_debug_logs(&*doh, ptr::null(), qlog_dir), expected);
+            let missing = "/nonexistent/doh_keylog\0".as_ptr() as *const c_char;
+            assert_eq!(doh_set_debug_logs(&*doh, missing, ptr::null()), -libc::EINVAL);
+            assert_eq!(
+                doh_set_debug_logs(&*doh, b"\xff\0".as_ptr() as *const c_char, ptr::null()),
+                -libc::EINVAL
+            );
+            doh_dispatcher_delete(doh);
+        }
+        let _ = std::fs::remove_file(path.trim_end_matches('\0'));
+    }
+
+    #[cfg(feature = "metrics_text")]
+    #[test]
+    fn metrics_text() {
+        let doh = doh_dispatcher_new(ignore_validation, tag_socket_cb);
+        unsafe {
+            let len = doh_metrics_text(&*doh, ptr::null_mut(), usize::MAX);
+            let mut out = vec![0; len];
+            assert_eq!(doh_metrics_text(&*doh, out.as_mut_ptr(), out.len()), len);
+            let text = String::from_utf8(out).unwrap();
+            assert!(text.lines().any(|line| line == "doh_queued_queries 0"), "{}", text);
+            doh_dispatcher_delete(doh);
+        }
+    }
+
+    #[cfg(feature = "self_test")]
+    #[test]
+    fn self_test() {
+        assert_eq!(doh_self_test(), 0);
+    }
+
+    #[test]
+    fn query_hedged() {
+        use crate::connection::loopback::{DohServer, Reply};
+        let server = DohServer::start(Box::new(|_, _| Reply::After(Duration::ZERO))).unwrap();
+        let query = encoding::probe_query().unwrap();
+        let query = base64::decode_config(query, base64::URL_SAFE_NO_PAD).unwrap();
+        let mut response = [0; 512];
+        let doh = doh_dispatcher_new(ignore_validation, tag_socket_cb);
+        unsafe {
+            let query_hedged = |ip_addrs: &[&str], response: &mut [u8]| {
+                let ip_addrs: Vec<_> =
+                    ip_addrs.iter().map(|ip_addr| CString::new(*ip_addr).unwrap()).collect();
+                let ip_addrs: Vec<_> = ip_addrs.iter().map(|ip_addr| ip_addr.as_ptr()).collect();
+                doh_query_hedged(
+                    &*doh,
+                    TEST_NET_ID,
+                    ip_addrs.as_ptr(),
+                    ip_addrs.len(),
+                    10,
+                    query.as_ptr(),
+                    query.len(),
+                    response.as_mut_ptr(),
+                    response.len(),
+                    1000,
+                )
+            };
+            // Nothing was probed for the network.
+            assert_eq!(query_hedged(&["127.0.0.1"], &mut response), DOH_RESULT_CAN_NOT_SEND);
+            let info = ServerInfo { net_id: TEST_NET_ID, ..ServerInfo::for_test(server.addr) };
+            let network = ProbedNetwork { info, query_timeout: None };
+            (*doh).networks.lock().unwrap().insert(TEST_NET_ID, network);
+            // Only the second address has a server listening on the network's port.
+            let len = query_hedged(&["127.0.0.2", "127.0.0.1"], &mut response);
+            assert_eq!(len, query.len() as ssize_t);
+            assert_eq!(response[3..query.len()], query[3..]);
+            assert_eq!(query_hedged(&[], &mut response), DOH_RESULT_CAN_NOT_SEND);
+            assert_eq!(query_hedged(&["mylocal.com"], &mut response), DOH_RESULT_CAN_NOT_SEND);
+            doh_dispatcher_delete(doh);
+        }
+    }
+
+    #[test]
+    fn diagnose() {
+        use crate::connection::loopback::{DohServer, Reply};
+        let server = DohServer::start(Box::new(|_, _| Reply::After(Duration::ZERO))).unwrap();
+        let doh = doh_dispatcher_new(ignore_validation, tag_socket_cb);
+        let mut out = [0; 1024];
+        unsafe {
+            let diagnose = |out: &mut [u8]| {
+                let len = doh_diagnose(&*doh, TEST_NET_ID, 1000, out.as_mut_ptr(), out.len());
+                String::from_utf8_lossy(&out[..len.min(out.len())]).into_owned()
+            };
+            assert_eq!(diagnose(&mut out), format!("No DoH server for net_id={}\n", TEST_NET_ID));
+
+            let info = ServerInfo { net_id: TEST_NET_ID, ..ServerInfo::for_test(server.addr) };
+            let network = ProbedNetwork { info, query_timeout: None };
+            (*doh).networks.lock().unwrap().insert(TEST_NET_ID, network);
+            let report = diagnose(&mut out);
+            assert!(report.starts_with(&format!("Server {}\n", server.addr)), "{}", report);
+            assert!(report.contains("\nReachability: passed\n"), "{}", report);
+            assert!(report.contains("\nQuery: passed in "), "{}", report);
+            // The checks took a connection of their own.
+            assert_eq!((*doh).lock().list_connections().unwrap(), Vec::new());
+
+            // A short buffer gets the start of the report.
+            let mut short = [0; 6];
+            assert!(doh_diagnose(&*doh, TEST_NET_ID, 1000, short.as_mut_ptr(), 6) > 6);
+            assert_eq!(&short, b"Server");
+            assert!(doh_diagnose(&*doh, TEST_NET_ID, 1000, ptr::null_mut(), usize::MAX) > 6);
+            doh_dispatcher_delete(doh);
+        }
+    }
+
+    #[test]
+    fn trim_memory() {
+        let doh = doh_dispatcher_new(ignore_validation, tag_socket_cb);
+        unsafe {
+            // TRIM_MEMORY_COMPLETE, with nothing to release.
+            doh_trim_memory(&*doh, 80);
+            let mut metrics = DohMetrics::default();
+            doh_get_metrics(&*doh, &mut metrics);
+            assert_eq!(metrics.buffer_high_water_mark, 0);
+            doh_dispatcher_delete(doh);
+        }
+    }
+
+    #[test]
+    fn dump() {
+        use crate::connection::loopback::{DohServer, Reply};
+        let server = DohServer::start(Box::new(|_, _| Reply::After(Duration::ZERO))).unwrap();
+        let doh = doh_dispatcher_new(ignore_validation, tag_socket_cb);
+        unsafe {
+            // Nothing was probed, so there is nothing to describe.
+            assert_eq!(doh_dump(&*doh, [].as_mut_ptr(), 0), 0);
+            let info = ServerInfo { net_id: TEST_NET_ID, ..ServerInfo::for_test(server.addr) };
+            let timeout = Duration::from_secs(5);
+            (*doh).lock().send_cmd(Command::Probe { info, timeout }).unwrap();
+            // Too small a buffer is left alone, and the size it needs is returned.
+            let mut out = [0xff; 4];
+            let len = doh_dump(&*doh, out.as_mut_ptr(), out.len());
+            assert!(len > out.len());
+            assert_eq!(out, [0xff; 4]);
+            // As is a null buffer, whatever size it is said to be.
+            assert!(doh_dump(&*doh, ptr::null_mut(), usize::MAX) > out.len());
+            let mut out = vec![0; len + 100];
+            let len = doh_dump(&*doh, out.as_mut_ptr(), out.len());
+            assert!(len <= out.len());
+            let dump = String::from_utf8(out[..len].to_vec()).unwrap();
+            assert!(dump.contains(&format!("net_id: {}", TEST_NET_ID)), "{}", dump);
+            assert!(dump.contains("Config for cert path None"), "{}", dump);
+            doh_dispatcher_delete(doh);
+        }
+    }
+
+    #[test]
+    fn get_metrics() {
+        let doh = doh_dispatcher_new(ignore_validation, tag_socket_cb);
+        unsafe {
+            let mut metrics = DohMetrics::default();
+            doh_get_metrics(&*doh, &mut metrics);
+            assert_eq!(metrics.connection_attempts, 0);
+            {
+                let dispatcher = (*doh).lock();
+                dispatcher.metrics().connection_attempted();
+                dispatcher.metrics().connection_attempted();
+                dispatcher.metrics().connection_failed(ConnectFailure::CaptivePortal);
+            }
+            doh_get_metrics(&*doh, &mut metrics);
+            assert_eq!(metrics.connection_attempts, 2);
+            assert_eq!(metrics.connection_successes, 0);
+            assert_eq!(metrics.connection_failures_captive_portal, 1);
+            assert_eq!(metrics.connection_failures_tls_verify, 0);
+            assert_eq!(metrics.config_constructions, 0);
+            doh_dispatcher_delete(doh);
+        }
+    }
 }
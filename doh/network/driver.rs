@@ -16,28 +16,64 @@
 
 //! Provides a backing task to implement a network
 
-use crate::boot_time::{timeout, BootTime, Duration};
+use crate::boot_time::{timeout, Duration};
 use crate::config::Config;
-use crate::connection::Connection;
+use crate::connection::{self, Connection, ConnectionSource, Environment, Monitor};
 use crate::dispatcher::{QueryError, Response};
-use crate::encoding;
+use crate::encoding::{self, Cookie};
 use anyhow::{anyhow, bail, Result};
-use std::sync::Arc;
+use quiche::h3;
+use std::future;
+use std::sync::{Arc, Mutex};
+use tokio::select;
 use tokio::sync::{mpsc, watch};
 use tokio::task;
 
-use super::{Query, ServerInfo, SocketTagger, ValidationReporter};
+use super::response_cache::ResponseCache;
+use super::server_errors::Backoff;
+use super::window_tuner::WindowTuner;
+use super::{
+    check_truncation, restore_message_id, Query, ServerInfo, SessionStore, ValidationReporter,
+};
 
 use log::debug;
 
 pub struct Driver {
+    // The port of `info.peer_addr` is the one currently in use, which may be a fallback port.
     info: ServerInfo,
+    primary_port: u16,
     config: Config,
+    // Tells the `Network` handle which connection to describe.
+    monitor_tx: watch::Sender<Monitor>,
+    // The only connection queries are sent on. A network never pools connections, so there is
+    // nothing for a query to be pinned to: repeated queries share this one until it is rotated
+    // or lost, and a replacement serves every query alike.
     connection: Connection,
     command_rx: mpsc::Receiver<Command>,
     status_tx: watch::Sender<Status>,
     validation: ValidationReporter,
-    tag_socket: SocketTagger,
+    env: Environment,
+    // Shared with the tasks awaiting each query's response.
+    response_cache: Arc<Mutex<ResponseCache>>,
+    // Number of queries sent on `connection`, for retiring it once it reaches
+    // `ServerInfo::max_queries_per_connection`.
+    queries_on_connection: u64,
+    // When false, each connection carries a single query whatever
+    // `ServerInfo::max_queries_per_connection` says.
+    reuse_connections: bool,
+    // Set to true by `Network::lost`.
+    lost_rx: watch::Receiver<bool>,
+    session_store: Arc<SessionStore>,
+    // Present if `ServerInfo::connection_window_cap` is set. Shared with the tasks awaiting each
+    // query's response, which feed it what the connection measured.
+    window_tuner: Option<Arc<Mutex<WindowTuner>>>,
+    // Shared with the tasks awaiting each query's response, which report 5xx answers to it.
+    backoff: Arc<Mutex<Backoff>>,
+    // Lets those tasks queue a query again, without keeping the network alive.
+    retry_tx: mpsc::WeakSender<Command>,
+    // Present if `ServerInfo::use_dns_cookies` is set. A network talks to a single server, so
+    // this is that server's cookie, which the tasks awaiting responses keep up to date.
+    cookie: Option<Arc<Mutex<Cookie>>>,
 }
 
 #[derive(Debug)]
@@ -47,6 +83,9 @@ pub enum Command {
     Query(Query),
     /// Run a probe to check the health of the network. Argument is timeout.
     Probe(Duration),
+    /// Close the connection if nothing is in flight on it, to save memory. The next query
+    /// connects again.
+    RetireIfIdle,
 }
 
 #[derive(Clone, Debug)]
@@ -72,23 +111,39 @@ impl Status {
     }
 }
 
+// Resolves once the network has been reported lost. Never resolves if the `Network` goes away
+// without that happening.
+async fn until_lost(mut lost_rx: watch::Receiver<bool>) {
+    while !*lost_rx.borrow() {
+        if lost_rx.changed().await.is_err() {
+            future::pending::<()>().await;
+        }
+    }
+}
+
+// Puts `cookie` in a base64 query.
+fn with_cookie(base64_query: &str, cookie: &Cookie) -> Result<String> {
+    let query = base64::decode_config(base64_query, base64::URL_SAFE_NO_PAD)?;
+    Ok(base64::encode_config(encoding::set_cookie(&query, cookie)?, base64::URL_SAFE_NO_PAD))
+}
+
 async fn build_connection(
     info: &ServerInfo,
-    tag_socket: &SocketTagger,
+    env: &Environment,
     config: &mut Config,
     session: Option<Vec<u8>>,
+    window_tuner: Option<&Mutex<WindowTuner>>,
 ) -> Result<Connection> {
-    use std::ops::DerefMut;
-    Ok(Connection::new(
-        info.domain.as_deref(),
+    let connection_window = window_tuner.map(|tuner| tuner.lock().unwrap().window(env.clock.now()));
+    let options = connection::Options { connection_window, ..info.connection_options.clone() };
+    let connection = Connection::new(info.peer(), config, session, options, env).await?;
+    debug!(
+        "[{}] Connecting to server {} on Network {}",
+        connection.trace_id(),
         info.peer_addr,
-        info.sk_mark,
-        info.net_id,
-        tag_socket,
-        config.take().await.deref_mut(),
-        session,
-    )
-    .await?)
+        info.net_id
+    );
+    Ok(connection)
 }
 
 impl Driver {
@@ -98,37 +153,142 @@ impl Driver {
         info: ServerInfo,
         mut config: Config,
         validation: ValidationReporter,
-        tag_socket: SocketTagger,
-    ) -> Result<(Self, mpsc::Sender<Command>, watch::Receiver<Status>)> {
+        env: Environment,
+        lost_rx: watch::Receiver<bool>,
+        session_store: Arc<SessionStore>,
+        reuse_connections: bool,
+    ) -> Result<(Self, mpsc::Sender<Command>, watch::Receiver<Status>, watch::Receiver<Monitor>)>
+    {
         let (command_tx, command_rx) = mpsc::channel(Self::MAX_BUFFERED_COMMANDS);
         let (status_tx, status_rx) = watch::channel(Status::Unprobed);
-        let connection = build_connection(&info, &tag_socket, &mut config, None).await?;
+        let session = if info.use_session_resumption {
+            session_store.get(info.net_id, info.url.as_str())
+        } else {
+            None
+        };
+        let window_tuner =
+            info.connection_window_cap.map(|cap| Arc::new(Mutex::new(WindowTuner::new(cap))));
+        let connection =
+            build_connection(&info, &env, &mut config, session, window_tuner.as_deref()).await?;
+        let cookie = if info.use_dns_cookies {
+            Some(Arc::new(Mutex::new(Cookie::generate()?)))
+        } else {
+            None
+        };
+        let (monitor_tx, monitor_rx) = watch::channel(connection.monitor());
+        let response_cache =
+            ResponseCache::new(info.response_cache, env.metrics.clone(), env.clock.clone());
+        let response_cache = Arc::new(Mutex::new(response_cache));
         Ok((
-            Self { info, config, connection, status_tx, command_rx, validation, tag_socket },
+            Self {
+                primary_port: info.peer_addr.port(),
+                info,
+                config,
+                monitor_tx,
+                connection,
+                status_tx,
+                command_rx,
+                validation,
+                env,
+                response_cache,
+                queries_on_connection: 0,
+                reuse_connections,
+                lost_rx,
+                session_store,
+                window_tuner,
+                backoff: Arc::new(Mutex::new(Backoff::default())),
+                retry_tx: command_tx.downgrade(),
+                cookie,
+            },
             command_tx,
             status_rx,
+            monitor_rx,
         ))
     }
 
+    // Sends queries on `connection` from now on, in place of the current one.
+    fn install(&mut self, connection: Connection) {
+        // Nobody listing connections is no reason to stop.
+        let _ = self.monitor_tx.send(connection.monitor());
+        self.response_cache.lock().unwrap().keep_only(connection.trace_id());
+        self.connection = connection;
+        self.queries_on_connection = 0;
+    }
+
     pub async fn drive(mut self) -> Result<()> {
-        while let Some(cmd) = self.command_rx.recv().await {
-            match cmd {
-                Command::Probe(duration) =>
-                    if let Err(e) = self.probe(duration).await { self.status_tx.send(Status::Failed(Arc::new(e)))? },
-                Command::Query(query) =>
-                    if let Err(e) = self.send_query(query).await { debug!("Unable to send query: {:?}", e) },
-            };
+        let lost = until_lost(self.lost_rx.clone());
+        tokio::pin!(lost);
+        loop {
+            // Once the network is lost, don't wait for a command in progress, such as a probe, to
+            // time out before giving up on it.
+            select! {
+                biased;
+                _ = &mut lost => break,
+                cmd = self.command_rx.recv() => match cmd {
+                    Some(cmd) => select! {
+                        biased;
+                        _ = &mut lost => break,
+                        result = self.run(cmd) => result?,
+                    },
+                    None => return Ok(()),
+                },
+            }
         }
+        self.abandon();
         Ok(())
     }
 
+    async fn run(&mut self, cmd: Command) -> Result<()> {
+        match cmd {
+            Command::Probe(duration) => {
+                if let Err(e) = self.probe(duration).await {
+                    self.status_tx.send(Status::Failed(Arc::new(e)))?
+                }
+            }
+            Command::Query(query) => {
+                if let Err(e) = self.send_query(query).await {
+                    debug!("Unable to send query: {:?}", e)
+                }
+            }
+            Command::RetireIfIdle => self.retire_if_idle(),
+        };
+        Ok(())
+    }
+
+    fn retire_if_idle(&mut self) {
+        if self.connection.monitor().info().open_streams == 0 {
+            self.connection.retire();
+        }
+    }
+
+    // Drops the connection and fails the queries still waiting to be sent. Those already sent are
+    // failed by their own tasks.
+    fn abandon(&mut self) {
+        debug!("Network {} lost, abandoning its queries", self.info.net_id);
+        self.connection.abort();
+        self.command_rx.close();
+        while let Ok(cmd) = self.command_rx.try_recv() {
+            if let Command::Query(query) = cmd {
+                // We don't care if the response is gone.
+                let _ = query.response.send(Response::Error { error: QueryError::NetworkLost });
+            }
+        }
+    }
+
     async fn probe(&mut self, probe_timeout: Duration) -> Result<()> {
         if self.status_tx.borrow().is_failed() {
             debug!("Network is currently failed, reconnecting");
             // If our network is currently failed, it may be due to issues with the connection.
             // Re-establish before re-probing
-            self.connection =
-                build_connection(&self.info, &self.tag_socket, &mut self.config, None).await?;
+            let connection = build_connection(
+                &self.info,
+                &self.env,
+                &mut self.config,
+                None,
+                self.window_tuner.as_deref(),
+            )
+            .await?;
+            self.install(connection);
             self.status_tx.send(Status::Unprobed)?;
         }
         if self.status_tx.borrow().is_live() {
@@ -139,13 +299,60 @@ impl Driver {
         self.force_probe(probe_timeout).await
     }
 
+    // Connects on the first candidate port which completes a handshake, giving each an equal
+    // share of `budget`. Returns whether any port did.
+    async fn select_port(&mut self, budget: Duration) -> Result<bool> {
+        let ports: Vec<u16> = std::iter::once(self.primary_port)
+            .chain(self.info.fallback_ports.iter().copied())
+            .collect();
+        let attempt_timeout = budget / ports.len() as u32;
+        for port in ports {
+            if self.info.peer_addr.port() != port {
+                self.info.peer_addr.set_port(port);
+                let connection = build_connection(
+                    &self.info,
+                    &self.env,
+                    &mut self.config,
+                    None,
+                    self.window_tuner.as_deref(),
+                )
+                .await?;
+                self.install(connection);
+            }
+            if let Ok(true) = timeout(attempt_timeout, self.connection.wait_for_live()).await {
+                debug!(
+                    "Server {} on Network {} is reachable",
+                    self.info.peer_addr, self.info.net_id
+                );
+                return Ok(true);
+            }
+            debug!(
+                "Handshake with server {} on Network {} failed within {:?}",
+                self.info.peer_addr, self.info.net_id, attempt_timeout
+            );
+        }
+        Ok(false)
+    }
+
     async fn force_probe(&mut self, probe_timeout: Duration) -> Result<()> {
+        let start = self.env.clock.now();
+        if !self.info.fallback_ports.is_empty() && !self.select_port(probe_timeout).await? {
+            self.status_tx.send(Status::Failed(Arc::new(anyhow!(
+                "No candidate port completed a handshake within {:?}",
+                probe_timeout
+            ))))?;
+            (self.validation)(&self.info, false).await;
+            return Ok(());
+        }
+        // Whatever port selection took comes out of the probe's time.
+        let probe_timeout =
+            probe_timeout.checked_sub(self.env.clock.elapsed(start)).unwrap_or_default();
         debug!("Sending probe to server {} on Network {}", self.info.peer_addr, self.info.net_id);
         let probe = encoding::probe_query()?;
         let dns_request = encoding::dns_request(&probe, &self.info.url)?;
-        let expiry = BootTime::now().checked_add(probe_timeout);
+        let expiry = self.env.clock.now().checked_add(probe_timeout);
         let request = async {
-            match self.connection.query(dns_request, expiry).await {
+            match self.connection.query(dns_request, start, expiry, None).await {
                 Err(e) => self.status_tx.send(Status::Failed(Arc::new(anyhow!(e)))),
                 Ok(rsp) => {
                     if let Some(_stream) = rsp.await {
@@ -172,6 +379,29 @@ impl Driver {
         Ok(())
     }
 
+    // The session for a new connection to present, if it may resume one. One forcing a full
+    // handshake presents none and leaves the store as it is.
+    fn session_for_new_connection(&self, force_full_handshake: bool) -> Option<Vec<u8>> {
+        if force_full_handshake || !self.info.use_session_resumption {
+            None
+        } else {
+            self.resumable_session()
+        }
+    }
+
+    // The session to resume on a new connection: the current one's, which is recorded for after a
+    // restart, or failing that the last one recorded.
+    fn resumable_session(&self) -> Option<Vec<u8>> {
+        let server = self.info.url.as_str();
+        match self.connection.session() {
+            Some(session) => {
+                self.session_store.insert(self.info.net_id, server, session.clone());
+                Some(session)
+            }
+            None => self.session_store.get(self.info.net_id, server),
+        }
+    }
+
     async fn send_query(&mut self, query: Query) -> Result<()> {
         // If the associated receiver has been closed, meaning that the request has already
         // timed out, just drop it. This check helps drain the channel quickly in the case
@@ -179,34 +409,401 @@ impl Driver {
         if query.response.is_closed() {
             bail!("Abandoning expired DNS request")
         }
+        if let Some(error) = self.backoff.lock().unwrap().check(self.env.clock.now()) {
+            debug!("Failing query without sending it, as the server asked: {:?}", error);
+            // We don't care if the response is gone.
+            let _ = query.response.send(Response::Error { error });
+            return Ok(());
+        }
 
-        if !self.connection.wait_for_live().await {
-            let session =
-                if self.info.use_session_resumption { self.connection.session() } else { None };
+        let max_queries =
+            if self.reuse_connections { self.info.max_queries_per_connection } else { Some(1) };
+        let exhausted = matches!(max_queries, Some(max) if self.queries_on_connection >= max);
+        // A query forcing a full handshake gets a connection of its own, which is never installed,
+        // so its session is never recorded. Dropping it once the query is sent lets the query
+        // finish before it closes.
+        let dedicated = if query.force_full_handshake {
+            let session = self.session_for_new_connection(true);
+            let connection = build_connection(
+                &self.info,
+                &self.env,
+                &mut self.config,
+                session,
+                self.window_tuner.as_deref(),
+            )
+            .await?;
+            Some(connection)
+        } else {
+            None
+        };
+        let connection_source = if dedicated.is_some() {
+            ConnectionSource::NewForFullHandshake
+        } else if exhausted {
+            debug!(
+                "Rotating connection {} on Network {} after {} queries",
+                self.connection.trace_id(),
+                self.info.net_id,
+                self.queries_on_connection
+            );
+            // The new connection deliberately doesn't resume the old one's session, which would
+            // let the server link them. Dropping the old handle lets its in-flight queries finish
+            // before it closes.
+            let connection = build_connection(
+                &self.info,
+                &self.env,
+                &mut self.config,
+                None,
+                self.window_tuner.as_deref(),
+            )
+            .await?;
+            self.install(connection);
+            self.env.metrics.connection_rotated();
+            if self.reuse_connections {
+                ConnectionSource::NewBecauseExhausted
+            } else {
+                ConnectionSource::NewBecauseReuseDisabled
+            }
+        } else if !self.connection.wait_for_live().await {
+            let session = self.session_for_new_connection(false);
             // Try reconnecting
-            self.connection =
-                build_connection(&self.info, &self.tag_socket, &mut self.config, session).await?;
+            let connection = build_connection(
+                &self.info,
+                &self.env,
+                &mut self.config,
+                session,
+                self.window_tuner.as_deref(),
+            )
+            .await?;
+            self.install(connection);
+            ConnectionSource::NewBecauseNoneLive
+        } else {
+            ConnectionSource::Reused
+        };
+        let connection = dedicated.as_ref().unwrap_or(&self.connection);
+        if connection.saturated() {
+            debug!("Connection {} is saturated, refusing query", connection.trace_id());
+            // We don't care if the response is gone.
+            let _ = query.response.send(Response::Error { error: QueryError::ConnectionSaturated });
+            return Ok(());
         }
-        let request = encoding::dns_request(&query.query, &self.info.url)?;
-        let stream_fut = self.connection.query(request, Some(query.expiry)).await?;
-        task::spawn(async move {
-            let stream = match stream_fut.await {
-                Some(stream) => stream,
-                None => {
-                    debug!("Connection died while processing request");
-                    // We don't care if the response is gone
-                    let _ =
-                        query.response.send(Response::Error { error: QueryError::ConnectionError });
-                    return;
+        // The response cache goes by the query as submitted, so the cookie only goes in what is
+        // sent. A query it can't be added to is sent as it is.
+        let cookied = self.cookie.as_ref().and_then(|cookie| {
+            with_cookie(&query.query, &cookie.lock().unwrap())
+                .map_err(|e| debug!("Sending query without a cookie: {:?}", e))
+                .ok()
+        });
+        let mut request =
+            encoding::dns_request(cookied.as_deref().unwrap_or(&query.query), &self.info.url)?;
+        request.extend(query.priority.header());
+        request.extend(query.extra_headers.iter().cloned());
+        // If an earlier answer to this query came with an ETag, let an intermediary revalidate it.
+        let trace_id = connection.trace_id().to_string();
+        if let Some(etag) = self.response_cache.lock().unwrap().etag(&query.query, &trace_id) {
+            request.push(h3::Header::new(b"if-none-match", &etag));
+        }
+        let stream_fut = connection
+            .query(request, query.submitted, Some(query.expiry), query.max_response_size)
+            .await?;
+        if dedicated.is_none() {
+            self.queries_on_connection += 1;
+        }
+        let response_cache = self.response_cache.clone();
+        let window_tuner = self.window_tuner.clone();
+        let backoff = self.backoff.clone();
+        let policy = self.info.server_errors.clone();
+        let retry_tx = self.retry_tx.clone();
+        let cookie = self.cookie.clone();
+        let fail_truncated = self.info.fail_truncated_answers;
+        let retry_on_connection_loss = self.info.retry_on_connection_loss;
+        let clock = self.env.clock.clone();
+        let lost = until_lost(self.lost_rx.clone());
+        task::spawn(self.env.metrics.track(async move {
+            let response = select! {
+                biased;
+                _ = lost => Response::Error { error: QueryError::NetworkLost },
+                mut stream = stream_fut => {
+                    if let Some(stream) = &mut stream {
+                        stream.stats.connection_source = connection_source;
+                    }
+                    if let (Some(tuner), Some(stream)) = (&window_tuner, &stream) {
+                        tuner.lock().unwrap().observe(&stream.stats, clock.now());
+                    }
+                    response_cache.lock().unwrap().respond(&query.query, &trace_id, stream)
                 }
             };
+            if let (Some(cookie), Response::Success { answer, .. }) = (&cookie, &response) {
+                if let Ok(Some(received)) = encoding::cookie(answer) {
+                    cookie.lock().unwrap().update(&received);
+                }
+            }
+            let response = check_truncation(fail_truncated, response);
+            let response = restore_message_id(query.message_id, response);
+            if let Response::Error { error } = &response {
+                backoff.lock().unwrap().observe(&policy, error, clock.now());
+                let connection_lost = retry_on_connection_loss
+                    && matches!(error, QueryError::ConnectionError)
+                    && clock.now() <= query.expiry;
+                if !query.is_retry && (connection_lost || policy.should_retry(error)) {
+                    // If the network has gone, there is nothing to retry on, so fail the query.
+                    if let Some(Ok(permit)) = retry_tx.upgrade().map(|tx| tx.try_reserve_owned()) {
+                        debug!("Retrying query after {:?}", error);
+                        permit.send(Command::Query(Query { is_retry: true, ..query }));
+                        return;
+                    }
+                }
+            }
             // We don't care if the response is gone.
-            let _ = if let Some(err) = stream.error {
-                query.response.send(Response::Error { error: QueryError::Reset(err) })
-            } else {
-                query.response.send(Response::Success { answer: stream.data })
-            };
-        });
+            let _ = query.response.send(response);
+        }));
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boot_time::{system_clock, MockClock, SharedClock};
+    use crate::config::test_key;
+    use crate::connection::loopback::{DohServer, Handler, Reply};
+    use futures::FutureExt;
+    use tokio::sync::oneshot;
+
+    const TICKET: &[u8] = b"stored ticket";
+
+    #[tokio::test]
+    async fn forced_full_handshake_bypasses_session_store() {
+        // Nothing listens on the discard port, so the query is never answered.
+        let info = ServerInfo {
+            use_session_resumption: true,
+            ..ServerInfo::for_test("127.0.0.1:9".parse().unwrap())
+        };
+        let (net_id, url) = (info.net_id, info.url.to_string());
+        let clock = system_clock();
+        let session_store = Arc::new(SessionStore::new(clock.clone()));
+        session_store.insert(net_id, &url, TICKET.to_vec());
+        let key = test_key();
+        let validation: ValidationReporter = Arc::new(|_, _| async {}.boxed());
+        let env = Environment::for_test(clock.clone(), Default::default());
+        let (_lost_tx, lost_rx) = watch::channel(false);
+        let (mut driver, _command_tx, _status_rx, _monitor_rx) = Driver::new(
+            info,
+            Config::from_key(&key).unwrap(),
+            validation,
+            env,
+            lost_rx,
+            session_store.clone(),
+            true,
+        )
+        .await
+        .unwrap();
+        assert_eq!(driver.session_for_new_connection(true), None);
+        assert_eq!(driver.session_for_new_connection(false).as_deref(), Some(TICKET));
+
+        let shared = driver.connection.trace_id().to_string();
+        let (response, _response_rx) = oneshot::channel();
+        let submitted = clock.now();
+        let query = Query {
+            query: base64::encode_config([0; 12], base64::URL_SAFE_NO_PAD),
+            response,
+            submitted,
+            expiry: submitted.checked_add(Duration::from_secs(1)).unwrap(),
+            max_response_size: None,
+            priority: Default::default(),
+            extra_headers: Vec::new(),
+            is_retry: false,
+            message_id: 0,
+            force_full_handshake: true,
+        };
+        driver.send_query(query).await.unwrap();
+        // The query went on a connection of its own, so the shared one neither counts it nor has
+        // been replaced, and the store still holds exactly the ticket it started with.
+        assert_eq!(driver.connection.trace_id(), shared);
+        assert_eq!(driver.queries_on_connection, 0);
+        assert_eq!(session_store.get(net_id, &url).as_deref(), Some(TICKET));
+        assert_eq!(SessionStore::new(clock).import(&session_store.export()), 1);
+    }
+
+    // Starts a driver for `info`, returning where its commands go.
+    async fn start_driver(info: ServerInfo, clock: SharedClock) -> mpsc::Sender<Command> {
+        let validation: ValidationReporter = Arc::new(|_, _| async {}.boxed());
+        start_driver_with(info, validation, Environment::for_test(clock, Default::default())).await
+    }
+
+    // As `start_driver`, reporting validation to `validation` and going by `env`.
+    async fn start_driver_with(
+        info: ServerInfo,
+        validation: ValidationReporter,
+        env: Environment,
+    ) -> mpsc::Sender<Command> {
+        let clock = env.clock.clone();
+        // The sender going away leaves the network in place.
+        let (_lost_tx, lost_rx) = watch::channel(false);
+        let (driver, command_tx, status_rx, _monitor_rx) = Driver::new(
+            info,
+            Config::from_key(&test_key()).unwrap(),
+            validation,
+            env,
+            lost_rx,
+            Arc::new(SessionStore::new(clock)),
+            true,
+        )
+        .await
+        .unwrap();
+        // Status updates fail once nothing watches them, so the task keeps a watcher of its own.
+        task::spawn(async move {
+            let _status_rx = status_rx;
+            driver.drive().await
+        });
+        command_tx
+    }
+
+    // A probe query, due to expire after `timeout`, and where its response will arrive.
+    fn probe(clock: &SharedClock, timeout: Duration) -> (Query, oneshot::Receiver<Response>) {
+        let (response, response_rx) = oneshot::channel();
+        let submitted = clock.now();
+        let query = Query {
+            query: encoding::probe_query().unwrap(),
+            response,
+            submitted,
+            expiry: submitted.checked_add(timeout).unwrap(),
+            max_response_size: None,
+            priority: Default::default(),
+            extra_headers: Vec::new(),
+            is_retry: false,
+            message_id: 0,
+            force_full_handshake: false,
+        };
+        (query, response_rx)
+    }
+
+    // Sends a probe query through a network on a server replying as `handler` says, returning
+    // the response and how many requests the server got, on how many connections.
+    async fn resolve_with(
+        handler: Handler,
+        retry_on_connection_loss: bool,
+        clock: SharedClock,
+    ) -> (Response, usize, usize) {
+        let server = DohServer::start(handler).unwrap();
+        let info = ServerInfo { retry_on_connection_loss, ..ServerInfo::for_test(server.addr) };
+        let command_tx = start_driver(info, clock.clone()).await;
+        let (query, response_rx) = probe(&clock, Duration::from_secs(5));
+        command_tx.send(Command::Query(query)).await.unwrap();
+        let response = response_rx.await.unwrap();
+        (response, server.requests(), server.connections())
+    }
+
+    #[tokio::test]
+    async fn provided_socket() {
+        use crate::connection::{ProvidedSocket, SocketBinding};
+        use std::os::unix::io::IntoRawFd;
+        let server = DohServer::start(Box::new(|_, _| Reply::After(Duration::ZERO))).unwrap();
+        let fd = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().into_raw_fd();
+        let provided = unsafe { ProvidedSocket::from_raw_fd(fd) }.unwrap();
+        let info = ServerInfo {
+            socket_binding: SocketBinding::Provided(provided),
+            ..ServerInfo::for_test(server.addr)
+        };
+        let clock = system_clock();
+        let command_tx = start_driver(info, clock.clone()).await;
+        let (query, response_rx) = probe(&clock, Duration::from_secs(5));
+        command_tx.send(Command::Query(query)).await.unwrap();
+        let response = response_rx.await.unwrap();
+        assert!(matches!(response, Response::Success { .. }), "{:?}", response);
+    }
+
+    #[tokio::test]
+    async fn retried_on_connection_loss() {
+        // The connection closes on the first request, and a new one answers the retry.
+        let handler: Handler = Box::new(|previous, _| match previous {
+            0 => Reply::Close,
+            _ => Reply::After(Duration::ZERO),
+        });
+        let (response, requests, connections) = resolve_with(handler, true, system_clock()).await;
+        assert!(matches!(response, Response::Success { .. }), "{:?}", response);
+        assert_eq!((requests, connections), (2, 2));
+
+        // A query is only retried once.
+        let (response, requests, connections) =
+            resolve_with(Box::new(|_, _| Reply::Close), true, system_clock()).await;
+        assert_eq!(response, Response::Error { error: QueryError::ConnectionError });
+        assert_eq!((requests, connections), (2, 2));
+    }
+
+    #[tokio::test]
+    async fn not_retried_on_connection_loss() {
+        // Unless the server's settings allow it.
+        let (response, requests, _) =
+            resolve_with(Box::new(|_, _| Reply::Close), false, system_clock()).await;
+        assert_eq!(response, Response::Error { error: QueryError::ConnectionError });
+        assert_eq!(requests, 1);
+
+        // Nor once the query has expired, however it is then failed.
+        let clock = MockClock::new();
+        let server_clock = clock.clone();
+        let handler: Handler = Box::new(move |_, _| {
+            server_clock.advance(Duration::from_secs(6));
+            Reply::Close
+        });
+        let (response, requests, _) = resolve_with(handler, true, clock).await;
+        assert!(matches!(response, Response::Error { .. }), "{:?}", response);
+        assert_eq!(requests, 1);
+    }
+
+    // Queries sent all at once to a network rotating after two per connection go over three
+    // connections, with the ones on retired connections still answered.
+    #[tokio::test]
+    async fn rotated_after_max_queries() {
+        let server =
+            DohServer::start(Box::new(|_, _| Reply::After(Duration::from_millis(100)))).unwrap();
+        let info =
+            ServerInfo { max_queries_per_connection: Some(2), ..ServerInfo::for_test(server.addr) };
+        let clock = system_clock();
+        let metrics: Arc<crate::dispatcher::DispatcherMetrics> = Default::default();
+        let validation: ValidationReporter = Arc::new(|_, _| async {}.boxed());
+        let env = Environment::for_test(clock.clone(), metrics.clone());
+        let command_tx = start_driver_with(info, validation, env).await;
+        let mut response_rxs = Vec::new();
+        for _ in 0..5 {
+            let (query, response_rx) = probe(&clock, Duration::from_secs(5));
+            command_tx.send(Command::Query(query)).await.unwrap();
+            response_rxs.push(response_rx);
+        }
+        for response_rx in response_rxs {
+            let response = response_rx.await.unwrap();
+            assert!(matches!(response, Response::Success { .. }), "{:?}", response);
+        }
+        assert_eq!((server.requests(), server.connections()), (5, 3));
+        assert_eq!(metrics.connection_rotations(), 2);
+    }
+
+    // A probe finds the server on its fallback port when nothing answers on the primary one, and
+    // reports the port which worked. Queries then go there too.
+    #[tokio::test]
+    async fn probe_falls_back_to_next_port() {
+        use std::sync::mpsc::sync_channel;
+        let server = DohServer::start(Box::new(|_, _| Reply::After(Duration::ZERO))).unwrap();
+        // Nothing listens on the discard port.
+        let info = ServerInfo {
+            fallback_ports: vec![server.addr.port()],
+            ..ServerInfo::for_test("127.0.0.1:9".parse().unwrap())
+        };
+        let (validated_tx, validated_rx) = sync_channel(1);
+        let validation: ValidationReporter = Arc::new(move |info, valid| {
+            let _ = validated_tx.try_send((info.peer_addr, valid));
+            async {}.boxed()
+        });
+        let clock = system_clock();
+        let env = Environment::for_test(clock.clone(), Default::default());
+        let command_tx = start_driver_with(info, validation, env).await;
+        command_tx.send(Command::Probe(Duration::from_secs(4))).await.unwrap();
+        let validated = task::spawn_blocking(move || validated_rx.recv()).await.unwrap();
+        assert_eq!(validated, Ok((server.addr, true)));
+
+        let (query, response_rx) = probe(&clock, Duration::from_secs(5));
+        command_tx.send(Command::Query(query)).await.unwrap();
+        let response = response_rx.await.unwrap();
+        assert!(matches!(response, Response::Success { .. }), "{:?}", response);
+        assert_eq!((server.requests(), server.connections()), (2, 1));
+    }
+}
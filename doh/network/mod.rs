@@ -17,11 +17,14 @@
 //! Provides the ability to query DNS for a specific network configuration
 
 use crate::boot_time::{BootTime, Duration};
-use crate::config::Config;
+use crate::config::{Config, PemCerts, TransportParams};
+use crate::connection::{self, Environment, Peer};
 use crate::dispatcher::{QueryError, Response};
+use crate::encoding::{self, Priority};
 use anyhow::Result;
 use futures::future::BoxFuture;
-use log::warn;
+use log::{debug, warn};
+use quiche::h3;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::sync::{mpsc, oneshot, watch};
@@ -29,10 +32,18 @@ use tokio::task;
 use url::Url;
 
 mod driver;
+mod response_cache;
+mod server_errors;
+mod session_store;
+mod window_tuner;
 
 use driver::{Command, Driver};
 
+pub use connection::{ProvidedSocket, SocketBinding};
 pub use driver::Status;
+pub use response_cache::ResponseCacheLimits;
+pub use server_errors::ServerErrorPolicy;
+pub use session_store::SessionStore;
 
 /// Closure to signal validation status to outside world
 pub type ValidationReporter = Arc<dyn Fn(&ServerInfo, bool) -> BoxFuture<()> + Send + Sync>;
@@ -45,10 +56,93 @@ pub struct ServerInfo {
     pub url: Url,
     pub peer_addr: SocketAddr,
     pub domain: Option<String>,
-    pub sk_mark: u32,
+    /// How connections' sockets are kept on the network.
+    pub socket_binding: SocketBinding,
     pub cert_path: Option<String>,
+    /// PEM certificates to verify the server against, held in memory, in place of a
+    /// `cert_path`; see `config::Key::cert_pem`.
+    pub cert_pem: Option<PemCerts>,
     pub idle_timeout_ms: u64,
+    /// Whether to resume TLS sessions, including ones recorded by the `SessionStore` before a
+    /// restart.
     pub use_session_resumption: bool,
+    pub connection_options: connection::Options,
+    /// Queries to send on a connection before replacing it with a fresh one. `None` means
+    /// connections are kept for as long as they work.
+    pub max_queries_per_connection: Option<u64>,
+    /// Ports to try in order, after the port of `peer_addr`, if the server can't be reached on
+    /// it. Probing settles on the first port which completes a handshake, and the `ServerInfo`
+    /// given to the validation callback carries the port in use.
+    pub fallback_ports: Vec<u16>,
+    /// If set, each new connection's flow-control window is sized from the bandwidth-delay
+    /// product seen on earlier ones, up to this many bytes, and shrinks again while the network is
+    /// idle. `None` keeps the config's static window.
+    pub connection_window_cap: Option<u64>,
+    /// How to react when the server answers with an HTTP 5xx status.
+    pub server_errors: ServerErrorPolicy,
+    /// How many of the server's ETag-validated answers to keep, for sending repeated queries as
+    /// conditional requests. Off by default; see `ResponseCacheLimits`.
+    pub response_cache: ResponseCacheLimits,
+    /// Whether to send DNS Cookies (RFC 7873), keeping the server cookie from each answer for the
+    /// next query. Few DoH servers use them, so this is normally off. Answers are passed on with
+    /// the COOKIE option the server put in them.
+    pub use_dns_cookies: bool,
+    /// Whether to fail answers with the TC bit set with `QueryError::Truncated`, so that the
+    /// resolver can fall back to another transport. DoH has no message size limit to truncate
+    /// for, so only a misbehaving server sets it. Otherwise such answers are passed on as they
+    /// are, for the caller to check `ResponseMetadata::truncated`.
+    pub fail_truncated_answers: bool,
+    /// Whether to send a query again, once, if its connection closes before answering it. DNS
+    /// queries are idempotent, so this is safe; the retry goes out on a new connection if the old
+    /// one is gone. Queries past their deadline aren't retried.
+    pub retry_on_connection_loss: bool,
+    /// Transport parameters to build the server's QUIC config with. Servers only share a config
+    /// if these match, along with the rest of the config's key, such as the cert path.
+    pub transport_params: TransportParams,
+    /// ALPN protocols to offer after the one the stream mode calls for; see
+    /// `config::Key::extra_application_protos`.
+    pub extra_application_protos: Vec<Vec<u8>>,
+}
+
+impl ServerInfo {
+    /// Where connections to the server are made to.
+    pub fn peer(&self) -> Peer<'_> {
+        Peer {
+            server_name: self.domain.as_deref(),
+            addr: self.peer_addr,
+            net_id: self.net_id,
+            binding: &self.socket_binding,
+        }
+    }
+
+    /// A server for tests on network 42 at `peer_addr`, with a one second idle timeout and every
+    /// optional behaviour off. Tests name only the fields they care about, and take the rest with
+    /// `..ServerInfo::for_test(peer_addr)`.
+    #[cfg(test)]
+    pub fn for_test(peer_addr: SocketAddr) -> Self {
+        Self {
+            net_id: 42,
+            url: Url::parse("https://mylocal.com/dns-query").unwrap(),
+            peer_addr,
+            domain: None,
+            socket_binding: SocketBinding::Mark(0),
+            cert_path: None,
+            cert_pem: None,
+            idle_timeout_ms: 1000,
+            use_session_resumption: false,
+            connection_options: Default::default(),
+            max_queries_per_connection: None,
+            fallback_ports: Vec::new(),
+            connection_window_cap: None,
+            server_errors: Default::default(),
+            response_cache: Default::default(),
+            use_dns_cookies: false,
+            fail_truncated_answers: false,
+            retry_on_connection_loss: false,
+            transport_params: Default::default(),
+            extra_application_protos: Vec::new(),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -58,15 +152,60 @@ pub struct Query {
     pub query: String,
     /// Place to send the answer
     pub response: oneshot::Sender<Response>,
+    /// When the query was submitted to the dispatcher
+    pub submitted: BootTime,
     /// When this request is considered stale (will be ignored if not serviced by that point)
     pub expiry: BootTime,
+    /// Largest answer the requestor is willing to accept, if it wants a limit tighter than
+    /// the connection's default
+    pub max_response_size: Option<usize>,
+    /// Priority of the request relative to others on the connection
+    pub priority: Priority,
+    /// Headers to append to the standard DoH ones
+    pub extra_headers: Vec<h3::Header>,
+    /// Whether the query is being sent again after the server failed to answer it
+    pub is_retry: bool,
+    /// ID the query was submitted with. It is sent as 0, as RFC 8484 recommends so answers can be
+    /// cached, and written back into the answer, so queries sharing an ID each get their own.
+    pub message_id: u16,
+    /// Whether to send the query on a connection of its own which doesn't resume a session
+    pub force_full_handshake: bool,
+}
+
+/// Writes `message_id` into `response` if it is an answer, so the requestor sees the ID it asked
+/// with.
+pub fn restore_message_id(message_id: u16, response: Response) -> Response {
+    match response {
+        Response::Success { mut answer, metadata } => {
+            if let Err(e) = encoding::set_message_id(&mut answer, message_id) {
+                debug!("Unable to restore the ID of the answer: {:?}", e);
+            }
+            Response::Success { answer, metadata }
+        }
+        response => response,
+    }
+}
+
+/// Fails `response` with `QueryError::Truncated` if it is an answer with the TC bit set and
+/// `fail_truncated`, from `ServerInfo::fail_truncated_answers`, is set. Answers whose header
+/// can't be read are passed on as they are.
+pub fn check_truncation(fail_truncated: bool, response: Response) -> Response {
+    match response {
+        Response::Success { metadata, .. } if fail_truncated && metadata.truncated => {
+            debug!("Failing truncated answer");
+            Response::Error { error: QueryError::Truncated }
+        }
+        response => response,
+    }
 }
 
 /// Handle to a particular network's DNS resolution
 pub struct Network {
     info: ServerInfo,
     status_rx: watch::Receiver<Status>,
+    monitor_rx: watch::Receiver<connection::Monitor>,
     command_tx: mpsc::Sender<Command>,
+    lost_tx: watch::Sender<bool>,
 }
 
 impl Network {
@@ -74,12 +213,24 @@ impl Network {
         info: ServerInfo,
         config: Config,
         validation: ValidationReporter,
-        tagger: SocketTagger,
+        env: Environment,
+        session_store: Arc<SessionStore>,
+        reuse_connections: bool,
     ) -> Result<Network> {
-        let (driver, command_tx, status_rx) =
-            Driver::new(info.clone(), config, validation, tagger).await?;
-        task::spawn(driver.drive());
-        Ok(Network { info, command_tx, status_rx })
+        let (lost_tx, lost_rx) = watch::channel(false);
+        let metrics = env.metrics.clone();
+        let (driver, command_tx, status_rx, monitor_rx) = Driver::new(
+            info.clone(),
+            config,
+            validation,
+            env,
+            lost_rx,
+            session_store,
+            reuse_connections,
+        )
+        .await?;
+        task::spawn(metrics.track(driver.drive()));
+        Ok(Network { info, command_tx, status_rx, monitor_rx, lost_tx })
     }
 
     pub async fn probe(&mut self, timeout: Duration) -> Result<()> {
@@ -108,7 +259,26 @@ impl Network {
         Ok(())
     }
 
+    /// Fails every query in flight or waiting on this network with `QueryError::NetworkLost`, and
+    /// drops its connection without waiting for it to time out.
+    pub fn lost(&self) {
+        // The driver may already have exited, in which case there is nothing left to abort.
+        let _ = self.lost_tx.send(true);
+    }
+
     pub fn get_info(&self) -> &ServerInfo {
         &self.info
     }
+
+    /// Asks for the network's connection to be closed if it is idle, to save memory. Returns
+    /// whether it was idle, and so will be closed unless a query arrives first.
+    pub fn retire_if_idle(&self) -> bool {
+        self.connection_info().open_streams == 0
+            && self.command_tx.try_send(Command::RetireIfIdle).is_ok()
+    }
+
+    /// Describes the connection queries are currently sent on.
+    pub fn connection_info(&self) -> connection::ConnectionInfo {
+        self.monitor_rx.borrow().info()
+    }
 }
@@ -0,0 +1,512 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Remembers ETag-validated answers so repeated queries can be sent as conditional requests
+
+use crate::boot_time::{BootTime, Duration, SharedClock};
+use crate::connection::{stream_response, Stream};
+use crate::dispatcher::{DispatcherMetrics, Response};
+use crate::encoding::{self, ResponseMetadata};
+use log::debug;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+const HTTP_NOT_MODIFIED: u16 = 304;
+
+/// How much a network's `ResponseCache` may hold. An answer is evicted, least recently used
+/// first, whenever keeping it would exceed either limit.
+///
+/// The cache is off by default, and servers opt in through `ServerInfo::response_cache`. A server
+/// can hand each client ETags of its own, so sending one back tells the server which client is
+/// asking much as a cookie would; only servers trusted with that should have it on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResponseCacheLimits {
+    /// Most answers kept. Zero, the default, disables the cache.
+    pub max_entries: usize,
+    /// Most bytes of answers, ETags and queries kept. Answers vary from tens of bytes to many
+    /// kilobytes for TXT or DNSSEC records, so this bounds memory where `max_entries` can't.
+    /// An answer larger than this on its own is never cached.
+    pub max_bytes: usize,
+}
+
+impl Default for ResponseCacheLimits {
+    fn default() -> Self {
+        Self { max_entries: 0, max_bytes: 128 * 1024 }
+    }
+}
+
+struct Entry {
+    etag: Vec<u8>,
+    answer: Vec<u8>,
+    metadata: ResponseMetadata,
+    last_used: u64,
+    // Trace ID of the connection the answer came on.
+    connection: String,
+    // When the answer's shortest TTL runs out.
+    fresh_until: BootTime,
+}
+
+// Bytes an entry counts against `ResponseCacheLimits::max_bytes`.
+fn entry_size(query: &str, etag: &[u8], answer: &[u8]) -> usize {
+    query.len() + etag.len() + answer.len()
+}
+
+/// Answers the server tagged with an ETag, keyed by the base64 query they answered.
+///
+/// The key is the exact query, since the ETag validates the resource named by the request path.
+/// What the cache holds is added to the dispatcher's `response_cache_entries` and
+/// `response_cache_bytes`.
+///
+/// A 304 hands back the answer as it was cached, TTLs and all, so an answer is only revalidated
+/// until its shortest TTL runs out, and is fetched afresh after that. Answers without records have
+/// no TTL to go by, and aren't kept. An ETag is only sent on the connection its answer came on,
+/// so that it can't link connections which are otherwise kept apart.
+pub struct ResponseCache {
+    entries: HashMap<String, Entry>,
+    limits: ResponseCacheLimits,
+    bytes: usize,
+    // Orders uses of entries, for evicting the least recently used.
+    uses: u64,
+    clock: SharedClock,
+    metrics: Arc<DispatcherMetrics>,
+}
+
+impl ResponseCache {
+    pub fn new(
+        limits: ResponseCacheLimits,
+        metrics: Arc<DispatcherMetrics>,
+        clock: SharedClock,
+    ) -> Self {
+        Self { entries: HashMap::new(), limits, bytes: 0, uses: 0, clock, metrics }
+    }
+
+    /// Returns the ETag to send as `If-None-Match` for `query` on `connection`, the trace ID of
+    /// the connection the request goes on, if it brought a cached answer for it which is still
+    /// fresh. A stale answer is dropped.
+    pub fn etag(&mut self, query: &str, connection: &str) -> Option<Vec<u8>> {
+        if matches!(self.entries.get(query), Some(entry) if self.clock.now() > entry.fresh_until) {
+            self.remove(query);
+        }
+        self.uses += 1;
+        let uses = self.uses;
+        let entry = self.entries.get_mut(query).filter(|entry| entry.connection == connection)?;
+        entry.last_used = uses;
+        Some(entry.etag.clone())
+    }
+
+    /// Converts the outcome of a request for `query`, sent on the connection with trace ID
+    /// `connection`, into a response, substituting the cached answer for a 304 Not Modified and
+    /// remembering answers which carry an ETag.
+    pub fn respond(&mut self, query: &str, connection: &str, stream: Option<Stream>) -> Response {
+        let stream = match stream {
+            Some(stream) if stream.error.is_none() && !stream.too_large => stream,
+            other => return stream_response(other),
+        };
+        if encoding::status_code(&stream.headers) == Some(HTTP_NOT_MODIFIED) {
+            return match self.entries.get(query) {
+                Some(entry) => {
+                    Response::Success { answer: entry.answer.clone(), metadata: entry.metadata }
+                }
+                None => {
+                    debug!("Got 304 Not Modified for a query with no cached answer");
+                    stream_response(Some(stream))
+                }
+            };
+        }
+        let etag = encoding::header_value(&stream.headers, b"etag").map(<[u8]>::to_vec);
+        let response = stream_response(Some(stream));
+        match (etag, &response) {
+            // Only DNS messages are remembered, so a 304 never stands in for a broken answer.
+            (Some(etag), Response::Success { answer, metadata }) => {
+                self.insert(query, connection, etag, answer.clone(), *metadata)
+            }
+            _ => self.remove(query),
+        }
+        response
+    }
+
+    /// Forgets the answers which came on connections other than `connection`, whose ETags can
+    /// never be sent again once it has replaced them.
+    pub fn keep_only(&mut self, connection: &str) {
+        let others: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.connection != connection)
+            .map(|(query, _)| query.clone())
+            .collect();
+        for query in others {
+            self.remove(&query);
+        }
+    }
+
+    fn insert(
+        &mut self,
+        query: &str,
+        connection: &str,
+        etag: Vec<u8>,
+        answer: Vec<u8>,
+        metadata: ResponseMetadata,
+    ) {
+        self.remove(query);
+        let size = entry_size(query, &etag, &answer);
+        if self.limits.max_entries == 0 || size > self.limits.max_bytes {
+            return;
+        }
+        let min_ttl = metadata.min_ttl.unwrap_or(0);
+        if min_ttl == 0 {
+            return;
+        }
+        let fresh_until = self.clock.now().checked_add(Duration::from_secs(min_ttl.into()));
+        let fresh_until = match fresh_until {
+            Some(fresh_until) => fresh_until,
+            None => return,
+        };
+        while self.entries.len() >= self.limits.max_entries
+            || self.bytes + size > self.limits.max_bytes
+        {
+            // Evict the least recently used entry.
+            match self.entries.iter().min_by_key(|(_, e)| e.last_used).map(|(k, _)| k.clone()) {
+                Some(oldest) => self.remove(&oldest),
+                None => break,
+            }
+        }
+        self.uses += 1;
+        let connection = connection.to_string();
+        let entry = Entry { etag, answer, metadata, last_used: self.uses, connection, fresh_until };
+        self.entries.insert(query.to_string(), entry);
+        self.bytes += size;
+        self.metrics.response_cached(size);
+    }
+
+    fn remove(&mut self, query: &str) {
+        if let Some(entry) = self.entries.remove(query) {
+            let size = entry_size(query, &entry.etag, &entry.answer);
+            self.bytes -= size;
+            self.metrics.response_uncached(size);
+        }
+    }
+}
+
+impl Drop for ResponseCache {
+    fn drop(&mut self) {
+        for (query, entry) in self.entries.drain() {
+            self.metrics.response_uncached(entry_size(&query, &entry.etag, &entry.answer));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ResponseCache, ResponseCacheLimits};
+    use crate::boot_time::{system_clock, Duration, MockClock};
+    use crate::connection::Stream;
+    use crate::dispatcher::{DispatcherMetrics, QueryError, Response};
+    use crate::encoding::ResponseMetadata;
+    use quiche::h3;
+    use std::sync::Arc;
+
+    const QUERY: &str = "AAABAAABAAAAAAAAA2Zvbwdhbm";
+    const CONNECTION: &str = "0123abcd";
+    const LIMITS: ResponseCacheLimits =
+        ResponseCacheLimits { max_entries: 64, max_bytes: 128 * 1024 };
+    const TTL: u32 = 300;
+
+    fn stream(status: &[u8], etag: Option<&[u8]>, data: &[u8]) -> Option<Stream> {
+        let mut headers = vec![h3::Header::new(b":status", status)];
+        if let Some(etag) = etag {
+            headers.push(h3::Header::new(b"etag", etag));
+        }
+        Some(Stream {
+            headers,
+            data: data.to_vec(),
+            error: None,
+            too_large: false,
+            expired: false,
+            stats: Default::default(),
+        })
+    }
+
+    fn cache(limits: ResponseCacheLimits) -> ResponseCache {
+        ResponseCache::new(limits, Arc::new(DispatcherMetrics::default()), system_clock())
+    }
+
+    // A DNS response with a single A record valid for `TTL` seconds, distinguished by `id`.
+    fn message(id: u16, rcode: u8) -> Vec<u8> {
+        let [id_hi, id_lo] = id.to_be_bytes();
+        let mut message = vec![id_hi, id_lo, 0x81, 0x80 | rcode, 0, 0, 0, 1, 0, 0, 0, 0];
+        // Root owner name, type A, class IN, then the TTL and four bytes of address.
+        message.extend([0, 0, 1, 0, 1]);
+        message.extend(TTL.to_be_bytes());
+        message.extend([0, 4, 192, 0, 2, 1]);
+        message
+    }
+
+    fn answer(response: Response) -> Vec<u8> {
+        match response {
+            Response::Success { answer, .. } => answer,
+            Response::Error { error } => panic!("Unexpected error: {:?}", error),
+        }
+    }
+
+    #[test]
+    fn not_modified_uses_cached_answer() {
+        let mut cache = cache(LIMITS);
+        assert_eq!(cache.etag(QUERY, CONNECTION), None);
+        let first =
+            cache.respond(QUERY, CONNECTION, stream(b"200", Some(b"\"v1\""), &message(1, 0)));
+        assert_eq!(answer(first), message(1, 0));
+        assert_eq!(cache.etag(QUERY, CONNECTION), Some(b"\"v1\"".to_vec()));
+        let second = cache.respond(QUERY, CONNECTION, stream(b"304", None, b""));
+        // The cached answer stands in along with what was read from it.
+        let metadata =
+            ResponseMetadata { rcode: 0, answer_count: 1, truncated: false, min_ttl: Some(TTL) };
+        assert_eq!(second, Response::Success { answer: message(1, 0), metadata });
+    }
+
+    #[test]
+    fn off_by_default() {
+        let mut cache = cache(Default::default());
+        cache.respond(QUERY, CONNECTION, stream(b"200", Some(b"\"v1\""), &message(1, 0)));
+        assert_eq!(cache.etag(QUERY, CONNECTION), None);
+    }
+
+    #[test]
+    fn stale_answers_not_revalidated() {
+        let clock = MockClock::new();
+        let mut cache =
+            ResponseCache::new(LIMITS, Arc::new(DispatcherMetrics::default()), clock.clone());
+        cache.respond(QUERY, CONNECTION, stream(b"200", Some(b"\"v1\""), &message(1, 0)));
+        clock.advance(Duration::from_secs(TTL.into()));
+        assert!(cache.etag(QUERY, CONNECTION).is_some());
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(cache.etag(QUERY, CONNECTION), None);
+        // Nor is an answer without records, which has no TTL to go by.
+        let mut empty = message(2, 0);
+        empty.truncate(12);
+        empty[7] = 0;
+        cache.respond(QUERY, CONNECTION, stream(b"200", Some(b"\"v2\""), &empty));
+        assert_eq!(cache.etag(QUERY, CONNECTION), None);
+    }
+
+    #[test]
+    fn etags_stay_on_their_connection() {
+        let mut cache = cache(LIMITS);
+        cache.respond("a", CONNECTION, stream(b"200", Some(b"1"), &message(1, 0)));
+        cache.respond("b", "other", stream(b"200", Some(b"2"), &message(2, 0)));
+        assert_eq!(cache.etag("a", "other"), None);
+        assert!(cache.etag("b", "other").is_some());
+        // Once "other" replaces the connection, the answer from before is gone for good.
+        cache.keep_only("other");
+        assert_eq!(cache.etag("a", CONNECTION), None);
+        assert!(cache.etag("b", "other").is_some());
+    }
+
+    #[test]
+    fn untagged_answer_evicts_entry() {
+        let mut cache = cache(LIMITS);
+        cache.respond(QUERY, CONNECTION, stream(b"200", Some(b"\"v1\""), &message(1, 0)));
+        cache.respond(QUERY, CONNECTION, stream(b"200", None, &message(2, 0)));
+        assert_eq!(cache.etag(QUERY, CONNECTION), None);
+    }
+
+    #[test]
+    fn failures_are_not_cached() {
+        let mut cache = cache(LIMITS);
+        let mut reset = stream(b"200", Some(b"\"v1\""), b"partial");
+        reset.as_mut().unwrap().error = Some(0x10c);
+        assert!(matches!(
+            cache.respond(QUERY, CONNECTION, reset),
+            Response::Error { error: QueryError::Reset(0x10c) }
+        ));
+        assert_eq!(cache.etag(QUERY, CONNECTION), None);
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = cache(ResponseCacheLimits { max_entries: 2, ..Default::default() });
+        cache.respond("a", CONNECTION, stream(b"200", Some(b"1"), &message(1, 0)));
+        cache.respond("b", CONNECTION, stream(b"200", Some(b"2"), &message(2, 0)));
+        // Touch "a" so that "b" is the eviction candidate.
+        assert!(cache.etag("a", CONNECTION).is_some());
+        cache.respond("c", CONNECTION, stream(b"200", Some(b"3"), &message(3, 0)));
+        assert!(cache.etag("a", CONNECTION).is_some());
+        assert!(cache.etag("b", CONNECTION).is_none());
+        assert!(cache.etag("c", CONNECTION).is_some());
+    }
+
+    #[test]
+    fn evicts_over_byte_budget() {
+        let metrics = Arc::new(DispatcherMetrics::default());
+        let limits = ResponseCacheLimits { max_entries: 64, max_bytes: 5000 };
+        let mut cache = ResponseCache::new(limits, metrics.clone(), system_clock());
+        // Each entry counts its query and ETag as well as the answer.
+        let large = |id| {
+            let mut answer = message(id, 0);
+            answer.resize(2000 - 2, 0);
+            answer
+        };
+        cache.respond("a", CONNECTION, stream(b"200", Some(b"1"), &large(1)));
+        cache.respond("b", CONNECTION, stream(b"200", Some(b"2"), &large(2)));
+        assert_eq!(metrics.response_cache_entries(), 2);
+        assert_eq!(metrics.response_cache_bytes(), 4000);
+
+        // A third large answer only fits once the least recently used one is gone.
+        assert!(cache.etag("a", CONNECTION).is_some());
+        cache.respond("c", CONNECTION, stream(b"200", Some(b"3"), &large(3)));
+        assert!(cache.etag("a", CONNECTION).is_some());
+        assert!(cache.etag("b", CONNECTION).is_none());
+        assert!(cache.etag("c", CONNECTION).is_some());
+        assert_eq!(metrics.response_cache_entries(), 2);
+        assert_eq!(metrics.response_cache_bytes(), 4000);
+
+        // An answer bigger than the whole budget isn't cached, and displaces nothing.
+        let mut huge = message(4, 0);
+        huge.resize(6000, 0);
+        cache.respond("d", CONNECTION, stream(b"200", Some(b"4"), &huge));
+        assert!(cache.etag("d", CONNECTION).is_none());
+        assert_eq!(metrics.response_cache_entries(), 2);
+
+        // Replacing an answer counts only the new one, and small answers fit alongside.
+        cache.respond("a", CONNECTION, stream(b"200", Some(b"5"), &message(5, 0)));
+        assert_eq!(metrics.response_cache_bytes(), 2000 + 1 + 1 + 27);
+        drop(cache);
+        assert_eq!(metrics.response_cache_entries(), 0);
+        assert_eq!(metrics.response_cache_bytes(), 0);
+    }
+
+    #[test]
+    fn dns_errors_are_answers() {
+        const FORMERR: u8 = 1;
+        const SERVFAIL: u8 = 2;
+        let mut cache = cache(LIMITS);
+        for rcode in [FORMERR, SERVFAIL] {
+            let response =
+                cache.respond(QUERY, CONNECTION, stream(b"200", None, &message(1, rcode)));
+            assert_eq!(answer(response), message(1, rcode));
+        }
+    }
+
+    #[test]
+    fn non_dns_bodies_fail() {
+        let mut cache = cache(LIMITS);
+        for body in [&b""[..], b"busy", &message(1, 0)[..11]] {
+            assert_eq!(
+                cache.respond(QUERY, CONNECTION, stream(b"200", Some(b"\"v1\""), body)),
+                Response::Error { error: QueryError::MalformedResponse }
+            );
+            // Nor are they remembered for a later 304 to stand in for.
+            assert_eq!(cache.etag(QUERY, CONNECTION), None);
+        }
+        // A 304 for a query with no cached answer has nothing to give back either.
+        assert_eq!(
+            cache.respond(QUERY, CONNECTION, stream(b"304", None, b"")),
+            Response::Error { error: QueryError::MalformedResponse }
+        );
+    }
+
+    #[test]
+    fn server_errors_carry_status() {
+        use crate::boot_time::Duration;
+        let mut cache = cache(LIMITS);
+        cache.respond(QUERY, CONNECTION, stream(b"200", Some(b"\"v1\""), &message(1, 0)));
+        // Even a DNS body doesn't make a 5xx an answer.
+        let mut unavailable = stream(b"503", None, &message(2, 0));
+        unavailable.as_mut().unwrap().headers.push(h3::Header::new(b"retry-after", b"30"));
+        assert_eq!(
+            cache.respond(QUERY, CONNECTION, unavailable),
+            Response::Error {
+                error: QueryError::ServerError {
+                    status: 503,
+                    retry_after: Some(Duration::from_secs(30))
+                }
+            }
+        );
+        assert_eq!(cache.etag(QUERY, CONNECTION), None);
+        assert_eq!(
+            cache.respond(QUERY, CONNECTION, stream(b"500", None, b"")),
+            Response::Error { error: QueryError::ServerError { status: 500, retry_after: None } }
+        );
+    }
+
+    #[test]
+    fn captive_portal_responses() {
+        let mut cache = cache(LIMITS);
+        let mut redirect = stream(b"302", None, b"");
+        redirect
+            .as_mut()
+            .unwrap()
+            .headers
+            .push(h3::Header::new(b"location", b"http://portal.example/login"));
+        assert_eq!(
+            cache.respond(QUERY, CONNECTION, redirect),
+            Response::Error {
+                error: QueryError::CaptivePortal {
+                    status: 302,
+                    location: Some("http://portal.example/login".to_string())
+                }
+            }
+        );
+        // A sign-in page served in place of the answer, whatever it claims to be.
+        let mut page = stream(b"200", None, b"\n<!DOCTYPE html><html>Welcome</html>");
+        page.as_mut()
+            .unwrap()
+            .headers
+            .push(h3::Header::new(b"content-type", b"application/dns-message"));
+        assert_eq!(
+            cache.respond(QUERY, CONNECTION, page),
+            Response::Error { error: QueryError::CaptivePortal { status: 200, location: None } }
+        );
+        // A revalidated answer is not a redirect.
+        cache.respond(QUERY, CONNECTION, stream(b"200", Some(b"\"v1\""), &message(1, 0)));
+        assert_eq!(
+            answer(cache.respond(QUERY, CONNECTION, stream(b"304", None, b""))),
+            message(1, 0)
+        );
+        // Nor is a server which is failing mistaken for a portal because its error page is HTML.
+        assert_eq!(
+            cache.respond(QUERY, CONNECTION, stream(b"502", None, b"<html>Bad Gateway</html>")),
+            Response::Error { error: QueryError::ServerError { status: 502, retry_after: None } }
+        );
+    }
+
+    #[test]
+    fn not_doh_endpoints() {
+        let mut cache = cache(LIMITS);
+        assert_eq!(
+            cache.respond(QUERY, CONNECTION, stream(b"404", None, b"<html>Not Found</html>")),
+            Response::Error {
+                error: QueryError::NotADohEndpoint { status: 404, content_type: None }
+            }
+        );
+        let mut html = stream(b"200", None, &message(1, 0));
+        html.as_mut().unwrap().headers.push(h3::Header::new(b"content-type", b"text/html"));
+        assert_eq!(
+            cache.respond(QUERY, CONNECTION, html),
+            Response::Error {
+                error: QueryError::NotADohEndpoint {
+                    status: 200,
+                    content_type: Some("text/html".to_string())
+                }
+            }
+        );
+        let mut dns = stream(b"200", None, &message(1, 0));
+        dns.as_mut()
+            .unwrap()
+            .headers
+            .push(h3::Header::new(b"content-type", b"application/dns-message"));
+        assert_eq!(answer(cache.respond(QUERY, CONNECTION, dns)), message(1, 0));
+    }
+}
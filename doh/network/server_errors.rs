@@ -0,0 +1,137 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Decides how a network reacts to HTTP 5xx answers from its server
+
+use crate::boot_time::{BootTime, Duration};
+use crate::dispatcher::QueryError;
+
+const HTTP_INTERNAL_SERVER_ERROR: u16 = 500;
+
+/// What a network does when its server answers a query with an HTTP 5xx status.
+///
+/// A query still failing after this ends with `QueryError::ServerError`, which the resolver treats
+/// like any other failure of the server: by failing over to the next one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServerErrorPolicy {
+    /// Whether a query answered with 500 Internal Server Error is sent once more before failing.
+    pub retry_internal_error: bool,
+    /// Longest `Retry-After` delay to honor. While the delay runs, queries fail without being
+    /// sent, so the resolver fails over without waiting on a server which said it is unavailable.
+    /// `None` ignores `Retry-After`.
+    pub max_retry_after: Option<Duration>,
+}
+
+impl ServerErrorPolicy {
+    /// Whether a query which failed with `error` should be sent again. Queries are retried once
+    /// at most, which is up to the caller to track.
+    pub fn should_retry(&self, error: &QueryError) -> bool {
+        self.retry_internal_error
+            && matches!(error, QueryError::ServerError { status: HTTP_INTERNAL_SERVER_ERROR, .. })
+    }
+}
+
+impl Default for ServerErrorPolicy {
+    fn default() -> Self {
+        Self { retry_internal_error: true, max_retry_after: Some(Duration::from_secs(300)) }
+    }
+}
+
+/// Remembers how long the server asked to be left alone for.
+#[derive(Debug, Default)]
+pub struct Backoff {
+    // The status which came with the request, and when the delay runs out.
+    until: Option<(u16, BootTime)>,
+}
+
+impl Backoff {
+    /// Takes a query which failed with `error` at `now` into account. Only a 5xx carrying
+    /// `Retry-After` starts a delay, and a shorter one doesn't cut a running delay short.
+    pub fn observe(&mut self, policy: &ServerErrorPolicy, error: &QueryError, now: BootTime) {
+        let (status, delay, max) = match (error, policy.max_retry_after) {
+            (QueryError::ServerError { status, retry_after: Some(delay) }, Some(max)) => {
+                (*status, *delay, max)
+            }
+            _ => return,
+        };
+        if let Some(until) = now.checked_add(delay.min(max)) {
+            if !matches!(self.until, Some((_, later)) if later >= until) {
+                self.until = Some((status, until));
+            }
+        }
+    }
+
+    /// The error to fail a query with instead of sending it at `now`, if the delay is running.
+    pub fn check(&self, now: BootTime) -> Option<QueryError> {
+        let (status, until) = self.until?;
+        let remaining = until.checked_duration_since(now).filter(|d| !d.is_zero())?;
+        Some(QueryError::ServerError { status, retry_after: Some(remaining) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Backoff, ServerErrorPolicy};
+    use crate::boot_time::{Clock, Duration, MockClock};
+    use crate::dispatcher::QueryError;
+
+    fn server_error(status: u16, retry_after: Option<u64>) -> QueryError {
+        QueryError::ServerError { status, retry_after: retry_after.map(Duration::from_secs) }
+    }
+
+    #[test]
+    fn internal_error_retried() {
+        let policy = ServerErrorPolicy::default();
+        assert!(policy.should_retry(&server_error(500, None)));
+        assert!(!policy.should_retry(&server_error(503, None)));
+        assert!(!policy.should_retry(&QueryError::ConnectionError));
+        let policy = ServerErrorPolicy { retry_internal_error: false, ..Default::default() };
+        assert!(!policy.should_retry(&server_error(500, None)));
+    }
+
+    #[test]
+    fn retry_after_honored() {
+        let clock = MockClock::new();
+        let policy = ServerErrorPolicy::default();
+        let mut backoff = Backoff::default();
+        backoff.observe(&policy, &server_error(503, None), clock.now());
+        assert_eq!(backoff.check(clock.now()), None);
+        backoff.observe(&policy, &server_error(503, Some(30)), clock.now());
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(backoff.check(clock.now()), Some(server_error(503, Some(20))));
+        // A shorter delay doesn't end the running one early.
+        backoff.observe(&policy, &server_error(502, Some(5)), clock.now());
+        assert_eq!(backoff.check(clock.now()), Some(server_error(503, Some(20))));
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(backoff.check(clock.now()), None);
+    }
+
+    #[test]
+    fn retry_after_capped() {
+        let clock = MockClock::new();
+        let mut backoff = Backoff::default();
+        let policy = ServerErrorPolicy {
+            max_retry_after: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        backoff.observe(&policy, &server_error(503, Some(3600)), clock.now());
+        assert_eq!(backoff.check(clock.now()), Some(server_error(503, Some(60))));
+        let policy = ServerErrorPolicy { max_retry_after: None, ..Default::default() };
+        let mut backoff = Backoff::default();
+        backoff.observe(&policy, &server_error(503, Some(30)), clock.now());
+        assert_eq!(backoff.check(clock.now()), None);
+    }
+}
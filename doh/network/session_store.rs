@@ -0,0 +1,274 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Keeps TLS session tickets so that connections can resume, even across a process restart
+
+use crate::boot_time::{self, BootTime, Duration, SharedClock};
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::sync::Mutex;
+
+// Leads every export, so that data from something else is never mistaken for tickets.
+const MAGIC: &[u8] = b"DOHS";
+// Bumped whenever the layout of an export changes. Exports in another format are discarded.
+const FORMAT_VERSION: u8 = 3;
+
+/// Resumable sessions by network and server.
+///
+/// Sessions are only recorded and used for servers with `ServerInfo::use_session_resumption` set.
+/// A session is only ever resumed on the network it was recorded on, so that a server can't use
+/// the ticket to link a device's traffic across networks.
+/// `export` and `import` let the resolver persist them, so the first query after a restart can
+/// still resume a session.
+pub struct SessionStore {
+    sessions: Mutex<HashMap<(u32, String), Session>>,
+    max_age: Mutex<Option<Duration>>,
+    clock: SharedClock,
+}
+
+struct Session {
+    ticket: Vec<u8>,
+    recorded: BootTime,
+}
+
+impl Default for SessionStore {
+    fn default() -> Self {
+        Self::new(boot_time::system_clock())
+    }
+}
+
+// Takes `len` bytes off the front of `bytes`, if there are that many.
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if bytes.len() < len {
+        return None;
+    }
+    let (head, tail) = bytes.split_at(len);
+    *bytes = tail;
+    Some(head)
+}
+
+impl SessionStore {
+    /// Creates an empty store which measures the age of sessions on `clock`.
+    pub fn new(clock: SharedClock) -> Self {
+        Self { sessions: Default::default(), max_age: Default::default(), clock }
+    }
+
+    /// Limits how long a session may be resumed after it was recorded, so that connections made
+    /// far apart in time can't be linked by the ticket they present. Older sessions are discarded
+    /// and the next connection to their server does a full handshake. If `None`, which is the
+    /// default, sessions are kept for as long as the server allows them to be resumed.
+    pub fn set_max_age(&self, max_age: Option<Duration>) {
+        *self.max_age.lock().unwrap() = max_age;
+    }
+
+    /// Records the latest session for `server` on network `net_id`, replacing any earlier one.
+    pub fn insert(&self, net_id: u32, server: &str, session: Vec<u8>) {
+        let session = Session { ticket: session, recorded: self.clock.now() };
+        self.sessions.lock().unwrap().insert((net_id, server.to_string()), session);
+    }
+
+    /// The session to resume with `server` on network `net_id`, if one is known and isn't too
+    /// old.
+    pub fn get(&self, net_id: u32, server: &str) -> Option<Vec<u8>> {
+        let key = (net_id, server.to_string());
+        let mut sessions = self.sessions.lock().unwrap();
+        let age = self.clock.elapsed(sessions.get(&key)?.recorded);
+        if self.too_old(age) {
+            debug!("Discarding session for {} recorded {:?} ago", server, age);
+            sessions.remove(&key);
+            return None;
+        }
+        sessions.get(&key).map(|session| session.ticket.clone())
+    }
+
+    /// Discards every session recorded on network `net_id`.
+    pub fn clear_network(&self, net_id: u32) {
+        self.sessions.lock().unwrap().retain(|(session_net_id, _), _| *session_net_id != net_id);
+    }
+
+    fn too_old(&self, age: Duration) -> bool {
+        match *self.max_age.lock().unwrap() {
+            Some(max_age) => age > max_age,
+            None => false,
+        }
+    }
+
+    /// Serializes every session which isn't too old, tagged with the QUIC version it was
+    /// negotiated for, its network and its age.
+    pub fn export(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(FORMAT_VERSION);
+        for ((net_id, server), session) in self.sessions.lock().unwrap().iter() {
+            let age = self.clock.elapsed(session.recorded);
+            if self.too_old(age) {
+                continue;
+            }
+            out.extend_from_slice(&quiche::PROTOCOL_VERSION.to_be_bytes());
+            out.extend_from_slice(&net_id.to_be_bytes());
+            out.extend_from_slice(&(age.as_millis() as u64).to_be_bytes());
+            out.extend_from_slice(&(server.len() as u32).to_be_bytes());
+            out.extend_from_slice(server.as_bytes());
+            out.extend_from_slice(&(session.ticket.len() as u32).to_be_bytes());
+            out.extend_from_slice(&session.ticket);
+        }
+        out
+    }
+
+    /// Loads sessions written by `export`, returning how many were kept. Sessions from another
+    /// QUIC version, or an export in a format this version doesn't understand, are discarded
+    /// rather than reported as errors: the only cost is a full handshake. Sessions keep the age
+    /// they were exported with, though time spent between the export and the import isn't
+    /// counted.
+    pub fn import(&self, mut bytes: &[u8]) -> usize {
+        if take(&mut bytes, MAGIC.len()) != Some(MAGIC) {
+            warn!("Discarding sessions which weren't exported by a SessionStore");
+            return 0;
+        }
+        match take(&mut bytes, 1) {
+            Some([FORMAT_VERSION]) => (),
+            format => {
+                debug!("Discarding sessions in unsupported format {:?}", format);
+                return 0;
+            }
+        }
+        let now = self.clock.now();
+        let mut sessions = self.sessions.lock().unwrap();
+        let mut imported = 0;
+        while !bytes.is_empty() {
+            let entry = (|| {
+                let version = u32::from_be_bytes(take(&mut bytes, 4)?.try_into().ok()?);
+                let net_id = u32::from_be_bytes(take(&mut bytes, 4)?.try_into().ok()?);
+                let age = u64::from_be_bytes(take(&mut bytes, 8)?.try_into().ok()?);
+                let server_len = u32::from_be_bytes(take(&mut bytes, 4)?.try_into().ok()?);
+                let server = String::from_utf8(take(&mut bytes, server_len as usize)?.to_vec());
+                let session_len = u32::from_be_bytes(take(&mut bytes, 4)?.try_into().ok()?);
+                let ticket = take(&mut bytes, session_len as usize)?.to_vec();
+                Some((version, net_id, Duration::from_millis(age), server.ok(), ticket))
+            })();
+            match entry {
+                Some((quiche::PROTOCOL_VERSION, net_id, age, Some(server), ticket)) => {
+                    match now.checked_sub(age) {
+                        Some(recorded) if !self.too_old(age) => {
+                            sessions.insert((net_id, server), Session { ticket, recorded });
+                            imported += 1;
+                        }
+                        _ => debug!("Discarding session for {} recorded {:?} ago", server, age),
+                    }
+                }
+                Some((version, _, _, server, _)) => {
+                    debug!("Discarding session for {:?} from QUIC version {:x}", server, version)
+                }
+                None => {
+                    warn!("Discarding truncated session export");
+                    break;
+                }
+            }
+        }
+        imported
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SessionStore, FORMAT_VERSION, MAGIC};
+    use crate::boot_time::{Duration, MockClock};
+
+    const SERVER: &str = "https://dns.example.com/dns-query";
+    const NET_ID: u32 = 100;
+
+    #[test]
+    fn export_import() {
+        let store = SessionStore::default();
+        store.insert(NET_ID, SERVER, vec![1, 2, 3]);
+        store.insert(NET_ID, "https://other.example.com/dns-query", vec![4; 300]);
+        let restored = SessionStore::default();
+        assert_eq!(restored.import(&store.export()), 2);
+        assert_eq!(restored.get(NET_ID, SERVER), Some(vec![1, 2, 3]));
+        assert_eq!(restored.get(NET_ID, "https://other.example.com/dns-query"), Some(vec![4; 300]));
+        assert_eq!(restored.get(NET_ID, "https://unknown.example.com/dns-query"), None);
+    }
+
+    #[test]
+    fn per_network() {
+        let store = SessionStore::default();
+        store.insert(NET_ID, SERVER, vec![1]);
+        store.insert(NET_ID + 1, SERVER, vec![2]);
+        // A session recorded on one network is never resumed on another.
+        assert_eq!(store.get(NET_ID + 2, SERVER), None);
+        let restored = SessionStore::default();
+        assert_eq!(restored.import(&store.export()), 2);
+        assert_eq!(restored.get(NET_ID + 1, SERVER), Some(vec![2]));
+
+        store.clear_network(NET_ID);
+        assert_eq!(store.get(NET_ID, SERVER), None);
+        assert_eq!(store.get(NET_ID + 1, SERVER), Some(vec![2]));
+    }
+
+    #[test]
+    fn discard_incompatible() {
+        let store = SessionStore::default();
+        assert_eq!(store.import(b"garbage"), 0);
+
+        let mut other_format = MAGIC.to_vec();
+        other_format.push(FORMAT_VERSION + 1);
+        other_format.extend_from_slice(&[0; 16]);
+        assert_eq!(store.import(&other_format), 0);
+
+        // A session from another QUIC version is skipped, without losing the ones after it.
+        let mut export = MAGIC.to_vec();
+        export.push(FORMAT_VERSION);
+        for (version, session) in [(0xff00_001d_u32, 7), (quiche::PROTOCOL_VERSION, 8)].iter() {
+            export.extend_from_slice(&version.to_be_bytes());
+            export.extend_from_slice(&NET_ID.to_be_bytes());
+            export.extend_from_slice(&0u64.to_be_bytes());
+            export.extend_from_slice(&(SERVER.len() as u32).to_be_bytes());
+            export.extend_from_slice(SERVER.as_bytes());
+            export.extend_from_slice(&1u32.to_be_bytes());
+            export.push(*session);
+        }
+        assert_eq!(store.import(&export), 1);
+        assert_eq!(store.get(NET_ID, SERVER), Some(vec![8]));
+
+        // A truncated entry is dropped.
+        let truncated = &export[..export.len() - 1];
+        assert_eq!(SessionStore::default().import(truncated), 0);
+    }
+
+    #[test]
+    fn max_age() {
+        let clock = MockClock::new();
+        let store = SessionStore::new(clock.clone());
+        store.insert(NET_ID, SERVER, vec![1]);
+        clock.advance(Duration::from_secs(3600));
+        // By default, sessions are kept however old they are.
+        assert_eq!(store.get(NET_ID, SERVER), Some(vec![1]));
+
+        store.set_max_age(Some(Duration::from_secs(600)));
+        store.insert(NET_ID, "https://other.example.com/dns-query", vec![2]);
+        let restored = SessionStore::new(clock.clone());
+        restored.set_max_age(Some(Duration::from_secs(600)));
+        assert_eq!(restored.import(&store.export()), 1);
+        assert_eq!(store.get(NET_ID, SERVER), None);
+
+        // An imported session carries its age with it.
+        clock.advance(Duration::from_secs(500));
+        assert_eq!(restored.get(NET_ID, "https://other.example.com/dns-query"), Some(vec![2]));
+        clock.advance(Duration::from_secs(101));
+        assert_eq!(restored.get(NET_ID, "https://other.example.com/dns-query"), None);
+        assert_eq!(store.get(NET_ID, "https://other.example.com/dns-query"), None);
+    }
+}
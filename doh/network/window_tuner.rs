@@ -0,0 +1,127 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Sizes connection flow-control windows from the bandwidth-delay product of earlier connections
+
+use crate::boot_time::{BootTime, Duration};
+use crate::connection::QueryStats;
+
+/// Tracks the bandwidth-delay product seen on a network's connections, and picks the connection
+/// flow-control window for the next one.
+///
+/// The quiche version we build against fixes a connection's window when it is created, so tuning
+/// only takes effect as connections are replaced. Stream windows stay sized by the response size
+/// cap, which already bounds what a single stream needs.
+#[derive(Debug)]
+pub struct WindowTuner {
+    cap: u64,
+    // Largest bandwidth-delay product seen, in bytes, and when it was last raised or confirmed.
+    bdp: Option<(u64, BootTime)>,
+}
+
+impl WindowTuner {
+    /// Smallest window handed out, however slow the path. Streams which are abandoned keep
+    /// holding their share of the window, so a smaller one could be used up by a few of them.
+    pub const MIN_WINDOW: u64 = 1 << 20;
+    // How long the estimate takes to halve while nothing is measured.
+    const IDLE_HALF_LIFE: Duration = Duration::from_secs(30);
+
+    /// Creates a tuner whose windows never exceed `cap`, or `MIN_WINDOW` if that is larger.
+    pub fn new(cap: u64) -> Self {
+        Self { cap: cap.max(Self::MIN_WINDOW), bdp: None }
+    }
+
+    /// Takes the RTT and delivery rate a completed query saw into account.
+    pub fn observe(&mut self, stats: &QueryStats, now: BootTime) {
+        let bdp = (stats.rtt.as_secs_f64() * stats.delivery_rate as f64) as u64;
+        let current = self.decayed(now);
+        if bdp >= current {
+            self.bdp = Some((bdp, now));
+        }
+    }
+
+    /// The window for a connection created at `now`: twice the estimated bandwidth-delay product,
+    /// so the sender needn't stall waiting for window updates, rounded up to a power of two so that
+    /// small fluctuations don't change it.
+    pub fn window(&self, now: BootTime) -> u64 {
+        let wanted = self.decayed(now).saturating_mul(2).checked_next_power_of_two();
+        wanted.unwrap_or(u64::MAX).max(Self::MIN_WINDOW).min(self.cap)
+    }
+
+    // The estimate, halved for every `IDLE_HALF_LIFE` since it was last confirmed.
+    fn decayed(&self, now: BootTime) -> u64 {
+        match self.bdp {
+            None => 0,
+            Some((bdp, at)) => {
+                let idle = now.checked_duration_since(at).unwrap_or_default();
+                let halvings = idle.as_secs() / Self::IDLE_HALF_LIFE.as_secs();
+                bdp.checked_shr(halvings as u32).unwrap_or(0)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WindowTuner;
+    use crate::boot_time::{Clock, Duration, MockClock};
+    use crate::connection::QueryStats;
+
+    fn stats(rtt_ms: u64, delivery_rate: u64) -> QueryStats {
+        QueryStats { rtt: Duration::from_millis(rtt_ms), delivery_rate, ..Default::default() }
+    }
+
+    #[test]
+    fn grows_to_fit_path() {
+        let clock = MockClock::new();
+        let mut tuner = WindowTuner::new(64 << 20);
+        assert_eq!(tuner.window(clock.now()), WindowTuner::MIN_WINDOW);
+        // A slow path never goes below the minimum.
+        tuner.observe(&stats(20, 100_000), clock.now());
+        assert_eq!(tuner.window(clock.now()), WindowTuner::MIN_WINDOW);
+        // 300ms at 40MB/s holds 12MB in flight.
+        tuner.observe(&stats(300, 40_000_000), clock.now());
+        assert_eq!(tuner.window(clock.now()), 32 << 20);
+        // A lower sample right after doesn't undo what was seen.
+        tuner.observe(&stats(300, 1_000_000), clock.now());
+        assert_eq!(tuner.window(clock.now()), 32 << 20);
+        tuner.observe(&stats(1000, 1_000_000_000), clock.now());
+        assert_eq!(tuner.window(clock.now()), 64 << 20);
+    }
+
+    #[test]
+    fn shrinks_when_idle() {
+        let clock = MockClock::new();
+        let mut tuner = WindowTuner::new(64 << 20);
+        tuner.observe(&stats(300, 40_000_000), clock.now());
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(tuner.window(clock.now()), 16 << 20);
+        // Once the estimate has decayed below a new sample, the sample takes over.
+        clock.advance(Duration::from_secs(30));
+        tuner.observe(&stats(100, 40_000_000), clock.now());
+        assert_eq!(tuner.window(clock.now()), 8 << 20);
+        clock.advance(Duration::from_secs(600));
+        assert_eq!(tuner.window(clock.now()), WindowTuner::MIN_WINDOW);
+    }
+
+    #[test]
+    fn cap_below_minimum() {
+        let clock = MockClock::new();
+        let mut tuner = WindowTuner::new(1024);
+        tuner.observe(&stats(300, 40_000_000), clock.now());
+        assert_eq!(tuner.window(clock.now()), WindowTuner::MIN_WINDOW);
+    }
+}
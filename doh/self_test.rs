@@ -0,0 +1,95 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Sends a DNS query through DoH encoding and a `Connection`, the same driver production queries
+//! go through, to a server on the loopback interface, and checks that the answer comes back
+//! intact. Built for tests, and with the `self_test` feature for sanity checks at boot through
+//! `doh_self_test()`, which catch encoding and driver regressions without a network.
+
+use crate::boot_time::{system_clock, Duration};
+use crate::config::Config;
+use crate::connection::loopback::{client_key, DohServer, Reply};
+use crate::connection::{Connection, Environment, Peer, SocketBinding};
+use crate::dispatcher::Response;
+use crate::encoding;
+use anyhow::{bail, ensure, Context, Result};
+use std::sync::mpsc;
+use url::Url;
+
+const URL: &str = "https://dns.example.com/dns-query";
+const TTL: u32 = 300;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Runs a query end to end against an in-process server, and reports the first thing which
+/// didn't survive the trip.
+pub async fn self_test() -> Result<()> {
+    let query = base64::decode_config(encoding::probe_query()?, base64::URL_SAFE_NO_PAD)?;
+    let (received_tx, received_rx) = mpsc::channel();
+    let server = DohServer::start(Box::new(move |_, query| {
+        let _ = received_tx.send(query.to_vec());
+        match answer(query) {
+            Some(answer) => Reply::Answer(answer),
+            None => Reply::Close,
+        }
+    }))?;
+    let mut config = Config::from_key(&client_key(TIMEOUT.as_millis() as u64))?;
+    let binding = SocketBinding::Mark(0);
+    let peer = Peer { server_name: None, addr: server.addr, net_id: 0, binding: &binding };
+    let env = Environment::for_test(system_clock(), Default::default());
+    let mut connection = Connection::new(peer, &mut config, None, Default::default(), &env).await?;
+    ensure!(connection.wait_for_live().await, "Handshake did not complete");
+
+    let response = connection.dns_query(&Url::parse(URL)?, &query, TIMEOUT).await?.await;
+    let received = received_rx.try_recv().context("No request arrived")?;
+    ensure!(received == query, "Query changed");
+    let (answer, metadata) = match response {
+        Response::Success { answer, metadata } => (answer, metadata),
+        Response::Error { error } => bail!("Answer rejected: {:?}", error),
+    };
+    ensure!(answer[..2] == received[..2], "Answer is for another query");
+    ensure!(metadata.rcode == 0 && metadata.answer_count == 1, "Bad answer {:?}", metadata);
+    ensure!(metadata.min_ttl == Some(TTL), "Answer TTL changed");
+    Ok(())
+}
+
+// Answers `query` with a single AAAA record, or `None` if it is too short to be one.
+fn answer(query: &[u8]) -> Option<Vec<u8>> {
+    if query.len() <= 12 {
+        return None;
+    }
+    let mut answer = query.to_vec();
+    // QR, and one answer.
+    answer[2] |= 0x80;
+    answer[7] = 1;
+    #[rustfmt::skip]
+    answer.extend_from_slice(&[
+        0xc0, 12,  // pointer to the question's name
+        0, 28,     // AAAA
+        0, 1,      // IN
+    ]);
+    answer.extend_from_slice(&TTL.to_be_bytes());
+    answer.extend_from_slice(&16u16.to_be_bytes());
+    answer.extend_from_slice(&std::net::Ipv6Addr::LOCALHOST.octets());
+    Some(answer)
+}
+
+#[cfg(test)]
+mod tests {
+    #[tokio::test]
+    async fn round_trip() {
+        super::self_test().await.unwrap();
+    }
+}
@@ -57,6 +57,8 @@ TEST(DoHFFITest, SmokeTest) {
             .probe_timeout_ms = TIMEOUT_MS,
             .idle_timeout_ms = TIMEOUT_MS,
             .use_session_resumption = true,
+            .connect_timeout_ms = TIMEOUT_MS,
+            .query_timeout_ms = TIMEOUT_MS,
     };
 
     // TODO: Use a local server instead of dns.google.